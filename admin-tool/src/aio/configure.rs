@@ -164,6 +164,7 @@ impl Configuration {
             files: None,
             commands: None,
             diskencryption_clevis: None,
+            fdo_sys: None,
             additional_serviceinfo: None,
         })
     }
@@ -205,10 +206,17 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
                 AbsolutePathBuf::new(aio_dir.join("keys").join("manufacturer_cert.pem"))
                     .expect("Failed to build absolute path"),
             ),
+            trusted_device_keys_path: Some(
+                AbsolutePathBuf::new(aio_dir.join("keys").join("device_ca_cert.pem"))
+                    .expect("Failed to build absolute path"),
+            ),
+            allow_untrusted_device_certificates: false,
 
             max_wait_seconds: None,
 
             bind: get_bind(config_args.listen_port_rendezvous_server)?,
+            tls: None,
+            coap_bind: None,
         };
     write_config(aio_dir, "rendezvous_server.yml", &rendezvous_config)
         .context("Error writing rendezvous server configuration file")?;
@@ -221,6 +229,7 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
                 .context("Error generating serviceinfo settings")?,
 
             bind: get_bind(config_args.listen_port_serviceinfo_api_server)?,
+            tls: None,
 
             service_info_auth_token: config_args.serviceinfo_api_auth_token.clone(),
             admin_auth_token: Some(config_args.serviceinfo_api_admin_token.clone()),
@@ -252,6 +261,7 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
             },
 
             bind: get_bind(config_args.listen_port_manufacturing_server)?,
+            tls: None,
 
             ownership_voucher_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join(if config_args.separate_manufacturing_and_owner_voucher_store {
@@ -287,8 +297,8 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
                 .context("Error generating rendezvous info")?,
             manufacturing: fdo_util::servers::configuration::manufacturing_server::ManufacturingSettings {
                 manufacturer_cert_path: AbsolutePathBuf::new(aio_dir.join("keys").join("manufacturer_cert.pem")).unwrap(),
-                manufacturer_private_key: Some(AbsolutePathBuf::new(aio_dir.join("keys").join("manufacturer_key.der")).unwrap()),
-                device_cert_ca_private_key: AbsolutePathBuf::new(aio_dir.join("keys").join("device_ca_key.der")).unwrap(),
+                manufacturer_private_key: Some(AbsolutePathBuf::new(aio_dir.join("keys").join("manufacturer_key.der")).unwrap().into()),
+                device_cert_ca_private_key: AbsolutePathBuf::new(aio_dir.join("keys").join("device_ca_key.der")).unwrap().into(),
                 device_cert_ca_chain: AbsolutePathBuf::new(aio_dir.join("keys").join("device_ca_cert.pem")).unwrap(),
                 owner_cert_path: Some(AbsolutePathBuf::new(aio_dir.join("keys").join("owner_cert.pem")).unwrap()),
             }
@@ -308,23 +318,48 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
             },
 
             bind: get_bind(config_args.listen_port_owner_onboarding_server)?,
+            tls: None,
+            coap_bind: None,
 
             ownership_voucher_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join("owner_vouchers"),
             },
+            resale_ownership_voucher_store_driver: Some(StoreConfig::Directory {
+                path: aio_dir.join("stores").join("resale_vouchers"),
+            }),
+            credential_reuse: false,
+            allowed_kex_suites: None,
+            allowed_cipher_suites: None,
             trusted_device_keys_path: AbsolutePathBuf::new(
                 aio_dir.join("keys").join("device_ca_cert.pem"),
             )
             .unwrap(),
+            allow_untrusted_device_certificates: false,
             owner_private_key_path: AbsolutePathBuf::new(
                 aio_dir.join("keys").join("owner_key.der"),
             )
-            .unwrap(),
+            .unwrap()
+            .into(),
 
             owner_public_key_path: AbsolutePathBuf::new(
                 aio_dir.join("keys").join("owner_cert.pem"),
             )
             .unwrap(),
+            additional_owner_keys: Vec::new(),
+            // Generated by the owner onboarding server on first start
+            owner2_key: Some(
+                fdo_util::servers::configuration::owner_onboarding_server::OwnerKeySettings {
+                    private_key_path: AbsolutePathBuf::new(
+                        aio_dir.join("keys").join("owner2_key.der"),
+                    )
+                    .unwrap()
+                    .into(),
+                    public_key_path: AbsolutePathBuf::new(
+                        aio_dir.join("keys").join("owner2_cert.pem"),
+                    )
+                    .unwrap(),
+                },
+            ),
             service_info_api_url: format!(
                 "http://localhost:{}/device_info",
                 config_args.listen_port_serviceinfo_api_server
@@ -337,6 +372,8 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
                 .generate_owner_addresses()
                 .context("Error generating owner addresses")?,
             report_to_rendezvous_endpoint_enabled: true,
+            to0: Default::default(),
+            ov_management_api: None,
         };
     write_config(
         aio_dir,
@@ -391,6 +428,7 @@ pub(super) fn generate_configs_and_keys(
         "manufacturing_sessions",
         "rendezvous_registered",
         "owner_vouchers",
+        "resale_vouchers",
         "serviceinfo_api_per_device",
         "manufacturer_keys",
     ] {
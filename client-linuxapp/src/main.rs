@@ -1,4 +1,4 @@
-use std::{borrow::Borrow, env, fs, path::PathBuf, thread, time};
+use std::{borrow::Borrow, env, fs, path::PathBuf, str::FromStr, thread, time};
 
 use anyhow::{anyhow, bail, Context, Result};
 use rand::Rng;
@@ -7,20 +7,23 @@ use thiserror::Error;
 use fdo_data_formats::{
     cborparser::ParsedArray,
     constants::{
-        DeviceSigType, ErrorCode, HeaderKeys, MessageType, RendezvousProtocolValue,
-        TransportProtocol,
+        DeviceSigType, ErrorCode, HashType, HeaderKeys, MessageType, RendezvousProtocolValue,
+        TransportProtocol, DEFAULT_MAX_SERVICE_INFO_SIZE,
     },
     enhanced_types::{RendezvousInterpretedDirective, RendezvousInterpreterSide},
     messages,
     ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::PublicKey,
     types::{
-        new_eat, COSEHeaderMap, COSESign, CipherSuite, EATokenPayload, HMac, KexSuite,
-        KeyDeriveSide, KeyExchange, Nonce, PayloadCreating, SigInfo, TO1DataPayload,
-        TO2AddressEntry, TO2ProveDevicePayload, TO2ProveOVHdrPayload, UnverifiedValue,
+        new_eat, COSEHeaderMap, COSESign, CipherSuite, EATokenPayload, Guid, HMac, Hash, KexSuite,
+        KeyDeriveSide, KeyExchange, Nonce, PayloadCreating, ProveOVHdrPayload, RendezvousInfo,
+        SigInfo, TO1DataPayload, TO2AddressEntry, TO2ProveDevicePayload, TO2ProveOVHdrPayload,
+        TO2SetupDevicePayload, UnverifiedValue,
     },
     DeviceCredential, ProtocolVersion, Serializable,
 };
-use fdo_http_wrapper::client::{RequestResult, ServiceClient};
+use fdo_http_wrapper::client::{CertificatePins, RequestResult, ServiceClient};
+use fdo_http_wrapper::EncryptionKeys;
 use fdo_util::device_credential_locations;
 use fdo_util::device_credential_locations::UsableDeviceCredentialLocation;
 
@@ -89,6 +92,61 @@ async fn send_client_error(
     let _: RequestResult<messages::v11::ErrorMessage> = client.send_request(message, None).await;
 }
 
+/// Messages that have a protocol 1.0 counterpart carrying the same information
+trait V10Message: messages::Message {
+    type V10: messages::Message;
+}
+
+macro_rules! v10_messages {
+    ($($protocol:ident::$name:ident),* $(,)?) => {
+        $(
+            impl V10Message for messages::v11::$protocol::$name {
+                type V10 = messages::v10::$protocol::$name;
+            }
+        )*
+    };
+}
+
+v10_messages!(
+    to1::HelloRV,
+    to1::HelloRVAck,
+    to1::ProveToRV,
+    to1::RVRedirect,
+    to2::HelloDevice,
+    to2::ProveOVHdr,
+    to2::GetOVNextEntry,
+    to2::OVNextEntry,
+    to2::ProveDevice,
+    to2::SetupDevice,
+    to2::DeviceServiceInfoReady,
+    to2::OwnerServiceInfoReady,
+    to2::DeviceServiceInfo,
+    to2::OwnerServiceInfo,
+    to2::Done,
+    to2::Done2,
+);
+
+/// Sends a request in the protocol version the client was set up for
+pub(crate) async fn send_versioned_request<OM, SM>(
+    client: &mut ServiceClient,
+    to_send: OM,
+    new_keys: Option<EncryptionKeys>,
+) -> RequestResult<SM>
+where
+    OM: messages::ClientMessage + V10Message,
+    OM::V10: messages::ClientMessage + From<OM>,
+    SM: messages::ServerMessage + V10Message + From<SM::V10>,
+    SM::V10: messages::ServerMessage,
+{
+    match client.protocol_version() {
+        ProtocolVersion::Version1_0 => client
+            .send_request::<OM::V10, SM::V10>(to_send.into(), new_keys)
+            .await
+            .map(SM::from),
+        _ => client.send_request(to_send, new_keys).await,
+    }
+}
+
 fn mark_device_onboarding_executed() -> Result<()> {
     fs::write(&marker_file_location(), "executed").context("Error creating executed marker file")
 }
@@ -100,6 +158,7 @@ fn get_to2_urls(entries: &[TO2AddressEntry]) -> Vec<String> {
         let prot_text = match addr_entry.protocol() {
             TransportProtocol::Http => "http",
             TransportProtocol::Https => "https",
+            TransportProtocol::CoAP => "coap",
             _ => continue,
         };
         if let Some(dns_name) = addr_entry.dns() {
@@ -123,7 +182,17 @@ fn get_to2_urls(entries: &[TO2AddressEntry]) -> Vec<String> {
     urls
 }
 
-async fn get_client_list(rv_entry: &RendezvousInterpretedDirective) -> Result<Vec<ServiceClient>> {
+fn get_certificate_pins(rv_entry: &RendezvousInterpretedDirective) -> CertificatePins {
+    CertificatePins {
+        server_certificate_hash: rv_entry.server_certificate_hash.clone(),
+        ca_certificate_hash: rv_entry.ca_certificate_hash.clone(),
+    }
+}
+
+async fn get_client_list(
+    protocol_version: ProtocolVersion,
+    rv_entry: &RendezvousInterpretedDirective,
+) -> Result<Vec<ServiceClient>> {
     log::trace!("Getting client list from rv_entry {:?}", rv_entry);
     let mut service_client_list = Vec::new();
 
@@ -131,25 +200,27 @@ async fn get_client_list(rv_entry: &RendezvousInterpretedDirective) -> Result<Ve
     if urls.is_empty() {
         log::trace!("No URLs found");
     }
-    if rv_entry.bypass {
-        bail!("Rendezvous Bypass is not yet implemented");
-    }
     if rv_entry.wifi_ssid.is_some() {
         bail!("Rendezvous WiFi configuration is not yet implemented");
     }
     if rv_entry.user_input {
         bail!("Rendezvous User Input is not yet implemented");
     }
-    if rv_entry.protocol != RendezvousProtocolValue::Http
-        && rv_entry.protocol != RendezvousProtocolValue::Https
-    {
-        bail!("Non-HTTP(S) protocol is not implemented");
+    if !matches!(
+        rv_entry.protocol,
+        RendezvousProtocolValue::Http
+            | RendezvousProtocolValue::Https
+            | RendezvousProtocolValue::CoAPUDP
+            | RendezvousProtocolValue::CoAPTCP
+    ) {
+        bail!("Non-HTTP(S) or CoAP protocol is not implemented");
     }
+    let pins = get_certificate_pins(rv_entry);
     for url in &urls {
-        service_client_list.push(fdo_http_wrapper::client::ServiceClient::new(
-            ProtocolVersion::Version1_1,
-            url,
-        ));
+        match ServiceClient::new_with_pins(protocol_version, url, &pins) {
+            Ok(client) => service_client_list.push(client),
+            Err(e) => log::trace!("Error {:?} setting up client for URL {}", e, url),
+        }
     }
     log::trace!("Client list: {:?}", service_client_list);
     Ok(service_client_list)
@@ -160,14 +231,25 @@ async fn perform_hellorv(
     devcred: &dyn DeviceCredential,
     client: &mut ServiceClient,
 ) -> Result<COSESign, ClientError> {
-    let sig_type = DeviceSigType::StSECP384R1;
+    let sig_type = devcred
+        .get_signer()
+        .and_then(|signer| signer.cose_signing_key().device_sig_type())
+        .context("Error determining device signature type")
+        .map_err(|e| {
+            ClientError::Request(ErrorResult::new(
+                ErrorCode::InternalServerError,
+                "Error determining device signature type",
+                MessageType::TO1HelloRV,
+                e,
+            ))
+        })?;
 
     let hello_rv = messages::v11::to1::HelloRV::new(
         devcred.device_guid().clone(),
         SigInfo::new(sig_type, vec![]),
     );
     let hello_rv_ack: RequestResult<messages::v11::to1::HelloRVAck> =
-        client.send_request(hello_rv, None).await;
+        send_versioned_request(client, hello_rv, None).await;
 
     let hello_rv_ack = hello_rv_ack.context("Error sending HelloRV").map_err(|e| {
         ClientError::Request(ErrorResult::new(
@@ -243,7 +325,7 @@ async fn perform_provetorv(
 ) -> Result<COSESign, ClientError> {
     let prove_to_rv = messages::v11::to1::ProveToRV::new(token);
     let rv_redirect: RequestResult<messages::v11::to1::RVRedirect> =
-        client.send_request(prove_to_rv, None).await;
+        send_versioned_request(client, prove_to_rv, None).await;
 
     let rv_redirect = rv_redirect
         .context("Error proving self to renvezvous server")
@@ -341,12 +423,12 @@ async fn get_ov_entries(
     let mut entries = ParsedArray::new_empty();
 
     for entry_num in 0..num_entries {
-        let entry_result: RequestResult<messages::v11::to2::OVNextEntry> = client
-            .send_request(
-                messages::v11::to2::GetOVNextEntry::new(entry_num as u8),
-                None,
-            )
-            .await;
+        let entry_result: RequestResult<messages::v11::to2::OVNextEntry> = send_versioned_request(
+            client,
+            messages::v11::to2::GetOVNextEntry::new(entry_num as u8),
+            None,
+        )
+        .await;
         let entry_result =
             entry_result.with_context(|| format!("Error getting OV entry num {}", entry_num))?;
 
@@ -379,26 +461,39 @@ async fn get_nonce(message_type: MessageType) -> Result<Nonce, ClientError> {
 }
 
 /// TO2: Sends HelloDevice, Receives ProveOVHdr
-async fn perform_hellodevice(
+///
+/// Returns None if the owner rejected the key exchange or cipher suite.
+async fn perform_hellodevice<P: ProveOVHdrPayload>(
     devcred: &dyn DeviceCredential,
     client: &mut ServiceClient,
     nonce5: Nonce,
     sigtype: DeviceSigType,
     kexsuite: KexSuite,
     ciphersuite: CipherSuite,
-) -> Result<(COSESign, UnverifiedValue<TO2ProveOVHdrPayload>, HMac), ClientError> {
-    let prove_ov_hdr: RequestResult<messages::v11::to2::ProveOVHdr> = client
-        .send_request(
-            messages::v11::to2::HelloDevice::new(
-                devcred.device_guid().clone(),
-                nonce5.clone(),
+) -> Result<Option<(COSESign, UnverifiedValue<P>, HMac)>, ClientError> {
+    let prove_ov_hdr: RequestResult<messages::v11::to2::ProveOVHdr> = send_versioned_request(
+        client,
+        messages::v11::to2::HelloDevice::new(
+            devcred.device_guid().clone(),
+            nonce5.clone(),
+            kexsuite,
+            ciphersuite,
+            SigInfo::new(sigtype, vec![]),
+        ),
+        None,
+    )
+    .await;
+    if let Err(fdo_http_wrapper::client::Error::Error(error)) = &prove_ov_hdr {
+        if matches!(error.error_code(), ErrorCode::MessageBodyError) {
+            log::info!(
+                "Owner rejected HelloDevice with {:?} and {:?}: {}",
                 kexsuite,
                 ciphersuite,
-                SigInfo::new(sigtype, vec![]),
-            ),
-            None,
-        )
-        .await;
+                error.error_string()
+            );
+            return Ok(None);
+        }
+    }
     let prove_ov_hdr = prove_ov_hdr
         .context("Error sending HelloDevice")
         .map_err(|e| {
@@ -413,7 +508,7 @@ async fn perform_hellodevice(
 
     // NOTE: At this moment, we have not yet validated the signature on it...
     // We can only do so after we got all of the OV parts..
-    let prove_ov_hdr_payload: UnverifiedValue<TO2ProveOVHdrPayload> = prove_ov_hdr
+    let prove_ov_hdr_payload: UnverifiedValue<P> = prove_ov_hdr
         .get_payload_unverified()
         .context("Error parsing unverified paylod")
         .map_err(|e| {
@@ -518,7 +613,7 @@ async fn perform_hellodevice(
                 ))
             })?;
     }
-    Ok((prove_ov_hdr, prove_ov_hdr_payload, header_hmac))
+    Ok(Some((prove_ov_hdr, prove_ov_hdr_payload, header_hmac)))
 }
 
 async fn get_nonce6(prove_ov_hdr: &COSESign) -> Result<Nonce, ClientError> {
@@ -547,13 +642,13 @@ async fn get_nonce6(prove_ov_hdr: &COSESign) -> Result<Nonce, ClientError> {
     Ok(nonce6)
 }
 
-async fn get_and_verify_ov_header_signature(
+async fn get_and_verify_ov_header_signature<P: ProveOVHdrPayload>(
     client: &mut ServiceClient,
     prove_ov_hdr: &COSESign,
-    prove_ov_hdr_payload: &UnverifiedValue<TO2ProveOVHdrPayload>,
+    prove_ov_hdr_payload: &UnverifiedValue<P>,
     header_hmac: HMac,
-    to1d: &COSESign,
-) -> Result<TO2ProveOVHdrPayload, ClientError> {
+    to1d: Option<&COSESign>,
+) -> Result<(P, PublicKey), ClientError> {
     // Get the other OV entries
     let ov_entries = get_ov_entries(
         client,
@@ -573,7 +668,7 @@ async fn get_and_verify_ov_header_signature(
     // At this moment, we have validated all we can, we'll check the signature later (After we get the final bits of the OV)
     let ownership_voucher = {
         let header = prove_ov_hdr_payload.get_unverified_value().ov_header();
-        OwnershipVoucher::from_parts(ProtocolVersion::Version1_1, header, header_hmac, ov_entries)
+        OwnershipVoucher::from_parts(client.protocol_version(), header, header_hmac, ov_entries)
     }
     .context("Error reconstructing Ownership Voucher")
     .map_err(|e| {
@@ -623,7 +718,7 @@ async fn get_and_verify_ov_header_signature(
     log::trace!("Got owner entry: {:?}", ov_owner_entry);
 
     // Now, we can finally verify the OV Header signature we got at the top!
-    let prove_ov_hdr_payload: TO2ProveOVHdrPayload = prove_ov_hdr
+    let prove_ov_hdr_payload: P = prove_ov_hdr
         .get_payload(ov_owner_entry.public_key().pkey())
         .context("Error validating ProveOVHdr signature")
         .map_err(|e| {
@@ -639,23 +734,26 @@ async fn get_and_verify_ov_header_signature(
         ov_owner_entry.public_key()
     );
 
-    // Verify that to1d was signed by the current owner
-    to1d.verify(ov_owner_entry.public_key().pkey())
-        .context("Error validating to1d after receiving full ownership voucher")
-        .map_err(|e| {
-            ClientError::Response(ErrorResult::new(
-                ErrorCode::InvalidMessageError,
-                "Error validating to1d after receiving full ownership voucher",
-                MessageType::TO2OVNextEntry,
-                e,
-            ))
-        })?;
-    Ok(prove_ov_hdr_payload)
+    // Verify that to1d was signed by the current owner, there is none with rendezvous bypass
+    if let Some(to1d) = to1d {
+        to1d.verify(ov_owner_entry.public_key().pkey())
+            .context("Error validating to1d after receiving full ownership voucher")
+            .map_err(|e| {
+                ClientError::Response(ErrorResult::new(
+                    ErrorCode::InvalidMessageError,
+                    "Error validating to1d after receiving full ownership voucher",
+                    MessageType::TO2OVNextEntry,
+                    e,
+                ))
+            })?;
+    }
+    Ok((prove_ov_hdr_payload, ov_owner_entry.public_key().clone()))
 }
 
-async fn perform_key_derivation(
+async fn perform_key_derivation<P: ProveOVHdrPayload>(
     client: &mut ServiceClient,
-    prove_ov_hdr_payload: TO2ProveOVHdrPayload,
+    prove_ov_hdr_payload: P,
+    owner_public_key: &PublicKey,
     kexsuite: KexSuite,
     ciphersuite: CipherSuite,
 ) -> Result<(KeyExchange, fdo_http_wrapper::EncryptionKeys), ClientError> {
@@ -672,6 +770,7 @@ async fn perform_key_derivation(
     // Perform the key derivation
     let a_key_exchange = prove_ov_hdr_payload.a_key_exchange();
     let b_key_exchange = KeyExchange::new(kexsuite)
+        .and_then(|kex| kex.with_owner_public_key(owner_public_key.pkey()))
         .context("Error creating device side of key exchange")
         .map_err(|e| {
             ClientError::Response(ErrorResult::new(
@@ -687,6 +786,7 @@ async fn perform_key_derivation(
             KeyDeriveSide::Device,
             ciphersuite,
             a_key_exchange,
+            None,
             non_interoperable_kdf_required,
         )
         .context("Error performing key derivation")
@@ -711,7 +811,7 @@ async fn perform_provedevice(
     nonce6: &Nonce,
     nonce7: &Nonce,
     new_keys: fdo_http_wrapper::EncryptionKeys,
-) -> Result<(), ClientError> {
+) -> Result<TO2SetupDevicePayload, ClientError> {
     let prove_device_payload = TO2ProveDevicePayload::new(
         b_key_exchange
             .get_public()
@@ -780,7 +880,7 @@ async fn perform_provedevice(
     log::trace!("Prepared prove_device_token: {:?}", prove_device_token);
     let prove_device_msg = messages::v11::to2::ProveDevice::new(prove_device_token);
     let setup_device: RequestResult<messages::v11::to2::SetupDevice> =
-        client.send_request(prove_device_msg, Some(new_keys)).await;
+        send_versioned_request(client, prove_device_msg, Some(new_keys)).await;
     let setup_device = setup_device.context("Error proving device").map_err(|e| {
         ClientError::Response(ErrorResult::new(
             ErrorCode::InternalServerError,
@@ -791,14 +891,128 @@ async fn perform_provedevice(
     })?;
     log::trace!("Got setup_device response: {:?}", setup_device);
 
-    Ok(())
+    // SetupDevice is signed with the Owner2Key it carries
+    let setup_device = setup_device.into_token();
+    let owner2_key = setup_device
+        .get_payload_unverified::<TO2SetupDevicePayload>()
+        .context("Error parsing SetupDevice payload")
+        .map_err(|e| {
+            ClientError::Response(ErrorResult::new(
+                ErrorCode::MessageBodyError,
+                "Error parsing SetupDevice payload",
+                MessageType::TO2SetupDevice,
+                e,
+            ))
+        })?
+        .get_unverified_value()
+        .owner2_key()
+        .clone();
+    let setup_device_payload: TO2SetupDevicePayload = setup_device
+        .get_payload(owner2_key.pkey())
+        .context("Error validating SetupDevice signature")
+        .map_err(|e| {
+            ClientError::Response(ErrorResult::new(
+                ErrorCode::InvalidMessageError,
+                "Error validating SetupDevice signature",
+                MessageType::TO2SetupDevice,
+                e,
+            ))
+        })?;
+    if setup_device_payload.nonce7() != nonce7 {
+        return Err(ClientError::Response(ErrorResult::new(
+            ErrorCode::InvalidMessageError,
+            "Nonce7 did not match in SetupDevice",
+            MessageType::TO2SetupDevice,
+            anyhow!("Nonce7 did not match in SetupDevice"),
+        )));
+    }
+
+    Ok(setup_device_payload)
+}
+
+/// The values the device credential is replaced with at the end of TO2
+struct CredentialReplacement {
+    guid: Guid,
+    rvinfo: RendezvousInfo,
+    pubkey_hash: Hash,
+    hmac: HMac,
+}
+
+/// What happens to the device credential at the end of TO2
+enum CredentialUpdate {
+    /// The owner requested credential reuse, the credential stays as it is
+    Reuse,
+    /// The credential is replaced with the new values
+    Replace(CredentialReplacement),
+    /// The owner requested a replacement the device can not perform, the
+    /// credential is deactivated
+    Declined,
+}
+
+/// Determines whether the owner asked for credential reuse or replacement
+///
+/// Credential reuse means the GUID, rendezvous info and owner key are unchanged.
+fn get_credential_replacement(
+    devcred: &dyn DeviceCredential,
+    ov_header: &OwnershipVoucherHeader,
+    hmac_type: HashType,
+    owner_public_key: &PublicKey,
+    setup_device_payload: &TO2SetupDevicePayload,
+) -> Result<CredentialUpdate> {
+    let rvinfo_unchanged = setup_device_payload.rendezvous_info().serialize_data()?
+        == devcred.rendezvous_info().serialize_data()?;
+    if setup_device_payload.guid() == devcred.device_guid()
+        && rvinfo_unchanged
+        && setup_device_payload
+            .owner2_key()
+            .matches_pkey(owner_public_key.pkey())?
+    {
+        log::info!("Owner requested credential reuse");
+        return Ok(CredentialUpdate::Reuse);
+    }
+
+    let new_header = ov_header
+        .new_replacement(
+            setup_device_payload.guid().clone(),
+            setup_device_payload.rendezvous_info().clone(),
+            setup_device_payload.owner2_key().clone(),
+        )
+        .context("Error building replacement ownership voucher header")?;
+    let hmac = devcred
+        .perform_hmac(
+            &new_header
+                .serialize_data()
+                .context("Error serializing replacement ownership voucher header")?,
+            hmac_type,
+        )
+        .context("Error computing replacement HMAC")?;
+    let pubkey_hash = new_header
+        .manufacturer_public_key_hash(devcred.manufacturer_pubkey_hash().get_type())
+        .context("Error computing Owner2Key hash")?;
+    log::info!(
+        "Owner requested credential replacement, new GUID: {}",
+        new_header.guid().to_string()
+    );
+
+    Ok(CredentialUpdate::Replace(CredentialReplacement {
+        guid: new_header.guid().clone(),
+        rvinfo: new_header.rendezvous_info().clone(),
+        pubkey_hash,
+        hmac,
+    }))
 }
 
 /// TO2: Sends DeviceServiceInfoReady, Receives OwnerService
-async fn perform_deviceserviceinfoready(client: &mut ServiceClient) -> Result<(), ClientError> {
-    let owner_service_info_ready: RequestResult<messages::v11::to2::OwnerServiceInfoReady> = client
-        .send_request(
-            messages::v11::to2::DeviceServiceInfoReady::new(None, None),
+///
+/// Returns the maximum DeviceServiceInfo size the owner is able to receive
+async fn perform_deviceserviceinfoready(
+    client: &mut ServiceClient,
+    replacement_hmac: Option<HMac>,
+) -> Result<u64, ClientError> {
+    let owner_service_info_ready: RequestResult<messages::v11::to2::OwnerServiceInfoReady> =
+        send_versioned_request(
+            client,
+            messages::v11::to2::DeviceServiceInfoReady::new(replacement_hmac, None),
             None,
         )
         .await;
@@ -816,7 +1030,9 @@ async fn perform_deviceserviceinfoready(client: &mut ServiceClient) -> Result<()
         "Received OwnerServiceInfoReady: {:?}",
         owner_service_info_ready
     );
-    Ok(())
+    Ok(owner_service_info_ready
+        .max_device_service_info_size()
+        .unwrap_or(DEFAULT_MAX_SERVICE_INFO_SIZE))
 }
 
 /// TO2: Sends Done, Receives Done2
@@ -825,9 +1041,8 @@ async fn perform_done(
     nonce6: Nonce,
     client: &mut ServiceClient,
 ) -> Result<(), ClientError> {
-    let done2: RequestResult<messages::v11::to2::Done2> = client
-        .send_request(messages::v11::to2::Done::new(nonce6), None)
-        .await;
+    let done2: RequestResult<messages::v11::to2::Done2> =
+        send_versioned_request(client, messages::v11::to2::Done::new(nonce6), None).await;
     let done2 = done2.context("Error sending Done2").map_err(|e| {
         ClientError::Response(ErrorResult::new(
             ErrorCode::InternalServerError,
@@ -847,40 +1062,137 @@ async fn perform_done(
     Ok(())
 }
 
+/// Reads a comma-separated list of suites in order of preference from the
+/// environment, or returns the default preferences if it is not set.
+fn suite_preferences<T>(var: &str, default: &[T]) -> Result<Vec<T>>
+where
+    T: FromStr<Err = fdo_data_formats::Error> + Copy,
+{
+    let names = match env::var(var) {
+        Ok(names) => names,
+        Err(_) => return Ok(default.to_vec()),
+    };
+    let suites = names
+        .split(',')
+        .map(|name| T::from_str(name.trim()).with_context(|| format!("Invalid {}", var)))
+        .collect::<Result<Vec<T>>>()?;
+    if suites.is_empty() {
+        bail!("No suites in {}", var);
+    }
+    Ok(suites)
+}
+
+/// Sets up the client for the owner server.
+///
+/// TO2 addresses received in TO1 come without certificate hashes, so their TLS
+/// certificates are verified against the system trust store. The owner itself
+/// is authenticated by the TO2 protocol in either case.
+fn get_to2_client(
+    devcred: &dyn DeviceCredential,
+    url: &str,
+    pins: Option<&CertificatePins>,
+) -> Result<ServiceClient> {
+    match pins {
+        Some(pins) => ServiceClient::new_with_pins(devcred.protocol_version(), url, pins)
+            .context("Error setting up TO2 client"),
+        None => {
+            log::debug!(
+                "No certificate pins for TO2 address {}, using the system trust store",
+                url
+            );
+            Ok(ServiceClient::new(devcred.protocol_version(), url))
+        }
+    }
+}
+
 async fn perform_to2(
     devcredloc: &dyn UsableDeviceCredentialLocation,
     devcred: &dyn DeviceCredential,
     url: &str,
-    to1d: &COSESign,
+    pins: Option<&CertificatePins>,
+    to1d: Option<&COSESign>,
 ) -> Result<()> {
-    log::info!("Performing TO2 protocol, URL: {:?}", url);
+    log::info!(
+        "Performing TO2 protocol version {}, URL: {:?}",
+        devcred.protocol_version(),
+        url
+    );
 
-    let mut client = fdo_http_wrapper::client::ServiceClient::new(ProtocolVersion::Version1_1, url);
+    // The ProveOVHdr payload is the only TO2 message content that differs between versions
+    match devcred.protocol_version() {
+        ProtocolVersion::Version1_0 => {
+            perform_to2_with_payload::<messages::v10::to2::ProveOVHdrPayload>(
+                devcredloc, devcred, url, pins, to1d,
+            )
+            .await
+        }
+        _ => {
+            perform_to2_with_payload::<TO2ProveOVHdrPayload>(devcredloc, devcred, url, pins, to1d)
+                .await
+        }
+    }
+}
 
-    let nonce5 = match get_nonce(MessageType::TO1RVRedirect).await {
-        Ok(nonce5) => nonce5,
-        Err(e) => match e {
-            ClientError::Request(e) => {
-                send_client_error(&mut client, e.borrow()).await;
-                bail!(e.error);
-            }
-            ClientError::Response(e) => {
-                send_client_error(&mut client, e.borrow()).await;
-                bail!(e.error);
-            }
-        },
-    };
+async fn perform_to2_with_payload<P: ProveOVHdrPayload>(
+    devcredloc: &dyn UsableDeviceCredentialLocation,
+    devcred: &dyn DeviceCredential,
+    url: &str,
+    pins: Option<&CertificatePins>,
+    to1d: Option<&COSESign>,
+) -> Result<()> {
+    let mut client = get_to2_client(devcred, url, pins)?;
 
-    let sigtype = DeviceSigType::StSECP384R1;
-    let kexsuite = KexSuite::Ecdh384;
-    let ciphersuite = CipherSuite::A256Gcm;
+    let sigtype = devcred
+        .get_signer()
+        .and_then(|signer| signer.cose_signing_key().device_sig_type())
+        .context("Error determining device signature type")?;
+    let kexsuites = suite_preferences("KEX_SUITE", &[KexSuite::Ecdh384])?;
+    let ciphersuites = suite_preferences("CIPHER_SUITE", &[CipherSuite::A256Gcm])?;
+    let mut suites = kexsuites
+        .iter()
+        .flat_map(|kexsuite| {
+            ciphersuites
+                .iter()
+                .map(move |ciphersuite| (*kexsuite, *ciphersuite))
+        })
+        .peekable();
 
     // Send: HelloDevice, Receive: ProveOVHdr
-    let (prove_ov_hdr, prove_ov_hdr_payload, header_hmac) =
-        match perform_hellodevice(devcred, &mut client, nonce5, sigtype, kexsuite, ciphersuite)
+    // Only falls back to the next preferred suites if more than one is configured,
+    // since the rejection is not authenticated
+    let (kexsuite, ciphersuite, (prove_ov_hdr, prove_ov_hdr_payload, header_hmac)) = loop {
+        let (kexsuite, ciphersuite) = match suites.next() {
+            Some(suite) => suite,
+            None => bail!("Owner rejected all configured key exchange and cipher suites"),
+        };
+        let nonce5 = match get_nonce(MessageType::TO1RVRedirect).await {
+            Ok(nonce5) => nonce5,
+            Err(e) => match e {
+                ClientError::Request(e) => {
+                    send_client_error(&mut client, e.borrow()).await;
+                    bail!(e.error);
+                }
+                ClientError::Response(e) => {
+                    send_client_error(&mut client, e.borrow()).await;
+                    bail!(e.error);
+                }
+            },
+        };
+        match perform_hellodevice::<P>(devcred, &mut client, nonce5, sigtype, kexsuite, ciphersuite)
             .await
         {
-            Ok(values) => values,
+            Ok(Some(values)) => break (kexsuite, ciphersuite, values),
+            Ok(None) => {
+                if let Some((next_kexsuite, next_ciphersuite)) = suites.peek() {
+                    log::warn!(
+                        "Falling back to {:?} and {:?}",
+                        next_kexsuite,
+                        next_ciphersuite
+                    );
+                    // The rejected HelloDevice ended the session, start a new one
+                    client = get_to2_client(devcred, url, pins)?;
+                }
+            }
             Err(e) => match e {
                 ClientError::Request(e) => {
                     send_client_error(&mut client, e.borrow()).await;
@@ -891,7 +1203,8 @@ async fn perform_to2(
                     bail!(e.error);
                 }
             },
-        };
+        }
+    };
     // Get nonce6
     let nonce6 = match get_nonce6(&prove_ov_hdr).await {
         Ok(nonce6) => nonce6,
@@ -906,8 +1219,9 @@ async fn perform_to2(
             }
         },
     };
+    let hmac_type = header_hmac.get_type();
     // Get OV and verify its signature
-    let prove_ov_hdr_payload = match get_and_verify_ov_header_signature(
+    let (prove_ov_hdr_payload, owner_public_key) = match get_and_verify_ov_header_signature(
         &mut client,
         &prove_ov_hdr,
         &prove_ov_hdr_payload,
@@ -929,10 +1243,15 @@ async fn perform_to2(
         },
     };
 
+    // Validated as part of the ProveOVHdr signature
+    let ov_header = OwnershipVoucherHeader::deserialize_data(prove_ov_hdr_payload.ov_header())
+        .context("Error deserializing OV Header")?;
+
     // Key derivation
     let (b_key_exchange, new_keys) = match perform_key_derivation(
         &mut client,
         prove_ov_hdr_payload,
+        &owner_public_key,
         kexsuite,
         ciphersuite,
     )
@@ -967,7 +1286,7 @@ async fn perform_to2(
     };
 
     // Send: ProveDevice, Receive: SetupDevice
-    match perform_provedevice(
+    let setup_device_payload = match perform_provedevice(
         devcred,
         &mut client,
         b_key_exchange,
@@ -977,7 +1296,7 @@ async fn perform_to2(
     )
     .await
     {
-        Ok(_) => (),
+        Ok(setup_device_payload) => setup_device_payload,
         Err(e) => match e {
             ClientError::Request(e) => {
                 send_client_error(&mut client, e.borrow()).await;
@@ -990,23 +1309,55 @@ async fn perform_to2(
         },
     };
 
+    let credential_update = match get_credential_replacement(
+        devcred,
+        &ov_header,
+        hmac_type,
+        &owner_public_key,
+        &setup_device_payload,
+    ) {
+        Ok(CredentialUpdate::Replace(_)) if !devcredloc.is_replaceable() => {
+            // Without a replacement HMAC, the owner does not issue a resale voucher
+            log::warn!("Device credential can not be replaced, declining the replacement");
+            CredentialUpdate::Declined
+        }
+        Ok(credential_update) => credential_update,
+        Err(e) => {
+            let e_result = ErrorResult::new(
+                ErrorCode::InternalServerError,
+                "Error preparing the credential replacement",
+                MessageType::TO2SetupDevice,
+                e,
+            );
+            send_client_error(&mut client, &e_result).await;
+            bail!(e_result.error);
+        }
+    };
+
     // Send: DeviceServiceInfoReady, Receive: OwnerServiceInfoReady
-    match perform_deviceserviceinfoready(&mut client).await {
-        Ok(_) => (),
-        Err(e) => match e {
-            ClientError::Request(e) => {
-                send_client_error(&mut client, e.borrow()).await;
-                bail!(e.error);
-            }
-            ClientError::Response(e) => {
-                send_client_error(&mut client, e.borrow()).await;
-                bail!(e.error);
-            }
-        },
+    let replacement_hmac = match &credential_update {
+        CredentialUpdate::Replace(replacement) => Some(replacement.hmac.clone()),
+        CredentialUpdate::Reuse | CredentialUpdate::Declined => None,
     };
+    let max_device_service_info_size =
+        match perform_deviceserviceinfoready(&mut client, replacement_hmac).await {
+            Ok(max_device_service_info_size) => max_device_service_info_size,
+            Err(e) => match e {
+                ClientError::Request(e) => {
+                    send_client_error(&mut client, e.borrow()).await;
+                    bail!(e.error);
+                }
+                ClientError::Response(e) => {
+                    send_client_error(&mut client, e.borrow()).await;
+                    bail!(e.error);
+                }
+            },
+        };
 
     // Now, the magic: performing the roundtrip! We delegated that.
-    if let Err(serviceinfo_err) = serviceinfo::perform_to2_serviceinfos(&mut client).await {
+    if let Err(serviceinfo_err) =
+        serviceinfo::perform_to2_serviceinfos(&mut client, max_device_service_info_size).await
+    {
         log::info!("ServiceInfo failed, error: {:?}", serviceinfo_err);
         let e_result = ErrorResult::new(
             ErrorCode::InternalServerError,
@@ -1029,7 +1380,9 @@ async fn perform_to2(
         bail!(e_result.error);
     }
 
-    if devcredloc.deactivate().is_err() {
+    // A reused or replaced credential stays active, so the device can be
+    // onboarded again
+    if matches!(credential_update, CredentialUpdate::Declined) && devcredloc.deactivate().is_err() {
         let e_result = ErrorResult::new(
             ErrorCode::InternalServerError,
             "Error deactivating device credential",
@@ -1041,9 +1394,8 @@ async fn perform_to2(
     }
 
     // Send: Done, Receive: Done2
-    match perform_done(nonce7, nonce6, &mut client).await {
-        Ok(ok) => Ok(ok),
-        Err(e) => match e {
+    if let Err(e) = perform_done(nonce7, nonce6, &mut client).await {
+        match e {
             ClientError::Request(e) => {
                 send_client_error(&mut client, e.borrow()).await;
                 bail!(e.error);
@@ -1052,8 +1404,22 @@ async fn perform_to2(
                 send_client_error(&mut client, e.borrow()).await;
                 bail!(e.error);
             }
-        },
+        }
     }
+
+    // Only replace the credential once the owner confirmed the onboarding,
+    // and has the matching resale voucher
+    if let CredentialUpdate::Replace(replacement) = credential_update {
+        devcredloc
+            .replace(
+                &replacement.guid,
+                &replacement.rvinfo,
+                &replacement.pubkey_hash,
+            )
+            .context("Error replacing device credential")?;
+    }
+
+    Ok(())
 }
 
 fn get_delay_between_retries(rv_entry_delay: u32) -> u64 {
@@ -1123,13 +1489,6 @@ async fn main() -> Result<()> {
         log::info!("Device credential deactivated, skipping Device Onboarding");
         return Ok(());
     }
-    if dc.protocol_version() != ProtocolVersion::Version1_1 {
-        bail!(
-            "Device credential protocol version {} not supported",
-            dc.protocol_version()
-        );
-    }
-
     // Get rv entries
     let rv_info = get_rv_info(dc.as_ref())?;
     let rv_info_it = rv_info.iter();
@@ -1141,48 +1500,59 @@ async fn main() -> Result<()> {
         for rv_entry in rv_info_it.clone() {
             rv_entry_delay = rv_entry.delay;
 
-            let client_list = match get_client_list(rv_entry).await {
-                Ok(client_list) => client_list,
-                Err(e) => {
-                    log::trace!(
-                        "Error {:?} getting usable rendezvous client list from rv_entry {:?}",
-                        e,
-                        rv_entry
-                    );
-                    continue;
-                }
-            };
-
-            // Get owner info
-            let to1d = get_to1d(dc.as_ref(), client_list).await;
-            let to1d = match to1d {
-                Ok(to1d) => to1d,
-                Err(e) => {
-                    log::trace!(
-                        "Error {:?} getting usable To1d from rv_entry {:?}",
-                        e,
-                        rv_entry
-                    );
-                    continue;
-                }
-            };
+            let (to2_addresses, to2_pins, to1d) = if rv_entry.bypass {
+                // Rendezvous bypass: the directive points straight at the owner
+                log::info!("Rendezvous bypass, skipping TO1");
+                (
+                    rv_entry.get_urls(),
+                    Some(get_certificate_pins(rv_entry)),
+                    None,
+                )
+            } else {
+                let client_list = match get_client_list(dc.protocol_version(), rv_entry).await {
+                    Ok(client_list) => client_list,
+                    Err(e) => {
+                        log::trace!(
+                            "Error {:?} getting usable rendezvous client list from rv_entry {:?}",
+                            e,
+                            rv_entry
+                        );
+                        continue;
+                    }
+                };
 
-            let to1d_payload: UnverifiedValue<TO1DataPayload> = match to1d.get_payload_unverified()
-            {
-                Ok(to1d_payload) => to1d_payload,
-                Err(e) => {
-                    log::trace!(
-                        "Error getting TO1 payload unverified {:?} with rv_entry {:?}",
-                        e,
-                        rv_entry
-                    );
-                    continue;
-                }
+                // Get owner info
+                let to1d = get_to1d(dc.as_ref(), client_list).await;
+                let to1d = match to1d {
+                    Ok(to1d) => to1d,
+                    Err(e) => {
+                        log::trace!(
+                            "Error {:?} getting usable To1d from rv_entry {:?}",
+                            e,
+                            rv_entry
+                        );
+                        continue;
+                    }
+                };
+
+                let to1d_payload: UnverifiedValue<TO1DataPayload> =
+                    match to1d.get_payload_unverified() {
+                        Ok(to1d_payload) => to1d_payload,
+                        Err(e) => {
+                            log::trace!(
+                                "Error getting TO1 payload unverified {:?} with rv_entry {:?}",
+                                e,
+                                rv_entry
+                            );
+                            continue;
+                        }
+                    };
+
+                let to2_addresses = to1d_payload.get_unverified_value().to2_addresses();
+                (get_to2_urls(to2_addresses), None, Some(to1d))
             };
 
             // Contact owner and perform ownership transfer
-            let to2_addresses = to1d_payload.get_unverified_value().to2_addresses();
-            let to2_addresses = get_to2_urls(to2_addresses);
             log::info!("Got TO2 addresses: {:?}", to2_addresses);
 
             if to2_addresses.is_empty() {
@@ -1194,9 +1564,15 @@ async fn main() -> Result<()> {
             }
 
             for to2_address in to2_addresses {
-                match perform_to2(devcred_location.borrow(), dc.as_ref(), &to2_address, &to1d)
-                    .await
-                    .context("Error performing TO2 ownership protocol")
+                match perform_to2(
+                    devcred_location.borrow(),
+                    dc.as_ref(),
+                    &to2_address,
+                    to2_pins.as_ref(),
+                    to1d.as_ref(),
+                )
+                .await
+                .context("Error performing TO2 ownership protocol")
                 {
                     Ok(_) => {
                         onboarding_performed = true;
@@ -0,0 +1,366 @@
+//! The standard fdo_sys ServiceInfo module.
+//!
+//! - `filedesc` (tstr) creates or truncates a file with mode 0600, which
+//!   `write` (bstr) messages then append to.
+//! - `exec` ([tstr]) runs a command and waits for it to finish. Onboarding
+//!   fails if the command fails.
+//! - `exec_cb` ([tstr]) starts a command in the background. The device
+//!   reports its progress with `status_cb` ([completed, return code, wait
+//!   seconds]), and the owner answers every incomplete status with its own
+//!   `status_cb`. If the owner marks it completed, the command is killed.
+
+use std::env;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::process::{Child, Command};
+
+use fdo_data_formats::{
+    constants::StandardServiceInfoModule,
+    types::{CborSimpleType, CborSimpleTypeExt, ServiceInfo},
+};
+
+// How long to wait for an exec_cb command before the first status report
+const INITIAL_STATUS_WAIT_SECONDS: u64 = 5;
+// Upper bound for the wait time the owner asks for in status_cb
+const MAX_STATUS_WAIT_SECONDS: u64 = 60;
+// Files are only readable by root, the owner can change that with exec
+const FILE_MODE: u32 = 0o600;
+
+/// State of the fdo_sys module, kept over all ServiceInfo rounds
+#[derive(Debug)]
+pub(super) struct FdoSys {
+    enabled: bool,
+    path_prefix: Option<PathBuf>,
+    initial_status_wait_seconds: u64,
+    file: Option<(PathBuf, File)>,
+    running: Option<Child>,
+}
+
+fn parse_command(value: &CborSimpleType) -> Result<Command> {
+    let args: Vec<String> =
+        serde_cbor::value::from_value(value.clone()).context("Error parsing command")?;
+    let (program, args) = match args.split_first() {
+        Some(split) => split,
+        None => bail!("Empty command"),
+    };
+    let mut command = Command::new(program);
+    command.args(args);
+    Ok(command)
+}
+
+impl FdoSys {
+    /// The module can write any file and run any command, so it is only
+    /// enabled if ALLOW_FDO_SYS is set. Files are placed in
+    /// BINARYFILE_PATH_PREFIX, like those of the binaryfile module.
+    pub(super) fn new() -> Self {
+        FdoSys {
+            enabled: env::var("ALLOW_FDO_SYS").is_ok(),
+            path_prefix: env::var("BINARYFILE_PATH_PREFIX").ok().map(PathBuf::from),
+            initial_status_wait_seconds: INITIAL_STATUS_WAIT_SECONDS,
+            file: None,
+            running: None,
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) async fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        if !self.enabled {
+            bail!("The fdo_sys module is not enabled");
+        }
+        match key {
+            "filedesc" => {
+                let path = value.as_str().context("Error parsing filedesc")?;
+                let path = match &self.path_prefix {
+                    Some(prefix) => prefix.join(path),
+                    None => PathBuf::from(path),
+                };
+                if !path.is_absolute() {
+                    bail!("File path must be absolute");
+                }
+                log::debug!("Creating file {:?}", path);
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(FILE_MODE)
+                    .open(&path)
+                    .with_context(|| format!("Error creating file {:?}", path))?;
+                // The mode only applies to new files, so reset existing ones
+                file.set_permissions(Permissions::from_mode(FILE_MODE))
+                    .with_context(|| format!("Error setting permissions of {:?}", path))?;
+                self.file = Some((path, file));
+            }
+            "write" => {
+                let data = value.as_bytes().context("Error parsing write data")?;
+                let (path, file) = match &mut self.file {
+                    Some(file) => file,
+                    None => bail!("Got write before filedesc"),
+                };
+                file.write_all(data)
+                    .with_context(|| format!("Error writing to file {:?}", path))?;
+            }
+            "exec" => {
+                let mut command = parse_command(value)?;
+                log::debug!("Executing {:?}", command);
+                let status = command.status().await.context("Error running command")?;
+                if !status.success() {
+                    bail!("Command {:?} failed with {}", command, status);
+                }
+            }
+            "exec_cb" => {
+                if self.running.is_some() {
+                    bail!("Got exec_cb while another command is still running");
+                }
+                let mut command = parse_command(value)?;
+                log::debug!("Starting {:?}", command);
+                self.running = Some(command.spawn().context("Error starting command")?);
+                self.report_status(self.initial_status_wait_seconds, si_out)
+                    .await?;
+            }
+            "status_cb" => {
+                let (completed, _, wait_seconds): (bool, i64, u64) =
+                    serde_cbor::value::from_value(value.clone())
+                        .context("Error parsing status_cb")?;
+                if completed {
+                    // The owner is no longer interested in the command
+                    if let Some(mut child) = self.running.take() {
+                        log::info!("Owner marked command as completed, killing it");
+                        child.kill().await.context("Error killing command")?;
+                    }
+                } else if self.running.is_some() {
+                    self.report_status(wait_seconds.min(MAX_STATUS_WAIT_SECONDS), si_out)
+                        .await?;
+                } else {
+                    log::debug!("Got status_cb without running command, ignoring");
+                }
+            }
+            other => bail!("Unsupported fdo_sys message {}", other),
+        }
+        Ok(())
+    }
+
+    /// Waits up to wait_seconds for the running command, and reports its status
+    async fn report_status(&mut self, wait_seconds: u64, si_out: &mut ServiceInfo) -> Result<()> {
+        let child = self.running.as_mut().unwrap();
+        let status =
+            match tokio::time::timeout(Duration::from_secs(wait_seconds), child.wait()).await {
+                Ok(status) => Some(status.context("Error checking command status")?),
+                Err(_) => None,
+            };
+
+        let status = match status {
+            Some(status) => {
+                log::debug!("Command finished with {}", status);
+                self.running = None;
+                (true, i64::from(status.code().unwrap_or(-1)), 0)
+            }
+            None => (false, 0, wait_seconds),
+        };
+        si_out.add(StandardServiceInfoModule::FdoSys, "status_cb", &status)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fdo-sys-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn new_fdo_sys(path_prefix: Option<PathBuf>) -> FdoSys {
+        FdoSys {
+            enabled: true,
+            path_prefix,
+            initial_status_wait_seconds: INITIAL_STATUS_WAIT_SECONDS,
+            file: None,
+            running: None,
+        }
+    }
+
+    fn command(args: &[&str]) -> CborSimpleType {
+        serde_cbor::value::to_value(args).unwrap()
+    }
+
+    fn statuses(si_out: &ServiceInfo) -> Vec<(bool, i64, u64)> {
+        si_out
+            .iter()
+            .map(|(module, key, value)| {
+                assert_eq!(module, StandardServiceInfoModule::FdoSys.into());
+                assert_eq!(key, "status_cb");
+                serde_cbor::value::from_value(value).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let mut fdo_sys = FdoSys {
+            enabled: false,
+            ..new_fdo_sys(None)
+        };
+        let mut si_out = ServiceInfo::new();
+        assert!(fdo_sys
+            .handle("exec", &command(&["true"]), &mut si_out)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_filedesc_write() {
+        let dir = test_dir("write");
+        let mut fdo_sys = new_fdo_sys(Some(dir.clone()));
+        let mut si_out = ServiceInfo::new();
+
+        // Writes before filedesc have no file to go to
+        assert!(fdo_sys
+            .handle(
+                "write",
+                &CborSimpleType::Bytes(b"data".to_vec()),
+                &mut si_out
+            )
+            .await
+            .is_err());
+
+        // The path is placed in the prefix, and an existing file is truncated
+        fs::write(dir.join("file"), "old contents").unwrap();
+        fs::set_permissions(dir.join("file"), Permissions::from_mode(0o644)).unwrap();
+        fdo_sys
+            .handle(
+                "filedesc",
+                &CborSimpleType::Text("file".to_string()),
+                &mut si_out,
+            )
+            .await
+            .unwrap();
+        for data in [&b"some "[..], b"data"] {
+            fdo_sys
+                .handle("write", &CborSimpleType::Bytes(data.to_vec()), &mut si_out)
+                .await
+                .unwrap();
+        }
+        assert_eq!(fs::read(dir.join("file")).unwrap(), b"some data");
+        assert_eq!(
+            fs::metadata(dir.join("file")).unwrap().permissions().mode() & 0o777,
+            FILE_MODE
+        );
+        assert!(si_out.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Without a prefix, only absolute paths are accepted
+        let mut fdo_sys = new_fdo_sys(None);
+        assert!(fdo_sys
+            .handle(
+                "filedesc",
+                &CborSimpleType::Text("file".to_string()),
+                &mut si_out
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+        let dir = test_dir("exec");
+        let mut fdo_sys = new_fdo_sys(None);
+        let mut si_out = ServiceInfo::new();
+
+        let testfile = dir.join("testfile");
+        fdo_sys
+            .handle(
+                "exec",
+                &command(&["touch", testfile.to_str().unwrap()]),
+                &mut si_out,
+            )
+            .await
+            .unwrap();
+        assert!(testfile.exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(fdo_sys
+            .handle("exec", &command(&["false"]), &mut si_out)
+            .await
+            .is_err());
+        assert!(fdo_sys
+            .handle("exec", &command(&[]), &mut si_out)
+            .await
+            .is_err());
+        assert!(si_out.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_exec_cb() {
+        let mut fdo_sys = new_fdo_sys(None);
+
+        // A command that finishes before the first report returns its exit code
+        let mut si_out = ServiceInfo::new();
+        fdo_sys
+            .handle("exec_cb", &command(&["sh", "-c", "exit 3"]), &mut si_out)
+            .await
+            .unwrap();
+        assert_eq!(statuses(&si_out), vec![(true, 3, 0)]);
+        assert!(fdo_sys.running.is_none());
+
+        // A command that is still running is reported on every status_cb
+        fdo_sys.initial_status_wait_seconds = 0;
+        let mut si_out = ServiceInfo::new();
+        fdo_sys
+            .handle("exec_cb", &command(&["sleep", "1"]), &mut si_out)
+            .await
+            .unwrap();
+        assert_eq!(statuses(&si_out), vec![(false, 0, 0)]);
+        assert!(fdo_sys
+            .handle("exec_cb", &command(&["true"]), &mut si_out)
+            .await
+            .is_err());
+        let mut si_out = ServiceInfo::new();
+        fdo_sys
+            .handle(
+                "status_cb",
+                &serde_cbor::value::to_value((false, 0, 5)).unwrap(),
+                &mut si_out,
+            )
+            .await
+            .unwrap();
+        assert_eq!(statuses(&si_out), vec![(true, 0, 0)]);
+        assert!(fdo_sys.running.is_none());
+
+        // The command is killed if the owner marks it as completed
+        let mut si_out = ServiceInfo::new();
+        fdo_sys
+            .handle("exec_cb", &command(&["sleep", "100"]), &mut si_out)
+            .await
+            .unwrap();
+        assert_eq!(statuses(&si_out), vec![(false, 0, 0)]);
+        let mut si_out = ServiceInfo::new();
+        fdo_sys
+            .handle(
+                "status_cb",
+                &serde_cbor::value::to_value((true, 0, 0)).unwrap(),
+                &mut si_out,
+            )
+            .await
+            .unwrap();
+        assert!(si_out.is_empty());
+        assert!(fdo_sys.running.is_none());
+    }
+}
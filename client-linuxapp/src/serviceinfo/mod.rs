@@ -1,6 +1,6 @@
 use std::process::Command;
 use std::{
-    collections::HashSet,
+    collections::{HashSet, VecDeque},
     fs::{File, Permissions},
     io::Write,
     path::Path,
@@ -20,9 +20,15 @@ use fdo_data_formats::{
 };
 use fdo_http_wrapper::client::{RequestResult, ServiceClient};
 
+mod fdo_sys;
+mod plugins;
+
+use fdo_sys::FdoSys;
+use plugins::Plugins;
+
 const MAX_SERVICE_INFO_LOOPS: u32 = 1000;
 
-fn find_available_modules() -> Result<Vec<ServiceInfoModule>> {
+fn find_available_modules(plugins: &Plugins, fdo_sys: &FdoSys) -> Result<Vec<ServiceInfoModule>> {
     let mut module_list = vec![
         // These modules are always here
         StandardServiceInfoModule::DevMod.into(),
@@ -40,6 +46,12 @@ fn find_available_modules() -> Result<Vec<ServiceInfoModule>> {
         module_list.push(FedoraIotServiceInfoModule::DiskEncryptionClevis.into());
     }
 
+    if fdo_sys.is_enabled() {
+        module_list.push(StandardServiceInfoModule::FdoSys.into());
+    }
+
+    module_list.extend(plugins.modules().cloned());
+
     Ok(module_list)
 }
 
@@ -348,8 +360,14 @@ impl CommandInProgress {
     }
 }
 
-async fn process_serviceinfo_in(si_in: &ServiceInfo, si_out: &mut ServiceInfo) -> Result<()> {
+async fn process_serviceinfo_in(
+    si_in: &ServiceInfo,
+    si_out: &mut ServiceInfo,
+    plugins: &Plugins,
+    fdo_sys: &mut FdoSys,
+) -> Result<()> {
     let mut active_modules: HashSet<ServiceInfoModule> = HashSet::new();
+    let mut plugin_inputs = plugins.new_inputs();
 
     let mut sshkey_user: Option<String> = None;
     let mut sshkey_key: Option<String> = None;
@@ -364,6 +382,11 @@ async fn process_serviceinfo_in(si_in: &ServiceInfo, si_out: &mut ServiceInfo) -
 
     for (module, key, value) in si_in.iter() {
         log::trace!("Got module {}, command {}, value {:?}", module, key, value);
+        if let Some(plugin) = plugins.find(&module) {
+            // Plugins get all entries of their modules, including activation
+            plugin_inputs[plugin].push(&module, &key, value);
+            continue;
+        }
         if key == "active" {
             let value = value.as_bool().context("Error parsing active value")?;
             if value {
@@ -379,7 +402,12 @@ async fn process_serviceinfo_in(si_in: &ServiceInfo, si_out: &mut ServiceInfo) -
             log::trace!("Skipping non-activated module {}", module);
             bail!("Non-activated module {} got request", module);
         }
-        if module == FedoraIotServiceInfoModule::SSHKey.into() {
+        if module == StandardServiceInfoModule::FdoSys.into() {
+            fdo_sys
+                .handle(&key, &value, si_out)
+                .await
+                .with_context(|| format!("Error handling fdo_sys {}", key))?;
+        } else if module == FedoraIotServiceInfoModule::SSHKey.into() {
             let value = value.as_str().context("Error parsing sshkey value")?;
             if key == "username" {
                 sshkey_user = Some(value.to_string());
@@ -612,16 +640,29 @@ async fn process_serviceinfo_in(si_in: &ServiceInfo, si_out: &mut ServiceInfo) -
         .context("Error performing RHSM enrollment")?;
     }
 
+    plugins
+        .execute(plugin_inputs, si_out)
+        .await
+        .context("Error executing ServiceInfo plugins")?;
+
     Ok(())
 }
 
-pub(crate) async fn perform_to2_serviceinfos(client: &mut ServiceClient) -> Result<()> {
+pub(crate) async fn perform_to2_serviceinfos(
+    client: &mut ServiceClient,
+    max_device_service_info_size: u64,
+) -> Result<()> {
     let mut loop_num = 0;
     let mut out_si = ServiceInfo::new();
+    let mut out_messages: VecDeque<ServiceInfo> = VecDeque::new();
+    let mut in_si = ServiceInfo::new();
+    let plugins = Plugins::load().context("Error loading ServiceInfo plugins")?;
+    let mut fdo_sys = FdoSys::new();
 
     while loop_num < MAX_SERVICE_INFO_LOOPS {
         if loop_num == 0 {
-            let modules = find_available_modules().context("Error getting list of modules")?;
+            let modules = find_available_modules(&plugins, &fdo_sys)
+                .context("Error getting list of modules")?;
             let sysinfo = sys_info::linux_os_release()
                 .context("Error getting operating system information")?;
 
@@ -652,30 +693,53 @@ pub(crate) async fn perform_to2_serviceinfos(client: &mut ServiceClient) -> Resu
             out_si.add_modules(&modules)?;
         }
 
-        let send_si = DeviceServiceInfo::new(false, out_si);
-        out_si = ServiceInfo::new();
+        if !out_si.is_empty() {
+            out_messages = std::mem::take(&mut out_si)
+                .split(max_device_service_info_size)
+                .context("Error splitting ServiceInfo")?
+                .into();
+        }
+        let is_more_out = out_messages.len() > 1;
+        let send_si =
+            DeviceServiceInfo::new(is_more_out, out_messages.pop_front().unwrap_or_default());
         log::trace!("Sending ServiceInfo loop {}: {:?}", loop_num, send_si);
 
-        let return_si: RequestResult<OwnerServiceInfo> = client.send_request(send_si, None).await;
+        let return_si: RequestResult<OwnerServiceInfo> =
+            crate::send_versioned_request(client, send_si, None).await;
         let return_si =
             return_si.with_context(|| format!("Error during ServiceInfo loop {}", loop_num))?;
         log::trace!("Got ServiceInfo loop {}: {:?}", loop_num, return_si);
+        loop_num += 1;
+
+        if is_more_out {
+            // The owner needs to wait for the rest of our ServiceInfo before responding
+            if return_si.is_done()
+                || return_si.is_more_service_info()
+                || !return_si.service_info().is_empty()
+            {
+                bail!("OwnerServiceInfo was not empty while we had more ServiceInfo to send");
+            }
+            continue;
+        }
 
         if return_si.is_done() {
             log::trace!("ServiceInfo loops done, number taken: {}", loop_num);
             return Ok(());
         }
-        if return_si.is_more_service_info() {
-            // TODO
-            bail!("OwnerServiceInfo indicated it has more for us.. we don't support that yet");
+        let is_more_in = return_si.is_more_service_info();
+        in_si.extend(return_si.into_service_info());
+        if is_more_in {
+            // Gather the rest of the instructions before processing them
+            continue;
         }
 
         // Process
-        process_serviceinfo_in(return_si.service_info(), &mut out_si)
+        let joined_si = std::mem::take(&mut in_si)
+            .join_chunks()
+            .context("Error joining ServiceInfo chunks")?;
+        process_serviceinfo_in(&joined_si, &mut out_si, &plugins, &mut fdo_sys)
             .await
             .context("Error processing returned serviceinfo")?;
-
-        loop_num += 1;
     }
     Err(anyhow!(
         "Maximum number of ServiceInfo loops ({}) exceeded",
@@ -0,0 +1,519 @@
+//! External ServiceInfo module handlers.
+//!
+//! Plugins are registered with a YAML file in the plugin directory, listing
+//! the modules they handle and how to reach them:
+//!
+//! ```yaml
+//! modules:
+//!   - com.example.inventory
+//! executable: /usr/libexec/fdo/inventory-plugin
+//! # or, for a plugin that is already running:
+//! # socket: /run/fdo/inventory.sock
+//! ```
+//!
+//! For every batch of owner ServiceInfo, each plugin gets the entries for its
+//! modules (including `active`) as a CBOR sequence of `[module, key, value]`
+//! arrays, on the standard input of the executable or written to the socket.
+//! Once the input is closed, the plugin answers with a CBOR sequence of
+//! `[module, key, value]` arrays for its own modules, which are sent back to
+//! the owner as device ServiceInfo. Plugins that do not answer within
+//! a minute fail the onboarding.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+
+use fdo_data_formats::{
+    constants::ServiceInfoModule,
+    types::{CborSimpleType, ServiceInfo},
+};
+
+const DEFAULT_PLUGIN_DIR: &str = "/etc/fdo/client-linuxapp/serviceinfo-plugins.d";
+const PLUGIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginRegistration {
+    modules: Vec<String>,
+    #[serde(default)]
+    executable: Option<PathBuf>,
+    #[serde(default)]
+    socket: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+enum PluginEndpoint {
+    Executable(PathBuf),
+    Socket(PathBuf),
+}
+
+#[derive(Debug)]
+struct Plugin {
+    name: String,
+    modules: Vec<ServiceInfoModule>,
+    endpoint: PluginEndpoint,
+    timeout: Duration,
+}
+
+impl Plugin {
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read(path).context("Error reading plugin registration")?;
+        let registration: PluginRegistration =
+            serde_yaml::from_slice(&contents).context("Error parsing plugin registration")?;
+
+        let endpoint = match (registration.executable, registration.socket) {
+            (Some(executable), None) => PluginEndpoint::Executable(executable),
+            (None, Some(socket)) => PluginEndpoint::Socket(socket),
+            _ => bail!("Exactly one of executable or socket must be provided"),
+        };
+        if registration.modules.is_empty() {
+            bail!("No modules registered");
+        }
+        let mut modules = Vec::new();
+        for name in &registration.modules {
+            // Plugins can only provide modules the client does not handle itself
+            let module: ServiceInfoModule = name.parse()?;
+            if !matches!(module, ServiceInfoModule::Unsupported(_)) {
+                bail!("Module {} is handled by the client itself", module);
+            }
+            modules.push(module);
+        }
+
+        Ok(Plugin {
+            name: path.display().to_string(),
+            modules,
+            endpoint,
+            timeout: PLUGIN_TIMEOUT,
+        })
+    }
+
+    async fn exchange(&self, input: &[u8]) -> Result<Vec<u8>> {
+        tokio::time::timeout(self.timeout, self.exchange_inner(input))
+            .await
+            .map_err(|_| anyhow!("Plugin did not answer within {:?}", self.timeout))?
+    }
+
+    async fn exchange_inner(&self, input: &[u8]) -> Result<Vec<u8>> {
+        match &self.endpoint {
+            PluginEndpoint::Executable(executable) => {
+                // The plugin is killed if it times out
+                let mut child = Command::new(executable)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .context("Error starting plugin")?;
+                // Write while reading the output, so a plugin that answers while
+                // still reading cannot block on a full pipe. Dropping stdin
+                // closes it, which signals the end of the input.
+                let mut stdin = child.stdin.take().unwrap();
+                let write_input = async move { stdin.write_all(input).await };
+                let (written, output) = tokio::join!(write_input, child.wait_with_output());
+                let output = output.context("Error waiting for plugin")?;
+                written.context("Error writing plugin input")?;
+                if !output.status.success() {
+                    bail!("Plugin failed with {}", output.status);
+                }
+                Ok(output.stdout)
+            }
+            PluginEndpoint::Socket(socket) => {
+                let mut stream = UnixStream::connect(socket)
+                    .await
+                    .context("Error connecting to plugin")?;
+                stream
+                    .write_all(input)
+                    .await
+                    .context("Error writing plugin input")?;
+                stream
+                    .shutdown()
+                    .await
+                    .context("Error closing plugin input")?;
+                let mut output = Vec::new();
+                stream
+                    .read_to_end(&mut output)
+                    .await
+                    .context("Error reading plugin output")?;
+                Ok(output)
+            }
+        }
+    }
+}
+
+/// ServiceInfo entries for a plugin, waiting to be sent to it
+#[derive(Debug, Default)]
+pub(super) struct PluginInput {
+    entries: Vec<(String, String, CborSimpleType)>,
+}
+
+impl PluginInput {
+    pub(super) fn push(&mut self, module: &ServiceInfoModule, key: &str, value: CborSimpleType) {
+        self.entries
+            .push((module.to_string(), key.to_string(), value));
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Plugins(Vec<Plugin>);
+
+impl Plugins {
+    /// Loads the plugins registered in the plugin directory, which can be
+    /// overridden with the SERVICEINFO_PLUGIN_DIR environment variable.
+    pub(super) fn load() -> Result<Self> {
+        let dir = match env::var("SERVICEINFO_PLUGIN_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => PathBuf::from(DEFAULT_PLUGIN_DIR),
+        };
+        Self::load_dir(&dir)
+    }
+
+    fn load_dir(dir: &Path) -> Result<Self> {
+        if !dir.exists() {
+            log::trace!("No ServiceInfo plugin directory at {:?}", dir);
+            return Ok(Plugins::default());
+        }
+
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).context("Error reading plugin directory")? {
+            let path = entry.context("Error reading plugin directory")?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("yml") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut plugins: Vec<Plugin> = Vec::new();
+        for path in paths {
+            let plugin = Plugin::from_file(&path)
+                .with_context(|| format!("Error loading ServiceInfo plugin {:?}", path))?;
+            for module in &plugin.modules {
+                if let Some(other) = plugins.iter().find(|other| other.modules.contains(module)) {
+                    bail!(
+                        "Module {} registered by both {} and {}",
+                        module,
+                        other.name,
+                        plugin.name
+                    );
+                }
+            }
+            log::debug!(
+                "Loaded ServiceInfo plugin {} for modules {:?}",
+                plugin.name,
+                plugin.modules
+            );
+            plugins.push(plugin);
+        }
+        Ok(Plugins(plugins))
+    }
+
+    pub(super) fn modules(&self) -> impl Iterator<Item = &ServiceInfoModule> {
+        self.0.iter().flat_map(|plugin| plugin.modules.iter())
+    }
+
+    pub(super) fn new_inputs(&self) -> Vec<PluginInput> {
+        self.0.iter().map(|_| PluginInput::default()).collect()
+    }
+
+    /// Returns the index of the plugin handling a module, if any
+    pub(super) fn find(&self, module: &ServiceInfoModule) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|plugin| plugin.modules.contains(module))
+    }
+
+    /// Sends the gathered entries to their plugins, and adds their responses
+    pub(super) async fn execute(
+        &self,
+        inputs: Vec<PluginInput>,
+        si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        for (plugin, input) in self.0.iter().zip(inputs) {
+            if input.entries.is_empty() {
+                continue;
+            }
+            log::debug!(
+                "Sending {} ServiceInfo entries to plugin {}",
+                input.entries.len(),
+                plugin.name
+            );
+
+            let mut request = Vec::new();
+            for entry in &input.entries {
+                serde_cbor::to_writer(&mut request, entry)
+                    .context("Error encoding plugin input")?;
+            }
+            let response = plugin
+                .exchange(&request)
+                .await
+                .with_context(|| format!("Error running ServiceInfo plugin {}", plugin.name))?;
+
+            for entry in
+                serde_cbor::Deserializer::from_slice(&response)
+                    .into_iter::<(String, String, CborSimpleType)>()
+            {
+                let (module, key, value) = entry.with_context(|| {
+                    format!("Error parsing output of ServiceInfo plugin {}", plugin.name)
+                })?;
+                let module: ServiceInfoModule = module.parse()?;
+                if !plugin.modules.contains(&module) {
+                    bail!(
+                        "ServiceInfo plugin {} returned a value for module {} it does not handle",
+                        plugin.name,
+                        module
+                    );
+                }
+                si_out.add(module, &key, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::net::UnixListener;
+
+    fn plugin_dir(name: &str, registrations: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fdo-plugins-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in registrations {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn echo_inputs(plugins: &Plugins, module: &ServiceInfoModule) -> Vec<PluginInput> {
+        let mut inputs = plugins.new_inputs();
+        inputs[0].push(module, "active", CborSimpleType::Bool(true));
+        inputs[0].push(
+            module,
+            "greeting",
+            CborSimpleType::Text("hello".to_string()),
+        );
+        inputs
+    }
+
+    fn assert_echoed(si_out: &ServiceInfo, module: &ServiceInfoModule) {
+        assert_eq!(
+            si_out.iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    module.clone(),
+                    "active".to_string(),
+                    CborSimpleType::Bool(true)
+                ),
+                (
+                    module.clone(),
+                    "greeting".to_string(),
+                    CborSimpleType::Text("hello".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = plugin_dir(
+            "load",
+            &[
+                (
+                    "10-executable.yml",
+                    "modules:\n  - com.example.a\n  - com.example.b\nexecutable: /usr/libexec/a\n",
+                ),
+                (
+                    "20-socket.yml",
+                    "modules:\n  - com.example.c\nsocket: /run/c.sock\n",
+                ),
+                ("README", "Not a plugin registration"),
+            ],
+        );
+        let plugins = Plugins::load_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            plugins.modules().map(|m| m.to_string()).collect::<Vec<_>>(),
+            vec!["com.example.a", "com.example.b", "com.example.c"]
+        );
+        assert_eq!(plugins.find(&"com.example.b".parse().unwrap()), Some(0));
+        assert_eq!(plugins.find(&"com.example.c".parse().unwrap()), Some(1));
+        assert_eq!(plugins.find(&"com.example.d".parse().unwrap()), None);
+
+        let missing = env::temp_dir().join(format!("fdo-plugins-missing-{}", std::process::id()));
+        assert!(Plugins::load_dir(&missing).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        for (name, registration) in [
+            (
+                "both",
+                "modules:\n  - com.example.a\nexecutable: /a\nsocket: /a.sock\n",
+            ),
+            ("neither", "modules:\n  - com.example.a\n"),
+            ("no-modules", "modules: []\nexecutable: /a\n"),
+            (
+                "builtin",
+                "modules:\n  - org.fedoraiot.sshkey\nexecutable: /a\n",
+            ),
+            ("standard", "modules:\n  - devmod\nexecutable: /a\n"),
+            (
+                "unknown-field",
+                "modules:\n  - com.example.a\nexecutable: /a\nargs: []\n",
+            ),
+        ] {
+            let dir = plugin_dir(name, &[("plugin.yml", registration)]);
+            let result = Plugins::load_dir(&dir);
+            fs::remove_dir_all(&dir).unwrap();
+            assert!(result.is_err(), "{} registration was accepted", name);
+        }
+    }
+
+    #[test]
+    fn test_load_duplicate_module() {
+        let dir = plugin_dir(
+            "duplicate",
+            &[
+                (
+                    "10-first.yml",
+                    "modules:\n  - com.example.a\nexecutable: /a\n",
+                ),
+                (
+                    "20-second.yml",
+                    "modules:\n  - com.example.b\n  - com.example.a\nsocket: /b.sock\n",
+                ),
+            ],
+        );
+        let result = Plugins::load_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(
+            error.contains("Module com.example.a registered by both"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_executable_round_trip() {
+        // cat answers with the entries it was sent
+        let dir = plugin_dir(
+            "executable",
+            &[(
+                "plugin.yml",
+                "modules:\n  - com.example.echo\nexecutable: /bin/cat\n",
+            )],
+        );
+        let plugins = Plugins::load_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let module: ServiceInfoModule = "com.example.echo".parse().unwrap();
+        let mut si_out = ServiceInfo::new();
+        plugins
+            .execute(echo_inputs(&plugins, &module), &mut si_out)
+            .await
+            .unwrap();
+        assert_echoed(&si_out, &module);
+
+        // Plugins without input are not run
+        let mut si_out = ServiceInfo::new();
+        plugins
+            .execute(plugins.new_inputs(), &mut si_out)
+            .await
+            .unwrap();
+        assert!(si_out.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_executable_failure() {
+        let dir = plugin_dir(
+            "failure",
+            &[(
+                "plugin.yml",
+                "modules:\n  - com.example.fail\nexecutable: /bin/false\n",
+            )],
+        );
+        let plugins = Plugins::load_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let module: ServiceInfoModule = "com.example.fail".parse().unwrap();
+        let mut si_out = ServiceInfo::new();
+        assert!(plugins
+            .execute(echo_inputs(&plugins, &module), &mut si_out)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_round_trip() {
+        let dir = plugin_dir("socket", &[]);
+        let socket = dir.join("plugin.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut input = Vec::new();
+            stream.read_to_end(&mut input).await.unwrap();
+            stream.write_all(&input).await.unwrap();
+        });
+        fs::write(
+            dir.join("plugin.yml"),
+            format!(
+                "modules:\n  - com.example.echo\nsocket: {}\n",
+                socket.display()
+            ),
+        )
+        .unwrap();
+        let plugins = Plugins::load_dir(&dir).unwrap();
+
+        let module: ServiceInfoModule = "com.example.echo".parse().unwrap();
+        let mut si_out = ServiceInfo::new();
+        let result = plugins
+            .execute(echo_inputs(&plugins, &module), &mut si_out)
+            .await;
+        server.await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_echoed(&si_out, &module);
+    }
+
+    #[tokio::test]
+    async fn test_socket_timeout() {
+        let dir = plugin_dir("timeout", &[]);
+        let socket = dir.join("plugin.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        // Accepts the connection, but never answers
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+            drop(stream);
+        });
+        fs::write(
+            dir.join("plugin.yml"),
+            format!(
+                "modules:\n  - com.example.hang\nsocket: {}\n",
+                socket.display()
+            ),
+        )
+        .unwrap();
+        let mut plugins = Plugins::load_dir(&dir).unwrap();
+        plugins.0[0].timeout = Duration::from_millis(100);
+
+        let module: ServiceInfoModule = "com.example.hang".parse().unwrap();
+        let mut si_out = ServiceInfo::new();
+        let result = plugins
+            .execute(echo_inputs(&plugins, &module), &mut si_out)
+            .await;
+        server.abort();
+        fs::remove_dir_all(&dir).unwrap();
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("Plugin did not answer within"), "{}", error);
+    }
+}
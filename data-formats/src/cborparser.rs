@@ -155,7 +155,7 @@ fn encode_item_start(major_type: MajorType, val: u64) -> Vec<u8> {
     vec![((major_type as u8) << 5) | ((val as u8) & MASK_VAL)]
 }
 
-fn read_len<R>(mut reader: R, header: u8) -> Result<(u64, Vec<u8>), ArrayParseError>
+fn read_len<R>(mut reader: R, header: u8) -> Result<(u64, Vec<u8>), Error>
 where
     R: std::io::Read,
 {
@@ -166,13 +166,13 @@ where
         25 => 2, // uint16_t
         26 => 4, // uint32_t
         27 => 8, // uint64_t
-        _ => return Err(ArrayParseError::LengthParseFailure),
+        _ => return Err(ArrayParseError::LengthParseFailure.into()),
     };
     if length_size == 0 {
         Ok((minor as u64, vec![]))
     } else {
         let mut buf = vec![0; length_size as usize];
-        reader.read_exact(&mut buf).unwrap();
+        reader.read_exact(&mut buf)?;
 
         match length_size {
             1 => Ok((buf[0] as u64, buf)),
@@ -302,7 +302,8 @@ impl<N: ParsedArraySize> Serializable for ParsedArray<N> {
             }
 
             match major_type {
-                MajorType::Unsigned | MajorType::Negative => {
+                // Simple values (like null) and floats are encoded just like integers
+                MajorType::Unsigned | MajorType::Negative | MajorType::Special => {
                     let (_, mut val_bytes) = read_len(&mut reader, minor)?;
                     toplevel_buf.append(&mut val_bytes);
                 }
@@ -378,6 +379,34 @@ impl<N: ParsedArraySize> Serializable for ParsedArray<N> {
     }
 }
 
+impl<N: ParsedArraySize> ParsedArray<N> {
+    /// Builds an array out of items that have already been serialized
+    pub(crate) fn from_raw_items(items: Vec<Vec<u8>>) -> Result<Self, Error> {
+        if let Some(expected_len) = N::SIZE {
+            if items.len() as u64 != expected_len {
+                return Err(ArrayParseError::InvalidNumberOfElements(
+                    items.len() as u64,
+                    expected_len,
+                )
+                .into());
+            }
+        }
+
+        Ok(ParsedArray {
+            tag: None,
+
+            header: None,
+            contents: items,
+
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    pub(crate) fn into_raw_items(self) -> Vec<Vec<u8>> {
+        self.contents
+    }
+}
+
 impl ParsedArray<ParsedArraySizeDynamic> {
     pub fn new_empty() -> Self {
         Self {
@@ -597,6 +626,21 @@ mod test {
         assert_eq!(serialized, data);
     }
 
+    #[test]
+    fn test_array_with_simple_values() {
+        // [null, [true, 1.5]]
+        let data = vec![0x82, 0xf6, 0x82, 0xf5, 0xf9, 0x3e, 0x00];
+        let parsed: ParsedArray<super::ParsedArraySize2> =
+            ParsedArray::deserialize_data(&data).expect("Failed to parse");
+        assert_eq!(
+            parsed.raw_values(),
+            vec![vec![0xf6], vec![0x82, 0xf5, 0xf9, 0x3e, 0x00]]
+        );
+        assert_eq!(parsed.get::<Option<u8>>(0).unwrap(), None);
+        let serialized = parsed.serialize_data().expect("Failed to serialize");
+        assert_eq!(serialized, data);
+    }
+
     #[test]
     fn test_array_with_two_integers_and_tag() {
         let data = vec![0xc6, 0x82, 0x01, 0x02];
@@ -899,4 +943,15 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn truncated_length() {
+        // Array of two items, with the second one a byte string whose length is cut off
+        let data = hex::decode("82015A0000").unwrap();
+        let err = ParsedArray::<super::ParsedArraySize2>::deserialize_data(&data)
+            .expect_err("Parsed truncated data");
+        assert!(
+            matches!(err, crate::Error::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+    }
 }
@@ -29,6 +29,9 @@ impl std::fmt::Display for ProtocolVersion {
     }
 }
 
+/// The maximum ServiceInfo message size to use if the receiving party did not specify one
+pub const DEFAULT_MAX_SERVICE_INFO_SIZE: u64 = 1300;
+
 #[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr, PartialEq, Eq)]
 #[repr(i8)]
 #[non_exhaustive]
@@ -271,11 +274,15 @@ impl RendezvousVariable {
                 _ => return Err(Error::InconsistentValue(self.name())),
             },
 
+            // These are hashes, written as "<algorithm>:<hex digest>"
+            RendezvousVariable::ServerCertHash | RendezvousVariable::CaCertHash => match val {
+                serde_cbor::Value::Text(v) => {
+                    serde_cbor::value::to_value(crate::types::Hash::from_str(&v)?)?
+                }
+                _ => return Err(Error::InconsistentValue(self.name())),
+            },
+
             // TODO
-            RendezvousVariable::ServerCertHash => {
-                return Err(Error::NotImplemented("ServerCertHash"))
-            }
-            RendezvousVariable::CaCertHash => return Err(Error::NotImplemented("CaCertHash")),
             RendezvousVariable::Medium => return Err(Error::NotImplemented("Medium")),
         })
     }
@@ -342,6 +349,7 @@ impl RendezvousProtocolValue {
         match self {
             RendezvousProtocolValue::Http => Some(80),
             RendezvousProtocolValue::Https => Some(443),
+            RendezvousProtocolValue::CoAPTCP | RendezvousProtocolValue::CoAPUDP => Some(5683),
             _ => None,
         }
     }
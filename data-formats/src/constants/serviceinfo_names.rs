@@ -59,6 +59,7 @@ impl FromStr for ServiceInfoModule {
             }
 
             "devmod" => StandardServiceInfoModule::DevMod.into(),
+            "fdo_sys" => StandardServiceInfoModule::FdoSys.into(),
 
             other => ServiceInfoModule::Unsupported(other.to_string()),
         })
@@ -104,6 +105,7 @@ impl Display for ServiceInfoModule {
 #[non_exhaustive]
 pub enum StandardServiceInfoModule {
     DevMod,
+    FdoSys,
 }
 
 impl Display for StandardServiceInfoModule {
@@ -113,6 +115,7 @@ impl Display for StandardServiceInfoModule {
             "{}",
             match self {
                 StandardServiceInfoModule::DevMod => "devmod",
+                StandardServiceInfoModule::FdoSys => "fdo_sys",
             }
         )
     }
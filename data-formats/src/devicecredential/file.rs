@@ -7,12 +7,12 @@ use crate::{
     constants::HashType,
     errors::Error,
     types::HMac,
-    types::{Guid, Hash, RendezvousInfo},
+    types::{COSESigningKey, COSESigningKeyRef, Guid, Hash, RendezvousInfo, RsaSigningKey},
     DeviceCredential, ProtocolVersion,
 };
 
 use aws_nitro_enclaves_cose::{error::CoseError, sign::SignatureAlgorithm};
-use openssl::{pkey::PKey, sign::Signer};
+use openssl::{hash::MessageDigest, pkey::PKey, rsa::Padding, sign::Signer};
 use serde::{Deserialize, Serialize};
 use serde_tuple::Serialize_tuple;
 use tss_esapi::{
@@ -186,6 +186,10 @@ impl DeviceCredential for FileDeviceCredential {
         }
     }
 
+    fn perform_hmac(&self, data: &[u8], hmac_type: HashType) -> Result<HMac, Error> {
+        self.key_storage.perform_hmac(data, hmac_type)
+    }
+
     fn device_info(&self) -> &str {
         &self.device_info
     }
@@ -202,9 +206,7 @@ impl DeviceCredential for FileDeviceCredential {
         &self.pubkey_hash
     }
 
-    fn get_signer(
-        &self,
-    ) -> Result<Box<dyn aws_nitro_enclaves_cose::crypto::SigningPrivateKey>, Error> {
+    fn get_signer(&self) -> Result<Box<dyn COSESigningKey>, Error> {
         match self.key_storage {
             KeyStorage::Plain {
                 ref private_key, ..
@@ -256,7 +258,9 @@ impl TpmCoseSigner {
         aws_nitro_enclaves_cose::error::CoseError,
     > {
         match public {
-            tss_esapi::structures::Public::Rsa { .. } => unimplemented!(),
+            tss_esapi::structures::Public::Rsa { .. } => Err(CoseError::UnsupportedError(
+                "RSA TPM keys do not sign with ECDSA".into(),
+            )),
             tss_esapi::structures::Public::Ecc { parameters, .. } => {
                 let hash_alg = match parameters.ecc_scheme() {
                     tss_esapi::structures::EccScheme::EcDsa(sig_alg) => sig_alg.hashing_algorithm(),
@@ -307,7 +311,103 @@ impl TpmCoseSigner {
                 }
                 Ok(((sig_alg, param_hash_alg), hash_alg, key_length))
             }
-            _ => unimplemented!(),
+            _ => Err(CoseError::UnsupportedError(
+                "Unsupported signing key type".into(),
+            )),
+        }
+    }
+
+    fn public_to_rsa_parameters(
+        public: &tss_esapi::structures::Public,
+    ) -> Result<
+        (
+            u32,
+            Padding,
+            tss_esapi::interface_types::algorithm::HashingAlgorithm,
+        ),
+        Error,
+    > {
+        let parameters = match public {
+            tss_esapi::structures::Public::Rsa { parameters, .. } => parameters,
+            _ => return Err(Error::UnsupportedAlgorithm),
+        };
+        let (padding, hash_alg) = match parameters.rsa_scheme() {
+            tss_esapi::structures::RsaScheme::RsaSsa(scheme) => {
+                (Padding::PKCS1, scheme.hashing_algorithm())
+            }
+            tss_esapi::structures::RsaScheme::RsaPss(scheme) => {
+                (Padding::PKCS1_PSS, scheme.hashing_algorithm())
+            }
+            _ => return Err(Error::UnsupportedAlgorithm),
+        };
+        let key_bits = tss_esapi::tss2_esys::TPMI_RSA_KEY_BITS::from(parameters.key_bits());
+        Ok((key_bits.into(), padding, hash_alg))
+    }
+
+    fn tpm_sign(&self, digest: &[u8]) -> Result<tss_esapi::structures::Signature, CoseError> {
+        let validation = tss_esapi::tss2_esys::TPMT_TK_HASHCHECK {
+            tag: tss_esapi::constants::tss::TPM2_ST_HASHCHECK,
+            hierarchy: tss_esapi::constants::tss::TPM2_RH_NULL,
+            digest: Default::default(),
+        }
+        .try_into()
+        .map_err(|_| {
+            CoseError::UnsupportedError("Error converting TPMT_TK_HASHCHECK".to_string())
+        })?;
+        let data = tss_esapi::structures::Digest::try_from(digest).map_err(|_| {
+            CoseError::UnsupportedError("Invalid data signing attempted".to_string())
+        })?;
+
+        let mut ctx = self.tss_context.borrow_mut();
+        ctx.execute_with_nullauth_session(|ctx| {
+            ctx.sign(
+                self.signing_handle,
+                data,
+                tss_esapi::structures::SignatureScheme::Null,
+                validation,
+            )
+        })
+        .map_err(|e| CoseError::UnsupportedError(format!("Error signing: {}", e)))
+    }
+}
+
+impl COSESigningKey for TpmCoseSigner {
+    fn cose_signing_key(&self) -> COSESigningKeyRef<'_> {
+        match self.signing_public {
+            tss_esapi::structures::Public::Rsa { .. } => COSESigningKeyRef::Rsa(self),
+            _ => COSESigningKeyRef::Cose(self),
+        }
+    }
+}
+
+impl RsaSigningKey for TpmCoseSigner {
+    fn rsa_parameters(&self) -> Result<(u32, Padding), Error> {
+        let (key_bits, padding, _) = Self::public_to_rsa_parameters(&self.signing_public)?;
+        Ok((key_bits, padding))
+    }
+
+    fn rsa_sign(&self, digest: MessageDigest, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let key_digest = match Self::public_to_rsa_parameters(&self.signing_public)?.2 {
+            tss_esapi::interface_types::algorithm::HashingAlgorithm::Sha256 => {
+                MessageDigest::sha256()
+            }
+            tss_esapi::interface_types::algorithm::HashingAlgorithm::Sha384 => {
+                MessageDigest::sha384()
+            }
+            _ => return Err(Error::UnsupportedAlgorithm),
+        };
+        // The TPM only signs digests of the hashing algorithm in the key scheme
+        if key_digest.type_() != digest.type_() {
+            return Err(Error::UnsupportedAlgorithm);
+        }
+        let digest = openssl::hash::hash(digest, data)?;
+
+        match self.tpm_sign(&digest)? {
+            tss_esapi::structures::Signature::RsaSsa(signature)
+            | tss_esapi::structures::Signature::RsaPss(signature) => {
+                Ok(signature.signature().value().to_vec())
+            }
+            _ => Err(Error::InconsistentValue("Invalid signature type")),
         }
     }
 }
@@ -352,39 +452,15 @@ fn merge_ec_signature(bytes_r: &[u8], bytes_s: &[u8], key_length: usize) -> Vec<
 
 impl aws_nitro_enclaves_cose::crypto::SigningPrivateKey for TpmCoseSigner {
     fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, CoseError> {
-        let key_length = Self::public_to_parameters(&self.signing_public)?.2;
-        let validation = tss_esapi::tss2_esys::TPMT_TK_HASHCHECK {
-            tag: tss_esapi::constants::tss::TPM2_ST_HASHCHECK,
-            hierarchy: tss_esapi::constants::tss::TPM2_RH_NULL,
-            digest: Default::default(),
-        }
-        .try_into()
-        .map_err(|_| {
-            CoseError::UnsupportedError("Error converting TPMT_TK_HASHCHECK".to_string())
-        })?;
-        let data = tss_esapi::structures::Digest::try_from(digest).map_err(|_| {
-            CoseError::UnsupportedError("Invalid data signing attempted".to_string())
-        })?;
-
-        // Special scope to not leak the context
-        let signature = {
-            let mut ctx = self.tss_context.borrow_mut();
-            ctx.execute_with_nullauth_session(|ctx| {
-                ctx.sign(
-                    self.signing_handle,
-                    data,
-                    tss_esapi::structures::SignatureScheme::Null,
-                    validation,
-                )
-            })
-            .map_err(|e| CoseError::UnsupportedError(format!("Error signing: {}", e)))?
-        };
-        match signature {
-            tss_esapi::structures::Signature::EcDsa(signature) => Ok(merge_ec_signature(
-                signature.signature_r().value(),
-                signature.signature_s().value(),
-                key_length,
-            )),
+        match self.tpm_sign(digest)? {
+            tss_esapi::structures::Signature::EcDsa(signature) => {
+                let key_length = Self::public_to_parameters(&self.signing_public)?.2;
+                Ok(merge_ec_signature(
+                    signature.signature_r().value(),
+                    signature.signature_s().value(),
+                    key_length,
+                ))
+            }
             _ => Err(CoseError::UnsupportedError(
                 "Invalid signature type".to_string(),
             )),
@@ -43,6 +43,8 @@ impl RendezvousInterpretedDirective {
         let protocol_text = match self.protocol {
             RendezvousProtocolValue::Http => "http",
             RendezvousProtocolValue::Https => "https",
+            RendezvousProtocolValue::CoAPUDP => "coap",
+            RendezvousProtocolValue::CoAPTCP => "coap+tcp",
             _ => return Vec::new(),
         };
 
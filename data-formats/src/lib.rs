@@ -21,6 +21,7 @@ pub mod cborparser;
 
 mod serializable;
 pub use serializable::DeserializableMany;
+pub use serializable::MaybeSerializable;
 pub use serializable::Serializable;
 
 pub fn interoperable_kdf_available() -> bool {
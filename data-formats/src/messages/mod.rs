@@ -5,6 +5,7 @@ use crate::{
     ProtocolVersion, Serializable,
 };
 
+pub mod v10;
 pub mod v11;
 
 pub trait ClientMessage: Message {}
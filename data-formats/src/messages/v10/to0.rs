@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use serde_tuple::Serialize_tuple;
 
 use crate::{
-    cborparser::ParsedArray,
+    cborparser::{ParsedArray, ParsedArrayBuilder},
     constants::{HashType, MessageType},
     messages::{ClientMessage, EncryptionRequirement, Message, ServerMessage},
     types::{COSESign, Hash, Nonce, TO0Data},
@@ -121,9 +121,10 @@ impl Serializable for OwnerSign {
 
 impl OwnerSign {
     pub fn new(to0d: TO0Data, to1d: COSESign) -> Result<Self, Error> {
-        let mut contents = unsafe { ParsedArray::new() };
+        let mut contents = ParsedArrayBuilder::new();
         contents.set(0, &to0d)?;
         contents.set(1, &to1d)?;
+        let contents = contents.build();
 
         Ok(OwnerSign {
             contents,
@@ -4,7 +4,7 @@ use serde_tuple::Serialize_tuple;
 use crate::simple_message_serializable;
 use crate::{
     constants::MessageType,
-    messages::{ClientMessage, EncryptionRequirement, Message, ServerMessage},
+    messages::{v11, ClientMessage, EncryptionRequirement, Message, ServerMessage},
     types::{COSESign, Guid, Nonce, SigInfo},
 };
 
@@ -167,3 +167,33 @@ impl Message for RVRedirect {
 }
 
 impl ServerMessage for RVRedirect {}
+
+// The TO1 messages carry the same information in protocol 1.0 and 1.1, so
+// implementations can handle both versions with the same code.
+
+impl From<v11::to1::HelloRV> for HelloRV {
+    fn from(msg: v11::to1::HelloRV) -> Self {
+        HelloRV {
+            guid: msg.guid().clone(),
+            a_signature_info: msg.a_signature_info().clone(),
+        }
+    }
+}
+
+impl From<HelloRVAck> for v11::to1::HelloRVAck {
+    fn from(msg: HelloRVAck) -> Self {
+        v11::to1::HelloRVAck::new(msg.nonce4, msg.b_signature_info)
+    }
+}
+
+impl From<v11::to1::ProveToRV> for ProveToRV {
+    fn from(msg: v11::to1::ProveToRV) -> Self {
+        ProveToRV(msg.token().clone())
+    }
+}
+
+impl From<RVRedirect> for v11::to1::RVRedirect {
+    fn from(msg: RVRedirect) -> Self {
+        v11::to1::RVRedirect::new(msg.0)
+    }
+}
@@ -1,16 +1,17 @@
 use serde::Deserialize;
+use serde_bytes::ByteBuf;
 use serde_tuple::Serialize_tuple;
 
 use crate::{
-    cborparser::ParsedArray,
-    messages::{ClientMessage, EncryptionRequirement, Message, ServerMessage},
+    cborparser::{ParsedArray, ParsedArrayBuilder, ParsedArraySize6},
+    messages::{v11, ClientMessage, EncryptionRequirement, Message, ServerMessage},
     ownershipvoucher::OwnershipVoucherEntry,
     simple_message_serializable, Serializable,
 };
 
 use crate::{
     constants::MessageType,
-    types::{COSESign, CipherSuite, Guid, HMac, KexSuite, Nonce, ServiceInfo, SigInfo},
+    types::{self, COSESign, CipherSuite, Guid, HMac, KexSuite, Nonce, ServiceInfo, SigInfo},
 };
 
 #[derive(Debug, Serialize_tuple, Deserialize)]
@@ -115,6 +116,115 @@ impl Message for ProveOVHdr {
 
 impl ServerMessage for ProveOVHdr {}
 
+/// The payload of ProveOVHdr
+///
+/// Compared to protocol 1.1, this contains the Ownership Voucher header without
+/// bstr wrapping, and neither the HelloDevice hash nor the maximum message size.
+#[derive(Debug)]
+pub struct ProveOVHdrPayload {
+    contents: ParsedArray<ParsedArraySize6>,
+
+    cached_ov_header: ByteBuf,
+    cached_num_ov_entries: u16,
+    cached_hmac: HMac,
+    cached_nonce5: Nonce,
+    cached_b_signature_info: SigInfo,
+    cached_a_key_exchange: ByteBuf,
+}
+
+impl Serializable for ProveOVHdrPayload {
+    fn deserialize_from_reader<R>(reader: R) -> Result<Self, crate::Error>
+    where
+        R: std::io::Read,
+    {
+        let contents: ParsedArray<ParsedArraySize6> = ParsedArray::deserialize_from_reader(reader)?;
+
+        let cached_ov_header = ByteBuf::from(contents.get_raw(0));
+        let cached_num_ov_entries = contents.get(1)?;
+        let cached_hmac = contents.get(2)?;
+        let cached_nonce5 = contents.get(3)?;
+        let cached_b_signature_info = contents.get(4)?;
+        let cached_a_key_exchange = contents.get(5)?;
+
+        Ok(ProveOVHdrPayload {
+            contents,
+
+            cached_ov_header,
+            cached_num_ov_entries,
+            cached_hmac,
+            cached_nonce5,
+            cached_b_signature_info,
+            cached_a_key_exchange,
+        })
+    }
+
+    fn serialize_to_writer<W>(&self, writer: W) -> Result<(), crate::Error>
+    where
+        W: std::io::Write,
+    {
+        self.contents.serialize_to_writer(writer)
+    }
+}
+
+impl ProveOVHdrPayload {
+    pub fn new(
+        ov_header: ByteBuf,
+        num_ov_entries: u16,
+        hmac: HMac,
+        nonce5: Nonce,
+        b_signature_info: SigInfo,
+        a_key_exchange: Vec<u8>,
+    ) -> Result<Self, crate::Error> {
+        let a_key_exchange = ByteBuf::from(a_key_exchange);
+
+        let contents = ParsedArray::from_raw_items(vec![
+            ov_header.to_vec(),
+            num_ov_entries.serialize_data()?,
+            hmac.serialize_data()?,
+            nonce5.serialize_data()?,
+            b_signature_info.serialize_data()?,
+            a_key_exchange.serialize_data()?,
+        ])?;
+
+        Ok(ProveOVHdrPayload {
+            contents,
+
+            cached_ov_header: ov_header,
+            cached_num_ov_entries: num_ov_entries,
+            cached_hmac: hmac,
+            cached_nonce5: nonce5,
+            cached_b_signature_info: b_signature_info,
+            cached_a_key_exchange: a_key_exchange,
+        })
+    }
+}
+
+impl types::ProveOVHdrPayload for ProveOVHdrPayload {
+    fn ov_header(&self) -> &[u8] {
+        &self.cached_ov_header
+    }
+
+    fn num_ov_entries(&self) -> u16 {
+        self.cached_num_ov_entries
+    }
+
+    fn hmac(&self) -> &HMac {
+        &self.cached_hmac
+    }
+
+    fn nonce5(&self) -> &Nonce {
+        &self.cached_nonce5
+    }
+
+    fn b_signature_info(&self) -> &SigInfo {
+        &self.cached_b_signature_info
+    }
+
+    fn a_key_exchange(&self) -> &[u8] {
+        &self.cached_a_key_exchange
+    }
+}
+
 #[derive(Debug, Serialize_tuple, Deserialize)]
 pub struct GetOVNextEntry {
     entry_num: u8,
@@ -176,10 +286,11 @@ impl Serializable for OVNextEntry {
     where
         W: std::io::Write,
     {
-        let mut contents: ParsedArray<crate::cborparser::ParsedArraySize2> =
-            unsafe { ParsedArray::new() };
+        let mut contents: ParsedArrayBuilder<crate::cborparser::ParsedArraySize2> =
+            ParsedArrayBuilder::new();
         contents.set(0, &self.entry_num)?;
         contents.set(1, &self.entry)?;
+        let contents = contents.build();
 
         contents.serialize_to_writer(writer)
     }
@@ -533,3 +644,183 @@ impl Message for Done2 {
 }
 
 impl ServerMessage for Done2 {}
+
+// Apart from the ProveOVHdr payload, the TO2 messages carry the same information in
+// protocol 1.0 and 1.1, so implementations can handle both versions with the same code.
+
+impl From<HelloDevice> for v11::to2::HelloDevice {
+    fn from(msg: HelloDevice) -> Self {
+        v11::to2::HelloDevice::new(
+            msg.guid,
+            msg.nonce5,
+            msg.kex_suite,
+            msg.cipher_suite,
+            msg.a_signature_info,
+        )
+    }
+}
+
+impl From<v11::to2::ProveOVHdr> for ProveOVHdr {
+    fn from(msg: v11::to2::ProveOVHdr) -> Self {
+        ProveOVHdr(msg.into_token())
+    }
+}
+
+impl From<GetOVNextEntry> for v11::to2::GetOVNextEntry {
+    fn from(msg: GetOVNextEntry) -> Self {
+        v11::to2::GetOVNextEntry::new(msg.entry_num)
+    }
+}
+
+impl From<v11::to2::OVNextEntry> for OVNextEntry {
+    fn from(msg: v11::to2::OVNextEntry) -> Self {
+        OVNextEntry {
+            entry_num: msg.entry_num(),
+            entry: msg.into_entry(),
+        }
+    }
+}
+
+impl From<ProveDevice> for v11::to2::ProveDevice {
+    fn from(msg: ProveDevice) -> Self {
+        v11::to2::ProveDevice::new(msg.0)
+    }
+}
+
+impl From<v11::to2::SetupDevice> for SetupDevice {
+    fn from(msg: v11::to2::SetupDevice) -> Self {
+        SetupDevice(msg.into_token())
+    }
+}
+
+impl From<DeviceServiceInfoReady> for v11::to2::DeviceServiceInfoReady {
+    fn from(msg: DeviceServiceInfoReady) -> Self {
+        v11::to2::DeviceServiceInfoReady::new(msg.replacement_hmac, msg.max_owner_service_info_size)
+    }
+}
+
+impl From<v11::to2::OwnerServiceInfoReady> for OwnerServiceInfoReady {
+    fn from(msg: v11::to2::OwnerServiceInfoReady) -> Self {
+        OwnerServiceInfoReady {
+            max_device_service_info_size: msg.max_device_service_info_size(),
+        }
+    }
+}
+
+impl From<DeviceServiceInfo> for v11::to2::DeviceServiceInfo {
+    fn from(msg: DeviceServiceInfo) -> Self {
+        v11::to2::DeviceServiceInfo::new(msg.is_more_service_info, msg.service_info)
+    }
+}
+
+impl From<v11::to2::OwnerServiceInfo> for OwnerServiceInfo {
+    fn from(msg: v11::to2::OwnerServiceInfo) -> Self {
+        OwnerServiceInfo {
+            is_more_service_info: msg.is_more_service_info(),
+            is_done: msg.is_done(),
+            service_info: msg.into_service_info(),
+        }
+    }
+}
+
+impl From<Done> for v11::to2::Done {
+    fn from(msg: Done) -> Self {
+        v11::to2::Done::new(msg.nonce6)
+    }
+}
+
+impl From<v11::to2::Done2> for Done2 {
+    fn from(msg: v11::to2::Done2) -> Self {
+        Done2 {
+            nonce7: msg.nonce7().clone(),
+        }
+    }
+}
+
+impl From<v11::to2::HelloDevice> for HelloDevice {
+    fn from(msg: v11::to2::HelloDevice) -> Self {
+        HelloDevice {
+            guid: msg.guid().clone(),
+            nonce5: msg.nonce5().clone(),
+            kex_suite: msg.kex_suite(),
+            cipher_suite: msg.cipher_suite(),
+            a_signature_info: msg.a_signature_info().clone(),
+        }
+    }
+}
+
+impl From<ProveOVHdr> for v11::to2::ProveOVHdr {
+    fn from(msg: ProveOVHdr) -> Self {
+        v11::to2::ProveOVHdr::new(msg.0)
+    }
+}
+
+impl From<v11::to2::GetOVNextEntry> for GetOVNextEntry {
+    fn from(msg: v11::to2::GetOVNextEntry) -> Self {
+        GetOVNextEntry {
+            entry_num: msg.entry_num(),
+        }
+    }
+}
+
+impl From<OVNextEntry> for v11::to2::OVNextEntry {
+    fn from(msg: OVNextEntry) -> Self {
+        v11::to2::OVNextEntry::new(msg.entry_num, msg.entry)
+    }
+}
+
+impl From<v11::to2::ProveDevice> for ProveDevice {
+    fn from(msg: v11::to2::ProveDevice) -> Self {
+        ProveDevice(msg.into_token())
+    }
+}
+
+impl From<SetupDevice> for v11::to2::SetupDevice {
+    fn from(msg: SetupDevice) -> Self {
+        v11::to2::SetupDevice::new(msg.0)
+    }
+}
+
+impl From<v11::to2::DeviceServiceInfoReady> for DeviceServiceInfoReady {
+    fn from(msg: v11::to2::DeviceServiceInfoReady) -> Self {
+        DeviceServiceInfoReady {
+            replacement_hmac: msg.replacement_hmac().cloned(),
+            max_owner_service_info_size: msg.max_owner_service_info_size(),
+        }
+    }
+}
+
+impl From<OwnerServiceInfoReady> for v11::to2::OwnerServiceInfoReady {
+    fn from(msg: OwnerServiceInfoReady) -> Self {
+        v11::to2::OwnerServiceInfoReady::new(msg.max_device_service_info_size)
+    }
+}
+
+impl From<v11::to2::DeviceServiceInfo> for DeviceServiceInfo {
+    fn from(msg: v11::to2::DeviceServiceInfo) -> Self {
+        DeviceServiceInfo {
+            is_more_service_info: msg.is_more_service_info(),
+            service_info: msg.into_service_info(),
+        }
+    }
+}
+
+impl From<OwnerServiceInfo> for v11::to2::OwnerServiceInfo {
+    fn from(msg: OwnerServiceInfo) -> Self {
+        v11::to2::OwnerServiceInfo::new(msg.is_more_service_info, msg.is_done, msg.service_info)
+    }
+}
+
+impl From<v11::to2::Done> for Done {
+    fn from(msg: v11::to2::Done) -> Self {
+        Done {
+            nonce6: msg.nonce6().clone(),
+        }
+    }
+}
+
+impl From<Done2> for v11::to2::Done2 {
+    fn from(msg: Done2) -> Self {
+        v11::to2::Done2::new(msg.nonce7)
+    }
+}
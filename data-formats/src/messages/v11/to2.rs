@@ -400,6 +400,10 @@ impl DeviceServiceInfo {
     pub fn service_info(&self) -> &ServiceInfo {
         &self.service_info
     }
+
+    pub fn into_service_info(self) -> ServiceInfo {
+        self.service_info
+    }
 }
 
 impl Message for DeviceServiceInfo {
@@ -452,6 +456,10 @@ impl OwnerServiceInfo {
     pub fn service_info(&self) -> &ServiceInfo {
         &self.service_info
     }
+
+    pub fn into_service_info(self) -> ServiceInfo {
+        self.service_info
+    }
 }
 
 impl Message for OwnerServiceInfo {
@@ -8,7 +8,8 @@ use serde_tuple::Serialize_tuple;
 
 use crate::{
     cborparser::{
-        ParsedArray, ParsedArrayBuilder, ParsedArraySize5, ParsedArraySize6, ParsedArraySizeDynamic,
+        ArrayParseError, ParsedArray, ParsedArrayBuilder, ParsedArraySize4, ParsedArraySize5,
+        ParsedArraySize6, ParsedArraySizeDynamic,
     },
     constants::HashType,
     errors::Result,
@@ -18,7 +19,7 @@ use crate::{
     DeserializableMany, Error, ProtocolVersion, Serializable,
 };
 
-const VOUCHER_PEM_TAG: &str = "OWNERSHIP VOUCHER";
+pub const VOUCHER_PEM_TAG: &str = "OWNERSHIP VOUCHER";
 const ACCEPTABLE_ASCII_RANGE: Range<u8> = 32..127;
 
 type ExtraType = Option<HashMap<u128, ByteBuf>>;
@@ -73,14 +74,19 @@ impl Serializable for OwnershipVoucher {
     where
         R: std::io::Read,
     {
-        let contents = ParsedArray::deserialize_from_reader(reader);
-        if let Err(Error::ArrayParseError(
-            crate::cborparser::ArrayParseError::InvalidNumberOfElements(4, 5),
-        )) = contents
-        {
-            return Err(Error::UnsupportedVersion(Some(ProtocolVersion::Version1_0)));
+        let contents: ParsedArray<ParsedArraySizeDynamic> =
+            ParsedArray::deserialize_from_reader(reader)?;
+        let contents = match contents.len() {
+            5 => ParsedArray::from_raw_items(contents.into_raw_items())?,
+            4 => {
+                // Protocol 1.0 vouchers have no protocol version, and an unwrapped header
+                let mut items = contents.into_raw_items();
+                items[0] = ByteBuf::from(items[0].clone()).serialize_data()?;
+                items.insert(0, ProtocolVersion::Version1_0.serialize_data()?);
+                ParsedArray::from_raw_items(items)?
+            }
+            len => return Err(ArrayParseError::InvalidNumberOfElements(len as u64, 5).into()),
         };
-        let contents = contents?;
         Self::from_parsed_array(contents)
     }
 
@@ -88,7 +94,15 @@ impl Serializable for OwnershipVoucher {
     where
         W: std::io::Write,
     {
-        self.contents.serialize_to_writer(writer)
+        match self.cached_protocol_version {
+            ProtocolVersion::Version1_0 => {
+                let mut items = self.contents.clone().into_raw_items();
+                items.remove(OwnershipVoucherIndex::ProtocolVersion as usize);
+                items[0] = self.header_raw().into_vec();
+                ParsedArray::<ParsedArraySize4>::from_raw_items(items)?.serialize_to_writer(writer)
+            }
+            _ => self.contents.serialize_to_writer(writer),
+        }
     }
 }
 
@@ -146,13 +160,14 @@ impl OwnershipVoucher {
         device_certificate_chain: Option<X5Chain>,
     ) -> Result<Self> {
         let entries = ParsedArray::new_empty();
+        let protocol_version = header.protocol_version();
 
         let contents_header = ByteBuf::from(header.contents.serialize_data()?);
 
         let mut contents = ParsedArrayBuilder::new();
         contents.set(
             OwnershipVoucherIndex::ProtocolVersion as usize,
-            &ProtocolVersion::Version1_1,
+            &protocol_version,
         )?;
         contents.set(OwnershipVoucherIndex::Header as usize, &contents_header)?;
         contents.set(OwnershipVoucherIndex::HeaderHmac as usize, &header_hmac)?;
@@ -166,7 +181,7 @@ impl OwnershipVoucher {
         Ok(OwnershipVoucher {
             contents,
 
-            cached_protocol_version: ProtocolVersion::Version1_1,
+            cached_protocol_version: protocol_version,
             cached_header: header,
             cached_header_hmac: header_hmac,
             cached_device_certificate_chain: device_certificate_chain,
@@ -211,6 +226,10 @@ impl OwnershipVoucher {
         Ok(pem::encode(&block))
     }
 
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.cached_protocol_version
+    }
+
     fn hash_type(&self) -> HashType {
         self.cached_header_hmac.get_type().inner_hash()
     }
@@ -290,12 +309,24 @@ impl OwnershipVoucher {
             return Err(Error::NonOwnerKey);
         }
 
-        // Create new entry
-        let new_entry =
-            OwnershipVoucherEntryPayload::new(last_hash, hdrinfo_hash, extra, next_party.clone())?;
-
-        // Sign with private key
-        let signed_new_entry = COSESign::new(&new_entry, None, owner_private_key)?;
+        // Create new entry, and sign with private key
+        let signed_new_entry = match self.cached_protocol_version {
+            // Protocol 1.0 entries have no extra field
+            ProtocolVersion::Version1_0 => COSESign::new(
+                &(last_hash, hdrinfo_hash, next_party.clone()),
+                None,
+                owner_private_key,
+            )?,
+            _ => {
+                let new_entry = OwnershipVoucherEntryPayload::new(
+                    last_hash,
+                    hdrinfo_hash,
+                    extra,
+                    next_party.clone(),
+                )?;
+                COSESign::new(&new_entry, None, owner_private_key)?
+            }
+        };
         let signed_new_entry = OwnershipVoucherEntry::new(signed_new_entry);
 
         // Append
@@ -489,6 +520,26 @@ impl OwnershipVoucherHeader {
         })
     }
 
+    /// Builds the header of the voucher replacing this one after TO2 resale
+    ///
+    /// The device and the owner both build it from TO2.SetupDevice, so that the
+    /// owner can use the replacement HMAC the device computes over it.
+    pub fn new_replacement(
+        &self,
+        guid: Guid,
+        rendezvous_info: RendezvousInfo,
+        owner2_key: PublicKey,
+    ) -> Result<Self> {
+        OwnershipVoucherHeader::new(
+            self.protocol_version(),
+            guid,
+            rendezvous_info,
+            self.device_info().to_string(),
+            owner2_key,
+            self.device_certificate_chain_hash().cloned(),
+        )
+    }
+
     pub fn protocol_version(&self) -> ProtocolVersion {
         self.cached_protocol_version
     }
@@ -574,8 +625,13 @@ impl Serializable for OwnershipVoucherHeader {
         let cached_protocol_version =
             contents.get(OwnershipVoucherHeaderIndex::ProtocolVersion as usize)?;
         let cached_guid = contents.get(OwnershipVoucherHeaderIndex::Guid as usize)?;
-        let cached_rendezvous_info =
-            contents.get(OwnershipVoucherHeaderIndex::RendezvousInfo as usize)?;
+        let cached_rendezvous_info = match cached_protocol_version {
+            // In 1.0, the RendezvousInfo values are not wrapped in a bstr
+            ProtocolVersion::Version1_0 => RendezvousInfo::new(
+                contents.get(OwnershipVoucherHeaderIndex::RendezvousInfo as usize)?,
+            )?,
+            _ => contents.get(OwnershipVoucherHeaderIndex::RendezvousInfo as usize)?,
+        };
         let cached_device_info: String =
             contents.get(OwnershipVoucherHeaderIndex::DeviceInfo as usize)?;
         check_device_info(&cached_device_info)?;
@@ -638,7 +694,44 @@ impl std::ops::Deref for OwnershipVoucherEntry {
     }
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OwnershipVoucherEntryPayloadVersions {
+    V11(Hash, Hash, ExtraType, PublicKey),
+    // Protocol 1.0 entries have no extra field
+    V10(Hash, Hash, PublicKey),
+}
+
+impl From<OwnershipVoucherEntryPayloadVersions> for OwnershipVoucherEntryPayload {
+    fn from(versions: OwnershipVoucherEntryPayloadVersions) -> Self {
+        match versions {
+            OwnershipVoucherEntryPayloadVersions::V11(
+                hash_previous_entry,
+                hash_header_info,
+                extra,
+                public_key,
+            ) => OwnershipVoucherEntryPayload {
+                hash_previous_entry,
+                hash_header_info,
+                extra,
+                public_key,
+            },
+            OwnershipVoucherEntryPayloadVersions::V10(
+                hash_previous_entry,
+                hash_header_info,
+                public_key,
+            ) => OwnershipVoucherEntryPayload {
+                hash_previous_entry,
+                hash_header_info,
+                extra: None,
+                public_key,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize_tuple, Clone)]
+#[serde(from = "OwnershipVoucherEntryPayloadVersions")]
 pub struct OwnershipVoucherEntryPayload {
     hash_previous_entry: Hash,
     hash_header_info: Hash,
@@ -358,3 +358,147 @@ impl X5Chain {
         &self.chain
     }
 }
+
+#[cfg(test)]
+mod test_x5chain {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        x509::{X509Builder, X509NameBuilder, X509},
+    };
+
+    use crate::{
+        enhanced_types::X5Bag,
+        errors::{ChainError, Error},
+    };
+
+    use super::X5Chain;
+
+    fn new_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // Builds a certificate for subject_key, signed by the issuer (or self-signed if None)
+    fn new_cert(
+        subject: &str,
+        subject_key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, subject).unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(subject_key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder.sign(subject_key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        builder.build()
+    }
+
+    struct TestCa {
+        cert: X509,
+        key: PKey<Private>,
+    }
+
+    impl TestCa {
+        fn new(name: &str) -> Self {
+            let key = new_key();
+            let cert = new_cert(name, &key, None);
+            TestCa { cert, key }
+        }
+
+        fn issue(&self, subject: &str) -> X509 {
+            new_cert(subject, &new_key(), Some((&self.cert, &self.key)))
+        }
+
+        fn bag(&self) -> X5Bag {
+            X5Bag::with_certs(vec![self.cert.clone()]).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_x5chain_trusted_root() {
+        let ca = TestCa::new("Device CA");
+        let leaf = ca.issue("Device");
+        let chain = X5Chain::new(vec![leaf.clone(), ca.cert.clone()]).unwrap();
+
+        let verified = chain.verify_from_x5bag(&ca.bag()).unwrap();
+        assert_eq!(verified.to_der().unwrap(), leaf.to_der().unwrap());
+    }
+
+    #[test]
+    fn test_x5chain_untrusted_root() {
+        let ca = TestCa::new("Device CA");
+        let other_ca = TestCa::new("Other CA");
+        let chain = X5Chain::new(vec![ca.issue("Device"), ca.cert.clone()]).unwrap();
+
+        assert!(matches!(
+            chain.verify_from_x5bag(&other_ca.bag()),
+            Err(Error::InvalidChain(ChainError::NoTrustedRoot))
+        ));
+        // Without root verification, any self-consistent chain is accepted
+        assert!(chain.insecure_verify_without_root_verification().is_ok());
+    }
+
+    #[test]
+    fn test_x5chain_self_signed_leaf() {
+        let ca = TestCa::new("Device CA");
+        let key = new_key();
+        let chain = X5Chain::new(vec![new_cert("Device", &key, None)]).unwrap();
+
+        assert!(matches!(
+            chain.verify_from_x5bag(&ca.bag()),
+            Err(Error::InvalidChain(ChainError::NoTrustedRoot))
+        ));
+    }
+
+    #[test]
+    fn test_x5chain_mismatched_issuer() {
+        let ca = TestCa::new("Device CA");
+        let other_ca = TestCa::new("Other CA");
+        // The leaf was issued by another CA than the one the chain claims
+        let chain = X5Chain::new(vec![other_ca.issue("Device"), ca.cert.clone()]).unwrap();
+
+        assert!(matches!(
+            chain.verify_from_x5bag(&ca.bag()),
+            Err(Error::InvalidChain(ChainError::NonIssuer(0)))
+        ));
+        assert!(chain.insecure_verify_without_root_verification().is_err());
+    }
+
+    #[test]
+    fn test_x5chain_forged_signature() {
+        let ca = TestCa::new("Device CA");
+        // Same subject as the trusted CA, but a different key signed the leaf
+        let forged_ca = TestCa {
+            cert: ca.cert.clone(),
+            key: new_key(),
+        };
+        let chain = X5Chain::new(vec![forged_ca.issue("Device"), ca.cert.clone()]).unwrap();
+
+        assert!(matches!(
+            chain.verify_from_x5bag(&ca.bag()),
+            Err(Error::InvalidChain(ChainError::InvalidSignedCert(0)))
+        ));
+    }
+}
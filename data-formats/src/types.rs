@@ -7,6 +7,7 @@ use std::{
 };
 
 use aws_nitro_enclaves_cose::crypto::{SigningPrivateKey, SigningPublicKey};
+use aws_nitro_enclaves_cose::sign::SignatureAlgorithm;
 use aws_nitro_enclaves_cose::CoseSign1 as COSESignInner;
 use serde_bytes::ByteBuf;
 use serde_repr::{Deserialize_repr, Serialize_repr};
@@ -28,10 +29,13 @@ use openssl::{
     bn::{BigNum, BigNumContext},
     dh::Dh,
     ec::{EcGroup, EcKey, EcPoint},
+    encrypt::{Decrypter, Encrypter},
     hash::{hash, MessageDigest},
     nid::Nid,
-    pkey::Params,
+    pkey::{HasPublic, Id as PKeyId, PKey, PKeyRef, Params, Private, Public},
     rand::rand_bytes,
+    rsa::Padding,
+    sign::{RsaPssSaltlen, Signer, Verifier},
     symm::Cipher,
 };
 use openssl_kdf::{perform_kdf, KdfArgument, KdfKbMode, KdfMacType, KdfType};
@@ -731,7 +735,7 @@ impl TO2ProveDevicePayload {
     }
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct ServiceInfo(Vec<(String, ByteBuf)>);
 
@@ -778,6 +782,120 @@ impl ServiceInfo {
         ServiceInfoIter { info: self, pos: 0 }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn extend(&mut self, other: ServiceInfo) {
+        self.0.extend(other.0);
+    }
+
+    /// The encoded size of a DeviceServiceInfo or OwnerServiceInfo message carrying this ServiceInfo
+    pub fn message_size(&self) -> Result<usize, Error> {
+        // Array header and the IsMoreServiceInfo and IsDone booleans
+        Ok(3 + serde_cbor::to_vec(&self)?.len())
+    }
+
+    /// Splits the ServiceInfo over as many messages as needed to stay within max_message_size
+    ///
+    /// Byte string values too large for a single message are split over consecutive entries
+    /// with the same key, which the receiver joins again with [`ServiceInfo::join_chunks`].
+    /// Any other entry that is too large is sent in a message of its own.
+    pub fn split(self, max_message_size: u64) -> Result<Vec<ServiceInfo>, Error> {
+        let max_message_size = usize::try_from(max_message_size).unwrap_or(usize::MAX);
+        let mut messages = Vec::new();
+        let mut current = ServiceInfo::new();
+
+        for entry in self.0 {
+            let single = ServiceInfo(vec![entry]);
+            if single.message_size()? > max_message_size {
+                if !current.is_empty() {
+                    messages.push(std::mem::take(&mut current));
+                }
+                let mut chunks = single.chunk_entry(max_message_size)?;
+                // The last chunk can share its message with the next entries
+                current = chunks.pop().unwrap();
+                messages.extend(chunks);
+                continue;
+            }
+
+            current.0.extend(single.0);
+            if current.0.len() > 1 && current.message_size()? > max_message_size {
+                let entry = current.0.pop().unwrap();
+                messages.push(std::mem::replace(&mut current, ServiceInfo(vec![entry])));
+            }
+        }
+        if !current.is_empty() || messages.is_empty() {
+            messages.push(current);
+        }
+
+        Ok(messages)
+    }
+
+    /// Splits the value of a single entry over messages of at most max_message_size
+    fn chunk_entry(self, max_message_size: usize) -> Result<Vec<ServiceInfo>, Error> {
+        let (key, value) = &self.0[0];
+        let bytes = match serde_cbor::from_slice(value)? {
+            CborSimpleType::Bytes(bytes) => bytes,
+            _ => {
+                log::warn!(
+                    "ServiceInfo entry {} is larger than the maximum message size",
+                    key
+                );
+                return Ok(vec![self]);
+            }
+        };
+
+        // The byte string length header grows by up to eight bytes with the data
+        let empty = ServiceInfo(vec![(key.clone(), encode_bytes(&[])?)]);
+        let overhead = empty.message_size()? + 8;
+        let chunk_size = match max_message_size.checked_sub(overhead) {
+            Some(chunk_size) if chunk_size > 0 => chunk_size,
+            _ => {
+                return Err(Error::InconsistentValue(
+                    "Maximum message size too small for ServiceInfo entry",
+                ))
+            }
+        };
+
+        bytes
+            .chunks(chunk_size)
+            .map(|chunk| Ok(ServiceInfo(vec![(key.clone(), encode_bytes(chunk)?)])))
+            .collect()
+    }
+
+    /// Joins consecutive byte string values with the same key, as split by [`ServiceInfo::split`]
+    pub fn join_chunks(self) -> Result<ServiceInfo, Error> {
+        let mut joined: Vec<(String, ByteBuf)> = Vec::new();
+        let mut pending: Option<(String, Vec<u8>)> = None;
+
+        for (key, value) in self.0 {
+            match serde_cbor::from_slice(&value)? {
+                CborSimpleType::Bytes(bytes) => match &mut pending {
+                    Some((pending_key, pending_bytes)) if pending_key == &key => {
+                        pending_bytes.extend_from_slice(&bytes);
+                    }
+                    _ => {
+                        if let Some((key, bytes)) = pending.replace((key, bytes)) {
+                            joined.push((key, encode_bytes(&bytes)?));
+                        }
+                    }
+                },
+                _ => {
+                    if let Some((key, bytes)) = pending.take() {
+                        joined.push((key, encode_bytes(&bytes)?));
+                    }
+                    joined.push((key, value));
+                }
+            }
+        }
+        if let Some((key, bytes)) = pending {
+            joined.push((key, encode_bytes(&bytes)?));
+        }
+
+        Ok(ServiceInfo(joined))
+    }
+
     pub fn values(&self) -> Result<Vec<(String, String, CborSimpleType)>, Error> {
         self.0
             .iter()
@@ -833,6 +951,102 @@ impl Iterator for ServiceInfoIter<'_> {
     }
 }
 
+fn encode_bytes(bytes: &[u8]) -> Result<ByteBuf, Error> {
+    let mut buffer = Vec::new();
+    ciborium::ser::into_writer(&serde_bytes::Bytes::new(bytes), &mut buffer)?;
+    Ok(ByteBuf::from(buffer))
+}
+
+#[cfg(test)]
+mod test_serviceinfo {
+    use crate::constants::FedoraIotServiceInfoModule;
+
+    use super::{CborSimpleTypeExt, ServiceInfo};
+
+    #[test]
+    fn test_serviceinfo_split() {
+        let mut si = ServiceInfo::new();
+        for i in 0..20 {
+            si.add(
+                FedoraIotServiceInfoModule::BinaryFile,
+                &format!("data{:03}", i + 1),
+                &serde_bytes::ByteBuf::from(vec![i; 100]),
+            )
+            .unwrap();
+        }
+
+        let messages = si.split(500).unwrap();
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert!(message.message_size().unwrap() <= 500);
+        }
+
+        let keys: Vec<String> = messages
+            .iter()
+            .flat_map(|message| message.iter().map(|(_, key, _)| key))
+            .collect();
+        let expected: Vec<String> = (1..=20).map(|i| format!("data{:03}", i)).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_serviceinfo_split_entry_too_large() {
+        let contents: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let mut si = ServiceInfo::new();
+        si.add(
+            FedoraIotServiceInfoModule::Command,
+            "stdout",
+            &serde_bytes::Bytes::new(&contents),
+        )
+        .unwrap();
+        si.add(FedoraIotServiceInfoModule::Command, "exit_code", &0)
+            .unwrap();
+        si.add(
+            FedoraIotServiceInfoModule::Command,
+            "stderr",
+            &serde_bytes::Bytes::new(b"error"),
+        )
+        .unwrap();
+
+        let messages = si.split(500).unwrap();
+        assert!(messages.len() > 4);
+        let mut received = ServiceInfo::new();
+        for message in messages {
+            assert!(message.message_size().unwrap() <= 500);
+            received.extend(message);
+        }
+
+        let received = received.join_chunks().unwrap();
+        let entries: Vec<_> = received.iter().collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].1, "stdout");
+        assert_eq!(entries[0].2.as_bytes().unwrap(), &contents);
+        assert_eq!(entries[1].1, "exit_code");
+        assert_eq!(entries[2].1, "stderr");
+        assert_eq!(entries[2].2.as_bytes().unwrap(), b"error");
+    }
+
+    #[test]
+    fn test_serviceinfo_split_text_too_large() {
+        let mut si = ServiceInfo::new();
+        si.add(FedoraIotServiceInfoModule::SSHKey, "key", &"a".repeat(1000))
+            .unwrap();
+        si.add(FedoraIotServiceInfoModule::SSHKey, "username", &"admin")
+            .unwrap();
+
+        let messages = si.split(500).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].message_size().unwrap() > 500);
+    }
+
+    #[test]
+    fn test_serviceinfo_split_empty() {
+        let messages = ServiceInfo::new().split(500).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].is_empty());
+    }
+}
+
 #[derive(Debug)]
 pub struct TO2ProveOVHdrPayload {
     contents: ParsedArray<crate::cborparser::ParsedArraySize8>,
@@ -922,41 +1136,53 @@ impl TO2ProveOVHdrPayload {
         })
     }
 
-    pub fn ov_header(&self) -> &[u8] {
-        &self.cached_ov_header
-    }
-
     pub fn into_ov_header(self) -> ByteBuf {
         self.cached_ov_header
     }
 
-    pub fn num_ov_entries(&self) -> u16 {
+    pub fn hello_device_hash(&self) -> &Hash {
+        &self.cached_hello_device_hash
+    }
+
+    pub fn max_owner_message_size(&self) -> u16 {
+        self.cached_max_owner_message_size
+    }
+}
+
+/// The contents of the ProveOVHdr payload that all protocol versions have in common
+pub trait ProveOVHdrPayload: Serializable + std::fmt::Debug {
+    fn ov_header(&self) -> &[u8];
+    fn num_ov_entries(&self) -> u16;
+    fn hmac(&self) -> &HMac;
+    fn nonce5(&self) -> &Nonce;
+    fn b_signature_info(&self) -> &SigInfo;
+    fn a_key_exchange(&self) -> &[u8];
+}
+
+impl ProveOVHdrPayload for TO2ProveOVHdrPayload {
+    fn ov_header(&self) -> &[u8] {
+        &self.cached_ov_header
+    }
+
+    fn num_ov_entries(&self) -> u16 {
         self.cached_num_ov_entries
     }
 
-    pub fn hmac(&self) -> &HMac {
+    fn hmac(&self) -> &HMac {
         &self.cached_hmac
     }
 
-    pub fn nonce5(&self) -> &Nonce {
+    fn nonce5(&self) -> &Nonce {
         &self.cached_nonce5
     }
 
-    pub fn b_signature_info(&self) -> &SigInfo {
+    fn b_signature_info(&self) -> &SigInfo {
         &self.cached_b_signature_info
     }
 
-    pub fn a_key_exchange(&self) -> &[u8] {
+    fn a_key_exchange(&self) -> &[u8] {
         &self.cached_a_key_exchange
     }
-
-    pub fn hello_device_hash(&self) -> &Hash {
-        &self.cached_hello_device_hash
-    }
-
-    pub fn max_owner_message_size(&self) -> u16 {
-        self.cached_max_owner_message_size
-    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -995,6 +1221,8 @@ pub enum KeyDeriveSide {
 pub enum KeyExchange {
     Ecdh(KexSuite, Vec<u8>, Vec<u8>),
     Dhkex(KexSuite, Vec<u8>),
+    // Our random value, and on the Device side the random encrypted to the Owner key
+    Asymkex(KexSuite, Vec<u8>, Option<Vec<u8>>),
 }
 
 const KEY_DERIVE_LABEL: &[u8] = b"FIDO-KDF";
@@ -1018,6 +1246,35 @@ impl KeyExchange {
 
                 Ok(KeyExchange::Ecdh(suite, key, our_random))
             }
+            KexSuite::Asymkex2048 | KexSuite::Asymkex3072 => {
+                let mut our_random = vec![0; suite.get_asymkex_random_size()];
+                rand_bytes(&mut our_random)?;
+
+                Ok(KeyExchange::Asymkex(suite, our_random, None))
+            }
+        }
+    }
+
+    /// Encrypts the Device random to the Owner public key, as required for the ASYMKEX suites.
+    ///
+    /// This is a no-op for the other key exchange suites.
+    pub fn with_owner_public_key(self, owner_key: &PKeyRef<Public>) -> Result<Self, Error> {
+        match self {
+            KeyExchange::Asymkex(suite, our_random, _) => {
+                suite.check_asymkex_owner_key(owner_key)?;
+
+                let mut encrypter = Encrypter::new(owner_key)?;
+                encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+                encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+                encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+
+                let mut encrypted = vec![0; encrypter.encrypt_len(&our_random)?];
+                let encrypted_len = encrypter.encrypt(&our_random, &mut encrypted)?;
+                encrypted.truncate(encrypted_len);
+
+                Ok(KeyExchange::Asymkex(suite, our_random, Some(encrypted)))
+            }
+            other => Ok(other),
         }
     }
 
@@ -1049,6 +1306,8 @@ impl KeyExchange {
 
                 Ok(self.encode_ecdh_bstr(&public_x, &public_y, our_random))
             }
+            KeyExchange::Asymkex(_, our_random, None) => Ok(our_random.clone()),
+            KeyExchange::Asymkex(_, _, Some(encrypted_random)) => Ok(encrypted_random.clone()),
         }
     }
 
@@ -1170,16 +1429,63 @@ impl KeyExchange {
         }
     }
 
+    fn derive_key_asymkex(
+        &self,
+        our_side: KeyDeriveSide,
+        other: &[u8],
+        owner_key: Option<&PKeyRef<Private>>,
+    ) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        if let KeyExchange::Asymkex(suite, our_random, _) = self {
+            match our_side {
+                // The Device random is the shared secret, and the Owner random the context
+                KeyDeriveSide::Device => {
+                    if other.len() != suite.get_asymkex_random_size() {
+                        return Err(Error::KeyExchangeError("Other random is invalid size"));
+                    }
+                    Ok((our_random.clone(), other.to_vec()))
+                }
+                KeyDeriveSide::OwnerService => {
+                    let owner_key = owner_key.ok_or(Error::KeyExchangeError(
+                        "Owner key required for asymmetric key exchange",
+                    ))?;
+                    suite.check_asymkex_owner_key(owner_key)?;
+
+                    let mut decrypter = Decrypter::new(owner_key)?;
+                    decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+                    decrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+                    decrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+
+                    let mut other_random = vec![0; decrypter.decrypt_len(other)?];
+                    let other_random_len = decrypter.decrypt(other, &mut other_random)?;
+                    other_random.truncate(other_random_len);
+                    if other_random.len() != suite.get_asymkex_random_size() {
+                        return Err(Error::KeyExchangeError("Other random is invalid size"));
+                    }
+
+                    Ok((other_random, our_random.clone()))
+                }
+            }
+        } else {
+            // Only ASYMKEX suites call into here
+            unreachable!()
+        }
+    }
+
+    /// Derives the session keys from the other side's key exchange value.
+    ///
+    /// The Owner private key is only used by the Owner side of the ASYMKEX suites.
     pub fn derive_key(
         &self,
         our_side: KeyDeriveSide,
         cipher: CipherSuite,
         other: &[u8],
+        owner_key: Option<&PKeyRef<Private>>,
         mut use_noninteroperable_kdf: bool,
     ) -> Result<DerivedKeys, Error> {
         let (shared_secret, context_rand) = match self {
             KeyExchange::Dhkex(..) => self.derive_key_dh(other)?,
             KeyExchange::Ecdh(..) => self.derive_key_ecdh(our_side, other)?,
+            KeyExchange::Asymkex(..) => self.derive_key_asymkex(our_side, other, owner_key)?,
         };
 
         let mut salt = Vec::with_capacity(KEY_DERIVE_CONTEXT_PREFIX.len() + context_rand.len() + 2);
@@ -1238,6 +1544,135 @@ impl std::fmt::Debug for KeyExchange {
     }
 }
 
+#[cfg(test)]
+mod test_keyexchange {
+    use openssl::{pkey::PKey, rsa::Rsa};
+
+    use super::{CipherSuite, DerivedKeys, KexSuite, KeyDeriveSide, KeyExchange};
+
+    fn combined_key(keys: DerivedKeys) -> Vec<u8> {
+        match keys {
+            DerivedKeys::Combined { sevk } => sevk,
+            DerivedKeys::Split { .. } => panic!("Split keys derived for combined cipher"),
+        }
+    }
+
+    #[test]
+    fn test_asymkex() {
+        for (suite, bits) in [(KexSuite::Asymkex2048, 2048), (KexSuite::Asymkex3072, 3072)] {
+            let owner_key = PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap();
+            let owner_public_key =
+                PKey::public_key_from_der(&owner_key.public_key_to_der().unwrap()).unwrap();
+
+            let owner_kex = KeyExchange::new(suite).unwrap();
+            let device_kex = KeyExchange::new(suite)
+                .unwrap()
+                .with_owner_public_key(&owner_public_key)
+                .unwrap();
+
+            let owner_public = owner_kex.get_public().unwrap();
+            let device_public = device_kex.get_public().unwrap();
+            assert_eq!(device_public.len(), bits as usize / 8);
+
+            let owner_keys = owner_kex
+                .derive_key(
+                    KeyDeriveSide::OwnerService,
+                    CipherSuite::A256Gcm,
+                    &device_public,
+                    Some(&owner_key),
+                    true,
+                )
+                .unwrap();
+            let device_keys = device_kex
+                .derive_key(
+                    KeyDeriveSide::Device,
+                    CipherSuite::A256Gcm,
+                    &owner_public,
+                    None,
+                    true,
+                )
+                .unwrap();
+            assert_eq!(combined_key(owner_keys), combined_key(device_keys));
+        }
+    }
+
+    #[test]
+    fn test_asymkex_owner_key_mismatch() {
+        let owner_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        assert!(KexSuite::Asymkex2048.supports_owner_key(&owner_key));
+        assert!(!KexSuite::Asymkex3072.supports_owner_key(&owner_key));
+
+        let owner_public_key =
+            PKey::public_key_from_der(&owner_key.public_key_to_der().unwrap()).unwrap();
+        assert!(KeyExchange::new(KexSuite::Asymkex3072)
+            .unwrap()
+            .with_owner_public_key(&owner_public_key)
+            .is_err());
+
+        let owner_kex = KeyExchange::new(KexSuite::Asymkex2048).unwrap();
+        assert!(owner_kex
+            .derive_key(
+                KeyDeriveSide::OwnerService,
+                CipherSuite::A256Gcm,
+                &[0; 256],
+                None,
+                true,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_split_keys() {
+        for (cipher, svk_len, sek_len) in [
+            (CipherSuite::A128Cbc, 32, 16),
+            (CipherSuite::A128Ctr, 32, 16),
+            (CipherSuite::A256Cbc, 48, 32),
+            (CipherSuite::A256Ctr, 48, 32),
+        ] {
+            let owner_kex = KeyExchange::new(KexSuite::Ecdh256).unwrap();
+            let device_kex = KeyExchange::new(KexSuite::Ecdh256).unwrap();
+
+            let owner_keys = owner_kex
+                .derive_key(
+                    KeyDeriveSide::OwnerService,
+                    cipher,
+                    &device_kex.get_public().unwrap(),
+                    None,
+                    true,
+                )
+                .unwrap();
+            let device_keys = device_kex
+                .derive_key(
+                    KeyDeriveSide::Device,
+                    cipher,
+                    &owner_kex.get_public().unwrap(),
+                    None,
+                    true,
+                )
+                .unwrap();
+
+            match (owner_keys, device_keys) {
+                (
+                    DerivedKeys::Split {
+                        sek: owner_sek,
+                        svk: owner_svk,
+                    },
+                    DerivedKeys::Split {
+                        sek: device_sek,
+                        svk: device_svk,
+                    },
+                ) => {
+                    assert_eq!(owner_svk.len(), svk_len);
+                    assert_eq!(owner_sek.len(), sek_len);
+                    assert_eq!(owner_svk, device_svk);
+                    assert_eq!(owner_sek, device_sek);
+                }
+                _ => panic!("Combined keys derived for split cipher"),
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageProtocolInfo {
     token: Option<Vec<u8>>,
@@ -1252,7 +1687,7 @@ pub struct SizedMessage {
     body: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KexSuite {
     // Elliptic Curve Diffie-Hellman Key Exchange Protocol
     Ecdh256,
@@ -1260,6 +1695,9 @@ pub enum KexSuite {
     // Diffie-Hellmann Key Exchange Protocol
     DhkexId14,
     DhkexId15,
+    // Asymmetric Key Exchange Protocol, using RSA-OAEP with the Owner key
+    Asymkex2048,
+    Asymkex3072,
 }
 
 impl FromStr for KexSuite {
@@ -1271,6 +1709,8 @@ impl FromStr for KexSuite {
             "ECDH384" => Ok(KexSuite::Ecdh384),
             "DHKEXid14" => Ok(KexSuite::DhkexId14),
             "DHKEXid15" => Ok(KexSuite::DhkexId15),
+            "ASYMKEX2048" => Ok(KexSuite::Asymkex2048),
+            "ASYMKEX3072" => Ok(KexSuite::Asymkex3072),
             other => Err(Error::InvalidSuiteName(other.to_string())),
         }
     }
@@ -1283,6 +1723,8 @@ impl ToString for KexSuite {
             KexSuite::Ecdh384 => "ECDH384".to_string(),
             KexSuite::DhkexId14 => "DHKEXid14".to_string(),
             KexSuite::DhkexId15 => "DHKEXid15".to_string(),
+            KexSuite::Asymkex2048 => "ASYMKEX2048".to_string(),
+            KexSuite::Asymkex3072 => "ASYMKEX3072".to_string(),
         }
     }
 }
@@ -1328,6 +1770,42 @@ impl<'de> Deserialize<'de> for KexSuite {
 }
 
 impl KexSuite {
+    /// Checks whether the suite can be used with an Owner key of this type.
+    ///
+    /// The ASYMKEX suites require an RSA Owner key of the matching size.
+    pub fn supports_owner_key<T: HasPublic>(&self, owner_key: &PKeyRef<T>) -> bool {
+        match self {
+            KexSuite::Asymkex2048 | KexSuite::Asymkex3072 => {
+                self.check_asymkex_owner_key(owner_key).is_ok()
+            }
+            _ => true,
+        }
+    }
+
+    fn get_asymkex_random_size(&self) -> usize {
+        match self {
+            KexSuite::Asymkex2048 => 32,
+            KexSuite::Asymkex3072 => 96,
+            // Only ASYMKEX suites call into here
+            _ => unreachable!(),
+        }
+    }
+
+    fn check_asymkex_owner_key<T: HasPublic>(&self, owner_key: &PKeyRef<T>) -> Result<(), Error> {
+        let required_bits = match self {
+            KexSuite::Asymkex2048 => 2048,
+            KexSuite::Asymkex3072 => 3072,
+            // Only ASYMKEX suites call into here
+            _ => unreachable!(),
+        };
+        if owner_key.id() != PKeyId::RSA || owner_key.bits() != required_bits {
+            return Err(Error::KeyExchangeError(
+                "Owner key is not an RSA key of the size required by the key exchange suite",
+            ));
+        }
+        Ok(())
+    }
+
     fn get_ecdh_random_size(&self) -> usize {
         match self {
             KexSuite::Ecdh256 => 16,
@@ -1398,40 +1876,69 @@ impl KexSuite {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr, PartialEq)]
 #[repr(i32)]
 pub enum CipherSuite {
     // Combined ciphers
     A128Gcm = 1,
     A256Gcm = 3,
+    // AES-CCM-64-128-128 and AES-CCM-64-128-256
+    A128Ccm = 32,
+    A256Ccm = 33,
+    // Split ciphers, encrypt-then-MAC with HMAC
+    A128Cbc = -17760703,
+    A128Ctr = -17760704,
+    A256Cbc = -17760705,
+    A256Ctr = -17760706,
 }
 
 impl CipherSuite {
     fn uses_combined_key(&self) -> bool {
         match self {
-            CipherSuite::A128Gcm | CipherSuite::A256Gcm => true,
+            CipherSuite::A128Gcm
+            | CipherSuite::A256Gcm
+            | CipherSuite::A128Ccm
+            | CipherSuite::A256Ccm => true,
+            CipherSuite::A128Cbc
+            | CipherSuite::A128Ctr
+            | CipherSuite::A256Cbc
+            | CipherSuite::A256Ctr => false,
         }
     }
 
+    // The SVK comes first in the derived key material, followed by the SEK
     fn split_key_split_pos(&self) -> usize {
         match self {
-            CipherSuite::A128Gcm | CipherSuite::A256Gcm => {
-                // Gcm ciphers should never call into the split_key
+            CipherSuite::A128Gcm
+            | CipherSuite::A256Gcm
+            | CipherSuite::A128Ccm
+            | CipherSuite::A256Ccm => {
+                // Combined ciphers should never call into the split_key
                 unreachable!()
             }
+            CipherSuite::A128Cbc | CipherSuite::A128Ctr => 32,
+            CipherSuite::A256Cbc | CipherSuite::A256Ctr => 48,
         }
     }
 
     fn required_keylen(&self) -> usize {
         match self {
-            CipherSuite::A128Gcm => 16,
-            CipherSuite::A256Gcm => 32,
+            CipherSuite::A128Gcm | CipherSuite::A128Ccm => 16,
+            CipherSuite::A256Gcm | CipherSuite::A256Ccm => 32,
+            CipherSuite::A128Cbc | CipherSuite::A128Ctr => 32 + 16,
+            CipherSuite::A256Cbc | CipherSuite::A256Ctr => 48 + 32,
         }
     }
 
     fn kdf_digest(&self) -> MessageDigest {
         match self {
-            CipherSuite::A128Gcm | CipherSuite::A256Gcm => MessageDigest::sha256(),
+            CipherSuite::A128Gcm
+            | CipherSuite::A256Gcm
+            | CipherSuite::A128Ccm
+            | CipherSuite::A256Ccm
+            | CipherSuite::A128Cbc
+            | CipherSuite::A128Ctr => MessageDigest::sha256(),
+            CipherSuite::A256Cbc | CipherSuite::A256Ctr => MessageDigest::sha384(),
         }
     }
 
@@ -1439,6 +1946,24 @@ impl CipherSuite {
         match self {
             CipherSuite::A128Gcm => Cipher::aes_128_gcm(),
             CipherSuite::A256Gcm => Cipher::aes_256_gcm(),
+            CipherSuite::A128Ccm => Cipher::aes_128_ccm(),
+            CipherSuite::A256Ccm => Cipher::aes_256_ccm(),
+            CipherSuite::A128Cbc => Cipher::aes_128_cbc(),
+            CipherSuite::A128Ctr => Cipher::aes_128_ctr(),
+            CipherSuite::A256Cbc => Cipher::aes_256_cbc(),
+            CipherSuite::A256Ctr => Cipher::aes_256_ctr(),
+        }
+    }
+
+    /// The HMAC used to authenticate messages for split key ciphers
+    pub fn mac_type(&self) -> Option<HashType> {
+        match self {
+            CipherSuite::A128Gcm
+            | CipherSuite::A256Gcm
+            | CipherSuite::A128Ccm
+            | CipherSuite::A256Ccm => None,
+            CipherSuite::A128Cbc | CipherSuite::A128Ctr => Some(HashType::HmacSha256),
+            CipherSuite::A256Cbc | CipherSuite::A256Ctr => Some(HashType::HmacSha384),
         }
     }
 }
@@ -1450,6 +1975,12 @@ impl FromStr for CipherSuite {
         match s {
             "A128GCM" => Ok(CipherSuite::A128Gcm),
             "A256GCM" => Ok(CipherSuite::A256Gcm),
+            "AES-CCM-64-128-128" => Ok(CipherSuite::A128Ccm),
+            "AES-CCM-64-128-256" => Ok(CipherSuite::A256Ccm),
+            "AES128/CBC/HMAC-SHA256" => Ok(CipherSuite::A128Cbc),
+            "AES128/CTR/HMAC-SHA256" => Ok(CipherSuite::A128Ctr),
+            "AES256/CBC/HMAC-SHA384" => Ok(CipherSuite::A256Cbc),
+            "AES256/CTR/HMAC-SHA384" => Ok(CipherSuite::A256Ctr),
             other => Err(Error::InvalidSuiteName(other.to_string())),
         }
     }
@@ -1460,6 +1991,12 @@ impl ToString for CipherSuite {
         match self {
             CipherSuite::A128Gcm => "A128GCM".to_string(),
             CipherSuite::A256Gcm => "A256GCM".to_string(),
+            CipherSuite::A128Ccm => "AES-CCM-64-128-128".to_string(),
+            CipherSuite::A256Ccm => "AES-CCM-64-128-256".to_string(),
+            CipherSuite::A128Cbc => "AES128/CBC/HMAC-SHA256".to_string(),
+            CipherSuite::A128Ctr => "AES128/CTR/HMAC-SHA256".to_string(),
+            CipherSuite::A256Cbc => "AES256/CBC/HMAC-SHA384".to_string(),
+            CipherSuite::A256Ctr => "AES256/CTR/HMAC-SHA384".to_string(),
         }
     }
 }
@@ -1729,6 +2266,178 @@ impl COSEHeaderMap {
 
 const COSESIGN_TAG: u64 = 18;
 
+/// A key that can sign a [`COSESign`].
+///
+/// The COSE library only implements the ECDSA signature algorithms, so RSA keys are handled
+/// here instead, and sign with RS256/PS256 (RSA2048) or RS384/PS384 (RSA3072).
+pub trait COSESigningKey {
+    fn cose_signing_key(&self) -> COSESigningKeyRef<'_>;
+}
+
+/// A private RSA key that can sign a [`COSESign`], see [`COSESigningKey`].
+pub trait RsaSigningKey {
+    /// The size of the modulus in bits, and the padding of the signatures: either
+    /// `Padding::PKCS1` (RS256/RS384) or `Padding::PKCS1_PSS` (PS256/PS384)
+    fn rsa_parameters(&self) -> Result<(u32, Padding), Error>;
+    /// Hashes data with digest and signs the result
+    fn rsa_sign(&self, digest: MessageDigest, data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// A key that can verify a [`COSESign`], see [`COSESigningKey`].
+pub trait COSEVerificationKey {
+    fn cose_verification_key(&self) -> COSEVerificationKeyRef<'_>;
+}
+
+pub enum COSESigningKeyRef<'a> {
+    Cose(&'a dyn SigningPrivateKey),
+    Rsa(&'a dyn RsaSigningKey),
+}
+
+impl COSESigningKeyRef<'_> {
+    /// The device signature type that describes signatures made with this key
+    pub fn device_sig_type(&self) -> Result<DeviceSigType, Error> {
+        match self {
+            COSESigningKeyRef::Cose(key) => match key.get_parameters()?.0 {
+                SignatureAlgorithm::ES256 => Ok(DeviceSigType::StSECP256R1),
+                SignatureAlgorithm::ES384 => Ok(DeviceSigType::StSECP384R1),
+                _ => Err(Error::UnsupportedAlgorithm),
+            },
+            COSESigningKeyRef::Rsa(key) => match key.rsa_parameters()?.0 {
+                2048 => Ok(DeviceSigType::StRSA2048),
+                3072 => Ok(DeviceSigType::StRSA3072),
+                _ => Err(Error::UnsupportedAlgorithm),
+            },
+        }
+    }
+}
+
+pub enum COSEVerificationKeyRef<'a> {
+    Cose(&'a dyn SigningPublicKey),
+    Rsa(&'a PKeyRef<Public>),
+}
+
+impl COSESigningKey for PKeyRef<Private> {
+    fn cose_signing_key(&self) -> COSESigningKeyRef<'_> {
+        if self.id() == PKeyId::RSA {
+            COSESigningKeyRef::Rsa(self)
+        } else {
+            COSESigningKeyRef::Cose(self)
+        }
+    }
+}
+
+impl COSESigningKey for PKey<Private> {
+    fn cose_signing_key(&self) -> COSESigningKeyRef<'_> {
+        self.as_ref().cose_signing_key()
+    }
+}
+
+impl RsaSigningKey for PKeyRef<Private> {
+    fn rsa_parameters(&self) -> Result<(u32, Padding), Error> {
+        Ok((self.bits(), Padding::PKCS1))
+    }
+
+    fn rsa_sign(&self, digest: MessageDigest, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signer = Signer::new(digest, self)?;
+        signer.set_rsa_padding(Padding::PKCS1)?;
+        Ok(signer.sign_oneshot_to_vec(data)?)
+    }
+}
+
+impl<'a> COSESigningKey for dyn SigningPrivateKey + 'a {
+    fn cose_signing_key(&self) -> COSESigningKeyRef<'_> {
+        COSESigningKeyRef::Cose(self)
+    }
+}
+
+impl COSEVerificationKey for PKeyRef<Public> {
+    fn cose_verification_key(&self) -> COSEVerificationKeyRef<'_> {
+        if self.id() == PKeyId::RSA {
+            COSEVerificationKeyRef::Rsa(self)
+        } else {
+            COSEVerificationKeyRef::Cose(self)
+        }
+    }
+}
+
+impl COSEVerificationKey for PKey<Public> {
+    fn cose_verification_key(&self) -> COSEVerificationKeyRef<'_> {
+        self.as_ref().cose_verification_key()
+    }
+}
+
+impl<'a> COSEVerificationKey for dyn SigningPublicKey + 'a {
+    fn cose_verification_key(&self) -> COSEVerificationKeyRef<'_> {
+        COSEVerificationKeyRef::Cose(self)
+    }
+}
+
+const COSE_ALG_PS256: i128 = -37;
+const COSE_ALG_PS384: i128 = -38;
+
+/// Returns the COSE algorithm and digest to use for an RSA key size and padding
+fn rsa_signature_parameters(bits: u32, padding: Padding) -> Result<(i128, MessageDigest), Error> {
+    match (bits, padding) {
+        (2048, Padding::PKCS1) => Ok((DeviceSigType::StRSA2048 as i128, MessageDigest::sha256())),
+        (3072, Padding::PKCS1) => Ok((DeviceSigType::StRSA3072 as i128, MessageDigest::sha384())),
+        (2048, Padding::PKCS1_PSS) => Ok((COSE_ALG_PS256, MessageDigest::sha256())),
+        (3072, Padding::PKCS1_PSS) => Ok((COSE_ALG_PS384, MessageDigest::sha384())),
+        _ => Err(Error::UnsupportedAlgorithm),
+    }
+}
+
+fn rsa_sig_structure(protected: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(aws_nitro_enclaves_cose::sign::SigStructure::new_sign1(protected, payload)?.as_bytes()?)
+}
+
+fn new_rsa_inner(
+    payload: &[u8],
+    mut protected: aws_nitro_enclaves_cose::header_map::HeaderMap,
+    unprotected: aws_nitro_enclaves_cose::header_map::HeaderMap,
+    sign_key: &dyn RsaSigningKey,
+) -> Result<COSESignInner, Error> {
+    let (bits, padding) = sign_key.rsa_parameters()?;
+    let (sig_alg, digest) = rsa_signature_parameters(bits, padding)?;
+    protected.insert(1.into(), serde_cbor::Value::Integer(sig_alg));
+    let protected = serde_cbor::to_vec(&protected)?;
+
+    let signature = sign_key.rsa_sign(digest, &rsa_sig_structure(&protected, payload)?)?;
+
+    let contents = serde_cbor::to_vec(&(
+        ByteBuf::from(protected),
+        unprotected,
+        ByteBuf::from(payload),
+        ByteBuf::from(signature),
+    ))?;
+    Ok(COSESignInner::from_bytes(&contents)?)
+}
+
+fn verify_rsa_inner(inner: &COSESignInner, key: &PKeyRef<Public>) -> Result<bool, Error> {
+    let (protected, _, payload, signature): (ByteBuf, serde_cbor::Value, ByteBuf, ByteBuf) =
+        serde_cbor::from_slice(&inner.as_bytes(false)?)?;
+
+    let protected_alg = aws_nitro_enclaves_cose::header_map::HeaderMap::from_bytes(&protected)?
+        .get(&serde_cbor::Value::Integer(1))
+        .cloned();
+    let mut parameters = None;
+    for padding in [Padding::PKCS1, Padding::PKCS1_PSS] {
+        let (sig_alg, digest) = rsa_signature_parameters(key.bits(), padding)?;
+        if protected_alg == Some(serde_cbor::Value::Integer(sig_alg)) {
+            parameters = Some((padding, digest));
+        }
+    }
+    let (padding, digest) =
+        parameters.ok_or(Error::InconsistentValue("COSESign signature algorithm"))?;
+
+    let mut verifier = Verifier::new(digest, key)?;
+    verifier.set_rsa_padding(padding)?;
+    if padding == Padding::PKCS1_PSS {
+        // For verification, this detects the salt length from the signature
+        verifier.set_rsa_pss_saltlen(RsaPssSaltlen::MAXIMUM_LENGTH)?;
+    }
+    Ok(verifier.verify_oneshot(&signature, &rsa_sig_structure(&protected, &payload)?)?)
+}
+
 #[derive(Debug, Clone)]
 pub struct COSESign {
     contents: ParsedArray<crate::cborparser::ParsedArraySize4>,
@@ -1791,13 +2500,14 @@ impl COSESign {
         })
     }
 
-    pub fn new<T>(
+    pub fn new<T, K>(
         payload: &T,
         unprotected: Option<COSEHeaderMap>,
-        sign_key: &dyn SigningPrivateKey,
+        sign_key: &K,
     ) -> Result<Self, Error>
     where
         T: Serializable,
+        K: COSESigningKey + ?Sized,
     {
         let unprotected = match unprotected {
             Some(v) => v,
@@ -1805,19 +2515,30 @@ impl COSESign {
         };
         let payload = payload.serialize_data()?;
 
-        let inner = COSESignInner::new(&payload, &unprotected.into(), sign_key)?;
+        let inner = match sign_key.cose_signing_key() {
+            COSESigningKeyRef::Cose(sign_key) => {
+                COSESignInner::new(&payload, &unprotected.into(), sign_key)?
+            }
+            COSESigningKeyRef::Rsa(sign_key) => new_rsa_inner(
+                &payload,
+                aws_nitro_enclaves_cose::header_map::HeaderMap::new(),
+                unprotected.into(),
+                sign_key,
+            )?,
+        };
 
         Self::new_from_inner(inner)
     }
 
-    pub fn new_with_protected<T>(
+    pub fn new_with_protected<T, K>(
         payload: &T,
         protected: COSEHeaderMap,
         unprotected: Option<COSEHeaderMap>,
-        sign_key: &dyn SigningPrivateKey,
+        sign_key: &K,
     ) -> Result<Self, Error>
     where
         T: Serializable,
+        K: COSESigningKey + ?Sized,
     {
         let unprotected = match unprotected {
             Some(v) => v,
@@ -1825,31 +2546,70 @@ impl COSESign {
         };
         let payload = payload.serialize_data()?;
 
-        let (sig_alg, _) = sign_key.get_parameters()?;
         let mut protected: aws_nitro_enclaves_cose::header_map::HeaderMap = protected.into();
-        protected.insert(1.into(), (sig_alg as i8).into());
 
-        let inner =
-            COSESignInner::new_with_protected(&payload, &protected, &unprotected.into(), sign_key)?;
+        let inner = match sign_key.cose_signing_key() {
+            COSESigningKeyRef::Cose(sign_key) => {
+                let (sig_alg, _) = sign_key.get_parameters()?;
+                protected.insert(1.into(), (sig_alg as i8).into());
+
+                COSESignInner::new_with_protected(
+                    &payload,
+                    &protected,
+                    &unprotected.into(),
+                    sign_key,
+                )?
+            }
+            COSESigningKeyRef::Rsa(sign_key) => {
+                new_rsa_inner(&payload, protected, unprotected.into(), sign_key)?
+            }
+        };
 
         Self::new_from_inner(inner)
     }
 
-    pub fn verify(&self, sign_key: &dyn SigningPublicKey) -> Result<(), Error> {
-        if self.cached_inner.verify_signature(sign_key)? {
+    fn verify_signature<K>(&self, key: &K) -> Result<bool, Error>
+    where
+        K: COSEVerificationKey + ?Sized,
+    {
+        match key.cose_verification_key() {
+            COSEVerificationKeyRef::Cose(key) => Ok(self.cached_inner.verify_signature(key)?),
+            COSEVerificationKeyRef::Rsa(key) => verify_rsa_inner(&self.cached_inner, key),
+        }
+    }
+
+    fn get_verified_protected_and_payload<K>(
+        &self,
+        key: &K,
+    ) -> Result<(aws_nitro_enclaves_cose::header_map::HeaderMap, Vec<u8>), Error>
+    where
+        K: COSEVerificationKey + ?Sized,
+    {
+        if !self.verify_signature(key)? {
+            return Err(aws_nitro_enclaves_cose::error::CoseError::UnverifiedSignature.into());
+        }
+        Ok(self.cached_inner.get_protected_and_payload(None)?)
+    }
+
+    pub fn verify<K>(&self, sign_key: &K) -> Result<(), Error>
+    where
+        K: COSEVerificationKey + ?Sized,
+    {
+        if self.verify_signature(sign_key)? {
             Ok(())
         } else {
             Err(Error::InconsistentValue("Signature verification failed"))
         }
     }
 
-    pub fn from_eat<ES>(
+    pub fn from_eat<ES, K>(
         eat: EATokenPayload<ES>,
         unprotected: Option<COSEHeaderMap>,
-        sign_key: &dyn SigningPrivateKey,
+        sign_key: &K,
     ) -> Result<Self, Error>
     where
         ES: PayloadState,
+        K: COSESigningKey + ?Sized,
     {
         let claims = eat.to_map();
         Self::new(&claims.0, unprotected, sign_key)
@@ -1863,11 +2623,12 @@ impl COSESign {
         Ok(UnverifiedValue(T::deserialize_data(&payload)?))
     }
 
-    pub fn get_payload<T>(&self, key: &dyn SigningPublicKey) -> Result<T, Error>
+    pub fn get_payload<T, K>(&self, key: &K) -> Result<T, Error>
     where
         T: Serializable,
+        K: COSEVerificationKey + ?Sized,
     {
-        let payload = self.cached_inner.get_payload(Some(key))?;
+        let (_, payload) = self.get_verified_protected_and_payload(key)?;
         T::deserialize_data(&payload)
     }
 
@@ -1878,10 +2639,10 @@ impl COSESign {
         eat_from_map(claims)
     }
 
-    pub fn get_eat(
-        &self,
-        key: &dyn SigningPublicKey,
-    ) -> Result<EATokenPayload<PayloadVerified>, Error> {
+    pub fn get_eat<K>(&self, key: &K) -> Result<EATokenPayload<PayloadVerified>, Error>
+    where
+        K: COSEVerificationKey + ?Sized,
+    {
         let claims: COSEHeaderMapType = self.get_payload(key)?;
         let claims = COSEHeaderMap(claims);
 
@@ -1904,15 +2665,16 @@ impl COSESign {
         }
     }
 
-    pub fn get_protected_value<T>(
+    pub fn get_protected_value<T, K>(
         &self,
         header_key: HeaderKeys,
-        key: &dyn SigningPublicKey,
+        key: &K,
     ) -> Result<Option<T>, Error>
     where
         T: serde::de::DeserializeOwned,
+        K: COSEVerificationKey + ?Sized,
     {
-        let (protected, _) = self.cached_inner.get_protected_and_payload(Some(key))?;
+        let (protected, _) = self.get_verified_protected_and_payload(key)?;
         match protected.get(&header_key.cbor_value()) {
             None => Ok(None),
             Some(val) => Ok(Some(serde_cbor::value::from_value(val.clone())?)),
@@ -1930,6 +2692,130 @@ impl COSESign {
     }
 }
 
+#[cfg(test)]
+mod test_cosesign {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private, Public},
+        rsa::{Padding, Rsa},
+        sign::{RsaPssSaltlen, Signer},
+    };
+
+    use crate::{
+        constants::{DeviceSigType, HeaderKeys},
+        types::Nonce,
+        Error,
+    };
+
+    use super::{COSEHeaderMap, COSESign, COSESigningKey, COSESigningKeyRef, RsaSigningKey};
+
+    /// Signs with RSASSA-PSS, like a TPM key with the RSAPSS scheme
+    struct PssSigningKey(PKey<Private>);
+
+    impl RsaSigningKey for PssSigningKey {
+        fn rsa_parameters(&self) -> Result<(u32, Padding), Error> {
+            Ok((self.0.bits(), Padding::PKCS1_PSS))
+        }
+
+        fn rsa_sign(&self, digest: MessageDigest, data: &[u8]) -> Result<Vec<u8>, Error> {
+            let mut signer = Signer::new(digest, &self.0)?;
+            signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+            signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+            Ok(signer.sign_oneshot_to_vec(data)?)
+        }
+    }
+
+    impl COSESigningKey for PssSigningKey {
+        fn cose_signing_key(&self) -> COSESigningKeyRef<'_> {
+            COSESigningKeyRef::Rsa(self)
+        }
+    }
+
+    fn public_key(key: &PKey<Private>) -> PKey<Public> {
+        PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_cosesign_rsa() {
+        for bits in [2048, 3072] {
+            let key = PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap();
+            let nonce = Nonce::new().unwrap();
+            let mut protected = COSEHeaderMap::new();
+            protected.insert(HeaderKeys::CUPHNonce, &nonce).unwrap();
+
+            let signed =
+                COSESign::new_with_protected(&"payload".to_string(), protected, None, &key)
+                    .unwrap();
+
+            let pubkey = public_key(&key);
+            signed.verify(&pubkey).unwrap();
+            let payload: String = signed.get_payload(&pubkey).unwrap();
+            assert_eq!(payload, "payload");
+            let signed_nonce: Nonce = signed
+                .get_protected_value(HeaderKeys::CUPHNonce, &pubkey)
+                .unwrap()
+                .unwrap();
+            assert_eq!(signed_nonce, nonce);
+        }
+    }
+
+    #[test]
+    fn test_cosesign_rsa_pss() {
+        for (bits, sig_type) in [
+            (2048, DeviceSigType::StRSA2048),
+            (3072, DeviceSigType::StRSA3072),
+        ] {
+            let key = PssSigningKey(PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap());
+            assert_eq!(key.cose_signing_key().device_sig_type().unwrap(), sig_type);
+
+            let signed = COSESign::new(&"payload".to_string(), None, &key).unwrap();
+
+            let pubkey = public_key(&key.0);
+            signed.verify(&pubkey).unwrap();
+            let payload: String = signed.get_payload(&pubkey).unwrap();
+            assert_eq!(payload, "payload");
+
+            let other_key = PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap();
+            assert!(signed.verify(&public_key(&other_key)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cosesign_rsa_wrong_key() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let signed = COSESign::new(&"payload".to_string(), None, &key).unwrap();
+
+        let other_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        assert!(signed.verify(&public_key(&other_key)).is_err());
+        assert!(signed
+            .get_payload::<String, _>(&public_key(&other_key))
+            .is_err());
+
+        let bigger_key = PKey::from_rsa(Rsa::generate(3072).unwrap()).unwrap();
+        assert!(matches!(
+            signed.verify(&public_key(&bigger_key)),
+            Err(Error::InconsistentValue(_))
+        ));
+
+        let ec_key = PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert!(signed.verify(&public_key(&ec_key)).is_err());
+    }
+
+    #[test]
+    fn test_cosesign_rsa_unsupported_size() {
+        let key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+        assert!(matches!(
+            COSESign::new(&"payload".to_string(), None, &key),
+            Err(Error::UnsupportedAlgorithm)
+        ));
+    }
+}
+
 #[derive(Debug)]
 pub enum RemoteTransport {
     Tcp,
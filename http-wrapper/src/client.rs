@@ -1,5 +1,16 @@
-use std::{convert::TryFrom, str::FromStr};
+use std::{convert::TryFrom, str::FromStr, sync::Arc, time::SystemTime};
 
+use openssl::{
+    stack::Stack,
+    x509::{
+        store::X509StoreBuilder, verify::X509VerifyFlags, verify::X509VerifyParam,
+        X509StoreContext, X509,
+    },
+};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ServerName,
+};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -7,6 +18,7 @@ use aws_nitro_enclaves_cose::error::CoseError;
 use fdo_data_formats::{
     constants::MessageType,
     messages::{v11::ErrorMessage, ClientMessage, EncryptionRequirement, Message, ServerMessage},
+    types::Hash,
     ProtocolVersion, Serializable,
 };
 
@@ -43,6 +55,12 @@ pub enum Error {
     UrlParseError(#[from] url::ParseError),
     #[error("The URL {0:?} is not valid: {1:?}")]
     InvalidUrl(String, &'static str),
+    #[error("Cryptographic error: {0}")]
+    Openssl(#[from] openssl::error::ErrorStack),
+    #[error("Error connecting to server: {0}")]
+    Connect(#[from] std::io::Error),
+    #[error("CoAP error: {0}")]
+    Coap(String),
 }
 
 pub type RequestResult<MT> = Result<MT, Error>;
@@ -98,10 +116,9 @@ impl JsonClient {
         })
     }
 
-    pub async fn send_get<'a, QT, OT>(&self, query: QT) -> RequestResult<OT>
+    fn request_builder<'a, QT>(&self, method: reqwest::Method, query: QT) -> reqwest::RequestBuilder
     where
         QT: IntoIterator<Item = (&'a str, &'a str)>,
-        OT: serde::de::DeserializeOwned,
     {
         let mut url = self.base_url.clone();
 
@@ -113,9 +130,9 @@ impl JsonClient {
 
         url.set_query(Some(&query_str));
 
-        let request_builder = self.client.request(reqwest::Method::GET, url);
+        let request_builder = self.client.request(method, url);
 
-        let request_builder = match &self.authentication {
+        match &self.authentication {
             JsonAuthentication::None => request_builder,
             JsonAuthentication::BearerToken { token } => {
                 request_builder.header("Authorization", format!("Bearer {}", token))
@@ -123,8 +140,13 @@ impl JsonClient {
             JsonAuthentication::ClientCertificate { .. } => {
                 unreachable!("Should not be possible to get here")
             }
-        };
+        }
+    }
 
+    async fn execute<OT>(&self, request_builder: reqwest::RequestBuilder) -> RequestResult<OT>
+    where
+        OT: serde::de::DeserializeOwned,
+    {
         let request = request_builder.build()?;
 
         log::trace!("Sending JSON API request: {:?}", request);
@@ -135,6 +157,156 @@ impl JsonClient {
 
         resp?.error_for_status()?.json().await.map_err(Error::from)
     }
+
+    pub async fn send_get<'a, QT, OT>(&self, query: QT) -> RequestResult<OT>
+    where
+        QT: IntoIterator<Item = (&'a str, &'a str)>,
+        OT: serde::de::DeserializeOwned,
+    {
+        self.execute(self.request_builder(reqwest::Method::GET, query))
+            .await
+    }
+
+    pub async fn send_post<'a, QT, BT, OT>(&self, query: QT, body: &BT) -> RequestResult<OT>
+    where
+        QT: IntoIterator<Item = (&'a str, &'a str)>,
+        BT: serde::Serialize,
+        OT: serde::de::DeserializeOwned,
+    {
+        self.execute(
+            self.request_builder(reqwest::Method::POST, query)
+                .json(body),
+        )
+        .await
+    }
+}
+
+/// Certificate hashes that the TLS server certificate of a service is pinned
+/// against, as provided by the ServerCertHash and CaCertHash rendezvous
+/// directives.
+#[derive(Debug, Clone, Default)]
+pub struct CertificatePins {
+    pub server_certificate_hash: Option<Hash>,
+    pub ca_certificate_hash: Option<Hash>,
+}
+
+impl CertificatePins {
+    pub fn is_empty(&self) -> bool {
+        self.server_certificate_hash.is_none() && self.ca_certificate_hash.is_none()
+    }
+}
+
+/// Verifies the TLS server certificate against the certificate pins during
+/// the handshake, instead of against the system trust store.
+struct PinnedCertificateVerifier {
+    pins: CertificatePins,
+}
+
+impl PinnedCertificateVerifier {
+    fn check_server_certificate(&self, server_cert: &[u8], pin: &Hash) -> Result<(), String> {
+        let server_cert_hash = Hash::from_data(pin.get_type(), server_cert)
+            .map_err(|e| format!("error hashing server certificate: {}", e))?;
+        if server_cert_hash != *pin {
+            return Err(format!("server certificate does not match {}", pin));
+        }
+        Ok(())
+    }
+
+    /// Verifies the server certificate against the presented certificate that
+    /// matches the CA certificate hash, which the server must include in the
+    /// chain it presents.
+    fn check_ca_certificate(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        pin: &Hash,
+    ) -> Result<(), String> {
+        let server_cert = X509::from_der(&end_entity.0)
+            .map_err(|e| format!("error parsing server certificate: {}", e))?;
+
+        let mut ca_cert = None;
+        let mut untrusted =
+            Stack::new().map_err(|e| format!("error creating certificate stack: {}", e))?;
+        for cert in intermediates {
+            let cert_hash = Hash::from_data(pin.get_type(), &cert.0)
+                .map_err(|e| format!("error hashing presented certificate: {}", e))?;
+            let cert = X509::from_der(&cert.0)
+                .map_err(|e| format!("error parsing presented certificate: {}", e))?;
+            if cert_hash == *pin {
+                ca_cert = Some(cert.clone());
+            }
+            untrusted
+                .push(cert)
+                .map_err(|e| format!("error building certificate stack: {}", e))?;
+        }
+        let ca_cert = ca_cert.ok_or_else(|| {
+            "no presented certificate matches the CA certificate hash".to_string()
+        })?;
+
+        let verify_result = (|| {
+            let mut param = X509VerifyParam::new()?;
+            // The pinned CA does not have to be a self-signed root
+            param.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+            match server_name {
+                ServerName::IpAddress(ip) => param.set_ip(*ip)?,
+                ServerName::DnsName(name) => param.set_host(name.as_ref())?,
+                _ => {}
+            }
+            let mut store = X509StoreBuilder::new()?;
+            store.add_cert(ca_cert)?;
+            store.set_param(&param)?;
+            let store = store.build();
+
+            let mut context = X509StoreContext::new()?;
+            context.init(&store, &server_cert, &untrusted, |ctx| {
+                Ok(if ctx.verify_cert()? {
+                    None
+                } else {
+                    Some(ctx.error())
+                })
+            })
+        })()
+        .map_err(|e| format!("error verifying server certificate: {}", e))?;
+        if let Some(verify_error) = verify_result {
+            return Err(format!(
+                "server certificate not issued by the pinned CA: {}",
+                verify_error
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(pin) = &self.pins.server_certificate_hash {
+            self.check_server_certificate(&end_entity.0, pin)
+                .map_err(rustls::Error::General)?;
+        }
+        if let Some(pin) = &self.pins.ca_certificate_hash {
+            self.check_ca_certificate(end_entity, intermediates, server_name, pin)
+                .map_err(rustls::Error::General)?;
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A response as received over either HTTP or CoAP
+pub(crate) struct RawResponse {
+    pub(crate) is_success: bool,
+    pub(crate) message_type: Option<String>,
+    pub(crate) authorization: Option<String>,
+    pub(crate) non_interoperable_kdf: bool,
+    pub(crate) body: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -142,6 +314,8 @@ pub struct ServiceClient {
     protocol_version: ProtocolVersion,
     base_url: String,
     client: reqwest::Client,
+    // Set if the service is reached over CoAP instead of HTTP
+    coap_url: Option<reqwest::Url>,
     authorization_token: Option<String>,
     encryption_keys: EncryptionKeys,
     last_message_type: Option<MessageType>,
@@ -150,10 +324,14 @@ pub struct ServiceClient {
 
 impl ServiceClient {
     pub fn new(protocol_version: ProtocolVersion, base_url: &str) -> Self {
+        let coap_url = reqwest::Url::parse(base_url).ok().filter(|url| {
+            url.scheme() == crate::coap::SCHEME_UDP || url.scheme() == crate::coap::SCHEME_TCP
+        });
         ServiceClient {
             protocol_version,
             base_url: base_url.trim_end_matches('/').to_string(),
             client: reqwest::Client::new(),
+            coap_url,
             authorization_token: None,
             encryption_keys: EncryptionKeys::unencrypted(),
             last_message_type: None,
@@ -161,10 +339,97 @@ impl ServiceClient {
         }
     }
 
+    /// Creates a client for which the TLS server certificate is checked
+    /// against the provided pins instead of the system trust store.
+    ///
+    /// The certificate is checked during the TLS handshake, so no request is
+    /// sent to a server that does not match the pins.
+    /// A CA certificate hash only matches if the server includes the CA
+    /// certificate in the chain it presents.
+    /// Pins are ignored for non-HTTPS URLs.
+    pub fn new_with_pins(
+        protocol_version: ProtocolVersion,
+        base_url: &str,
+        pins: &CertificatePins,
+    ) -> RequestResult<Self> {
+        let mut service_client = ServiceClient::new(protocol_version, base_url);
+
+        let url = reqwest::Url::parse(&service_client.base_url)?;
+        if pins.is_empty() || url.scheme() != "https" {
+            return Ok(service_client);
+        }
+
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificateVerifier {
+                pins: pins.clone(),
+            }))
+            .with_no_client_auth();
+        service_client.client = reqwest::Client::builder()
+            .use_preconfigured_tls(tls_config)
+            .build()?;
+
+        Ok(service_client)
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
     pub fn non_interoperable_kdf_required(&self) -> Option<bool> {
         self.non_interoperable_kdf_required
     }
 
+    async fn send_http(
+        &self,
+        path: &str,
+        authorization_token: Option<&str>,
+        to_send: Vec<u8>,
+    ) -> RequestResult<RawResponse> {
+        let url = format!("{}{}", &self.base_url, path);
+
+        let mut req = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/cbor")
+            .body(to_send);
+
+        if let Some(authorization_token) = authorization_token {
+            req = req.header("Authorization", authorization_token);
+        }
+
+        if !fdo_data_formats::interoperable_kdf_available() {
+            req = req.header("X-Non-Interoperable-KDF", "true");
+        }
+
+        let resp = req.send().await?;
+
+        let non_interoperable_kdf = resp
+            .headers()
+            .get("X-Non-Interoperable-KDF")
+            .map(|v| v.to_str().unwrap_or("").eq("true"))
+            .unwrap_or(false);
+        let message_type = resp
+            .headers()
+            .get("message-type")
+            .map(reqwest::header::HeaderValue::to_str)
+            .transpose()
+            .map_err(|_| Error::InvalidMessageType("non-string".to_string()))?
+            .map(String::from);
+        let authorization = resp
+            .headers()
+            .get("authorization")
+            .map(|val| val.to_str().unwrap().to_string());
+
+        Ok(RawResponse {
+            is_success: resp.status().is_success(),
+            message_type,
+            authorization,
+            non_interoperable_kdf,
+            body: resp.bytes().await?.to_vec(),
+        })
+    }
+
     pub async fn send_request<OM, SM>(
         &mut self,
         to_send: OM,
@@ -212,52 +477,43 @@ impl ServiceClient {
         let to_send = self.encryption_keys.encrypt(&to_send)?;
         log::trace!("Sending message: {:?}", hex::encode(&to_send));
 
-        let url = format!(
-            "{}/fdo/{}/msg/{}",
-            &self.base_url,
+        let path = format!(
+            "/fdo/{}/msg/{}",
             self.protocol_version,
             OM::message_type() as u8
         );
-
-        let mut req = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/cbor")
-            .body(to_send);
-
-        if let Some(authorization_token) = &self.authorization_token {
-            req = req.header("Authorization", authorization_token);
-        }
-
-        if !fdo_data_formats::interoperable_kdf_available() {
-            req = req.header("X-Non-Interoperable-KDF", "true");
-        }
+        let authorization_token = self.authorization_token.clone();
 
         if let Some(new_keys) = new_keys {
             self.encryption_keys = new_keys;
         }
 
-        let resp = req.send().await?;
+        let resp = match self.coap_url.as_ref() {
+            Some(coap_url) => {
+                crate::coap::client::send(
+                    coap_url,
+                    &path,
+                    authorization_token.as_deref(),
+                    !fdo_data_formats::interoperable_kdf_available(),
+                    to_send,
+                )
+                .await?
+            }
+            None => {
+                self.send_http(&path, authorization_token.as_deref(), to_send)
+                    .await?
+            }
+        };
 
         if self.non_interoperable_kdf_required.is_none() {
-            self.non_interoperable_kdf_required = Some(
-                resp.headers()
-                    .get("X-Non-Interoperable-KDF")
-                    .map(|v| v.to_str().unwrap_or("").eq("true"))
-                    .unwrap_or(false),
-            );
+            self.non_interoperable_kdf_required = Some(resp.non_interoperable_kdf);
             log::trace!(
                 "Determined whether non-interoperable KDF is required: {:?}",
                 self.non_interoperable_kdf_required
             );
         }
 
-        let msgtype = resp
-            .headers()
-            .get("message-type")
-            .map(reqwest::header::HeaderValue::to_str)
-            .transpose()
-            .map_err(|_| Error::InvalidMessageType("non-string".to_string()))?;
+        let msgtype = resp.message_type.as_deref();
         let msgtype = msgtype
             .map(u8::from_str)
             .transpose()
@@ -268,7 +524,7 @@ impl ServiceClient {
         let msgtype = match msgtype {
             Some(msgtype) => msgtype,
             None => {
-                if resp.status().is_success() {
+                if resp.is_success {
                     return Err(Error::MissingMessageType);
                 } else {
                     MessageType::Error
@@ -276,11 +532,11 @@ impl ServiceClient {
             }
         };
 
-        if let Some(val) = resp.headers().get("authorization") {
-            self.authorization_token = Some(val.to_str().unwrap().to_string());
+        if let Some(val) = resp.authorization {
+            self.authorization_token = Some(val);
         }
 
-        let is_success = if resp.status().is_success() {
+        let is_success = if resp.is_success {
             if msgtype != SM::message_type() {
                 return Err(Error::InvalidMessage(msgtype, SM::message_type()));
             }
@@ -292,7 +548,7 @@ impl ServiceClient {
             false
         };
 
-        let resp = resp.bytes().await?;
+        let resp = resp.body;
         log::trace!("Received: {:?}", hex::encode(&resp));
 
         if is_success {
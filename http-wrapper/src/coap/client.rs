@@ -0,0 +1,254 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use coap_lite::{
+    block_handler::BlockValue, CoapOption, ContentFormat, MessageClass, MessageType, Packet,
+    RequestType, ResponseType,
+};
+use tokio::net::{TcpStream, UdpSocket};
+
+use super::{
+    handle_signaling, is_signaling, option_string, read_tcp_frame, write_tcp_frame, BLOCK_SIZE,
+    DEFAULT_PORT, OPTION_AUTHORIZATION, OPTION_MESSAGE_TYPE, OPTION_NON_INTEROPERABLE_KDF,
+    SCHEME_TCP,
+};
+use crate::client::{Error, RawResponse, RequestResult};
+
+// Retransmission parameters (RFC 7252, section 4.8)
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+// How long to wait for a separate response after an empty acknowledgement
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn raw_response(packet: &Packet, body: Vec<u8>) -> RequestResult<RawResponse> {
+    let is_success = match packet.header.code {
+        MessageClass::Response(response_type) => !response_type.is_error(),
+        _ => return Err(Error::Coap("Received a non-response message".to_string())),
+    };
+    Ok(RawResponse {
+        is_success,
+        message_type: option_string(packet, OPTION_MESSAGE_TYPE),
+        authorization: option_string(packet, OPTION_AUTHORIZATION),
+        non_interoperable_kdf: packet
+            .get_first_option(OPTION_NON_INTEROPERABLE_KDF)
+            .is_some(),
+        body,
+    })
+}
+
+fn request_packet(
+    path: &str,
+    authorization: Option<&str>,
+    non_interoperable_kdf: bool,
+) -> RequestResult<Packet> {
+    let mut packet = Packet::new();
+    packet.header.code = MessageClass::Request(RequestType::Post);
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        packet.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+    }
+    packet.set_content_format(ContentFormat::ApplicationCBOR);
+    if let Some(authorization) = authorization {
+        packet.add_option(OPTION_AUTHORIZATION, authorization.as_bytes().to_vec());
+    }
+    if non_interoperable_kdf {
+        packet.add_option(OPTION_NON_INTEROPERABLE_KDF, Vec::new());
+    }
+    let mut token = [0; 8];
+    openssl::rand::rand_bytes(&mut token)?;
+    packet.set_token(token.to_vec());
+    Ok(packet)
+}
+
+/// Sends a request to the CoAP server at `url` and returns its response
+pub(crate) async fn send(
+    url: &reqwest::Url,
+    path: &str,
+    authorization: Option<&str>,
+    non_interoperable_kdf: bool,
+    body: Vec<u8>,
+) -> RequestResult<RawResponse> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::InvalidUrl(url.to_string(), "URL has no host"))?;
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+    let addr = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+        .await?
+        .next()
+        .ok_or_else(|| Error::InvalidUrl(url.to_string(), "Host could not be resolved"))?;
+
+    let request = request_packet(path, authorization, non_interoperable_kdf)?;
+    if url.scheme() == SCHEME_TCP {
+        send_tcp(addr, request, body).await
+    } else {
+        send_udp(addr, request, body).await
+    }
+}
+
+async fn send_tcp(
+    addr: SocketAddr,
+    mut request: Packet,
+    body: Vec<u8>,
+) -> RequestResult<RawResponse> {
+    let mut stream = TcpStream::connect(addr).await?;
+
+    write_tcp_frame(&mut stream, &super::csm_packet()).await?;
+    request.payload = body;
+    write_tcp_frame(&mut stream, &request).await?;
+
+    loop {
+        let packet = match read_tcp_frame(&mut stream).await? {
+            Some(packet) => packet,
+            None => return Err(Error::Coap("Connection closed by server".to_string())),
+        };
+        if is_signaling(&packet) {
+            if !handle_signaling(&mut stream, &packet).await? {
+                return Err(Error::Coap("Connection released by server".to_string()));
+            }
+            continue;
+        }
+        if packet.get_token() != request.get_token() {
+            continue;
+        }
+        let body = packet.payload.clone();
+        return raw_response(&packet, body);
+    }
+}
+
+async fn send_udp(addr: SocketAddr, request: Packet, body: Vec<u8>) -> RequestResult<RawResponse> {
+    let local_addr: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local_addr).await?;
+    socket.connect(addr).await?;
+
+    let mut message_id = [0; 2];
+    openssl::rand::rand_bytes(&mut message_id)?;
+    let mut message_id = u16::from_be_bytes(message_id);
+
+    // Send the request, in blocks if it does not fit in one
+    let blocks: Vec<&[u8]> = if body.is_empty() {
+        vec![&[]]
+    } else {
+        body.chunks(BLOCK_SIZE).collect()
+    };
+    let mut response = None;
+    for (num, block) in blocks.iter().enumerate() {
+        let more = num + 1 < blocks.len();
+        let mut packet = request.clone();
+        packet.payload = block.to_vec();
+        if blocks.len() > 1 {
+            packet.add_option_as(CoapOption::Block1, block_value(num, more)?);
+        }
+        message_id = message_id.wrapping_add(1);
+        let block_response = exchange_udp(&socket, packet, message_id).await?;
+        if more && block_response.header.code == MessageClass::Response(ResponseType::Continue) {
+            continue;
+        }
+        response = Some(block_response);
+        break;
+    }
+    let response = response.expect("At least one block is always sent");
+
+    // Retrieve the rest of the response if it was sent in blocks
+    let mut body = response.payload.clone();
+    let mut last_response = response.clone();
+    while let Some(block) = last_response.get_first_option_as::<BlockValue>(CoapOption::Block2) {
+        let block = block.map_err(|e| Error::Coap(format!("Invalid Block2 option: {:?}", e)))?;
+        if !block.more {
+            break;
+        }
+        let mut packet = request.clone();
+        packet.add_option_as(
+            CoapOption::Block2,
+            block_value(block.num as usize + 1, false)?,
+        );
+        message_id = message_id.wrapping_add(1);
+        last_response = exchange_udp(&socket, packet, message_id).await?;
+        if last_response.header.code != response.header.code {
+            return Err(Error::Coap(
+                "Response code changed during block-wise transfer".to_string(),
+            ));
+        }
+        body.extend_from_slice(&last_response.payload);
+    }
+
+    raw_response(&response, body)
+}
+
+fn block_value(num: usize, more: bool) -> RequestResult<BlockValue> {
+    BlockValue::new(num, more, BLOCK_SIZE)
+        .map_err(|e| Error::Coap(format!("Invalid block number: {:?}", e)))
+}
+
+/// Sends a confirmable request, retransmitting it until it is acknowledged,
+/// and waits for the matching response
+async fn exchange_udp(
+    socket: &UdpSocket,
+    mut packet: Packet,
+    message_id: u16,
+) -> RequestResult<Packet> {
+    packet.header.set_type(MessageType::Confirmable);
+    packet.header.message_id = message_id;
+    let request_bytes = packet
+        .to_bytes_unlimited()
+        .map_err(|e| Error::Coap(format!("Error encoding request: {:?}", e)))?;
+
+    let mut timeout = ACK_TIMEOUT;
+    let mut retransmissions = 0;
+    let mut acknowledged = false;
+    let mut buf = vec![0; 65536];
+
+    socket.send(&request_bytes).await?;
+    loop {
+        let len = match tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+            Ok(len) => len?,
+            Err(_) if acknowledged || retransmissions == MAX_RETRANSMIT => {
+                return Err(Error::Coap("Timeout waiting for response".to_string()));
+            }
+            Err(_) => {
+                retransmissions += 1;
+                timeout *= 2;
+                socket.send(&request_bytes).await?;
+                continue;
+            }
+        };
+        let response = match Packet::from_bytes(&buf[..len]) {
+            Ok(response) => response,
+            Err(e) => {
+                log::trace!("Ignoring invalid CoAP message: {:?}", e);
+                continue;
+            }
+        };
+
+        match response.header.get_type() {
+            MessageType::Acknowledgement if response.header.message_id == message_id => {
+                if response.header.code == MessageClass::Empty {
+                    // The response will follow separately
+                    acknowledged = true;
+                    timeout = RESPONSE_TIMEOUT;
+                    continue;
+                }
+                return Ok(response);
+            }
+            MessageType::Reset if response.header.message_id == message_id => {
+                return Err(Error::Coap("Request rejected by server".to_string()));
+            }
+            MessageType::Confirmable | MessageType::NonConfirmable
+                if response.get_token() == packet.get_token() =>
+            {
+                if response.header.get_type() == MessageType::Confirmable {
+                    let mut ack = Packet::new();
+                    ack.header.set_type(MessageType::Acknowledgement);
+                    ack.header.message_id = response.header.message_id;
+                    if let Ok(ack) = ack.to_bytes() {
+                        socket.send(&ack).await?;
+                    }
+                }
+                return Ok(response);
+            }
+            _ => continue,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! CoAP transport for the FDO protocol messages.
+//!
+//! Messages are carried the same way as over HTTP: a POST to
+//! `/fdo/<version>/msg/<message type>` with the CBOR message as payload.
+//! The HTTP headers used by the protocol are carried in options from the
+//! experimental range.
+//!
+//! Both CoAP over UDP (`coap://`, RFC 7252, with RFC 7959 block-wise
+//! transfers for large messages) and CoAP over TCP (`coap+tcp://`, RFC 8323)
+//! are supported.
+
+use std::convert::TryFrom;
+
+use coap_lite::{CoapOption, MessageClass, Packet, ResponseType};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[cfg(feature = "client")]
+pub(crate) mod client;
+#[cfg(feature = "server")]
+pub mod server;
+
+pub const DEFAULT_PORT: u16 = 5683;
+
+pub(crate) const SCHEME_UDP: &str = "coap";
+pub(crate) const SCHEME_TCP: &str = "coap+tcp";
+
+// Elective options (even numbers) from the experimental range
+pub(crate) const OPTION_AUTHORIZATION: CoapOption = CoapOption::Unknown(65000);
+pub(crate) const OPTION_MESSAGE_TYPE: CoapOption = CoapOption::Unknown(65002);
+pub(crate) const OPTION_NON_INTEROPERABLE_KDF: CoapOption = CoapOption::Unknown(65004);
+
+// Block size for block-wise transfers over UDP
+pub(crate) const BLOCK_SIZE: usize = 1024;
+
+// Largest message accepted over TCP, or reassembled from blocks over UDP
+pub(crate) const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// Signaling codes (RFC 8323, section 5)
+const CODE_CSM: u8 = 0xE1;
+const CODE_PING: u8 = 0xE2;
+const CODE_PONG: u8 = 0xE3;
+const CODE_RELEASE: u8 = 0xE4;
+const CODE_ABORT: u8 = 0xE5;
+const CSM_OPTION_MAX_MESSAGE_SIZE: CoapOption = CoapOption::Unknown(2);
+
+pub(crate) fn status_to_response_type(status: u16) -> ResponseType {
+    match status {
+        200..=299 => ResponseType::Changed,
+        400 => ResponseType::BadRequest,
+        401 => ResponseType::Unauthorized,
+        403 => ResponseType::Forbidden,
+        404 => ResponseType::NotFound,
+        405 => ResponseType::MethodNotAllowed,
+        413 => ResponseType::RequestEntityTooLarge,
+        415 => ResponseType::UnsupportedContentFormat,
+        500..=599 => ResponseType::InternalServerError,
+        _ => ResponseType::BadRequest,
+    }
+}
+
+pub(crate) fn option_string(packet: &Packet, option: CoapOption) -> Option<String> {
+    packet
+        .get_first_option(option)
+        .map(|value| String::from_utf8_lossy(value).to_string())
+}
+
+pub(crate) fn is_signaling(packet: &Packet) -> bool {
+    matches!(packet.header.code, MessageClass::Reserved(code) if code & 0xE0 == 0xE0)
+}
+
+fn signaling_packet(code: u8) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.code = MessageClass::Reserved(code);
+    packet
+}
+
+/// Builds the Capabilities and Settings Message that both sides send first
+/// on a CoAP over TCP connection.
+pub(crate) fn csm_packet() -> Packet {
+    let mut packet = signaling_packet(CODE_CSM);
+    packet.add_option(
+        CSM_OPTION_MAX_MESSAGE_SIZE,
+        (MAX_MESSAGE_SIZE as u32).to_be_bytes().to_vec(),
+    );
+    packet
+}
+
+/// Handles a signaling message received over TCP, returning whether the
+/// connection should be kept open.
+pub(crate) async fn handle_signaling<W>(writer: &mut W, packet: &Packet) -> std::io::Result<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    match u8::from(packet.header.code) {
+        CODE_PING => {
+            let mut pong = signaling_packet(CODE_PONG);
+            pong.set_token(packet.get_token().to_vec());
+            write_tcp_frame(writer, &pong).await?;
+            Ok(true)
+        }
+        CODE_RELEASE | CODE_ABORT => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Writes a packet with the CoAP over TCP framing.
+///
+/// The framing only differs from UDP in the first bytes: the version, type
+/// and message ID are replaced by the length of the options and payload.
+pub(crate) async fn write_tcp_frame<W>(writer: &mut W, packet: &Packet) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let udp_bytes = packet.to_bytes_unlimited().map_err(invalid_data)?;
+    let token_length = (udp_bytes[0] & 0x0F) as usize;
+    let code = udp_bytes[1];
+    let token = &udp_bytes[4..4 + token_length];
+    let rest = &udp_bytes[4 + token_length..];
+
+    let mut frame = Vec::with_capacity(udp_bytes.len() + 4);
+    match rest.len() {
+        len if len < 13 => frame.push((len as u8) << 4 | token_length as u8),
+        len if len < 269 => {
+            frame.push(13 << 4 | token_length as u8);
+            frame.push((len - 13) as u8);
+        }
+        len if len < 65805 => {
+            frame.push(14 << 4 | token_length as u8);
+            frame.extend_from_slice(&((len - 269) as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(15 << 4 | token_length as u8);
+            let len = u32::try_from(len - 65805).map_err(invalid_data)?;
+            frame.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+    frame.push(code);
+    frame.extend_from_slice(token);
+    frame.extend_from_slice(rest);
+
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Reads a packet with the CoAP over TCP framing, returning None when the
+/// connection was closed.
+pub(crate) async fn read_tcp_frame<R>(reader: &mut R) -> std::io::Result<Option<Packet>>
+where
+    R: AsyncRead + Unpin,
+{
+    let first = match reader.read_u8().await {
+        Ok(first) => first,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let token_length = (first & 0x0F) as usize;
+    let length = match first >> 4 {
+        13 => reader.read_u8().await? as usize + 13,
+        14 => reader.read_u16().await? as usize + 269,
+        15 => reader.read_u32().await? as usize + 65805,
+        len => len as usize,
+    };
+    if length > MAX_MESSAGE_SIZE {
+        return Err(invalid_data("CoAP message too large"));
+    }
+    let code = reader.read_u8().await?;
+
+    let mut udp_bytes = vec![0; 4 + token_length + length];
+    // Version 1, confirmable, no message ID
+    udp_bytes[0] = 0x40 | token_length as u8;
+    udp_bytes[1] = code;
+    reader.read_exact(&mut udp_bytes[4..]).await?;
+
+    Packet::from_bytes(&udp_bytes)
+        .map(Some)
+        .map_err(invalid_data)
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use warp::Filter;
+
+    use crate::client::RawResponse;
+
+    async fn echo_server() -> std::net::SocketAddr {
+        let filter = warp::post()
+            .and(warp::path!("fdo" / "101" / "msg" / "30"))
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(warp::body::bytes())
+            .map(
+                |authorization: Option<String>, body: warp::hyper::body::Bytes| {
+                    warp::http::Response::builder()
+                        .header("Message-Type", "31")
+                        .header("Authorization", authorization.unwrap_or_default())
+                        .body(body.to_vec())
+                        .unwrap()
+                },
+            )
+            .boxed();
+
+        // Pick a free port; the TCP listener binds the same port number
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(super::server::bind(filter, addr).unwrap());
+        addr
+    }
+
+    async fn roundtrip(scheme: &str, body: Vec<u8>) -> RawResponse {
+        let addr = echo_server().await;
+        let url = reqwest::Url::parse(&format!("{}://{}", scheme, addr)).unwrap();
+        super::client::send(&url, "/fdo/101/msg/30", Some("Bearer token"), false, body)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let response = roundtrip(super::SCHEME_UDP, b"hello".to_vec()).await;
+        assert!(response.is_success);
+        assert_eq!(response.message_type.as_deref(), Some("31"));
+        assert_eq!(response.authorization.as_deref(), Some("Bearer token"));
+        assert_eq!(response.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_udp_blockwise() {
+        let body: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let response = roundtrip(super::SCHEME_UDP, body.clone()).await;
+        assert!(response.is_success);
+        assert_eq!(response.body, body);
+    }
+
+    #[tokio::test]
+    async fn test_udp_too_large() {
+        let body = vec![0; super::MAX_MESSAGE_SIZE + 1];
+        let response = roundtrip(super::SCHEME_UDP, body).await;
+        assert!(!response.is_success);
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        let body: Vec<u8> = (0..70000).map(|i| i as u8).collect();
+        let response = roundtrip(super::SCHEME_TCP, body.clone()).await;
+        assert!(response.is_success);
+        assert_eq!(response.message_type.as_deref(), Some("31"));
+        assert_eq!(response.body, body);
+    }
+
+    #[tokio::test]
+    async fn test_not_found() {
+        let addr = echo_server().await;
+        let url = reqwest::Url::parse(&format!("coap://{}", addr)).unwrap();
+        let response = super::client::send(&url, "/fdo/101/msg/32", None, false, Vec::new())
+            .await
+            .unwrap();
+        assert!(!response.is_success);
+    }
+}
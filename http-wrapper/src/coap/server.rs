@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use coap_lite::{
+    block_handler::BlockValue, CoapOption, ContentFormat, MessageClass, MessageType, Packet,
+    RequestType, ResponseType,
+};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use warp::hyper::service::Service;
+use warp::{filters::BoxedFilter, Reply};
+
+use super::{
+    csm_packet, handle_signaling, is_signaling, option_string, read_tcp_frame,
+    status_to_response_type, write_tcp_frame, BLOCK_SIZE, MAX_MESSAGE_SIZE, OPTION_AUTHORIZATION,
+    OPTION_MESSAGE_TYPE, OPTION_NON_INTEROPERABLE_KDF,
+};
+
+// How long partial block-wise transfers and cached responses are kept
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(250);
+
+// Most request bodies that are received in blocks at the same time
+const MAX_PARTIAL_REQUESTS: usize = 64;
+
+/// Binds a CoAP server on UDP and TCP at `bind_addr`, which passes the
+/// requests it receives to the provided warp filter.
+///
+/// The returned future runs the server.
+pub fn bind<T>(
+    filter: BoxedFilter<(T,)>,
+    bind_addr: SocketAddr,
+) -> std::io::Result<impl Future<Output = ()>>
+where
+    T: Reply + 'static,
+{
+    let udp_socket = std::net::UdpSocket::bind(bind_addr)?;
+    udp_socket.set_nonblocking(true)?;
+    let udp_socket = UdpSocket::from_std(udp_socket)?;
+    let tcp_listener = std::net::TcpListener::bind(bind_addr)?;
+    tcp_listener.set_nonblocking(true)?;
+    let tcp_listener = TcpListener::from_std(tcp_listener)?;
+
+    let handler = Handler {
+        service: warp::service(filter),
+    };
+
+    Ok(async move {
+        tokio::join!(
+            serve_udp(handler.clone(), udp_socket),
+            serve_tcp(handler, tcp_listener)
+        );
+    })
+}
+
+#[derive(Clone)]
+struct Handler<S> {
+    service: S,
+}
+
+impl<S> Handler<S>
+where
+    S: Service<
+            warp::http::Request<warp::hyper::Body>,
+            Response = warp::http::Response<warp::hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Clone,
+{
+    /// Passes the request to the warp filter, and returns a response packet
+    /// without header type, message ID or token, and the full response body.
+    async fn handle(&self, request: &Packet, body: Vec<u8>) -> (Packet, Vec<u8>) {
+        let mut response = Packet::new();
+
+        if request.header.code != MessageClass::Request(RequestType::Post) {
+            response.header.code = MessageClass::Response(ResponseType::MethodNotAllowed);
+            return (response, Vec::new());
+        }
+
+        let path = request
+            .get_option(CoapOption::UriPath)
+            .map(|segments| {
+                segments
+                    .iter()
+                    .map(|segment| String::from_utf8_lossy(segment).to_string())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .unwrap_or_default();
+
+        let mut builder = warp::http::Request::builder()
+            .method(warp::http::Method::POST)
+            .uri(format!("/{}", path))
+            .header("Content-Length", body.len());
+        if request.get_content_format() == Some(ContentFormat::ApplicationCBOR) {
+            builder = builder.header("Content-Type", "application/cbor");
+        }
+        if let Some(authorization) = option_string(request, OPTION_AUTHORIZATION) {
+            builder = builder.header("Authorization", authorization);
+        }
+        if request
+            .get_first_option(OPTION_NON_INTEROPERABLE_KDF)
+            .is_some()
+        {
+            builder = builder.header("X-Non-Interoperable-KDF", "true");
+        }
+        let http_request = match builder.body(body.into()) {
+            Ok(http_request) => http_request,
+            Err(e) => {
+                log::warn!("Error converting CoAP request: {:?}", e);
+                response.header.code = MessageClass::Response(ResponseType::BadRequest);
+                return (response, Vec::new());
+            }
+        };
+
+        let http_response = match self.service.clone().call(http_request).await {
+            Ok(http_response) => http_response,
+            Err(e) => match e {},
+        };
+
+        response.header.code =
+            MessageClass::Response(status_to_response_type(http_response.status().as_u16()));
+        let headers = http_response.headers();
+        if let Some(message_type) = headers.get("Message-Type") {
+            response.add_option(OPTION_MESSAGE_TYPE, message_type.as_bytes().to_vec());
+        }
+        if let Some(authorization) = headers.get("Authorization") {
+            response.add_option(OPTION_AUTHORIZATION, authorization.as_bytes().to_vec());
+        }
+        if headers.get("X-Non-Interoperable-KDF").is_some() {
+            response.add_option(OPTION_NON_INTEROPERABLE_KDF, Vec::new());
+        }
+        response.set_content_format(ContentFormat::ApplicationCBOR);
+
+        let body = match warp::hyper::body::to_bytes(http_response.into_body()).await {
+            Ok(body) => body.to_vec(),
+            Err(e) => {
+                log::warn!("Error reading response body: {:?}", e);
+                response.header.code = MessageClass::Response(ResponseType::InternalServerError);
+                Vec::new()
+            }
+        };
+
+        (response, body)
+    }
+}
+
+async fn serve_tcp<S>(handler: Handler<S>, listener: TcpListener)
+where
+    S: Service<
+            warp::http::Request<warp::hyper::Body>,
+            Response = warp::http::Response<warp::hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::warn!("Error accepting CoAP connection: {:?}", e);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_tcp_connection(handler, stream).await {
+                log::debug!("Error on CoAP connection from {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_tcp_connection<S>(handler: Handler<S>, mut stream: TcpStream) -> std::io::Result<()>
+where
+    S: Service<
+            warp::http::Request<warp::hyper::Body>,
+            Response = warp::http::Response<warp::hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Clone,
+{
+    write_tcp_frame(&mut stream, &csm_packet()).await?;
+
+    while let Some(request) = read_tcp_frame(&mut stream).await? {
+        if is_signaling(&request) {
+            if !handle_signaling(&mut stream, &request).await? {
+                break;
+            }
+            continue;
+        }
+        let (mut response, body) = handler.handle(&request, request.payload.clone()).await;
+        response.set_token(request.get_token().to_vec());
+        response.payload = body;
+        write_tcp_frame(&mut stream, &response).await?;
+    }
+    Ok(())
+}
+
+type ExchangeKey = (SocketAddr, Vec<u8>);
+
+#[derive(Default)]
+struct UdpState {
+    // Request bodies being received in blocks, with the time of the last block
+    partial_requests: HashMap<ExchangeKey, (Instant, Vec<u8>)>,
+    // Response bodies being sent in blocks
+    partial_responses: HashMap<ExchangeKey, (Instant, Packet, Vec<u8>)>,
+    // Last message ID received from each peer, with the response once sent,
+    // to deal with retransmissions
+    last_messages: HashMap<SocketAddr, (Instant, u16, Option<Vec<u8>>)>,
+}
+
+impl UdpState {
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.partial_requests
+            .retain(|_, (time, _)| now.duration_since(*time) < EXCHANGE_LIFETIME);
+        self.partial_responses
+            .retain(|_, (time, _, _)| now.duration_since(*time) < EXCHANGE_LIFETIME);
+        self.last_messages
+            .retain(|_, (time, _, _)| now.duration_since(*time) < EXCHANGE_LIFETIME);
+    }
+
+    /// Adds a Block1 block to the request body being received.
+    ///
+    /// Returns the complete body once the last block is received, or the
+    /// error response to send if the block cannot be accepted.
+    fn receive_block(
+        &mut self,
+        key: &ExchangeKey,
+        block1: &BlockValue,
+        payload: &[u8],
+    ) -> Result<Option<Vec<u8>>, Packet> {
+        let mut response = Packet::new();
+        if !self.partial_requests.contains_key(key)
+            && self.partial_requests.len() >= MAX_PARTIAL_REQUESTS
+        {
+            response.header.code = MessageClass::Response(ResponseType::ServiceUnavailable);
+            return Err(response);
+        }
+        let (last_block, body) = self
+            .partial_requests
+            .entry(key.clone())
+            .or_insert_with(|| (Instant::now(), Vec::new()));
+        if block1.num as usize * block1.size() != body.len() {
+            self.partial_requests.remove(key);
+            response.header.code = MessageClass::Response(ResponseType::RequestEntityIncomplete);
+            return Err(response);
+        }
+        if body.len() + payload.len() > MAX_MESSAGE_SIZE {
+            self.partial_requests.remove(key);
+            response.header.code = MessageClass::Response(ResponseType::RequestEntityTooLarge);
+            response.add_option(
+                CoapOption::Size1,
+                (MAX_MESSAGE_SIZE as u32).to_be_bytes().to_vec(),
+            );
+            return Err(response);
+        }
+        *last_block = Instant::now();
+        body.extend_from_slice(payload);
+        if block1.more {
+            Ok(None)
+        } else {
+            Ok(self.partial_requests.remove(key).map(|(_, body)| body))
+        }
+    }
+}
+
+async fn serve_udp<S>(handler: Handler<S>, socket: UdpSocket)
+where
+    S: Service<
+            warp::http::Request<warp::hyper::Body>,
+            Response = warp::http::Response<warp::hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send,
+{
+    let socket = Arc::new(socket);
+    let state = Arc::new(Mutex::new(UdpState::default()));
+    let mut buf = vec![0; 65536];
+
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("Error receiving CoAP message: {:?}", e);
+                continue;
+            }
+        };
+        let request = match Packet::from_bytes(&buf[..len]) {
+            Ok(request) => request,
+            Err(e) => {
+                log::debug!("Ignoring invalid CoAP message from {}: {:?}", peer, e);
+                continue;
+            }
+        };
+        if !matches!(request.header.code, MessageClass::Request(_)) {
+            continue;
+        }
+
+        let handler = handler.clone();
+        let socket = socket.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Some(response) = handle_udp_request(&handler, &state, peer, request).await {
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    log::warn!("Error sending CoAP response to {}: {:?}", peer, e);
+                }
+            }
+        });
+    }
+}
+
+fn block_option(num: usize, more: bool, size: usize) -> BlockValue {
+    BlockValue::new(num, more, size).expect("Block numbers are bounded by the message size")
+}
+
+async fn handle_udp_request<S>(
+    handler: &Handler<S>,
+    state: &Mutex<UdpState>,
+    peer: SocketAddr,
+    request: Packet,
+) -> Option<Vec<u8>>
+where
+    S: Service<
+            warp::http::Request<warp::hyper::Body>,
+            Response = warp::http::Response<warp::hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Clone,
+{
+    let key = (peer, request.get_token().to_vec());
+    let message_id = request.header.message_id;
+
+    // Retransmissions get the same response, or none if still being processed
+    {
+        let mut state = state.lock().unwrap();
+        state.expire();
+        if let Some((_, last_id, last_response)) = state.last_messages.get(&peer) {
+            if *last_id == message_id {
+                return last_response.clone();
+            }
+        }
+        state
+            .last_messages
+            .insert(peer, (Instant::now(), message_id, None));
+    }
+
+    let block1 = request
+        .get_first_option_as::<BlockValue>(CoapOption::Block1)
+        .and_then(Result::ok);
+    let block2 = request
+        .get_first_option_as::<BlockValue>(CoapOption::Block2)
+        .and_then(Result::ok);
+
+    let (mut response, payload) =
+        if let Some(block2) = block2.as_ref().filter(|block| block.num > 0) {
+            // Follow-up request for the next block of a response
+            let state = state.lock().unwrap();
+            match state.partial_responses.get(&key) {
+                Some((_, response, body)) => {
+                    let start = block2.num as usize * block2.size();
+                    let mut response = response.clone();
+                    if start >= body.len() {
+                        response.header.code = MessageClass::Response(ResponseType::BadOption);
+                        (response, Vec::new())
+                    } else {
+                        let end = std::cmp::min(start + block2.size(), body.len());
+                        response.add_option_as(
+                            CoapOption::Block2,
+                            block_option(block2.num as usize, end < body.len(), block2.size()),
+                        );
+                        (response, body[start..end].to_vec())
+                    }
+                }
+                None => {
+                    let mut response = Packet::new();
+                    response.header.code = MessageClass::Response(ResponseType::NotFound);
+                    (response, Vec::new())
+                }
+            }
+        } else {
+            let body = match block1.clone() {
+                None => Some(request.payload.clone()),
+                Some(block1) => {
+                    let mut state = state.lock().unwrap();
+                    match state.receive_block(&key, &block1, &request.payload) {
+                        Ok(body) => body,
+                        Err(error) => return finish_udp_response(state, peer, &request, error),
+                    }
+                }
+            };
+
+            match body {
+                None => {
+                    let mut response = Packet::new();
+                    response.header.code = MessageClass::Response(ResponseType::Continue);
+                    response.add_option_as(CoapOption::Block1, block1.unwrap());
+                    (response, Vec::new())
+                }
+                Some(body) => {
+                    let (mut response, body) = handler.handle(&request, body).await;
+                    if let Some(block1) = block1 {
+                        response.add_option_as(CoapOption::Block1, block1);
+                    }
+                    let size = block2.as_ref().map(BlockValue::size).unwrap_or(BLOCK_SIZE);
+                    if body.len() > size {
+                        let mut first = response.clone();
+                        first.add_option_as(CoapOption::Block2, block_option(0, true, size));
+                        state
+                            .lock()
+                            .unwrap()
+                            .partial_responses
+                            .insert(key, (Instant::now(), response, body.clone()));
+                        (first, body[..size].to_vec())
+                    } else {
+                        (response, body)
+                    }
+                }
+            }
+        };
+
+    response.payload = payload;
+    let state = state.lock().unwrap();
+    finish_udp_response(state, peer, &request, response)
+}
+
+fn finish_udp_response(
+    mut state: std::sync::MutexGuard<UdpState>,
+    peer: SocketAddr,
+    request: &Packet,
+    mut response: Packet,
+) -> Option<Vec<u8>> {
+    response.set_token(request.get_token().to_vec());
+    if request.header.get_type() == MessageType::Confirmable {
+        // Piggybacked response
+        response.header.set_type(MessageType::Acknowledgement);
+        response.header.message_id = request.header.message_id;
+    } else {
+        response.header.set_type(MessageType::NonConfirmable);
+        response.header.message_id = request.header.message_id.wrapping_add(0x8000);
+    }
+    let response = match response.to_bytes_unlimited() {
+        Ok(response) => response,
+        Err(e) => {
+            log::warn!("Error encoding CoAP response: {:?}", e);
+            return None;
+        }
+    };
+    state.last_messages.insert(
+        peer,
+        (
+            Instant::now(),
+            request.header.message_id,
+            Some(response.clone()),
+        ),
+    );
+    Some(response)
+}
@@ -0,0 +1,405 @@
+//! COSE message protection for the cipher suites not handled by aws-nitro-enclaves-cose.
+//!
+//! For the split key cipher suites (encrypt-then-MAC), the message is encrypted into a
+//! COSE_Encrypt0 structure with AES-CBC or AES-CTR, which is then carried as the payload of a
+//! COSE_Mac0 structure authenticated with HMAC.
+//! For the AES-CCM cipher suites, the message is encrypted into a COSE_Encrypt0 structure.
+//!
+//! Like the AES-GCM messages produced by aws-nitro-enclaves-cose, all structures are sent
+//! tagged, including the COSE_Encrypt0 inside the COSE_Mac0. Untagged structures are accepted.
+
+use std::collections::BTreeMap;
+
+use aws_nitro_enclaves_cose::error::CoseError;
+use openssl::{
+    cipher::Cipher, cipher_ctx::CipherCtx, error::ErrorStack, memcmp, pkey::PKey, rand::rand_bytes,
+    sign::Signer, symm,
+};
+use serde_cbor::Value;
+
+use fdo_data_formats::{constants::HashType, types::CipherSuite};
+
+const COSE_ENCRYPT0_TAG: u64 = 16;
+const COSE_MAC0_TAG: u64 = 17;
+
+const HEADER_ALG: i128 = 1;
+const HEADER_IV: i128 = 5;
+
+const AES_IV_LEN: usize = 16;
+// For AES-CTR, the last four bytes of the IV are the block counter
+const AES_CTR_NONCE_LEN: usize = 12;
+// AES-CCM-64-*: 64 bit message length field, leaving 7 bytes of nonce
+const AES_CCM_NONCE_LEN: usize = 7;
+const AES_CCM_TAG_LEN: usize = 16;
+
+fn spec_error(msg: &str) -> CoseError {
+    CoseError::SpecificationError(msg.to_string())
+}
+
+fn mac_type(cipher_suite: CipherSuite) -> Result<HashType, CoseError> {
+    cipher_suite
+        .mac_type()
+        .ok_or_else(|| spec_error("Cipher suite does not use split keys"))
+}
+
+fn header_map(entries: Vec<(i128, Value)>) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .map(|(k, v)| (Value::Integer(k), v))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+fn header_value(map: &Value, key: i128) -> Option<&Value> {
+    match map {
+        Value::Map(map) => map.get(&Value::Integer(key)),
+        _ => None,
+    }
+}
+
+/// Splits a (possibly tagged) COSE structure into its array elements
+fn untag(value: Value, expected_tag: u64) -> Result<Vec<Value>, CoseError> {
+    match value {
+        Value::Tag(tag, inner) if tag == expected_tag => match *inner {
+            Value::Array(elements) => Ok(elements),
+            _ => Err(spec_error("Tagged value is not an array")),
+        },
+        Value::Tag(tag, _) => Err(CoseError::TagError(Some(tag))),
+        Value::Array(elements) => Ok(elements),
+        _ => Err(spec_error("Value is not an array")),
+    }
+}
+
+fn compute_mac(
+    mac_type: HashType,
+    svk: &[u8],
+    protected: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>, CoseError> {
+    let mac_structure = Value::Array(vec![
+        Value::Text("MAC0".to_string()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(vec![]),
+        Value::Bytes(payload.to_vec()),
+    ]);
+    let to_mac = serde_cbor::to_vec(&mac_structure).map_err(CoseError::SerializationError)?;
+
+    let key = PKey::hmac(svk).map_err(CoseError::EncryptionError)?;
+    let mut signer = Signer::new(mac_type.get_md(), &key).map_err(CoseError::EncryptionError)?;
+    signer.update(&to_mac).map_err(CoseError::EncryptionError)?;
+    signer.sign_to_vec().map_err(CoseError::EncryptionError)
+}
+
+fn generate_iv(cipher_suite: CipherSuite) -> Result<Vec<u8>, CoseError> {
+    let mut iv = vec![0; AES_IV_LEN];
+    let random_len = match cipher_suite {
+        CipherSuite::A128Ctr | CipherSuite::A256Ctr => AES_CTR_NONCE_LEN,
+        _ => AES_IV_LEN,
+    };
+    rand_bytes(&mut iv[..random_len]).map_err(CoseError::EncryptionError)?;
+    Ok(iv)
+}
+
+pub(crate) fn encrypt_etm(
+    cipher_suite: CipherSuite,
+    sek: &[u8],
+    svk: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CoseError> {
+    let mac_type = mac_type(cipher_suite)?;
+
+    let iv = generate_iv(cipher_suite)?;
+    let ciphertext = symm::encrypt(cipher_suite.openssl_cipher(), sek, Some(&iv), plaintext)
+        .map_err(CoseError::EncryptionError)?;
+
+    let inner_protected = header_map(vec![(HEADER_ALG, Value::Integer(cipher_suite as i128))]);
+    let inner = Value::Tag(
+        COSE_ENCRYPT0_TAG,
+        Box::new(Value::Array(vec![
+            Value::Bytes(
+                serde_cbor::to_vec(&inner_protected).map_err(CoseError::SerializationError)?,
+            ),
+            header_map(vec![(HEADER_IV, Value::Bytes(iv))]),
+            Value::Bytes(ciphertext),
+        ])),
+    );
+    let inner = serde_cbor::to_vec(&inner).map_err(CoseError::SerializationError)?;
+
+    let outer_protected = serde_cbor::to_vec(&header_map(vec![(
+        HEADER_ALG,
+        Value::Integer(mac_type as i128),
+    )]))
+    .map_err(CoseError::SerializationError)?;
+    let mac = compute_mac(mac_type, svk, &outer_protected, &inner)?;
+
+    let outer = Value::Tag(
+        COSE_MAC0_TAG,
+        Box::new(Value::Array(vec![
+            Value::Bytes(outer_protected),
+            header_map(vec![]),
+            Value::Bytes(inner),
+            Value::Bytes(mac),
+        ])),
+    );
+    serde_cbor::to_vec(&outer).map_err(CoseError::SerializationError)
+}
+
+pub(crate) fn decrypt_etm(
+    cipher_suite: CipherSuite,
+    sek: &[u8],
+    svk: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, CoseError> {
+    let mac_type = mac_type(cipher_suite)?;
+
+    let outer: Value = serde_cbor::from_slice(ciphertext).map_err(CoseError::SerializationError)?;
+    let (outer_protected, inner, mac) = match &untag(outer, COSE_MAC0_TAG)?[..] {
+        [Value::Bytes(protected), Value::Map(_), Value::Bytes(payload), Value::Bytes(mac)] => {
+            (protected.clone(), payload.clone(), mac.clone())
+        }
+        _ => return Err(spec_error("Invalid COSE_Mac0 structure")),
+    };
+
+    let outer_headers: Value =
+        serde_cbor::from_slice(&outer_protected).map_err(CoseError::SerializationError)?;
+    if header_value(&outer_headers, HEADER_ALG) != Some(&Value::Integer(mac_type as i128)) {
+        return Err(spec_error("Unexpected MAC algorithm"));
+    }
+    let expected_mac = compute_mac(mac_type, svk, &outer_protected, &inner)?;
+    if mac.len() != expected_mac.len() || !memcmp::eq(&mac, &expected_mac) {
+        return Err(CoseError::UnverifiedSignature);
+    }
+
+    let inner: Value = serde_cbor::from_slice(&inner).map_err(CoseError::SerializationError)?;
+    let (inner_protected, iv, ciphertext) = match &untag(inner, COSE_ENCRYPT0_TAG)?[..] {
+        [Value::Bytes(protected), unprotected, Value::Bytes(ciphertext)] => {
+            match header_value(unprotected, HEADER_IV) {
+                Some(Value::Bytes(iv)) => (protected.clone(), iv.clone(), ciphertext.clone()),
+                _ => return Err(spec_error("Missing IV")),
+            }
+        }
+        _ => return Err(spec_error("Invalid COSE_Encrypt0 structure")),
+    };
+
+    let inner_headers: Value =
+        serde_cbor::from_slice(&inner_protected).map_err(CoseError::SerializationError)?;
+    if header_value(&inner_headers, HEADER_ALG) != Some(&Value::Integer(cipher_suite as i128)) {
+        return Err(spec_error("Unexpected encryption algorithm"));
+    }
+    if iv.len() != AES_IV_LEN {
+        return Err(spec_error("Invalid IV length"));
+    }
+
+    symm::decrypt(cipher_suite.openssl_cipher(), sek, Some(&iv), &ciphertext)
+        .map_err(CoseError::EncryptionError)
+}
+
+// The symm::encrypt_aead helpers set the CCM tag length after the key, which OpenSSL 3 rejects
+fn ccm_crypt(
+    cipher_suite: CipherSuite,
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    data: &[u8],
+    tag: Option<&[u8]>,
+) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let cipher = match cipher_suite {
+        CipherSuite::A128Ccm => Cipher::aes_128_ccm(),
+        _ => Cipher::aes_256_ccm(),
+    };
+    let mut ctx = CipherCtx::new()?;
+    let mut out = Vec::with_capacity(data.len() + AES_CCM_TAG_LEN);
+
+    match tag {
+        None => {
+            ctx.encrypt_init(Some(cipher), None, None)?;
+            ctx.set_iv_length(nonce.len())?;
+            ctx.set_tag_length(AES_CCM_TAG_LEN)?;
+            ctx.encrypt_init(None, Some(key), Some(nonce))?;
+        }
+        Some(tag) => {
+            ctx.decrypt_init(Some(cipher), None, None)?;
+            ctx.set_iv_length(nonce.len())?;
+            ctx.set_tag(tag)?;
+            ctx.decrypt_init(None, Some(key), Some(nonce))?;
+        }
+    }
+    ctx.set_data_len(data.len())?;
+    ctx.cipher_update(aad, None)?;
+    ctx.cipher_update_vec(data, &mut out)?;
+
+    if tag.is_some() {
+        // For CCM, the tag is verified by the update call
+        return Ok((out, Vec::new()));
+    }
+    ctx.cipher_final_vec(&mut out)?;
+    let mut tag = vec![0; AES_CCM_TAG_LEN];
+    ctx.tag(&mut tag)?;
+    Ok((out, tag))
+}
+
+fn enc_structure(protected: &[u8]) -> Result<Vec<u8>, CoseError> {
+    let enc_structure = Value::Array(vec![
+        Value::Text("Encrypt0".to_string()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(vec![]),
+    ]);
+    serde_cbor::to_vec(&enc_structure).map_err(CoseError::SerializationError)
+}
+
+pub(crate) fn encrypt_ccm(
+    cipher_suite: CipherSuite,
+    key: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CoseError> {
+    let mut nonce = vec![0; AES_CCM_NONCE_LEN];
+    rand_bytes(&mut nonce).map_err(CoseError::EncryptionError)?;
+
+    let protected = serde_cbor::to_vec(&header_map(vec![(
+        HEADER_ALG,
+        Value::Integer(cipher_suite as i128),
+    )]))
+    .map_err(CoseError::SerializationError)?;
+    let aad = enc_structure(&protected)?;
+
+    let (mut ciphertext, tag) = ccm_crypt(cipher_suite, key, &nonce, &aad, plaintext, None)
+        .map_err(CoseError::EncryptionError)?;
+    ciphertext.extend_from_slice(&tag);
+
+    let encrypted = Value::Tag(
+        COSE_ENCRYPT0_TAG,
+        Box::new(Value::Array(vec![
+            Value::Bytes(protected),
+            header_map(vec![(HEADER_IV, Value::Bytes(nonce))]),
+            Value::Bytes(ciphertext),
+        ])),
+    );
+    serde_cbor::to_vec(&encrypted).map_err(CoseError::SerializationError)
+}
+
+pub(crate) fn decrypt_ccm(
+    cipher_suite: CipherSuite,
+    key: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, CoseError> {
+    let encrypted: Value =
+        serde_cbor::from_slice(ciphertext).map_err(CoseError::SerializationError)?;
+    let (protected, nonce, ciphertext) = match &untag(encrypted, COSE_ENCRYPT0_TAG)?[..] {
+        [Value::Bytes(protected), unprotected, Value::Bytes(ciphertext)] => {
+            match header_value(unprotected, HEADER_IV) {
+                Some(Value::Bytes(nonce)) => (protected.clone(), nonce.clone(), ciphertext.clone()),
+                _ => return Err(spec_error("Missing IV")),
+            }
+        }
+        _ => return Err(spec_error("Invalid COSE_Encrypt0 structure")),
+    };
+
+    let headers: Value =
+        serde_cbor::from_slice(&protected).map_err(CoseError::SerializationError)?;
+    if header_value(&headers, HEADER_ALG) != Some(&Value::Integer(cipher_suite as i128)) {
+        return Err(spec_error("Unexpected encryption algorithm"));
+    }
+    if nonce.len() != AES_CCM_NONCE_LEN {
+        return Err(spec_error("Invalid IV length"));
+    }
+    if ciphertext.len() < AES_CCM_TAG_LEN {
+        return Err(spec_error("Ciphertext too short"));
+    }
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - AES_CCM_TAG_LEN);
+
+    let aad = enc_structure(&protected)?;
+    ccm_crypt(cipher_suite, key, &nonce, &aad, ciphertext, Some(tag))
+        .map(|(plaintext, _)| plaintext)
+        .map_err(CoseError::EncryptionError)
+}
+
+#[cfg(test)]
+mod tests {
+    use fdo_data_formats::types::CipherSuite;
+    use serde_cbor::Value;
+
+    const PLAINTEXT: &[u8] = b"some protocol message";
+
+    fn keys(cipher_suite: CipherSuite) -> (Vec<u8>, Vec<u8>) {
+        match cipher_suite {
+            CipherSuite::A128Cbc | CipherSuite::A128Ctr => (vec![1; 16], vec![2; 32]),
+            _ => (vec![1; 32], vec![2; 48]),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for cipher_suite in [
+            CipherSuite::A128Cbc,
+            CipherSuite::A128Ctr,
+            CipherSuite::A256Cbc,
+            CipherSuite::A256Ctr,
+        ] {
+            let (sek, svk) = keys(cipher_suite);
+            let encrypted = super::encrypt_etm(cipher_suite, &sek, &svk, PLAINTEXT).unwrap();
+            let decrypted = super::decrypt_etm(cipher_suite, &sek, &svk, &encrypted).unwrap();
+            assert_eq!(decrypted, PLAINTEXT);
+        }
+    }
+
+    #[test]
+    fn test_tagged() {
+        let cipher_suite = CipherSuite::A256Cbc;
+        let (sek, svk) = keys(cipher_suite);
+        let encrypted = super::encrypt_etm(cipher_suite, &sek, &svk, PLAINTEXT).unwrap();
+
+        let outer: Value = serde_cbor::from_slice(&encrypted).unwrap();
+        let inner = match outer {
+            Value::Tag(super::COSE_MAC0_TAG, outer) => match *outer {
+                Value::Array(elements) => match &elements[2] {
+                    Value::Bytes(inner) => inner.clone(),
+                    other => panic!("Unexpected COSE_Mac0 payload: {:?}", other),
+                },
+                other => panic!("Unexpected COSE_Mac0 contents: {:?}", other),
+            },
+            other => panic!("Unexpected COSE_Mac0: {:?}", other),
+        };
+        let inner: Value = serde_cbor::from_slice(&inner).unwrap();
+        assert!(matches!(inner, Value::Tag(super::COSE_ENCRYPT0_TAG, _)));
+
+        let encrypted = super::encrypt_ccm(CipherSuite::A128Ccm, &[1; 16], PLAINTEXT).unwrap();
+        let encrypted: Value = serde_cbor::from_slice(&encrypted).unwrap();
+        assert!(matches!(encrypted, Value::Tag(super::COSE_ENCRYPT0_TAG, _)));
+    }
+
+    #[test]
+    fn test_tampered() {
+        let cipher_suite = CipherSuite::A128Ctr;
+        let (sek, svk) = keys(cipher_suite);
+        let mut encrypted = super::encrypt_etm(cipher_suite, &sek, &svk, PLAINTEXT).unwrap();
+        let pos = encrypted.len() - 40;
+        encrypted[pos] ^= 1;
+        assert!(super::decrypt_etm(cipher_suite, &sek, &svk, &encrypted).is_err());
+
+        let encrypted = super::encrypt_etm(cipher_suite, &sek, &svk, PLAINTEXT).unwrap();
+        assert!(super::decrypt_etm(cipher_suite, &sek, &[3; 32], &encrypted).is_err());
+        assert!(super::decrypt_etm(CipherSuite::A128Cbc, &sek, &svk, &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_ccm_roundtrip() {
+        for (cipher_suite, key) in [
+            (CipherSuite::A128Ccm, vec![1; 16]),
+            (CipherSuite::A256Ccm, vec![1; 32]),
+        ] {
+            let encrypted = super::encrypt_ccm(cipher_suite, &key, PLAINTEXT).unwrap();
+            let decrypted = super::decrypt_ccm(cipher_suite, &key, &encrypted).unwrap();
+            assert_eq!(decrypted, PLAINTEXT);
+
+            let encrypted_empty = super::encrypt_ccm(cipher_suite, &key, &[]).unwrap();
+            let decrypted_empty = super::decrypt_ccm(cipher_suite, &key, &encrypted_empty).unwrap();
+            assert!(decrypted_empty.is_empty());
+
+            let mut tampered = encrypted.clone();
+            let pos = tampered.len() - 1;
+            tampered[pos] ^= 1;
+            assert!(super::decrypt_ccm(cipher_suite, &key, &tampered).is_err());
+        }
+    }
+}
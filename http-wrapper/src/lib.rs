@@ -4,6 +4,11 @@ use aws_nitro_enclaves_cose::error::CoseError;
 use aws_nitro_enclaves_cose::{CipherConfiguration, CoseEncrypt0};
 use fdo_data_formats::types::{CipherSuite, DerivedKeys};
 
+mod cose;
+
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod coap;
+
 #[cfg(feature = "server")]
 pub mod server;
 
@@ -47,36 +52,89 @@ impl EncryptionKeys {
         }
     }
 
-    #[allow(clippy::panic)]
     fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CoseError> {
-        if self.cipher_suite.is_none() {
-            Ok(plaintext.to_vec())
-        } else {
-            let k = match &self.keys {
-                Some(DerivedKeys::Combined { sevk: k }) => k,
-                _ => panic!(),
-            };
-            CoseEncrypt0::new(plaintext, CipherConfiguration::Gcm, &k[..])
-                .map(|c| c.as_bytes(true))?
+        match (&self.cipher_suite, &self.keys) {
+            (None, _) => Ok(plaintext.to_vec()),
+            (
+                Some(cipher_suite @ (CipherSuite::A128Ccm | CipherSuite::A256Ccm)),
+                Some(DerivedKeys::Combined { sevk: k }),
+            ) => cose::encrypt_ccm(*cipher_suite, k, plaintext),
+            (
+                Some(CipherSuite::A128Gcm | CipherSuite::A256Gcm),
+                Some(DerivedKeys::Combined { sevk: k }),
+            ) => CoseEncrypt0::new(plaintext, CipherConfiguration::Gcm, &k[..])
+                .map(|c| c.as_bytes(true))?,
+            (Some(cipher_suite), Some(DerivedKeys::Split { sek, svk })) => {
+                cose::encrypt_etm(*cipher_suite, sek, svk, plaintext)
+            }
+            (Some(cipher_suite), _) => Err(Self::missing_keys(*cipher_suite)),
         }
     }
 
-    #[allow(clippy::panic)]
     fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CoseError> {
-        if self.cipher_suite.is_none() {
-            Ok(ciphertext.to_vec())
-        } else {
-            let k = match &self.keys {
-                Some(DerivedKeys::Combined { sevk: k }) => k,
-                _ => panic!(),
-            };
-            match CoseEncrypt0::from_bytes(ciphertext) {
-                Ok(v) => match v.decrypt(k) {
-                    Ok((_, _, payload)) => Ok(payload),
-                    Err(e) => Err(e),
-                },
-                Err(e) => Err(e),
+        match (&self.cipher_suite, &self.keys) {
+            (None, _) => Ok(ciphertext.to_vec()),
+            (
+                Some(cipher_suite @ (CipherSuite::A128Ccm | CipherSuite::A256Ccm)),
+                Some(DerivedKeys::Combined { sevk: k }),
+            ) => cose::decrypt_ccm(*cipher_suite, k, ciphertext),
+            (
+                Some(CipherSuite::A128Gcm | CipherSuite::A256Gcm),
+                Some(DerivedKeys::Combined { sevk: k }),
+            ) => {
+                let (_, _, payload) = CoseEncrypt0::from_bytes(ciphertext)?.decrypt(k)?;
+                Ok(payload)
+            }
+            (Some(cipher_suite), Some(DerivedKeys::Split { sek, svk })) => {
+                cose::decrypt_etm(*cipher_suite, sek, svk, ciphertext)
             }
+            (Some(cipher_suite), _) => Err(Self::missing_keys(*cipher_suite)),
         }
     }
+
+    fn missing_keys(cipher_suite: CipherSuite) -> CoseError {
+        CoseError::SpecificationError(format!(
+            "No matching encryption keys for cipher suite {:?}",
+            cipher_suite
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fdo_data_formats::types::{CipherSuite, DerivedKeys};
+
+    use super::EncryptionKeys;
+
+    #[test]
+    fn test_missing_keys() {
+        let keys = EncryptionKeys {
+            cipher_suite: Some(CipherSuite::A256Gcm),
+            keys: None,
+        };
+        assert!(keys.encrypt(b"message").is_err());
+        assert!(keys.decrypt(b"message").is_err());
+
+        let keys = EncryptionKeys::from_derived(
+            CipherSuite::A256Gcm,
+            DerivedKeys::Split {
+                sek: vec![1; 32],
+                svk: vec![2; 48],
+            },
+        );
+        assert!(keys.encrypt(b"message").is_err());
+
+        let keys = EncryptionKeys::from_derived(
+            CipherSuite::A256Ctr,
+            DerivedKeys::Combined { sevk: vec![1; 32] },
+        );
+        assert!(keys.encrypt(b"message").is_err());
+    }
+
+    #[test]
+    fn test_unencrypted() {
+        let keys = EncryptionKeys::unencrypted();
+        assert_eq!(keys.encrypt(b"message").unwrap(), b"message");
+        assert_eq!(keys.decrypt(b"message").unwrap(), b"message");
+    }
 }
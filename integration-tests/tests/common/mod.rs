@@ -16,13 +16,19 @@ use openssl::{
     ec::{EcGroup, EcKey},
     hash::MessageDigest,
     nid::Nid,
-    pkey::PKey,
-    x509::{X509Builder, X509NameBuilder},
+    pkey::{PKey, PKeyRef, Private},
+    rsa::Rsa,
+    x509::{
+        extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName},
+        X509Builder, X509NameBuilder, X509Ref, X509,
+    },
 };
 
 use fdo_util::servers::format_conf_env;
 
 const PORT_BASE: u16 = 5080;
+// CoAP listeners bind this far above the HTTP port, as their TCP side would clash
+const COAP_PORT_OFFSET: u16 = 1000;
 
 lazy_static::lazy_static! {
     static ref CURRENT_PORT: std::sync::Mutex<u16> = std::sync::Mutex::new(PORT_BASE);
@@ -38,6 +44,8 @@ const KEY_NAMES: &[&str] = &[
     "diun",
     "reseller",
 ];
+// RSA Owner keys, as required by the ASYMKEX key exchange suites
+const RSA_KEY_NAMES: &[(&str, u32)] = &[("owner_rsa2048", 2048), ("owner_rsa3072", 3072)];
 
 const MAX_WAIT_FOR_OWNER_TOOL: Duration = Duration::from_millis(200);
 const MAX_WAIT_FOR_READY: Duration = Duration::from_secs(2);
@@ -167,9 +175,18 @@ impl TestBinaryNumber {
         self.server_port
     }
 
-    fn server_url(&self) -> Option<String> {
+    pub fn coap_port(&self) -> Option<u16> {
+        self.server_port.map(|port| port + COAP_PORT_OFFSET)
+    }
+
+    fn server_url(&self, tls: bool) -> Option<String> {
         if self.binary.is_server() {
-            Some(format!("http://localhost:{}", self.server_port().unwrap()))
+            let scheme = if tls { "https" } else { "http" };
+            Some(format!(
+                "{}://localhost:{}",
+                scheme,
+                self.server_port().unwrap()
+            ))
         } else {
             None
         }
@@ -204,6 +221,9 @@ pub struct TestContext {
 
     test_binary_number_generator: TestBinaryNumberGenerator,
 
+    // Whether the servers are configured to serve HTTPS
+    tls: bool,
+
     // This is here just to make sure the destructor is called.
     // This is also at the end, to make sure that everything else gets to use
     //  the testpath before it gets dropped.
@@ -213,6 +233,15 @@ pub struct TestContext {
 
 impl TestContext {
     pub fn new() -> Result<Self> {
+        Self::new_impl(false)
+    }
+
+    /// Creates a test context in which all servers serve HTTPS
+    pub fn new_with_tls() -> Result<Self> {
+        Self::new_impl(true)
+    }
+
+    fn new_impl(tls: bool) -> Result<Self> {
         let testdir = tempfile::Builder::new()
             .prefix("fido-device-onboard-integration-test-")
             .tempdir_in(&TARGET_TMPDIR)
@@ -235,6 +264,7 @@ impl TestContext {
             test_servers: Vec::new(),
             test_servers_ready: true,
             test_binary_number_generator: TestBinaryNumberGenerator::new(),
+            tls,
         };
 
         new_context.create_keys().context("Error creating keys")?;
@@ -264,13 +294,23 @@ impl TestContext {
         let key_group =
             EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).context("Error creating EcGroup")?;
 
+        // First create the new private keys
+        let mut private_keys = Vec::new();
         for key_name in KEY_NAMES {
-            // First create a new private key
             let private_key =
                 EcKey::generate(&key_group).context("Error generating private key")?;
             let private_key =
                 PKey::from_ec_key(private_key).context("Error converting private key to PKey")?;
+            private_keys.push((*key_name, private_key));
+        }
+        for (key_name, bits) in RSA_KEY_NAMES {
+            let private_key = Rsa::generate(*bits).context("Error generating private key")?;
+            let private_key =
+                PKey::from_rsa(private_key).context("Error converting private key to PKey")?;
+            private_keys.push((*key_name, private_key));
+        }
 
+        for (key_name, private_key) in private_keys {
             // Now create a certificate over (and with) the key
             let mut subject_name =
                 X509NameBuilder::new().context("Error creating X509NameBuilder")?;
@@ -334,9 +374,83 @@ impl TestContext {
                 .context("Error writing certificate")?;
         }
 
+        self.create_tls_keys().context("Error creating TLS keys")
+    }
+
+    fn create_tls_keys(&self) -> Result<()> {
+        let keys_path = self.keys_path();
+
+        let (ca_key, ca_cert) =
+            create_tls_certificate("tls_ca", None).context("Error creating TLS CA")?;
+        let (server_key, server_cert) =
+            create_tls_certificate("localhost", Some((&ca_key, &ca_cert)))
+                .context("Error creating TLS server certificate")?;
+        let (client_key, client_cert) =
+            create_tls_certificate("tls-client", Some((&ca_key, &ca_cert)))
+                .context("Error creating TLS client certificate")?;
+
+        // The servers present the CA certificate too, so that clients can pin it
+        let ca_cert_pem = ca_cert
+            .to_pem()
+            .context("Error converting certificate to PEM")?;
+        for (name, key, cert, chain) in [
+            ("tls_ca", ca_key, ca_cert, None),
+            ("tls_server", server_key, server_cert, Some(&ca_cert_pem)),
+            ("tls_client", client_key, client_cert, None),
+        ] {
+            fs::write(
+                keys_path.join(format!("{}_key.pem", name)),
+                key.private_key_to_pem_pkcs8()
+                    .context("Error converting private key to PEM")?,
+            )
+            .context("Error writing private key")?;
+            let mut cert = cert
+                .to_pem()
+                .context("Error converting certificate to PEM")?;
+            if let Some(chain) = chain {
+                cert.extend_from_slice(chain);
+            }
+            fs::write(keys_path.join(format!("{}_cert.pem", name)), cert)
+                .context("Error writing certificate")?;
+        }
+
         Ok(())
     }
 
+    pub fn tls_enabled(&self) -> bool {
+        self.tls
+    }
+
+    pub fn server_url(&self, server: &TestBinaryNumber) -> Option<String> {
+        server.server_url(self.tls)
+    }
+
+    /// Returns an HTTP client that trusts the test TLS CA, and authenticates with the test
+    /// TLS client certificate
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        self.http_client_builder()?
+            .identity(self.tls_client_identity()?)
+            .build()
+            .context("Error building HTTP client")
+    }
+
+    /// Returns an HTTP client builder that trusts the test TLS CA
+    pub fn http_client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let ca_cert = fs::read(self.keys_path().join("tls_ca_cert.pem"))
+            .context("Error reading TLS CA certificate")?;
+        let ca_cert =
+            reqwest::Certificate::from_pem(&ca_cert).context("Error parsing TLS CA certificate")?;
+        Ok(reqwest::Client::builder().add_root_certificate(ca_cert))
+    }
+
+    fn tls_client_identity(&self) -> Result<reqwest::Identity> {
+        let cert = fs::read(self.keys_path().join("tls_client_cert.pem"))
+            .context("Error reading TLS client certificate")?;
+        let key = fs::read(self.keys_path().join("tls_client_key.pem"))
+            .context("Error reading TLS client key")?;
+        reqwest::Identity::from_pkcs8_pem(&cert, &key).context("Error parsing TLS client identity")
+    }
+
     pub fn testpath(&self) -> &Path {
         &self.testpath
     }
@@ -383,6 +497,9 @@ impl TestContext {
             )
             .stdout(File::create(server_path.join("stdout")).context("Error creating stdout")?)
             .stderr(File::create(server_path.join("stderr")).context("Error creating stdout")?);
+        if self.tls {
+            cmd.env("SSL_CERT_FILE", self.keys_path().join("tls_ca_cert.pem"));
+        }
 
         // Call Command configurator
         cmd_configurator(&mut cmd).context("Error configuring server command")?;
@@ -408,13 +525,18 @@ impl TestContext {
     }
 
     pub async fn wait_until_servers_ready(&mut self) -> Result<()> {
+        let client = self.http_client()?;
         for test_server in &mut self.test_servers {
-            test_server.wait_until_ready().await.with_context(|| {
-                format!(
-                    "Error waiting for server {} to start",
-                    test_server.server_number.name()
-                )
-            })?;
+            let url = test_server.server_number.server_url(self.tls).unwrap();
+            test_server
+                .wait_until_ready(&client, &url)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Error waiting for server {} to start",
+                        test_server.server_number.name()
+                    )
+                })?;
         }
         self.test_servers_ready = true;
         Ok(())
@@ -510,10 +632,13 @@ impl TestContext {
             )
             .stdout(File::create(&stdout_path).context("Error creating stdout")?)
             .stderr(File::create(&stderr_path).context("Error creating stderr")?);
+        if self.tls {
+            cmd.env("SSL_CERT_FILE", self.keys_path().join("tls_ca_cert.pem"));
+        }
 
         if let Some(server) = server {
             if let Some(url_variable) = binary.url_environment_variable() {
-                if let Some(server_url) = server.server_url() {
+                if let Some(server_url) = server.server_url(self.tls) {
                     L.l(format!(
                         "Setting client env var {} to server url {}",
                         url_variable, server_url
@@ -573,6 +698,7 @@ impl TestContext {
 
         // TODO: Insert defaults
         template_context.insert("keys_path", &self.keys_path());
+        template_context.insert("tls", &self.tls);
 
         context_configurator(&mut template_context)
             .context("Error running context configurator")?;
@@ -783,6 +909,11 @@ impl<'a> TestServerConfigurator<'a> {
                 );
                 cfg.insert("test_dir", &self.test_context.testpath());
                 cfg.insert("owner_port", &self.server_number.server_port().unwrap());
+                cfg.insert(
+                    "coap_bind",
+                    &format!("127.0.0.1:{}", self.server_number.coap_port().unwrap()),
+                );
+                cfg.insert("coap_port", &self.server_number.coap_port().unwrap());
                 cfg.insert(
                     "config_dir",
                     &self.test_context.runner_path(&self.server_number),
@@ -816,17 +947,10 @@ impl TestServer {
         })
     }
 
-    async fn wait_until_ready(&mut self) -> Result<()> {
+    async fn wait_until_ready(&mut self, client: &reqwest::Client, url: &str) -> Result<()> {
         let start = Instant::now();
-        let client = reqwest::Client::new();
         loop {
-            let res = client
-                .post(&format!(
-                    "http://localhost:{}/ping",
-                    self.server_number.server_port().unwrap()
-                ))
-                .send()
-                .await;
+            let res = client.post(format!("{}/ping", url)).send().await;
             if res.is_ok() {
                 L.l("Server is ready");
                 return Ok(());
@@ -896,3 +1020,108 @@ impl Drop for TestServer {
         }
     }
 }
+
+/// Creates a TLS key and certificate, self-signed if no issuer is provided
+fn create_tls_certificate(
+    common_name: &str,
+    issuer: Option<(&PKeyRef<Private>, &X509Ref)>,
+) -> Result<(PKey<Private>, X509)> {
+    let key_group =
+        EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).context("Error creating EcGroup")?;
+    let private_key = EcKey::generate(&key_group).context("Error generating private key")?;
+    let private_key =
+        PKey::from_ec_key(private_key).context("Error converting private key to PKey")?;
+
+    let mut subject_name = X509NameBuilder::new().context("Error creating X509NameBuilder")?;
+    subject_name
+        .append_entry_by_text("CN", common_name)
+        .context("Error adding CN")?;
+    let subject_name = subject_name.build();
+
+    let serial_number = BigNum::from_u32(42).context("Error creating serial number")?;
+    let serial_number = Asn1Integer::from_bn(&serial_number)
+        .context("Error converting serial number to ASN1Integer")?;
+
+    let mut cert_builder = X509Builder::new().context("Error creating X509Builder")?;
+    cert_builder
+        .set_version(2)
+        .context("Error setting version")?;
+    cert_builder
+        .set_not_after(
+            Asn1Time::days_from_now(1)
+                .context("Error creating Asn1Time")?
+                .as_ref(),
+        )
+        .context("Error setting not after")?;
+    cert_builder
+        .set_not_before(
+            Asn1Time::days_from_now(0)
+                .context("Error creating Asn1Time")?
+                .as_ref(),
+        )
+        .context("Error setting not before")?;
+    cert_builder
+        .set_subject_name(&subject_name)
+        .context("Error setting subject name")?;
+    cert_builder
+        .set_issuer_name(match issuer {
+            Some((_, issuer_cert)) => issuer_cert.subject_name(),
+            None => &subject_name,
+        })
+        .context("Error setting issuer name")?;
+    cert_builder
+        .set_pubkey(&private_key)
+        .context("Error setting public key")?;
+    cert_builder
+        .set_serial_number(&serial_number)
+        .context("Error setting serial number")?;
+
+    if issuer.is_none() {
+        cert_builder
+            .append_extension(
+                BasicConstraints::new()
+                    .critical()
+                    .ca()
+                    .build()
+                    .context("Error building basic constraints")?,
+            )
+            .context("Error adding basic constraints")?;
+        cert_builder
+            .append_extension(
+                KeyUsage::new()
+                    .critical()
+                    .key_cert_sign()
+                    .build()
+                    .context("Error building key usage")?,
+            )
+            .context("Error adding key usage")?;
+    } else {
+        let san = SubjectAlternativeName::new()
+            .dns(common_name)
+            .ip("127.0.0.1")
+            .build(&cert_builder.x509v3_context(issuer.map(|(_, cert)| cert), None))
+            .context("Error building subject alternative name")?;
+        cert_builder
+            .append_extension(san)
+            .context("Error adding subject alternative name")?;
+        cert_builder
+            .append_extension(
+                ExtendedKeyUsage::new()
+                    .server_auth()
+                    .client_auth()
+                    .build()
+                    .context("Error building extended key usage")?,
+            )
+            .context("Error adding extended key usage")?;
+    }
+
+    let signing_key = match issuer {
+        Some((issuer_key, _)) => issuer_key,
+        None => &*private_key,
+    };
+    cert_builder
+        .sign(signing_key, MessageDigest::sha256())
+        .context("Error signing certificate")?;
+
+    Ok((private_key, cert_builder.build()))
+}
@@ -0,0 +1,388 @@
+mod common;
+use std::convert::TryFrom;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    path::Path,
+};
+
+use common::{Binary, LogSide, TestContext};
+
+use anyhow::{bail, Context, Result};
+use openssl::{
+    pkey::{PKey, Private},
+    x509::X509,
+};
+
+use fdo_data_formats::{
+    constants::HashType,
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::PublicKey,
+    types::{Guid, HMac, RendezvousInfo},
+    ProtocolVersion, Serializable,
+};
+
+const L: LogSide = LogSide::Test;
+
+const MANAGEMENT_TOKEN: &str = "Bearer TestManagementToken";
+
+#[derive(Debug, serde::Deserialize)]
+struct VoucherInfo {
+    guid: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ErrorReply {
+    error_code: String,
+    error_details: serde_json::Value,
+}
+
+fn load_private_key(keys_path: &Path, name: &str) -> Result<PKey<Private>> {
+    let contents = fs::read(keys_path.join(format!("{}_key.der", name)))
+        .with_context(|| format!("Error reading {} key", name))?;
+    Ok(PKey::private_key_from_der(&contents)?)
+}
+
+fn load_public_key(keys_path: &Path, name: &str) -> Result<PublicKey> {
+    let contents = fs::read(keys_path.join(format!("{}_cert.pem", name)))
+        .with_context(|| format!("Error reading {} certificate", name))?;
+    Ok(PublicKey::try_from(X509::from_pem(&contents)?)?)
+}
+
+/// Creates a new voucher, and extends it from the manufacturer to each of the owners in turn
+fn create_voucher(ctx: &TestContext, owners: &[&str]) -> Result<OwnershipVoucher> {
+    let keys_path = ctx.keys_path();
+
+    let header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        Guid::new()?,
+        RendezvousInfo::new(Vec::new())?,
+        "test".to_string(),
+        load_public_key(&keys_path, "manufacturer")?,
+        None,
+    )?;
+    let header_hmac = HMac::from_digest(HashType::HmacSha256, vec![0; 32])?;
+    let mut ov = OwnershipVoucher::new(header, header_hmac, None)?;
+
+    let mut source = "manufacturer";
+    for target in owners {
+        let source_key = load_private_key(&keys_path, source)?;
+        ov.extend(&source_key, None, &load_public_key(&keys_path, target)?)
+            .with_context(|| format!("Error extending voucher ({} -> {})", source, target))?;
+        source = target;
+    }
+
+    Ok(ov)
+}
+
+async fn expect_error(res: reqwest::Response, error_code: &str) -> Result<serde_json::Value> {
+    let status = res.status();
+    let reply: ErrorReply = res.json().await.context("Error parsing error reply")?;
+    L.l(format!("Error reply ({}): {:?}", status, reply));
+    if reply.error_code != error_code {
+        bail!(
+            "Unexpected error code {}, expected {}",
+            reply.error_code,
+            error_code
+        );
+    }
+    Ok(reply.error_details)
+}
+
+#[tokio::test]
+async fn test_ov_management() -> Result<()> {
+    let mut ctx = TestContext::new().context("Error building test context")?;
+
+    let owner_onboarding_server = ctx
+        .start_test_server(
+            Binary::OwnerOnboardingServer,
+            |cfg| {
+                cfg.prepare_config_file(None, |cfg| {
+                    cfg.insert("serviceinfo_api_server_port", &8083);
+                    Ok(())
+                })
+            },
+            |_| Ok(()),
+        )
+        .context("Error creating owner server")?;
+    ctx.wait_until_servers_ready()
+        .await
+        .context("Error waiting for servers to start")?;
+
+    let owned_ov = create_voucher(&ctx, &["reseller", "owner"])?;
+    let unowned_ov = create_voucher(&ctx, &["reseller"])?;
+    let owned_guid = owned_ov.header().guid().to_string();
+
+    let url = format!(
+        "http://localhost:{}/management/v1/ownership_voucher",
+        owner_onboarding_server.server_port().unwrap()
+    );
+    let client = reqwest::Client::new();
+
+    L.l("Listing without authentication");
+    let res = client
+        .get(&url)
+        .send()
+        .await
+        .context("Error listing vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    L.l("Uploading an unowned voucher");
+    let mut body = owned_ov.serialize_data()?;
+    body.extend_from_slice(&unowned_ov.serialize_data()?);
+    let res = client
+        .post(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .header("Content-Type", "application/cbor")
+        .header("X-Number-Of-Vouchers", "2")
+        .body(body)
+        .send()
+        .await
+        .context("Error uploading vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    let details = expect_error(res, "unowned_voucher").await?;
+    assert_eq!(details["unowned"], serde_json::json!([1]));
+
+    L.l("Uploading with an invalid number of vouchers");
+    let res = client
+        .post(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .header("Content-Type", "application/x-pem-file")
+        .header("X-Number-Of-Vouchers", "2")
+        .body(owned_ov.to_pem()?)
+        .send()
+        .await
+        .context("Error uploading vouchers")?;
+    let details = expect_error(res, "invalid_number_of_vouchers").await?;
+    assert_eq!(details["parsed"], serde_json::json!(1));
+
+    L.l("Uploading a body over the size limit");
+    // Only the request headers are sent: the server refuses the upload based on its length,
+    // and may close the connection before a body of that size could be written
+    let mut stream =
+        TcpStream::connect(("localhost", owner_onboarding_server.server_port().unwrap()))
+            .context("Error connecting to owner server")?;
+    write!(
+        stream,
+        "POST /management/v1/ownership_voucher HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\nContent-Type: application/cbor\r\nContent-Length: {}\r\n\r\n",
+        MANAGEMENT_TOKEN,
+        16 * 1024 * 1024 + 1
+    )
+    .context("Error uploading vouchers")?;
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .context("Error reading upload response")?;
+    assert_eq!(response.trim_end(), "HTTP/1.1 413 Payload Too Large");
+
+    L.l("Uploading an incomplete voucher");
+    let mut body = owned_ov.serialize_data()?;
+    body.truncate(body.len() - 10);
+    let res = client
+        .post(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .header("Content-Type", "application/cbor")
+        .body(body)
+        .send()
+        .await
+        .context("Error uploading vouchers")?;
+    let details = expect_error(res, "incomplete_voucher").await?;
+    assert_eq!(details["parsed_correctly"], serde_json::json!(0));
+
+    L.l("Uploading an owned voucher");
+    let res = client
+        .post(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .header("Content-Type", "application/x-pem-file")
+        .header("X-Number-Of-Vouchers", "1")
+        .header("X-TO0-Wait-Seconds", "3600")
+        .body(owned_ov.to_pem()?)
+        .send()
+        .await
+        .context("Error uploading vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    let uploaded: Vec<VoucherInfo> = res.json().await?;
+    assert_eq!(uploaded.len(), 1);
+    assert_eq!(uploaded[0].guid, owned_guid);
+    assert!(ctx
+        .testpath()
+        .join("ownership_vouchers")
+        .join(&owned_guid)
+        .exists());
+
+    L.l("Listing vouchers");
+    let listed: Vec<VoucherInfo> = client
+        .get(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .send()
+        .await
+        .context("Error listing vouchers")?
+        .json()
+        .await?;
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].guid, owned_guid);
+
+    L.l("Deleting an unknown voucher");
+    let unowned_guid = unowned_ov.header().guid().to_string();
+    let res = client
+        .post(format!("{}/delete", url))
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .json(&[&owned_guid, &unowned_guid])
+        .send()
+        .await
+        .context("Error deleting vouchers")?;
+    let details = expect_error(res, "unknown_device").await?;
+    assert_eq!(details["unknown"], serde_json::json!([unowned_guid]));
+
+    L.l("Deleting the owned voucher");
+    let res = client
+        .post(format!("{}/delete", url))
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .json(&[&owned_guid])
+        .send()
+        .await
+        .context("Error deleting vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+    assert!(!ctx
+        .testpath()
+        .join("ownership_vouchers")
+        .join(&owned_guid)
+        .exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ov_management_additional_owner_keys() -> Result<()> {
+    let mut ctx = TestContext::new().context("Error building test context")?;
+
+    let owner_onboarding_server = ctx
+        .start_test_server(
+            Binary::OwnerOnboardingServer,
+            |cfg| {
+                cfg.prepare_config_file(None, |cfg| {
+                    cfg.insert("serviceinfo_api_server_port", &8083);
+                    cfg.insert("additional_owner_key", "reseller");
+                    Ok(())
+                })
+            },
+            |_| Ok(()),
+        )
+        .context("Error creating owner server")?;
+    ctx.wait_until_servers_ready()
+        .await
+        .context("Error waiting for servers to start")?;
+
+    let url = format!(
+        "http://localhost:{}/management/v1/ownership_voucher",
+        owner_onboarding_server.server_port().unwrap()
+    );
+    let client = reqwest::Client::new();
+
+    L.l("Uploading vouchers owned by either of our owner keys");
+    let mut body = create_voucher(&ctx, &["reseller", "owner"])?.serialize_data()?;
+    body.extend_from_slice(&create_voucher(&ctx, &["reseller"])?.serialize_data()?);
+    let res = client
+        .post(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .header("Content-Type", "application/cbor")
+        .header("X-Number-Of-Vouchers", "2")
+        .body(body)
+        .send()
+        .await
+        .context("Error uploading vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    let uploaded: Vec<VoucherInfo> = res.json().await?;
+    assert_eq!(uploaded.len(), 2);
+
+    L.l("Uploading a voucher still owned by the manufacturer");
+    let res = client
+        .post(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .header("Content-Type", "application/cbor")
+        .body(create_voucher(&ctx, &[])?.serialize_data()?)
+        .send()
+        .await
+        .context("Error uploading vouchers")?;
+    let details = expect_error(res, "unowned_voucher").await?;
+    assert_eq!(details["unowned"], serde_json::json!([0]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ov_management_client_certificate() -> Result<()> {
+    let mut ctx = TestContext::new_with_tls().context("Error building test context")?;
+
+    let owner_onboarding_server = ctx
+        .start_test_server(
+            Binary::OwnerOnboardingServer,
+            |cfg| {
+                cfg.prepare_config_file(None, |cfg| {
+                    cfg.insert("serviceinfo_api_server_port", &8083);
+                    cfg.insert("tls_client_ca", &true);
+                    cfg.insert("tls_client_auth_optional", &true);
+                    cfg.insert("management_client_certificate", &true);
+                    Ok(())
+                })
+            },
+            |_| Ok(()),
+        )
+        .context("Error creating owner server")?;
+    ctx.wait_until_servers_ready()
+        .await
+        .context("Error waiting for servers to start")?;
+
+    let url = format!(
+        "{}/management/v1/ownership_voucher",
+        ctx.server_url(&owner_onboarding_server).unwrap()
+    );
+    let anonymous_client = ctx
+        .http_client_builder()?
+        .build()
+        .context("Error building client")?;
+
+    L.l("Listing without client certificate");
+    let res = anonymous_client
+        .get(&url)
+        .send()
+        .await
+        .context("Error listing vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    L.l("Listing with bearer token, without client certificate");
+    let res = anonymous_client
+        .get(&url)
+        .header("Authorization", MANAGEMENT_TOKEN)
+        .send()
+        .await
+        .context("Error listing vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+    L.l("Uploading a voucher with client certificate");
+    let client = ctx.http_client()?;
+    let ov = create_voucher(&ctx, &["reseller", "owner"])?;
+    let res = client
+        .post(&url)
+        .header("Content-Type", "application/cbor")
+        .header("X-Number-Of-Vouchers", "1")
+        .body(ov.serialize_data()?)
+        .send()
+        .await
+        .context("Error uploading vouchers")?;
+    assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+    L.l("Listing with client certificate");
+    let listed: Vec<VoucherInfo> = client
+        .get(&url)
+        .send()
+        .await
+        .context("Error listing vouchers")?
+        .json()
+        .await?;
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].guid, ov.header().guid().to_string());
+
+    Ok(())
+}
@@ -0,0 +1,180 @@
+mod common;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use common::LogSide;
+
+use anyhow::{bail, Context, Result};
+use openssl::{
+    hash::MessageDigest,
+    pkey::PKey,
+    sign::{Signer, Verifier},
+};
+
+use fdo_util::servers::{configuration::PrivateKeyLocation, load_private_key};
+
+const L: LogSide = LogSide::Test;
+
+const SOFTHSM_MODULES: &[&str] = &[
+    "/usr/lib64/pkcs11/libsofthsm2.so",
+    "/usr/lib/softhsm/libsofthsm2.so",
+    "/usr/lib/x86_64-linux-gnu/softhsm/libsofthsm2.so",
+];
+const PKCS11_PROVIDERS: &[&str] = &[
+    "/usr/lib64/ossl-modules/pkcs11.so",
+    "/usr/lib/x86_64-linux-gnu/ossl-modules/pkcs11.so",
+];
+
+const TOKEN_LABEL: &str = "fdo-test";
+const KEY_LABEL: &str = "fdo-owner";
+const USER_PIN: &str = "1234";
+
+fn find_file(candidates: &[&str]) -> Option<PathBuf> {
+    candidates
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+}
+
+fn run(cmd: &mut Command) -> Result<()> {
+    // The tools should not load the PKCS#11 provider themselves
+    let status = cmd
+        .env_remove("OPENSSL_CONF")
+        .status()
+        .with_context(|| format!("Error running {:?}", cmd))?;
+    if !status.success() {
+        bail!("{:?} failed: {:?}", cmd, status);
+    }
+    Ok(())
+}
+
+fn import_object(softhsm_module: &Path, path: &Path, object_type: &str) -> Result<()> {
+    run(Command::new("pkcs11-tool")
+        .arg("--module")
+        .arg(softhsm_module)
+        .args(["--token-label", TOKEN_LABEL, "--login", "--pin", USER_PIN])
+        .arg("--write-object")
+        .arg(path)
+        .args(["--type", object_type, "--id", "01", "--label", KEY_LABEL]))
+}
+
+#[test]
+fn test_pkcs11_private_key() -> Result<()> {
+    let (softhsm_module, pkcs11_provider) =
+        match (find_file(SOFTHSM_MODULES), find_file(PKCS11_PROVIDERS)) {
+            (Some(softhsm_module), Some(pkcs11_provider)) => (softhsm_module, pkcs11_provider),
+            _ => {
+                L.l("SoftHSM or pkcs11-provider not installed, skipping");
+                return Ok(());
+            }
+        };
+
+    let testdir = tempfile::tempdir().context("Error creating test directory")?;
+    let testpath = testdir.path();
+
+    L.l("Creating SoftHSM token");
+    let token_dir = testpath.join("tokens");
+    fs::create_dir(&token_dir).context("Error creating token directory")?;
+    let softhsm_conf = testpath.join("softhsm2.conf");
+    fs::write(
+        &softhsm_conf,
+        format!(
+            "directories.tokendir = {}\nobjectstore.backend = file\n",
+            token_dir.display()
+        ),
+    )
+    .context("Error writing SoftHSM configuration")?;
+    env::set_var("SOFTHSM2_CONF", &softhsm_conf);
+    run(Command::new("softhsm2-util").args([
+        "--init-token",
+        "--free",
+        "--label",
+        TOKEN_LABEL,
+        "--pin",
+        USER_PIN,
+        "--so-pin",
+        "123456",
+    ]))?;
+
+    // The key is generated and imported with the command line tools, so that this process
+    // only initializes OpenSSL once the PKCS#11 provider is configured
+    L.l("Importing key into token");
+    let key_path = testpath.join("key.pem");
+    let pubkey_path = testpath.join("pubkey.der");
+    run(Command::new("openssl")
+        .args([
+            "genpkey",
+            "-algorithm",
+            "EC",
+            "-pkeyopt",
+            "ec_paramgen_curve:P-256",
+        ])
+        .arg("-out")
+        .arg(&key_path))?;
+    run(Command::new("openssl")
+        .arg("pkey")
+        .arg("-in")
+        .arg(&key_path)
+        .args(["-pubout", "-outform", "DER", "-out"])
+        .arg(&pubkey_path))?;
+    import_object(&softhsm_module, &key_path, "privkey")?;
+    import_object(&softhsm_module, &pubkey_path, "pubkey")?;
+
+    let openssl_conf = testpath.join("openssl.cnf");
+    fs::write(
+        &openssl_conf,
+        format!(
+            "openssl_conf = openssl_init
+
+[openssl_init]
+providers = provider_sect
+
+[provider_sect]
+default = default_sect
+pkcs11 = pkcs11_sect
+
+[default_sect]
+activate = 1
+
+[pkcs11_sect]
+module = {}
+pkcs11-module-path = {}
+activate = 1
+",
+            pkcs11_provider.display(),
+            softhsm_module.display()
+        ),
+    )
+    .context("Error writing OpenSSL configuration")?;
+    env::set_var("OPENSSL_CONF", &openssl_conf);
+
+    L.l("Loading key from token");
+    let location = PrivateKeyLocation::Pkcs11(format!(
+        "pkcs11:token={};object={};type=private?pin-value={}",
+        TOKEN_LABEL, KEY_LABEL, USER_PIN
+    ));
+    let private_key = load_private_key(&location).context("Error loading key from token")?;
+
+    let public_key =
+        PKey::public_key_from_der(&fs::read(&pubkey_path).context("Error reading public key")?)
+            .context("Error parsing public key")?;
+    assert!(private_key.public_eq(&public_key));
+
+    L.l("Signing with key on token");
+    let mut signer = Signer::new(MessageDigest::sha256(), &private_key)?;
+    let signature = signer.sign_oneshot_to_vec(b"some data")?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+    assert!(verifier.verify_oneshot(&signature, b"some data")?);
+
+    L.l("Loading a key that is not on the token");
+    let location = PrivateKeyLocation::Pkcs11(format!(
+        "pkcs11:token={};object=missing;type=private?pin-value={}",
+        TOKEN_LABEL, USER_PIN
+    ));
+    assert!(load_private_key(&location).is_err());
+
+    Ok(())
+}
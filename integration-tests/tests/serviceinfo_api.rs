@@ -0,0 +1,108 @@
+mod common;
+use common::{Binary, LogSide, TestBinaryNumber, TestContext};
+
+use anyhow::{Context, Result};
+
+use fdo_data_formats::{
+    constants::{FedoraIotServiceInfoModule, ServiceInfoModule, StandardServiceInfoModule},
+    types::Guid,
+};
+use fdo_util::servers::ServiceInfoApiReply;
+
+const L: LogSide = LogSide::Test;
+
+async fn get_device_info(
+    ctx: &TestContext,
+    server: &TestBinaryNumber,
+    modules: &[ServiceInfoModule],
+) -> Result<Vec<(ServiceInfoModule, String, serde_json::Value)>> {
+    let modules: Vec<String> = modules.iter().map(|module| module.to_string()).collect();
+    let reply: ServiceInfoApiReply = ctx
+        .http_client()?
+        .get(format!("{}/device_info", ctx.server_url(server).unwrap()))
+        .header("Authorization", "Bearer TestAuthToken")
+        .query(&[
+            ("serviceinfo_api_version", "1".to_string()),
+            ("device_guid", Guid::new()?.to_string()),
+            ("modules", modules.join(",")),
+        ])
+        .send()
+        .await
+        .context("Error sending ServiceInfo API request")?
+        .error_for_status()
+        .context("Error from the ServiceInfo API request")?
+        .json()
+        .await
+        .context("Error parsing ServiceInfo API reply")?;
+    Ok(reply.extra_commands.unwrap_or_default())
+}
+
+fn keys(
+    commands: &[(ServiceInfoModule, String, serde_json::Value)],
+    module: ServiceInfoModule,
+) -> Vec<&str> {
+    commands
+        .iter()
+        .filter(|(m, _, _)| m == &module)
+        .map(|(_, key, _)| key.as_str())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_serviceinfo_api_fdo_sys() -> Result<()> {
+    let mut ctx = TestContext::new().context("Error building test context")?;
+
+    let serviceinfo_api_server = ctx
+        .start_test_server(
+            Binary::ServiceInfoApiServer,
+            |cfg| cfg.prepare_config_file(None, |_| Ok(())),
+            |_| Ok(()),
+        )
+        .context("Error creating serviceinfo API server")?;
+    ctx.wait_until_servers_ready()
+        .await
+        .context("Error waiting for servers to start")?;
+
+    let binaryfile: ServiceInfoModule = FedoraIotServiceInfoModule::BinaryFile.into();
+    let command: ServiceInfoModule = FedoraIotServiceInfoModule::Command.into();
+    let fdo_sys: ServiceInfoModule = StandardServiceInfoModule::FdoSys.into();
+
+    L.l("Requesting ServiceInfo for a device that only supports fdo_sys");
+    let commands = get_device_info(
+        &ctx,
+        &serviceinfo_api_server,
+        std::slice::from_ref(&fdo_sys),
+    )
+    .await?;
+    assert!(keys(&commands, binaryfile.clone()).is_empty());
+    assert!(keys(&commands, command.clone()).is_empty());
+    pretty_assertions::assert_eq!(
+        keys(&commands, fdo_sys.clone()),
+        vec!["active", "filedesc", "write|hex", "exec", "exec_cb"]
+    );
+    // Commands that may fail are run with exec_cb, without any wrapper
+    let exec_cb = commands
+        .iter()
+        .find(|(_, key, _)| key == "exec_cb")
+        .map(|(_, _, value)| value.clone());
+    pretty_assertions::assert_eq!(
+        exec_cb,
+        Some(serde_json::json!(["ls", "/etc/doesnotexist/whatever.foo"]))
+    );
+
+    L.l("Requesting ServiceInfo for a device that supports all modules");
+    let commands = get_device_info(
+        &ctx,
+        &serviceinfo_api_server,
+        &[binaryfile.clone(), command.clone(), fdo_sys.clone()],
+    )
+    .await?;
+    assert!(!keys(&commands, binaryfile).is_empty());
+    assert!(!keys(&commands, command).is_empty());
+    pretty_assertions::assert_eq!(
+        keys(&commands, fdo_sys),
+        vec!["active", "filedesc", "write|hex", "exec", "exec_cb"]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,201 @@
+mod common;
+use common::{Binary, LogSide, TestContext};
+
+use std::fs;
+
+use anyhow::{Context, Result};
+use openssl::x509::X509;
+
+use fdo_data_formats::{
+    constants::{DeviceSigType, HashType},
+    messages,
+    types::{Guid, Hash, SigInfo},
+    ProtocolVersion,
+};
+use fdo_http_wrapper::client::{CertificatePins, Error, RequestResult, ServiceClient};
+
+const L: LogSide = LogSide::Test;
+
+#[tokio::test]
+async fn test_tls() -> Result<()> {
+    let mut ctx = TestContext::new_with_tls().context("Error building test context")?;
+
+    let rendezvous_server = ctx
+        .start_test_server(
+            Binary::RendezvousServer,
+            |cfg| {
+                cfg.prepare_config_file(None, |cfg| {
+                    cfg.insert("tls_client_ca", &true);
+                    Ok(())
+                })
+            },
+            |_| Ok(()),
+        )
+        .context("Error creating rendezvous server")?;
+    let serviceinfo_api_server = ctx
+        .start_test_server(
+            Binary::ServiceInfoApiServer,
+            |cfg| cfg.prepare_config_file(None, |_| Ok(())),
+            |_| Ok(()),
+        )
+        .context("Error creating serviceinfo API server")?;
+    ctx.wait_until_servers_ready()
+        .await
+        .context("Error waiting for servers to start")?;
+
+    let rendezvous_url = format!("{}/ping", ctx.server_url(&rendezvous_server).unwrap());
+    let serviceinfo_url = format!("{}/ping", ctx.server_url(&serviceinfo_api_server).unwrap());
+    assert!(serviceinfo_url.starts_with("https://"));
+
+    L.l("Connecting over plain HTTP");
+    let res = reqwest::Client::new()
+        .post(serviceinfo_url.replace("https://", "http://"))
+        .send()
+        .await;
+    L.l(format!("Result: {:?}", res));
+    assert!(res.is_err());
+
+    L.l("Connecting without trusting the server CA");
+    let res = reqwest::Client::new().post(&serviceinfo_url).send().await;
+    L.l(format!("Result: {:?}", res));
+    assert!(res.is_err());
+
+    let anonymous_client = ctx
+        .http_client_builder()?
+        .build()
+        .context("Error building client")?;
+
+    L.l("Connecting without client certificate");
+    let res = anonymous_client
+        .post(&serviceinfo_url)
+        .send()
+        .await
+        .context("Error sending ping to serviceinfo API server")?;
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+    L.l("Connecting without client certificate to server requiring one");
+    let res = anonymous_client.post(&rendezvous_url).send().await;
+    L.l(format!("Result: {:?}", res));
+    assert!(res.is_err());
+
+    L.l("Connecting with client certificate to server requiring one");
+    let res = ctx
+        .http_client()?
+        .post(&rendezvous_url)
+        .send()
+        .await
+        .context("Error sending ping to rendezvous server")?;
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+    Ok(())
+}
+
+async fn send_hello_rv(
+    url: &str,
+    pins: &CertificatePins,
+) -> RequestResult<messages::v11::to1::HelloRVAck> {
+    let mut client = ServiceClient::new_with_pins(ProtocolVersion::Version1_1, url, pins)?;
+    client
+        .send_request(
+            messages::v11::to1::HelloRV::new(
+                Guid::new()?,
+                SigInfo::new(DeviceSigType::StSECP384R1, vec![]),
+            ),
+            None,
+        )
+        .await
+}
+
+#[tokio::test]
+async fn test_tls_pins() -> Result<()> {
+    let mut ctx = TestContext::new_with_tls().context("Error building test context")?;
+
+    let rendezvous_server = ctx
+        .start_test_server(
+            Binary::RendezvousServer,
+            |cfg| cfg.prepare_config_file(None, |_| Ok(())),
+            |_| Ok(()),
+        )
+        .context("Error creating rendezvous server")?;
+    ctx.wait_until_servers_ready()
+        .await
+        .context("Error waiting for servers to start")?;
+
+    let rendezvous_url = ctx.server_url(&rendezvous_server).unwrap();
+    let rendezvous_ip_url = rendezvous_url.replace("localhost", "127.0.0.1");
+
+    let hash_cert = |name: &str| -> Result<Hash> {
+        let cert = fs::read(ctx.keys_path().join(format!("{}_cert.pem", name)))
+            .context("Error reading certificate")?;
+        let cert = X509::from_pem(&cert).context("Error parsing certificate")?;
+        Ok(Hash::from_data(HashType::Sha384, &cert.to_der()?)?)
+    };
+    let server_hash = hash_cert("tls_server")?;
+    let ca_hash = hash_cert("tls_ca")?;
+    let other_hash = hash_cert("tls_client")?;
+
+    // The test process does not trust the TLS CA, so only the pins can make the
+    // connection succeed. A successful connection gets an error message for the
+    // unknown device back.
+    for (url, pins, matches) in [
+        (
+            &rendezvous_url,
+            CertificatePins {
+                server_certificate_hash: Some(server_hash.clone()),
+                ca_certificate_hash: None,
+            },
+            true,
+        ),
+        (
+            &rendezvous_url,
+            CertificatePins {
+                server_certificate_hash: Some(other_hash.clone()),
+                ca_certificate_hash: None,
+            },
+            false,
+        ),
+        (
+            &rendezvous_url,
+            CertificatePins {
+                server_certificate_hash: None,
+                ca_certificate_hash: Some(ca_hash.clone()),
+            },
+            true,
+        ),
+        (
+            &rendezvous_ip_url,
+            CertificatePins {
+                server_certificate_hash: None,
+                ca_certificate_hash: Some(ca_hash.clone()),
+            },
+            true,
+        ),
+        (
+            &rendezvous_url,
+            CertificatePins {
+                server_certificate_hash: None,
+                ca_certificate_hash: Some(other_hash.clone()),
+            },
+            false,
+        ),
+        (
+            &rendezvous_url,
+            CertificatePins {
+                server_certificate_hash: Some(other_hash.clone()),
+                ca_certificate_hash: Some(ca_hash.clone()),
+            },
+            false,
+        ),
+    ] {
+        L.l(format!("Connecting to {} with pins {:?}", url, pins));
+        let res = send_hello_rv(url, &pins).await;
+        L.l(format!("Result: {:?}", res));
+        if matches {
+            assert!(matches!(res, Err(Error::Error(_))));
+        } else {
+            assert!(matches!(res, Err(Error::Request(_))));
+        }
+    }
+
+    Ok(())
+}
@@ -4,7 +4,12 @@ use std::{fs, os::unix::prelude::PermissionsExt, path::Path, time::Duration};
 use common::{Binary, LogSide, TestContext};
 
 use anyhow::{bail, Context, Result};
-use fdo_data_formats::{devicecredential::FileDeviceCredential, types::Guid};
+use openssl::x509::X509;
+
+use fdo_data_formats::{
+    devicecredential::FileDeviceCredential, ownershipvoucher::OwnershipVoucher, DeviceCredential,
+    Serializable,
+};
 
 const L: LogSide = LogSide::Test;
 
@@ -16,8 +21,12 @@ async fn test_to() -> Result<()> {
         for server_noninteroperable_kdf in [true, false] {
             L.l(format!("Starting test case, client_noninteroperable_kdf: {:?}, server_noninteroperable_kdf: {:?}", client_noninteroperable_kdf, server_noninteroperable_kdf));
             L.l("********************************************************============================================================");
-            if let Err(e) =
-                test_to_impl(client_noninteroperable_kdf, server_noninteroperable_kdf).await
+            if let Err(e) = test_to_impl(ToTestOptions {
+                client_noninteroperable_kdf,
+                server_noninteroperable_kdf,
+                ..Default::default()
+            })
+            .await
             {
                 L.l(format!("Test FAILED: {:?}", e));
                 failed.push(TestCase {
@@ -41,6 +50,205 @@ async fn test_to() -> Result<()> {
     }
 }
 
+#[tokio::test]
+async fn test_to_tls() -> Result<()> {
+    test_to_impl(ToTestOptions {
+        tls: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_asymkex() -> Result<()> {
+    for (kex_suite, owner_key) in [
+        ("ASYMKEX2048", "owner_rsa2048"),
+        ("ASYMKEX3072", "owner_rsa3072"),
+    ] {
+        L.l(format!(
+            "Starting test case, kex_suite: {}, owner_key: {}",
+            kex_suite, owner_key
+        ));
+        test_to_impl(ToTestOptions {
+            kex_suite: Some(kex_suite),
+            owner_key: Some(owner_key),
+            ..Default::default()
+        })
+        .await?;
+    }
+    // The owner needs to refuse the RSA based suites with the EC owner key, or an RSA key of
+    // the wrong size
+    for (kex_suite, owner_key) in [
+        ("ASYMKEX2048", None),
+        ("ASYMKEX3072", Some("owner_rsa2048")),
+    ] {
+        L.l(format!(
+            "Starting test case, kex_suite: {}, owner_key: {:?}",
+            kex_suite, owner_key
+        ));
+        test_to_impl(ToTestOptions {
+            kex_suite: Some(kex_suite),
+            owner_key,
+            suite_refused: true,
+            ..Default::default()
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_to_cipher_suites() -> Result<()> {
+    for cipher_suite in [
+        "AES-CCM-64-128-128",
+        "AES-CCM-64-128-256",
+        "AES128/CBC/HMAC-SHA256",
+        "AES128/CTR/HMAC-SHA256",
+        "AES256/CBC/HMAC-SHA384",
+        "AES256/CTR/HMAC-SHA384",
+    ] {
+        L.l(format!(
+            "Starting test case, cipher_suite: {}",
+            cipher_suite
+        ));
+        test_to_impl(ToTestOptions {
+            cipher_suite: Some(cipher_suite),
+            ..Default::default()
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_to_allowed_suites() -> Result<()> {
+    L.l("Starting test case, allowed_kex_suite: ECDH256");
+    test_to_impl(ToTestOptions {
+        kex_suite: Some("ECDH256"),
+        allowed_kex_suite: Some("ECDH256"),
+        ..Default::default()
+    })
+    .await?;
+    // The owner needs to refuse the default suites if they are not allowed, and the client
+    // must not fall back to other suites on its own
+    L.l("Starting test case, allowed_kex_suite: ECDH256, default kex_suite");
+    test_to_impl(ToTestOptions {
+        allowed_kex_suite: Some("ECDH256"),
+        suite_refused: true,
+        ..Default::default()
+    })
+    .await?;
+    L.l("Starting test case, allowed_cipher_suite: A128GCM, default cipher_suite");
+    test_to_impl(ToTestOptions {
+        allowed_cipher_suite: Some("A128GCM"),
+        suite_refused: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_suite_fallback() -> Result<()> {
+    L.l("Starting test case, kex_suite: ECDH384,ECDH256");
+    test_to_impl(ToTestOptions {
+        kex_suite: Some("ECDH384,ECDH256"),
+        allowed_kex_suite: Some("ECDH256"),
+        suite_fallback: true,
+        ..Default::default()
+    })
+    .await?;
+    L.l("Starting test case, cipher_suite: A256GCM,A128GCM");
+    test_to_impl(ToTestOptions {
+        cipher_suite: Some("A256GCM,A128GCM"),
+        allowed_cipher_suite: Some("A128GCM"),
+        suite_fallback: true,
+        ..Default::default()
+    })
+    .await?;
+    // Falling back does not help if none of the suites is allowed
+    L.l("Starting test case, kex_suite: ECDH384,ECDH256, allowed_kex_suite: DHKEXid15");
+    test_to_impl(ToTestOptions {
+        kex_suite: Some("ECDH384,ECDH256"),
+        allowed_kex_suite: Some("DHKEXid15"),
+        suite_refused: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_rv_bypass() -> Result<()> {
+    test_to_impl(ToTestOptions {
+        rv_bypass: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_coap() -> Result<()> {
+    test_to_impl(ToTestOptions {
+        coap: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_v10() -> Result<()> {
+    test_to_impl(ToTestOptions {
+        protocol_v10: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_untrusted_device_ca() -> Result<()> {
+    // The rendezvous server refuses the TO0 registration, so TO1 fails
+    test_to_impl(ToTestOptions {
+        untrusted_device_ca: true,
+        ..Default::default()
+    })
+    .await?;
+    // With bypass the device reaches the owner, which refuses it in TO2
+    test_to_impl(ToTestOptions {
+        untrusted_device_ca: true,
+        rv_bypass: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_credential_reuse() -> Result<()> {
+    test_to_impl(ToTestOptions {
+        credential_reuse: true,
+        resale: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_resale() -> Result<()> {
+    test_to_impl(ToTestOptions {
+        resale: true,
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_to_resale_failed_onboarding() -> Result<()> {
+    test_to_impl(ToTestOptions {
+        resale: true,
+        fail_before_done: true,
+        ..Default::default()
+    })
+    .await
+}
+
 #[derive(Debug)]
 struct TestCase {
     #[allow(dead_code)]
@@ -51,16 +259,84 @@ struct TestCase {
     error: anyhow::Error,
 }
 
-async fn test_to_impl(
+/// The variations of a full onboarding that are tested
+#[derive(Debug, Default)]
+struct ToTestOptions {
     client_noninteroperable_kdf: bool,
     server_noninteroperable_kdf: bool,
-) -> Result<()> {
-    let mut ctx = TestContext::new().context("Error building test context")?;
+    /// Run all servers with TLS enabled
+    tls: bool,
+    /// The key exchange suites the client is configured to use
+    kex_suite: Option<&'static str>,
+    /// The owner is expected to refuse the key exchange or cipher suites
+    suite_refused: bool,
+    /// The client is expected to fall back to the next configured suites
+    suite_fallback: bool,
+    /// The only key exchange suite the owner server allows
+    allowed_kex_suite: Option<&'static str>,
+    /// The only cipher suite the owner server allows
+    allowed_cipher_suite: Option<&'static str>,
+    /// The key the ownership voucher is extended to instead of the EC owner key, which the owner
+    /// server is configured with as an additional owner key
+    owner_key: Option<&'static str>,
+    /// The cipher suites the client is configured to use
+    cipher_suite: Option<&'static str>,
+    /// Let the device contact the owner directly, skipping TO1
+    rv_bypass: bool,
+    /// Use CoAP instead of HTTP for TO1 and TO2
+    coap: bool,
+    /// Use a device credential and ownership voucher for protocol version 1.0
+    protocol_v10: bool,
+    /// Make the servers trust a different device CA than the one that issued the device certificate
+    untrusted_device_ca: bool,
+    /// Let the device keep its credential, instead of replacing it with a new GUID and the Owner2Key
+    credential_reuse: bool,
+    /// Make the owner server store resale vouchers for devices that replace their credential
+    resale: bool,
+    /// Make the client fail after the ServiceInfo exchange, before it sends Done
+    fail_before_done: bool,
+}
+
+async fn test_to_impl(options: ToTestOptions) -> Result<()> {
+    let ToTestOptions {
+        client_noninteroperable_kdf,
+        server_noninteroperable_kdf,
+        tls,
+        kex_suite,
+        suite_refused,
+        suite_fallback,
+        allowed_kex_suite,
+        allowed_cipher_suite,
+        owner_key,
+        cipher_suite,
+        rv_bypass,
+        coap,
+        protocol_v10,
+        untrusted_device_ca,
+        credential_reuse,
+        resale,
+        fail_before_done,
+    } = options;
+
+    let mut ctx = if tls {
+        TestContext::new_with_tls()
+    } else {
+        TestContext::new()
+    }
+    .context("Error building test context")?;
 
     let rendezvous_server = ctx
         .start_test_server(
             Binary::RendezvousServer,
-            |cfg| Ok(cfg.prepare_config_file(None, |_| Ok(()))?),
+            |cfg| {
+                Ok(cfg.prepare_config_file(None, |cfg| {
+                    cfg.insert("coap", &coap);
+                    if untrusted_device_ca {
+                        cfg.insert("trusted_device_ca", "manufacturer");
+                    }
+                    Ok(())
+                })?)
+            },
             |_| Ok(()),
         )
         .context("Error creating rendezvous server")?;
@@ -80,6 +356,21 @@ async fn test_to_impl(
                         "serviceinfo_api_server_port",
                         &serviceinfo_api_server.server_port().unwrap(),
                     );
+                    cfg.insert("coap", &coap);
+                    if untrusted_device_ca {
+                        cfg.insert("trusted_device_ca", "manufacturer");
+                    }
+                    if let Some(owner_key) = owner_key {
+                        cfg.insert("additional_owner_key", owner_key);
+                    }
+                    if let Some(allowed_kex_suite) = allowed_kex_suite {
+                        cfg.insert("allowed_kex_suite", allowed_kex_suite);
+                    }
+                    if let Some(allowed_cipher_suite) = allowed_cipher_suite {
+                        cfg.insert("allowed_cipher_suite", allowed_cipher_suite);
+                    }
+                    cfg.insert("credential_reuse", &credential_reuse);
+                    cfg.insert("resale", &resale);
                     Ok(())
                 })?)
             },
@@ -103,7 +394,17 @@ async fn test_to_impl(
 
     L.l("Generating configuration files");
     ctx.generate_config_file(&rendezvous_info_path, "rendezvous-info.yml", |cfg| {
-        cfg.insert("rendezvous_port", &rendezvous_server.server_port().unwrap());
+        if coap {
+            cfg.insert("rendezvous_port", &rendezvous_server.coap_port().unwrap());
+        } else {
+            cfg.insert("rendezvous_port", &rendezvous_server.server_port().unwrap());
+        }
+        cfg.insert("coap", &coap);
+        cfg.insert(
+            "owner_port",
+            &owner_onboarding_server.server_port().unwrap(),
+        );
+        cfg.insert("rv_bypass", &rv_bypass);
         Ok(())
     })
     .context("Error generating rendezvous-info.yml")?;
@@ -129,6 +430,11 @@ async fn test_to_impl(
                     "--rendezvous-info={}",
                     rendezvous_info_path.to_str().unwrap()
                 ),
+                if protocol_v10 {
+                    "--protocol-version=100"
+                } else {
+                    "--protocol-version=101"
+                },
                 "testdevice",
                 ov_path.to_str().unwrap(),
                 dc_path.to_str().unwrap(),
@@ -139,7 +445,10 @@ async fn test_to_impl(
         .expect_success()
         .context("initialize-device failed")?;
 
-    for (source, target) in &[("manufacturer", "reseller"), ("reseller", "owner")] {
+    for (source, target) in &[
+        ("manufacturer", "reseller"),
+        ("reseller", owner_key.unwrap_or("owner")),
+    ] {
         let owner_output = ctx
             .run_owner_tool(
                 &key_path,
@@ -173,8 +482,9 @@ async fn test_to_impl(
         })?;
     }
 
-    let device_guid = determine_device_credential_guid(&dc_path)
+    let device_guid = read_device_credential(&dc_path)
         .context("Error determining device GUID")?
+        .guid
         .to_string();
     L.l(format!("Device GUID: {:?}", device_guid));
 
@@ -201,18 +511,23 @@ async fn test_to_impl(
     fs::write(ov_to, dump_output.raw_stdout())
         .context("Error writing ownership voucher to disk")?;
 
-    let client = reqwest::Client::new();
+    let client = ctx.http_client()?;
     let res = client
         .post(format!(
-            "http://localhost:{}/report-to-rendezvous",
-            owner_onboarding_server.server_port().unwrap()
+            "{}/report-to-rendezvous",
+            ctx.server_url(&owner_onboarding_server).unwrap()
         ))
         .send()
         .await?;
     L.l(format!("Status code report-to-rendezvous {}", res.status()));
 
     let ssh_authorized_keys_path = ctx.testpath().join("authorized_keys");
-    let marker_file_path = ctx.testpath().join("marker");
+    // The client fails to write the marker file into a missing directory
+    let marker_file_path = if fail_before_done {
+        ctx.testpath().join("missing").join("marker")
+    } else {
+        ctx.testpath().join("marker")
+    };
     let binary_file_path_prefix = ctx.testpath().join("binary_files");
 
     std::fs::create_dir(&binary_file_path_prefix).context("Error creating binary_files dir")?;
@@ -223,6 +538,7 @@ async fn test_to_impl(
             None,
             |cfg| {
                 cfg.env("DEVICE_CREDENTIAL", dc_path.to_str().unwrap())
+                    .env("DEVICE_CREDENTIAL_DEACTIVATE", "1")
                     .env("SSH_KEY_PATH", &ssh_authorized_keys_path.to_str().unwrap())
                     .env(
                         "BINARYFILE_PATH_PREFIX",
@@ -232,16 +548,57 @@ async fn test_to_impl(
                         "DEVICE_ONBOARDING_EXECUTED_MARKER_FILE_PATH",
                         &marker_file_path.to_str().unwrap(),
                     )
-                    .env("ALLOW_NONINTEROPERABLE_KDF", &"1");
+                    .env("ALLOW_NONINTEROPERABLE_KDF", &"1")
+                    .env("ALLOW_FDO_SYS", "1");
                 if client_noninteroperable_kdf {
                     cfg.env("FORCE_NONINTEROPERABLE_KDF", &"true");
                 }
+                if let Some(kex_suite) = kex_suite {
+                    cfg.env("KEX_SUITE", kex_suite);
+                }
+                if let Some(cipher_suite) = cipher_suite {
+                    cfg.env("CIPHER_SUITE", cipher_suite);
+                }
                 Ok(())
             },
             Duration::from_secs(5),
         )
         .context("Error running client")?;
+    if suite_refused {
+        output
+            .expect_failure()
+            .context("client succeeded with unsupported suites")?;
+        assert!(!marker_file_path.exists());
+        return Ok(());
+    }
+    if untrusted_device_ca {
+        output
+            .expect_failure()
+            .context("client succeeded with an untrusted device certificate")?;
+        assert!(!marker_file_path.exists());
+        return Ok(());
+    }
+    if fail_before_done {
+        output
+            .expect_failure()
+            .context("client succeeded without writing the marker file")?;
+        // The owner never saw Done, so the device keeps its credential and no
+        // resale voucher is stored
+        let dc = read_device_credential(&dc_path)?;
+        assert_eq!(dc.guid.to_string(), device_guid);
+        assert!(dc.active);
+        assert!(list_resale_vouchers(&ctx)?.is_empty());
+        return Ok(());
+    }
     output.expect_success().context("client failed")?;
+    if suite_fallback {
+        output.expect_stderr_line("Falling back to")?;
+    } else {
+        output.expect_not_stderr_line("Falling back to")?;
+    }
+    if protocol_v10 {
+        output.expect_stderr_line("Performing TO2 protocol version 100")?;
+    }
     if client_noninteroperable_kdf {
         output.expect_stderr_line(
             "Forcing the use of non-interoperable KDF via environment variable",
@@ -283,12 +640,80 @@ testkey
 
     assert!(key_path.join("command-testfile").exists());
 
+    // The fdo_sys instructions are sent in addition to the others
+    pretty_assertions::assert_eq!(
+        fs::read(binary_file_path_prefix.join("fdo-sys-hosts"))
+            .context("Error reading fdo_sys file")?,
+        fs::read("/etc/hosts").context("Error reading hosts file")?
+    );
+    assert!(key_path.join("fdo-sys-testfile").exists());
+
+    // Both a reused and a replaced credential stay active for the next onboarding
+    let dc = read_device_credential(&dc_path)?;
+    if credential_reuse {
+        assert_eq!(dc.guid.to_string(), device_guid);
+    } else {
+        assert_ne!(dc.guid.to_string(), device_guid);
+    }
+    assert!(dc.active);
+
+    if resale {
+        let resale_vouchers = list_resale_vouchers(&ctx)?;
+        if credential_reuse {
+            assert!(resale_vouchers.is_empty());
+        } else {
+            pretty_assertions::assert_eq!(resale_vouchers, vec![dc.guid.to_string()]);
+            check_resale_voucher(
+                &ctx.testpath()
+                    .join("resale_ownership_vouchers")
+                    .join(dc.guid.to_string()),
+                &ctx.testpath().join("owner2_cert.pem"),
+                &dc,
+            )?;
+        }
+    }
+
     Ok(())
 }
 
-fn determine_device_credential_guid(path: &Path) -> Result<Guid> {
+fn list_resale_vouchers(ctx: &TestContext) -> Result<Vec<String>> {
+    let mut resale_vouchers = Vec::new();
+    for entry in fs::read_dir(ctx.testpath().join("resale_ownership_vouchers"))
+        .context("Error listing resale vouchers")?
+    {
+        resale_vouchers.push(entry?.file_name().to_string_lossy().to_string());
+    }
+    Ok(resale_vouchers)
+}
+
+fn read_device_credential(path: &Path) -> Result<FileDeviceCredential> {
     let dc_contents = fs::read(path).context("Error reading device credential")?;
-    let dc: FileDeviceCredential =
-        serde_cbor::from_slice(&dc_contents).context("Error deserializing device credential")?;
-    Ok(dc.guid)
+    serde_cbor::from_slice(&dc_contents).context("Error deserializing device credential")
+}
+
+/// Checks that the resale voucher is issued to the Owner2Key, for the replaced credential
+fn check_resale_voucher(
+    path: &Path,
+    owner2_cert_path: &Path,
+    dc: &FileDeviceCredential,
+) -> Result<()> {
+    let ov =
+        OwnershipVoucher::from_pem_or_raw(&fs::read(path).context("Error reading resale voucher")?)
+            .context("Error parsing resale voucher")?;
+    let owner2_cert =
+        X509::from_pem(&fs::read(owner2_cert_path).context("Error reading Owner2Key certificate")?)
+            .context("Error parsing Owner2Key certificate")?;
+
+    assert_eq!(ov.header().guid(), &dc.guid);
+    assert_eq!(ov.num_entries(), 0);
+    assert!(ov
+        .header()
+        .manufacturer_public_key()
+        .matches_pkey(owner2_cert.public_key()?.as_ref())?);
+    let header_hmac = ov.header_hmac();
+    assert!(
+        dc.perform_hmac(&ov.header().serialize_data()?, header_hmac.get_type())? == *header_hmac,
+        "Resale voucher does not have the HMAC of the device"
+    );
+    Ok(())
 }
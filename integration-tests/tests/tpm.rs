@@ -0,0 +1,251 @@
+mod common;
+use std::{
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::Duration,
+};
+
+use common::{Binary, LogSide, TestContext};
+
+use anyhow::{bail, Context, Result};
+
+const L: LogSide = LogSide::Test;
+
+/// The device key types the manufacturing server can request, with the TPM public type
+const KEY_TYPES: &[(&str, &str)] = &[
+    ("SECP256R1", "Ecc"),
+    ("SECP384R1", "Ecc"),
+    ("RSA2048RESTR", "Rsa"),
+    ("RSAPKCS", "Rsa"),
+    ("RSAPSS", "Rsa"),
+];
+
+/// A swtpm simulator, which is stopped when dropped
+struct Swtpm {
+    child: Child,
+    _state_dir: tempfile::TempDir,
+    tcti: String,
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Finds a free port, where the next port is free as well for the swtpm control channel
+fn free_port_pair() -> Result<u16> {
+    for _ in 0..10 {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        if port < u16::MAX && TcpListener::bind(("127.0.0.1", port + 1)).is_ok() {
+            return Ok(port);
+        }
+    }
+    bail!("No free port pair found");
+}
+
+fn start_swtpm() -> Result<Option<Swtpm>> {
+    if Command::new("swtpm").arg("--version").output().is_err() {
+        return Ok(None);
+    }
+
+    let state_dir = tempfile::tempdir().context("Error creating swtpm state directory")?;
+    let port = free_port_pair()?;
+    let child = Command::new("swtpm")
+        .args(["socket", "--tpm2", "--tpmstate"])
+        .arg(format!("dir={}", state_dir.path().display()))
+        .arg("--server")
+        .arg(format!("type=tcp,port={}", port))
+        .arg("--ctrl")
+        .arg(format!("type=tcp,port={}", port + 1))
+        .args(["--flags", "not-need-init,startup-clear"])
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Error starting swtpm")?;
+    let swtpm = Swtpm {
+        child,
+        _state_dir: state_dir,
+        tcti: format!("swtpm:host=127.0.0.1,port={}", port),
+    };
+
+    for _ in 0..50 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return Ok(Some(swtpm));
+        }
+        sleep(Duration::from_millis(100));
+    }
+    bail!("swtpm did not start listening");
+}
+
+#[derive(Debug)]
+struct TestCase {
+    #[allow(dead_code)]
+    key_type: &'static str,
+    #[allow(dead_code)]
+    error: anyhow::Error,
+}
+
+#[tokio::test]
+async fn test_tpm_device_keys() -> Result<()> {
+    let swtpm = match start_swtpm()? {
+        Some(swtpm) => swtpm,
+        None => {
+            L.l("swtpm not installed, skipping");
+            return Ok(());
+        }
+    };
+
+    let mut failed = Vec::new();
+    for (key_type, tpm_public_type) in KEY_TYPES {
+        L.l(format!("Starting test case, key_type: {}", key_type));
+        if let Err(error) = test_tpm_device_key_impl(&swtpm, key_type, tpm_public_type).await {
+            failed.push(TestCase { key_type, error });
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        for failed_case in failed {
+            eprintln!("Failed test: {:?}", failed_case);
+        }
+        bail!("Some tests failed");
+    }
+}
+
+async fn test_tpm_device_key_impl(
+    swtpm: &Swtpm,
+    key_type: &str,
+    tpm_public_type: &str,
+) -> Result<()> {
+    let mut ctx = TestContext::new().context("Error building test context")?;
+
+    let rendezvous_server = ctx
+        .start_test_server(
+            Binary::RendezvousServer,
+            |cfg| cfg.prepare_config_file(None, |_| Ok(())),
+            |_| Ok(()),
+        )
+        .context("Error creating rendezvous server")?;
+    let serviceinfo_api_server = ctx
+        .start_test_server(
+            Binary::ServiceInfoApiServer,
+            |cfg| cfg.prepare_config_file(None, |_| Ok(())),
+            |_| Ok(()),
+        )
+        .context("Error creating serviceinfo API dev server")?;
+    let owner_onboarding_server = ctx
+        .start_test_server(
+            Binary::OwnerOnboardingServer,
+            |cfg| {
+                cfg.prepare_config_file(None, |cfg| {
+                    cfg.insert(
+                        "serviceinfo_api_server_port",
+                        &serviceinfo_api_server.server_port().unwrap(),
+                    );
+                    Ok(())
+                })
+            },
+            |cmd| {
+                cmd.env("ALLOW_NONINTEROPERABLE_KDF", "1");
+                Ok(())
+            },
+        )
+        .context("Error creating owner server")?;
+    let mfg_server = ctx
+        .start_test_server(
+            Binary::ManufacturingServer,
+            |cfg| {
+                cfg.prepare_config_file(None, |cfg| {
+                    cfg.insert("diun_key_type", "Tpm");
+                    cfg.insert("diun_public_key_type", key_type);
+                    cfg.insert("rendezvous_port", &rendezvous_server.server_port().unwrap());
+                    Ok(())
+                })
+            },
+            |_| Ok(()),
+        )
+        .context("Error creating manufacturing server")?;
+    ctx.wait_until_servers_ready()
+        .await
+        .context("Error waiting for servers to start")?;
+
+    let client_result = ctx
+        .run_client(
+            Binary::ManufacturingClient,
+            Some(&mfg_server),
+            |cfg| {
+                cfg.env("DEVICE_CREDENTIAL_FILENAME", "devicecredential.dc")
+                    .env("MANUFACTURING_INFO", "testdevice")
+                    .env("DIUN_PUB_KEY_INSECURE", "true")
+                    .env("TCTI", &swtpm.tcti);
+                Ok(())
+            },
+            Duration::from_secs(30),
+        )
+        .context("Error running manufacturing client")?;
+    client_result
+        .expect_success()
+        .context("Manufacturing client failed")?;
+
+    let dc_path = client_result.client_path().join("devicecredential.dc");
+    let owner_output = ctx
+        .run_owner_tool(
+            client_result.client_path(),
+            &["dump-device-credential", dc_path.to_str().unwrap()],
+        )
+        .context("Error running dump-device-credential")?;
+    owner_output
+        .expect_success()
+        .context("Dump-device-credential failed")?;
+    owner_output.expect_stdout_line("HMAC key TPM public: KeyedHash")?;
+    owner_output.expect_stdout_line(&format!("Signing key TPM public: {}", tpm_public_type))?;
+
+    let res = ctx
+        .http_client()?
+        .post(format!(
+            "{}/report-to-rendezvous",
+            ctx.server_url(&owner_onboarding_server).unwrap()
+        ))
+        .send()
+        .await?;
+    L.l(format!("Status code report-to-rendezvous {}", res.status()));
+
+    let ssh_authorized_keys_path = ctx.testpath().join("authorized_keys");
+    let marker_file_path = ctx.testpath().join("marker");
+    let binary_file_path_prefix = ctx.testpath().join("binary_files");
+    std::fs::create_dir(&binary_file_path_prefix).context("Error creating binary_files dir")?;
+
+    let output = ctx
+        .run_client(
+            Binary::ClientLinuxapp,
+            None,
+            |cfg| {
+                cfg.env("DEVICE_CREDENTIAL", dc_path.to_str().unwrap())
+                    .env("SSH_KEY_PATH", ssh_authorized_keys_path.to_str().unwrap())
+                    .env(
+                        "BINARYFILE_PATH_PREFIX",
+                        binary_file_path_prefix.to_str().unwrap(),
+                    )
+                    .env(
+                        "DEVICE_ONBOARDING_EXECUTED_MARKER_FILE_PATH",
+                        marker_file_path.to_str().unwrap(),
+                    )
+                    .env("ALLOW_NONINTEROPERABLE_KDF", "1")
+                    .env("TCTI", &swtpm.tcti);
+                Ok(())
+            },
+            Duration::from_secs(60),
+        )
+        .context("Error running client")?;
+    output.expect_success().context("client failed")?;
+
+    pretty_assertions::assert_eq!(
+        std::fs::read_to_string(&marker_file_path).context("Error reading marker file")?,
+        "executed"
+    );
+
+    Ok(())
+}
@@ -1,11 +1,26 @@
 mod common;
 
+use std::convert::TryFrom;
 use std::path::Path;
 
 use anyhow::{Context, Result};
 
+use openssl::{
+    asn1::Asn1Time,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    x509::{X509Builder, X509NameBuilder, X509},
+};
+
 use fdo_data_formats::{
-    ownershipvoucher::OwnershipVoucher, DeserializableMany, Error, ProtocolVersion,
+    constants::HashType,
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::{PublicKey, X5Chain},
+    types::{Guid, HMac, RendezvousInfo},
+    DeserializableMany, ProtocolVersion, Serializable,
 };
 
 fn test_single_voucher(_path: &Path, voucher: &[u8]) -> Result<()> {
@@ -100,22 +115,134 @@ fn test_multiple_vouchers_pem() -> Result<()> {
     Ok(())
 }
 
-// We explicitly decided to drop support for the old format.
+// These vouchers encode their keys with the COSE signature algorithm (-7) instead of a
+// public key type, which is not a valid protocol 1.0 encoding.
 #[test]
-fn test_voucher_v100() -> Result<()> {
-    let success = execute_for_each_voucher("vouchers/v100/", true, |success, _path, voucher| {
-        let voucher_result = OwnershipVoucher::from_pem(&voucher);
-        Ok(success
-            && matches!(
-                voucher_result,
-                Err(Error::UnsupportedVersion(Some(ProtocolVersion::Version1_0)))
-            ))
+fn test_voucher_v100_invalid_key_type() -> Result<()> {
+    let success = execute_for_each_voucher("vouchers/v100/", true, |success, path, voucher| {
+        Ok(success && test_single_voucher(path, voucher).is_err())
     })
     .context("Error running tests")?;
 
     if success {
         Ok(())
     } else {
-        Err(anyhow::anyhow!("One or more vouchers failed"))
+        Err(anyhow::anyhow!("One or more vouchers were accepted"))
+    }
+}
+
+fn generate_key(name: &str) -> Result<(PKey<Private>, X509)> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    generate_certificate(name, PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+fn generate_rsa_key(name: &str, bits: u32) -> Result<(PKey<Private>, X509)> {
+    generate_certificate(name, PKey::from_rsa(Rsa::generate(bits)?)?)
+}
+
+fn generate_certificate(name: &str, key: PKey<Private>) -> Result<(PKey<Private>, X509)> {
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_text("CN", name)?;
+    let subject = subject.build();
+
+    let mut builder = X509Builder::new()?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+    builder.set_issuer_name(&subject)?;
+    builder.set_subject_name(&subject)?;
+    builder.set_pubkey(&key)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((key, builder.build()))
+}
+
+#[test]
+fn test_voucher_v100_extend() -> Result<()> {
+    let (manufacturer_key, manufacturer_cert) = generate_key("Manufacturer")?;
+    let (_, owner_cert) = generate_key("Owner")?;
+    let (_, device_cert) = generate_key("Device")?;
+    let manufacturer_pubkey = PublicKey::try_from(manufacturer_cert)?;
+    let owner_pubkey = PublicKey::try_from(owner_cert)?;
+
+    let header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_0,
+        Guid::new()?,
+        RendezvousInfo::new(Vec::new())?,
+        "test".to_string(),
+        manufacturer_pubkey,
+        None,
+    )?;
+    let header_hmac = HMac::from_digest(HashType::HmacSha256, vec![0; 32])?;
+    let device_cert_chain = X5Chain::new(vec![device_cert])?;
+    let mut ov = OwnershipVoucher::new(header, header_hmac, Some(device_cert_chain))?;
+    ov.extend(&manufacturer_key, None, &owner_pubkey)
+        .context("Error extending OV")?;
+
+    let raw = ov.serialize_data()?;
+    // Protocol 1.0 vouchers have no protocol version field
+    match serde_cbor::from_slice(&raw)? {
+        serde_cbor::Value::Array(items) => assert_eq!(items.len(), 4),
+        other => panic!("Unexpected OV encoding: {:?}", other),
+    }
+
+    // Protocol 1.0 entries have no extra field
+    let entry = ov.entry(0)?.get_payload_unverified::<serde_cbor::Value>()?;
+    match entry.get_unverified_value() {
+        serde_cbor::Value::Array(items) => assert_eq!(items.len(), 3),
+        other => panic!("Unexpected OV entry encoding: {:?}", other),
     }
+
+    let ov = OwnershipVoucher::deserialize_data(&raw).context("Error parsing extended OV")?;
+    assert_eq!(ov.protocol_version(), ProtocolVersion::Version1_0);
+    assert_eq!(ov.serialize_data()?, raw);
+
+    let entries = ov
+        .iter_entries()
+        .context("Error constructing voucher iterator")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Error validating entries")?;
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].public_key().matches_pkey(owner_pubkey.pkey())?);
+
+    Ok(())
+}
+
+#[test]
+fn test_voucher_extend_rsa_owner() -> Result<()> {
+    let (manufacturer_key, manufacturer_cert) = generate_key("Manufacturer")?;
+    let (reseller_key, reseller_cert) = generate_rsa_key("Reseller", 2048)?;
+    let (_, owner_cert) = generate_rsa_key("Owner", 3072)?;
+    let (_, device_cert) = generate_key("Device")?;
+    let manufacturer_pubkey = PublicKey::try_from(manufacturer_cert)?;
+    let reseller_pubkey = PublicKey::try_from(reseller_cert)?;
+    let owner_pubkey = PublicKey::try_from(owner_cert)?;
+
+    let header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        Guid::new()?,
+        RendezvousInfo::new(Vec::new())?,
+        "test".to_string(),
+        manufacturer_pubkey,
+        None,
+    )?;
+    let header_hmac = HMac::from_digest(HashType::HmacSha256, vec![0; 32])?;
+    let device_cert_chain = X5Chain::new(vec![device_cert])?;
+    let mut ov = OwnershipVoucher::new(header, header_hmac, Some(device_cert_chain))?;
+    ov.extend(&manufacturer_key, None, &reseller_pubkey)
+        .context("Error extending OV to the reseller")?;
+    // The second entry is signed with the RSA reseller key
+    ov.extend(&reseller_key, None, &owner_pubkey)
+        .context("Error extending OV to the owner")?;
+
+    let ov = OwnershipVoucher::deserialize_data(&ov.serialize_data()?)
+        .context("Error parsing extended OV")?;
+    let entries = ov
+        .iter_entries()
+        .context("Error constructing voucher iterator")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Error validating entries")?;
+    assert_eq!(entries.len(), 2);
+    assert!(entries[1].public_key().matches_pkey(owner_pubkey.pkey())?);
+
+    Ok(())
 }
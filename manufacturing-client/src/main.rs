@@ -108,6 +108,7 @@ async fn perform_diun(
             KeyDeriveSide::Device,
             ciphersuite,
             accept_payload.key_exchange(),
+            None,
             non_interoperable_kdf_required,
         )
         .context("Error performing key derivation")?;
@@ -329,8 +330,10 @@ enum KeyReference {
 
 fn semi_tpm_hmac_key_template(keytype: PublicKeyType) -> Result<tss_esapi::structures::Public> {
     let hash_algo = match keytype {
-        PublicKeyType::SECP256R1 => HashingAlgorithm::Sha256,
-        PublicKeyType::SECP384R1 => HashingAlgorithm::Sha384,
+        PublicKeyType::SECP256R1 | PublicKeyType::Rsa2048RESTR => HashingAlgorithm::Sha256,
+        PublicKeyType::SECP384R1 | PublicKeyType::RsaPkcs | PublicKeyType::RsaPss => {
+            HashingAlgorithm::Sha384
+        }
         _ => bail!("Unsupported key type {:?}", keytype),
     };
     let primary_attributes = ObjectAttributesBuilder::new()
@@ -398,6 +401,39 @@ fn semi_tpm_signing_key_template(key_type: PublicKeyType) -> Result<tss_esapi::s
                 ))
                 .with_ecc_unique_identifier(Default::default())
         }
+        PublicKeyType::Rsa2048RESTR | PublicKeyType::RsaPkcs | PublicKeyType::RsaPss => {
+            // RSA2048RESTR keys are used with StRSA2048, the others with StRSA3072
+            let (key_bits, hash_algo) = match key_type {
+                PublicKeyType::Rsa2048RESTR => (
+                    tss_esapi::interface_types::key_bits::RsaKeyBits::Rsa2048,
+                    HashingAlgorithm::Sha256,
+                ),
+                _ => (
+                    tss_esapi::interface_types::key_bits::RsaKeyBits::Rsa3072,
+                    HashingAlgorithm::Sha384,
+                ),
+            };
+            let scheme = match key_type {
+                PublicKeyType::RsaPss => tss_esapi::structures::RsaScheme::RsaPss(
+                    tss_esapi::structures::HashScheme::new(hash_algo),
+                ),
+                _ => tss_esapi::structures::RsaScheme::RsaSsa(
+                    tss_esapi::structures::HashScheme::new(hash_algo),
+                ),
+            };
+            builder
+                .with_public_algorithm(tss_esapi::interface_types::algorithm::PublicAlgorithm::Rsa)
+                .with_name_hashing_algorithm(
+                    tss_esapi::interface_types::algorithm::HashingAlgorithm::Sha256,
+                )
+                .with_rsa_parameters(tss_esapi::structures::PublicRsaParameters::new(
+                    tss_esapi::structures::SymmetricDefinitionObject::Null,
+                    scheme,
+                    key_bits,
+                    Default::default(),
+                ))
+                .with_rsa_unique_identifier(Default::default())
+        }
         _ => bail!("Unsupported key type {:?}", key_type),
     }
     .build()
@@ -567,7 +603,12 @@ impl KeyReference {
                     tss_esapi::structures::Public::Rsa {
                         parameters, unique, ..
                     } => {
-                        let exponent = BigNum::from_u32(parameters.exponent().value())
+                        // The TPM uses 0 for the default exponent of 2^16 + 1
+                        let exponent = match parameters.exponent().value() {
+                            0 => 65537,
+                            exponent => exponent,
+                        };
+                        let exponent = BigNum::from_u32(exponent)
                             .context("Error converting exponent to BigNum")?;
                         let modulus = BigNum::from_slice(unique.value())
                             .context("Error converting modulus to BigNum")?;
@@ -56,6 +56,7 @@ pub(crate) async fn connect(
             KeyDeriveSide::OwnerService,
             *msg.cipher_suite(),
             msg.key_exchange(),
+            None,
             use_noninteroperable_kdf,
         )
         .map_err(Error::from_error::<messages::v11::diun::Connect, _>)?;
@@ -22,8 +22,9 @@ use fdo_data_formats::{
 };
 use fdo_store::Store;
 use fdo_util::servers::{
+    bind_server,
     configuration::manufacturing_server::{DiunSettings, ManufacturingServerSettings},
-    settings_for, yaml_to_cbor, OwnershipVoucherStoreMetadataKey,
+    load_private_key, settings_for, yaml_to_cbor, OwnershipVoucherStoreMetadataKey,
 };
 
 const PERFORMED_DIUN_SES_KEY: &str = "mfg_global_diun_performed";
@@ -197,11 +198,8 @@ async fn main() -> Result<()> {
     };
 
     // Read keys and certificates
-    let device_cert_key = PKey::private_key_from_der(
-        &fs::read(settings.manufacturing.device_cert_ca_private_key)
-            .context("Error reading device CA private key")?,
-    )
-    .context("Error parsing device CA private key")?;
+    let device_cert_key = load_private_key(&settings.manufacturing.device_cert_ca_private_key)
+        .context("Error loading device CA private key")?;
     let device_cert_chain = X5Chain::new(
         X509::stack_from_pem(
             &fs::read(settings.manufacturing.device_cert_ca_chain)
@@ -218,12 +216,9 @@ async fn main() -> Result<()> {
 
     let manufacturer_key = match settings.manufacturing.manufacturer_private_key {
         None => None,
-        Some(path) => Some(
-            PKey::private_key_from_der(
-                &fs::read(path).context("Error reading manufacturer private key")?,
-            )
-            .context("Error parsing manufacturer private key")?,
-        ),
+        Some(location) => {
+            Some(load_private_key(&location).context("Error loading manufacturer private key")?)
+        }
     };
     let owner_cert = match settings.manufacturing.owner_cert_path {
         None => None,
@@ -319,18 +314,19 @@ async fn main() -> Result<()> {
         .recover(fdo_http_wrapper::server::handle_rejection)
         .with(warp::log("manufacturing-server"));
 
-    log::info!("Listening on {}", bind_addr);
-    let server = warp::serve(routes);
+    let server = bind_server(
+        routes.boxed(),
+        bind_addr.into(),
+        settings.tls.as_ref(),
+        async {
+            signal(SignalKind::terminate()).unwrap().recv().await;
+            log::info!("Terminating");
+        },
+    )?;
 
     let maintenance_runner =
         tokio::spawn(async move { perform_maintenance(user_data.clone()).await });
 
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
-            signal(SignalKind::terminate()).unwrap().recv().await;
-            log::info!("Terminating");
-        })
-        .1;
     let server = tokio::spawn(server);
 
     #[allow(clippy::panic)]
@@ -1,17 +1,17 @@
-use std::{collections::HashSet, str::FromStr};
+use std::str::FromStr;
 
+use anyhow::Context;
+
+use fdo_data_formats::messages::{self, v11::to2::OwnerServiceInfo};
 use fdo_data_formats::{
-    constants::{DeviceSigType, ErrorCode, HeaderKeys},
+    constants::{DeviceSigType, ErrorCode, HeaderKeys, DEFAULT_MAX_SERVICE_INFO_SIZE},
     messages::Message,
+    ownershipvoucher::OwnershipVoucher,
     types::{
-        COSEHeaderMap, COSESign, CipherSuite, Guid, KeyDeriveSide, KeyExchange, Nonce,
-        RendezvousInfo, SigInfo, TO2ProveDevicePayload, TO2ProveOVHdrPayload,
-        TO2SetupDevicePayload,
+        COSEHeaderMap, COSESign, CipherSuite, Guid, HMac, KeyDeriveSide, KeyExchange, Nonce,
+        ServiceInfo, SigInfo, TO2ProveDevicePayload, TO2ProveOVHdrPayload, TO2SetupDevicePayload,
     },
-};
-use fdo_data_formats::{
-    constants::{FedoraIotServiceInfoModule, StandardServiceInfoModule},
-    messages::{self, v11::to2::OwnerServiceInfo},
+    ProtocolVersion,
 };
 
 use fdo_http_wrapper::server::Error;
@@ -20,10 +20,51 @@ use fdo_http_wrapper::EncryptionKeys;
 use fdo_store::MetadataKey;
 use fdo_util::servers::{OwnershipVoucherStoreMetadataKey, ServiceInfoApiReply};
 
+use crate::serviceinfo::ServiceInfoState;
+
+/// Checks that a message uses the protocol version the session was started with
+fn check_protocol_version<M: Message>(request_info: &RequestInformation) -> Result<(), Error> {
+    match request_info
+        .session
+        .get::<ProtocolVersion>("protocol_version")
+    {
+        Some(protocol_version) if protocol_version == M::protocol_version() => Ok(()),
+        _ => Err(Error::new(
+            ErrorCode::InvalidMessageError,
+            M::message_type(),
+            "Request sequence failure",
+        )),
+    }
+}
+
 pub(super) async fn hello_device(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v11::to2::HelloDevice,
+) -> Result<(messages::v11::to2::ProveOVHdr, RequestInformation), warp::Rejection> {
+    handle_hello_device(user_data, request_info, msg, ProtocolVersion::Version1_1).await
+}
+
+pub(super) async fn hello_device_v10(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v10::to2::HelloDevice,
+) -> Result<(messages::v10::to2::ProveOVHdr, RequestInformation), warp::Rejection> {
+    let (res, request_info) = handle_hello_device(
+        user_data,
+        request_info,
+        msg.into(),
+        ProtocolVersion::Version1_0,
+    )
+    .await?;
+    Ok((res.into(), request_info))
+}
+
+async fn handle_hello_device(
     user_data: super::OwnerServiceUDT,
     mut request_info: RequestInformation,
     msg: messages::v11::to2::HelloDevice,
+    protocol_version: ProtocolVersion,
 ) -> Result<(messages::v11::to2::ProveOVHdr, RequestInformation), warp::Rejection> {
     let mut session = request_info.session;
 
@@ -44,13 +85,46 @@ pub(super) async fn hello_device(
         }
         Some(dev) => dev,
     };
+
+    // Check whether the device certificate chains up to a device CA we trust
+    let device_cert_chain = match ownership_voucher.device_certificate_chain() {
+        Some(chain) => chain,
+        None => {
+            return Err(Error::new(
+                ErrorCode::InvalidOwnershipVoucher,
+                messages::v11::to2::HelloDevice::message_type(),
+                "No device certificate",
+            )
+            .into())
+        }
+    };
+    let device_cert = match &user_data.trusted_device_keys {
+        Some(trusted_device_keys) => device_cert_chain.verify_from_x5bag(trusted_device_keys),
+        None => device_cert_chain.insecure_verify_without_root_verification(),
+    };
+    if let Err(cert_chain_err) = device_cert {
+        log::debug!("Error verifying device certificate: {:?}", cert_chain_err);
+        return Err(Error::new(
+            ErrorCode::InvalidOwnershipVoucher,
+            messages::v11::to2::HelloDevice::message_type(),
+            "Device certificate not trusted",
+        )
+        .into());
+    }
+
     session
         .insert("device_guid", msg.guid().to_string())
         .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+    session
+        .insert("protocol_version", protocol_version)
+        .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
 
     // Check whether we support the specific siginfo
     match msg.a_signature_info().sig_type() {
-        DeviceSigType::StSECP256R1 | DeviceSigType::StSECP384R1 => {}
+        DeviceSigType::StSECP256R1
+        | DeviceSigType::StSECP384R1
+        | DeviceSigType::StRSA2048
+        | DeviceSigType::StRSA3072 => {}
         _ => {
             return Err(Error::new(
                 ErrorCode::MessageBodyError,
@@ -69,6 +143,62 @@ pub(super) async fn hello_device(
         .into());
     }
 
+    // Check whether the suites requested by the device are allowed
+    if let Some(allowed_kex_suites) = &user_data.allowed_kex_suites {
+        if !allowed_kex_suites.contains(&msg.kex_suite()) {
+            log::info!(
+                "Device requested disallowed kex suite {:?}",
+                msg.kex_suite()
+            );
+            return Err(Error::new(
+                ErrorCode::MessageBodyError,
+                messages::v11::to2::HelloDevice::message_type(),
+                "Key exchange suite not allowed",
+            )
+            .into());
+        }
+    }
+    if let Some(allowed_cipher_suites) = &user_data.allowed_cipher_suites {
+        if !allowed_cipher_suites.contains(&msg.cipher_suite()) {
+            log::info!(
+                "Device requested disallowed cipher suite {:?}",
+                msg.cipher_suite()
+            );
+            return Err(Error::new(
+                ErrorCode::MessageBodyError,
+                messages::v11::to2::HelloDevice::message_type(),
+                "Cipher suite not allowed",
+            )
+            .into());
+        }
+    }
+
+    // Find the owner key the voucher was extended to
+    let owner_key = match user_data
+        .owner_key_for(&ownership_voucher)
+        .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?
+    {
+        Some(owner_key) => owner_key,
+        None => {
+            return Err(Error::new(
+                ErrorCode::InvalidOwnershipVoucher,
+                messages::v11::to2::HelloDevice::message_type(),
+                "Voucher not owned by any of our owner keys",
+            )
+            .into())
+        }
+    };
+
+    // Check whether the key exchange suite can be used with our owner key
+    if !msg.kex_suite().supports_owner_key(&owner_key.private_key) {
+        return Err(Error::new(
+            ErrorCode::MessageBodyError,
+            messages::v11::to2::HelloDevice::message_type(),
+            "Key exchange suite not supported with owner key",
+        )
+        .into());
+    }
+
     // Build kex a
     let a_key_exchange = KeyExchange::new(msg.kex_suite())
         .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
@@ -77,18 +207,6 @@ pub(super) async fn hello_device(
         .get_public()
         .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
 
-    // Now produce the result
-    let res_payload = TO2ProveOVHdrPayload::new(
-        ownership_voucher.header_raw(),
-        ownership_voucher.num_entries(),
-        ownership_voucher.header_hmac().clone(),
-        msg.nonce5().clone(),
-        SigInfo::new(msg.a_signature_info().sig_type(), vec![]),
-        a_key_exchange_public,
-        request_info.req_hash.clone(),
-    )
-    .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
-
     // Store data
     session
         .insert("nonce6", nonce6.clone())
@@ -106,11 +224,40 @@ pub(super) async fn hello_device(
         .insert(HeaderKeys::CUPHNonce, &nonce6)
         .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
     res_header
-        .insert(HeaderKeys::CUPHOwnerPubKey, &user_data.owner_pubkey)
+        .insert(HeaderKeys::CUPHOwnerPubKey, &owner_key.public_key)
         .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
 
-    let res = COSESign::new(&res_payload, Some(res_header), &user_data.owner_key)
-        .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+    // Now produce the result
+    let b_signature_info = SigInfo::new(msg.a_signature_info().sig_type(), vec![]);
+    let res = match protocol_version {
+        // Protocol 1.0 has no HelloDevice hash and no maximum message size in the payload
+        ProtocolVersion::Version1_0 => {
+            let res_payload = messages::v10::to2::ProveOVHdrPayload::new(
+                ownership_voucher.header_raw(),
+                ownership_voucher.num_entries(),
+                ownership_voucher.header_hmac().clone(),
+                msg.nonce5().clone(),
+                b_signature_info,
+                a_key_exchange_public,
+            )
+            .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+            COSESign::new(&res_payload, Some(res_header), &owner_key.private_key)
+        }
+        _ => {
+            let res_payload = TO2ProveOVHdrPayload::new(
+                ownership_voucher.header_raw(),
+                ownership_voucher.num_entries(),
+                ownership_voucher.header_hmac().clone(),
+                msg.nonce5().clone(),
+                b_signature_info,
+                a_key_exchange_public,
+                request_info.req_hash.clone(),
+            )
+            .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+            COSESign::new(&res_payload, Some(res_header), &owner_key.private_key)
+        }
+    }
+    .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
     let res = messages::v11::to2::ProveOVHdr::new(res);
 
     request_info.session = session;
@@ -119,6 +266,25 @@ pub(super) async fn hello_device(
 }
 
 pub(super) async fn get_ov_next_entry(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v11::to2::GetOVNextEntry,
+) -> Result<(messages::v11::to2::OVNextEntry, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v11::to2::GetOVNextEntry>(&request_info)?;
+    handle_get_ov_next_entry(user_data, request_info, msg).await
+}
+
+pub(super) async fn get_ov_next_entry_v10(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v10::to2::GetOVNextEntry,
+) -> Result<(messages::v10::to2::OVNextEntry, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v10::to2::GetOVNextEntry>(&request_info)?;
+    let (res, request_info) = handle_get_ov_next_entry(user_data, request_info, msg.into()).await?;
+    Ok((res.into(), request_info))
+}
+
+async fn handle_get_ov_next_entry(
     user_data: super::OwnerServiceUDT,
     ses_with_store: RequestInformation,
     msg: messages::v11::to2::GetOVNextEntry,
@@ -134,7 +300,8 @@ pub(super) async fn get_ov_next_entry(
             .into())
         }
     };
-    let device_guid = Guid::from_str(&device_guid).unwrap();
+    let device_guid = Guid::from_str(&device_guid)
+        .map_err(Error::from_error::<messages::v11::to2::GetOVNextEntry, _>)?;
 
     let ownership_voucher = match user_data
         .ownership_voucher_store
@@ -162,6 +329,25 @@ pub(super) async fn get_ov_next_entry(
 }
 
 pub(super) async fn prove_device(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v11::to2::ProveDevice,
+) -> Result<(messages::v11::to2::SetupDevice, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v11::to2::ProveDevice>(&request_info)?;
+    handle_prove_device(user_data, request_info, msg).await
+}
+
+pub(super) async fn prove_device_v10(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v10::to2::ProveDevice,
+) -> Result<(messages::v10::to2::SetupDevice, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v10::to2::ProveDevice>(&request_info)?;
+    let (res, request_info) = handle_prove_device(user_data, request_info, msg.into()).await?;
+    Ok((res.into(), request_info))
+}
+
+async fn handle_prove_device(
     user_data: super::OwnerServiceUDT,
     mut request_info: RequestInformation,
     msg: messages::v11::to2::ProveDevice,
@@ -179,7 +365,8 @@ pub(super) async fn prove_device(
             .into())
         }
     };
-    let device_guid = Guid::from_str(&device_guid).unwrap();
+    let device_guid = Guid::from_str(&device_guid)
+        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
 
     let nonce6: Nonce = match session.get("nonce6") {
         Some(v) => v,
@@ -259,6 +446,20 @@ pub(super) async fn prove_device(
     let dev_pubkey = &device_certificate
         .public_key()
         .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    let owner_key = match user_data
+        .owner_key_for(&ownership_voucher)
+        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?
+    {
+        Some(owner_key) => owner_key,
+        None => {
+            return Err(Error::new(
+                ErrorCode::InvalidOwnershipVoucher,
+                messages::v11::to2::ProveDevice::message_type(),
+                "Voucher not owned by any of our owner keys",
+            )
+            .into())
+        }
+    };
 
     // Get device EAT
     let token = msg.into_token();
@@ -323,6 +524,7 @@ pub(super) async fn prove_device(
             KeyDeriveSide::OwnerService,
             ciphersuite,
             eat_payload.b_key_exchange(),
+            Some(&owner_key.private_key),
             use_noninteroperable_kdf,
         )
         .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
@@ -333,15 +535,28 @@ pub(super) async fn prove_device(
         new_keys,
     )?;
 
-    // Generate new ephemeral SetupDevicePayload
-    let new_payload = TO2SetupDevicePayload::new(
-        RendezvousInfo::new(Vec::new())
-            .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?,
-        Guid::new().unwrap(),
-        nonce7,
-        user_data.owner2_pub.clone(),
-    );
-    let new_token = COSESign::new(&new_payload, None, &user_data.owner2_key)
+    // Generate the SetupDevicePayload: either the device keeps its current
+    // credential (reuse), or it gets a new GUID and our Owner2Key
+    let rendezvous_info = ownership_voucher.header().rendezvous_info().clone();
+    let (replacement_guid, replacement_pub, replacement_key) = if user_data.credential_reuse {
+        (
+            device_guid.clone(),
+            owner_key.public_key.clone(),
+            &owner_key.private_key,
+        )
+    } else {
+        (
+            Guid::new().map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?,
+            user_data.owner2_key.public_key.clone(),
+            &user_data.owner2_key.private_key,
+        )
+    };
+    session
+        .insert("replacement_guid", replacement_guid.to_string())
+        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    let new_payload =
+        TO2SetupDevicePayload::new(rendezvous_info, replacement_guid, nonce7, replacement_pub);
+    let new_token = COSESign::new(&new_payload, None, replacement_key)
         .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
     let resp = messages::v11::to2::SetupDevice::new(new_token);
 
@@ -355,9 +570,41 @@ pub(super) async fn prove_device(
 }
 
 pub(super) async fn device_service_info_ready(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v11::to2::DeviceServiceInfoReady,
+) -> Result<
+    (
+        messages::v11::to2::OwnerServiceInfoReady,
+        RequestInformation,
+    ),
+    warp::Rejection,
+> {
+    check_protocol_version::<messages::v11::to2::DeviceServiceInfoReady>(&request_info)?;
+    handle_device_service_info_ready(user_data, request_info, msg).await
+}
+
+pub(super) async fn device_service_info_ready_v10(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v10::to2::DeviceServiceInfoReady,
+) -> Result<
+    (
+        messages::v10::to2::OwnerServiceInfoReady,
+        RequestInformation,
+    ),
+    warp::Rejection,
+> {
+    check_protocol_version::<messages::v10::to2::DeviceServiceInfoReady>(&request_info)?;
+    let (res, request_info) =
+        handle_device_service_info_ready(user_data, request_info, msg.into()).await?;
+    Ok((res.into(), request_info))
+}
+
+async fn handle_device_service_info_ready(
     _user_data: super::OwnerServiceUDT,
-    ses_with_store: RequestInformation,
-    _msg: messages::v11::to2::DeviceServiceInfoReady,
+    mut ses_with_store: RequestInformation,
+    msg: messages::v11::to2::DeviceServiceInfoReady,
 ) -> Result<
     (
         messages::v11::to2::OwnerServiceInfoReady,
@@ -378,15 +625,148 @@ pub(super) async fn device_service_info_ready(
         }
     };
 
+    // The resale voucher is only stored once the device completed onboarding
+    if let Some(replacement_hmac) = msg.replacement_hmac() {
+        ses_with_store
+            .session
+            .insert("replacement_hmac", replacement_hmac.clone())
+            .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfoReady, _>)?;
+    }
+
+    let max_owner_service_info_size = msg
+        .max_owner_service_info_size()
+        .unwrap_or(DEFAULT_MAX_SERVICE_INFO_SIZE);
+    ses_with_store
+        .session
+        .insert("max_owner_service_info_size", max_owner_service_info_size)
+        .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfoReady, _>)?;
+
     Ok((
         messages::v11::to2::OwnerServiceInfoReady::new(None),
         ses_with_store,
     ))
 }
 
+/// Builds the ownership voucher for the replacement credential of a device,
+/// issued to our Owner2Key so the device can be resold.
+fn resale_ownership_voucher(
+    user_data: &super::OwnerServiceUDT,
+    ownership_voucher: &OwnershipVoucher,
+    replacement_guid: Guid,
+    replacement_hmac: HMac,
+) -> Result<OwnershipVoucher, fdo_data_formats::Error> {
+    let header = ownership_voucher.header().new_replacement(
+        replacement_guid,
+        ownership_voucher.header().rendezvous_info().clone(),
+        user_data.owner2_key.public_key.clone(),
+    )?;
+    OwnershipVoucher::new(
+        header,
+        replacement_hmac,
+        ownership_voucher.device_certificate_chain().cloned(),
+    )
+}
+
+/// Stores the resale voucher for the replacement credential of the device,
+/// with the HMAC the device sent over the replacement header.
+async fn store_resale_ownership_voucher(
+    user_data: &super::OwnerServiceUDT,
+    ses_with_store: &RequestInformation,
+    device_guid: &Guid,
+) -> Result<(), warp::Rejection> {
+    let replacement_guid = match ses_with_store.session.get::<String>("replacement_guid") {
+        Some(replacement_guid) => Guid::from_str(&replacement_guid)
+            .map_err(Error::from_error::<messages::v11::to2::Done, _>)?,
+        None => {
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::to2::Done::message_type(),
+                "Request sequence failure",
+            )
+            .into())
+        }
+    };
+    let store = match &user_data.resale_ownership_voucher_store {
+        Some(store) => store,
+        None => {
+            log::info!(
+                "Device {:?} replaced its credential with GUID {:?}, resale voucher not stored",
+                device_guid,
+                replacement_guid
+            );
+            return Ok(());
+        }
+    };
+    let replacement_hmac = match ses_with_store.session.get::<HMac>("replacement_hmac") {
+        Some(replacement_hmac) => replacement_hmac,
+        None => {
+            log::info!(
+                "Device {:?} did not send a replacement HMAC, resale voucher not stored",
+                device_guid
+            );
+            return Ok(());
+        }
+    };
+
+    let ownership_voucher = match user_data
+        .ownership_voucher_store
+        .load_data(device_guid)
+        .await
+        .map_err(Error::from_error::<messages::v11::to2::Done, _>)?
+    {
+        Some(ov) => ov,
+        None => {
+            return Err(Error::new(
+                ErrorCode::ResourceNotFound,
+                messages::v11::to2::Done::message_type(),
+                "Device not found",
+            )
+            .into())
+        }
+    };
+    let resale_voucher = resale_ownership_voucher(
+        user_data,
+        &ownership_voucher,
+        replacement_guid.clone(),
+        replacement_hmac,
+    )
+    .map_err(Error::from_error::<messages::v11::to2::Done, _>)?;
+
+    store
+        .store_data(replacement_guid.clone(), resale_voucher)
+        .await
+        .map_err(Error::from_error::<messages::v11::to2::Done, _>)?;
+    log::info!(
+        "Stored resale ownership voucher {:?} for device {:?}",
+        replacement_guid,
+        device_guid
+    );
+    Ok(())
+}
+
 const MAX_SERVICE_INFO_LOOPS: u32 = 1000;
 
 pub(super) async fn device_service_info(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v11::to2::DeviceServiceInfo,
+) -> Result<(messages::v11::to2::OwnerServiceInfo, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v11::to2::DeviceServiceInfo>(&request_info)?;
+    handle_device_service_info(user_data, request_info, msg).await
+}
+
+pub(super) async fn device_service_info_v10(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v10::to2::DeviceServiceInfo,
+) -> Result<(messages::v10::to2::OwnerServiceInfo, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v10::to2::DeviceServiceInfo>(&request_info)?;
+    let (res, request_info) =
+        handle_device_service_info(user_data, request_info, msg.into()).await?;
+    Ok((res.into(), request_info))
+}
+
+async fn handle_device_service_info(
     user_data: super::OwnerServiceUDT,
     mut ses_with_store: RequestInformation,
     msg: messages::v11::to2::DeviceServiceInfo,
@@ -414,7 +794,8 @@ pub(super) async fn device_service_info(
             .into())
         }
     };
-    let device_guid = Guid::from_str(&device_guid).unwrap();
+    let device_guid = Guid::from_str(&device_guid)
+        .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfo, _>)?;
 
     let num_loops = match ses_with_store.session.get::<u32>("num_service_info_loops") {
         Some(v) => v,
@@ -475,99 +856,116 @@ pub(super) async fn device_service_info(
 
 async fn perform_service_info(
     user_data: super::OwnerServiceUDT,
-    _session: &mut fdo_http_wrapper::server::Session,
+    session: &mut fdo_http_wrapper::server::Session,
     device_guid: Guid,
     msg: messages::v11::to2::DeviceServiceInfo,
     loop_num: u32,
 ) -> Result<OwnerServiceInfo, anyhow::Error> {
-    if loop_num != 0 {
-        // Return DONE for now after the first loop.
-        return Ok(messages::v11::to2::OwnerServiceInfo::new(
-            false,
-            true,
-            Default::default(),
-        ));
-    }
     let in_si = msg.service_info();
 
     log::trace!("Received ServiceInfo loop {}: {:?}", loop_num, in_si);
 
-    let mut module_list: Option<Vec<String>> = None;
+    let mut state = if loop_num == 0 {
+        let max_owner_service_info_size: u64 = session
+            .get("max_owner_service_info_size")
+            .context("No maximum ServiceInfo size in session")?;
+        ServiceInfoState::new(max_owner_service_info_size)
+    } else {
+        ServiceInfoState::from_session(session)?
+    };
+    state.record_device_service_info(session, in_si, msg.is_more_service_info())?;
 
-    for (module, var, value) in in_si.iter() {
-        if module == StandardServiceInfoModule::DevMod.into() && var == "modules" {
-            let mut rawmodlist: Vec<serde_cbor::Value> = serde_cbor::value::from_value(value)?;
-            log::trace!("Received module list: {:?}", rawmodlist);
+    if msg.is_more_service_info() {
+        // The device has more to send before we can respond to it
+        state.store_in_session(session)?;
+        return Ok(OwnerServiceInfo::new(false, false, ServiceInfo::new()));
+    }
 
-            // Skip the first two items.... They are integers :()
-            let mut modlist: HashSet<String> = HashSet::new();
-            for rawmod in rawmodlist.drain(..).skip(2) {
-                modlist.insert(serde_cbor::value::from_value(rawmod)?);
-            }
-            log::trace!("Module list: {:?}", modlist);
+    if state.needs_api_reply() {
+        let module_list: Vec<String> = state.device_modules()?.map(|m| m.to_string()).collect();
 
-            module_list = Some(modlist.into_iter().collect());
-        }
-    }
+        let resp: ServiceInfoApiReply = user_data
+            .service_info_api_client
+            .send_get([
+                ("serviceinfo_api_version", "1"),
+                ("device_guid", &device_guid.to_string()),
+                ("modules", &module_list.join(",")),
+            ])
+            .await?;
 
-    let module_list = match module_list {
-        None => {
-            log::error!("No module list found in ServiceInfo");
-            anyhow::bail!("No module list found in ServiceInfo");
-        }
-        Some(l) => l,
-    };
+        log::trace!("ServiceInfo API reply: {:?}", resp);
 
-    let resp: ServiceInfoApiReply = user_data
-        .service_info_api_client
-        .send_get([
-            ("serviceinfo_api_version", "1"),
-            ("device_guid", &device_guid.to_string()),
-            ("modules", &module_list.join(",")),
-        ])
-        .await?;
-
-    log::trace!("ServiceInfo API reply: {:?}", resp);
-
-    let mut out_si = fdo_data_formats::types::ServiceInfo::new();
-
-    if let Some(initial_user) = resp.initial_user {
-        out_si.add(FedoraIotServiceInfoModule::SSHKey, "active", &true)?;
-        out_si.add(
-            FedoraIotServiceInfoModule::SSHKey,
-            "username",
-            &initial_user.username,
-        )?;
-        for key in initial_user.ssh_keys.iter() {
-            out_si.add(FedoraIotServiceInfoModule::SSHKey, "key", &key)?;
-        }
+        state.queue_api_reply(session, resp)?;
     }
 
-    if let Some(extra_commands) = resp.extra_commands {
-        for (module, key, value) in extra_commands {
-            if key.ends_with("|hex") {
-                let value = hex::decode(
-                    &value
-                        .as_str()
-                        .ok_or_else(|| anyhow::anyhow!("Invalid API response: non-hex"))?,
-                )?;
-                let value = serde_bytes::ByteBuf::from(value);
-                let key = key.replace("|hex", "");
-                out_si.add(module, &key, &value)?;
-            } else {
-                out_si.add(module, &key, &value)?;
-            }
-        }
+    let out_si = state.next_round(session)?;
+    state.store_in_session(session)?;
+
+    if out_si.is_done() {
+        report_device_results(&user_data, device_guid, &state, session).await;
     }
 
     log::trace!("Sending ServiceInfo result: {:?}", out_si);
 
-    Ok(messages::v11::to2::OwnerServiceInfo::new(
-        false, false, out_si,
-    ))
+    Ok(out_si)
+}
+
+/// Reports the ServiceInfo returned by the device back to the ServiceInfo API
+///
+/// This is for auditing only, so failures do not abort the onboarding.
+async fn report_device_results(
+    user_data: &super::OwnerServiceUDT,
+    device_guid: Guid,
+    state: &ServiceInfoState,
+    session: &fdo_http_wrapper::server::Session,
+) {
+    let results = match state.device_results(session) {
+        Ok(results) => results,
+        Err(e) => {
+            log::warn!("Error preparing device results for reporting: {:?}", e);
+            return;
+        }
+    };
+
+    let resp: Result<serde_json::Value, _> = user_data
+        .service_info_api_client
+        .send_post(
+            [
+                ("serviceinfo_api_version", "2"),
+                ("device_guid", &device_guid.to_string()),
+            ],
+            &results,
+        )
+        .await;
+    if let Err(e) = resp {
+        log::warn!(
+            "Error reporting device results for {:?} to the ServiceInfo API: {:?}",
+            device_guid,
+            e
+        );
+    }
 }
 
 pub(super) async fn done(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v11::to2::Done,
+) -> Result<(messages::v11::to2::Done2, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v11::to2::Done>(&request_info)?;
+    handle_done(user_data, request_info, msg).await
+}
+
+pub(super) async fn done_v10(
+    user_data: super::OwnerServiceUDT,
+    request_info: RequestInformation,
+    msg: messages::v10::to2::Done,
+) -> Result<(messages::v10::to2::Done2, RequestInformation), warp::Rejection> {
+    check_protocol_version::<messages::v10::to2::Done>(&request_info)?;
+    let (res, request_info) = handle_done(user_data, request_info, msg.into()).await?;
+    Ok((res.into(), request_info))
+}
+
+async fn handle_done(
     user_data: super::OwnerServiceUDT,
     mut ses_with_store: RequestInformation,
     msg: messages::v11::to2::Done,
@@ -616,7 +1014,8 @@ pub(super) async fn done(
             .into())
         }
     };
-    let device_guid = Guid::from_str(&device_guid).unwrap();
+    let device_guid =
+        Guid::from_str(&device_guid).map_err(Error::from_error::<messages::v11::to2::Done, _>)?;
     log::info!("Device {:?} has finished its onboarding", device_guid);
 
     let nonce7: Nonce = match ses_with_store.session.get("nonce7") {
@@ -631,15 +1030,21 @@ pub(super) async fn done(
         }
     };
 
-    user_data
-        .ownership_voucher_store
-        .store_metadata(
-            &device_guid,
-            &MetadataKey::Local(OwnershipVoucherStoreMetadataKey::To2Performed),
-            &true,
-        )
-        .await
-        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    // With credential reuse the device will onboard again with the same
+    // voucher, so it must stay registered with the rendezvous server
+    if !user_data.credential_reuse {
+        store_resale_ownership_voucher(&user_data, &ses_with_store, &device_guid).await?;
+
+        user_data
+            .ownership_voucher_store
+            .store_metadata(
+                &device_guid,
+                &MetadataKey::Local(OwnershipVoucherStoreMetadataKey::To2Performed),
+                &true,
+            )
+            .await
+            .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    }
 
     ses_with_store.session.remove("nonce7");
     ses_with_store.session.destroy();
@@ -1,11 +1,15 @@
 use std::convert::{TryFrom, TryInto};
-use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use fdo_data_formats::constants::HashType;
 use fdo_data_formats::enhanced_types::RendezvousInterpreterSide;
-use fdo_data_formats::types::{COSESign, Hash, TO0Data, TO1DataPayload};
+use fdo_data_formats::types::{
+    COSESign, CipherSuite, Hash, KexSuite, Nonce, TO0Data, TO1DataPayload,
+};
 use fdo_data_formats::{messages, ProtocolVersion, Serializable};
 use fdo_http_wrapper::client::RequestResult;
 use openssl::{
@@ -29,16 +33,22 @@ use fdo_data_formats::{
 };
 use fdo_store::Store;
 use fdo_util::servers::{
-    configuration::{owner_onboarding_server::OwnerOnboardingServerSettings, AbsolutePathBuf},
-    settings_for, OwnershipVoucherStoreMetadataKey,
+    bind_coap_server, bind_server,
+    configuration::{
+        owner_onboarding_server::{OwnerKeySettings, OwnerOnboardingServerSettings, To0Settings},
+        AbsolutePathBuf, PrivateKeyLocation,
+    },
+    load_private_key, settings_for, OwnershipVoucherStoreMetadataKey,
 };
 
 mod handlers;
+mod management;
+mod serviceinfo;
 
 pub(crate) struct OwnerServiceUD {
     // Trusted keys
-    #[allow(dead_code)]
-    trusted_device_keys: X5Bag,
+    // None if device certificate chains are accepted without root verification
+    trusted_device_keys: Option<X5Bag>,
 
     // Stores
     ownership_voucher_store: Box<
@@ -49,27 +59,134 @@ pub(crate) struct OwnerServiceUD {
             OwnershipVoucherStoreMetadataKey,
         >,
     >,
+    // None if replacement vouchers are not stored
+    resale_ownership_voucher_store: Option<
+        Box<
+            dyn Store<
+                fdo_store::ReadWriteOpen,
+                Guid,
+                OwnershipVoucher,
+                OwnershipVoucherStoreMetadataKey,
+            >,
+        >,
+    >,
     session_store: Arc<fdo_http_wrapper::server::SessionStore>,
 
     // Our keys
-    owner_key: PKey<Private>,
-    owner_pubkey: PublicKey,
+    owner_keys: Vec<OwnerKey>,
+
+    // The Owner2Key replacement credentials are issued to
+    owner2_key: OwnerKey,
+    // Whether devices keep their credential instead of replacing it
+    credential_reuse: bool,
 
-    // The new Owner2Key, randomly generated, but not stored
-    owner2_key: PKey<Private>,
-    owner2_pub: PublicKey,
+    // Suites devices may use, None if all supported suites are allowed
+    allowed_kex_suites: Option<Vec<KexSuite>>,
+    allowed_cipher_suites: Option<Vec<CipherSuite>>,
 
     // ServiceInfo API server configuration
     service_info_api_client: fdo_http_wrapper::client::JsonClient,
 
     owner_addresses: Vec<TO2AddressEntry>,
+
+    // Report to rendezvous policy
+    to0_policy: To0Policy,
 }
 
 pub(crate) type OwnerServiceUDT = Arc<OwnerServiceUD>;
 
-fn load_private_key(path: &AbsolutePathBuf) -> Result<PKey<Private>> {
-    let contents = fs::read(path)?;
-    Ok(PKey::private_key_from_der(&contents)?)
+struct OwnerKey {
+    private_key: PKey<Private>,
+    public_key: PublicKey,
+}
+
+impl OwnerServiceUD {
+    /// Returns the owner key the voucher was extended to, if it is one of ours
+    fn owner_key_for(
+        &self,
+        ov: &OwnershipVoucher,
+    ) -> std::result::Result<Option<&OwnerKey>, fdo_data_formats::Error> {
+        let owner = current_owner(ov)?;
+        for owner_key in &self.owner_keys {
+            if owner.matches_pkey(owner_key.public_key.pkey())? {
+                return Ok(Some(owner_key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Validates all entries of the voucher, and returns the public key of the current owner
+fn current_owner(ov: &OwnershipVoucher) -> std::result::Result<PublicKey, fdo_data_formats::Error> {
+    let mut owner = ov.header().manufacturer_public_key().clone();
+    for entry in ov.iter_entries()? {
+        owner = entry?.public_key().clone();
+    }
+    Ok(owner)
+}
+
+const DEFAULT_TO0_WAIT_SECONDS: u32 = 600;
+const DEFAULT_TO0_RENEW_BEFORE_EXPIRY_SECONDS: u32 = 2 * MAINTENANCE_INTERVAL as u32;
+const DEFAULT_TO0_RETRY_INITIAL_SECONDS: u32 = MAINTENANCE_INTERVAL as u32;
+const DEFAULT_TO0_RETRY_MAX_SECONDS: u32 = 3600;
+
+/// When and for how long vouchers get registered with the rendezvous servers
+#[derive(Debug, Clone)]
+struct To0Policy {
+    wait_seconds: u32,
+    renew_before_expiry_seconds: u32,
+    retry_initial_seconds: u32,
+    retry_max_seconds: u32,
+}
+
+impl From<&To0Settings> for To0Policy {
+    fn from(settings: &To0Settings) -> Self {
+        To0Policy {
+            wait_seconds: settings.wait_seconds.unwrap_or(DEFAULT_TO0_WAIT_SECONDS),
+            renew_before_expiry_seconds: settings
+                .renew_before_expiry_seconds
+                .unwrap_or(DEFAULT_TO0_RENEW_BEFORE_EXPIRY_SECONDS),
+            retry_initial_seconds: settings
+                .retry_initial_seconds
+                .unwrap_or(DEFAULT_TO0_RETRY_INITIAL_SECONDS),
+            retry_max_seconds: settings
+                .retry_max_seconds
+                .unwrap_or(DEFAULT_TO0_RETRY_MAX_SECONDS),
+        }
+    }
+}
+
+impl To0Policy {
+    /// Seconds after which a registration accepted for `accepted_wait_seconds` is renewed
+    fn renew_after(&self, accepted_wait_seconds: u32) -> u32 {
+        // Renew halfway through if the registration is too short for the margin
+        let margin = self
+            .renew_before_expiry_seconds
+            .min(accepted_wait_seconds / 2);
+        accepted_wait_seconds - margin
+    }
+
+    /// Seconds to wait before retrying after `failures` consecutive failures
+    fn retry_after(&self, failures: u32) -> u32 {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.retry_initial_seconds
+            .saturating_mul(factor)
+            .min(self.retry_max_seconds)
+    }
+}
+
+async fn load_u32_metadata(
+    udt: &OwnerServiceUD,
+    guid: &Guid,
+    key: OwnershipVoucherStoreMetadataKey,
+) -> Result<Option<u32>> {
+    let value = udt
+        .ownership_voucher_store
+        .load_metadata(guid, &fdo_store::MetadataKey::Local(key))
+        .await?;
+    Ok(value.and_then(|value| String::from_utf8_lossy(&value).parse().ok()))
 }
 
 async fn report_to_rendezvous(udt: OwnerServiceUDT) -> Result<()> {
@@ -86,44 +203,114 @@ async fn report_to_rendezvous(udt: OwnerServiceUDT) -> Result<()> {
     let ov_iter = ft.query().await?;
     if let Some(ovs) = ov_iter {
         for ov in ovs {
-            match report_ov_to_rendezvous(&ov, &udt.owner_addresses, &udt.owner_key).await {
-                Ok(wait_seconds) => {
-                    udt.ownership_voucher_store
-                        .store_metadata(
-                            ov.header().guid(),
-                            &fdo_store::MetadataKey::Local(
-                                OwnershipVoucherStoreMetadataKey::To0AcceptOwnerWaitSeconds,
-                            ),
-                            &time::Duration::new(wait_seconds.into(), 0),
-                        )
-                        .await?;
-                }
+            let guid = ov.header().guid();
+            let result = report_voucher_to_rendezvous(&udt, &ov).await;
+            let (next_attempt, failures, last_result) = match result {
+                Ok(accepted_wait_seconds) => (
+                    udt.to0_policy.renew_after(accepted_wait_seconds),
+                    0,
+                    format!("registered for {} seconds", accepted_wait_seconds),
+                ),
                 Err(e) => {
+                    let failures = match load_u32_metadata(
+                        &udt,
+                        guid,
+                        OwnershipVoucherStoreMetadataKey::To0Failures,
+                    )
+                    .await
+                    {
+                        Ok(failures) => failures.unwrap_or(0),
+                        Err(load_err) => {
+                            log::warn!(
+                                "OV({}): error loading TO0 failure count: {:?}",
+                                guid.to_string(),
+                                load_err
+                            );
+                            0
+                        }
+                    }
+                    .saturating_add(1);
+                    let retry_after = udt.to0_policy.retry_after(failures);
                     log::warn!(
-                        "OV({}): failed to report to rendezvous: {}",
-                        ov.header().guid().to_string(),
+                        "OV({}): failed to report to rendezvous (attempt {}, retrying in {} seconds): {}",
+                        guid.to_string(),
+                        failures,
+                        retry_after,
                         e
                     );
+                    (retry_after, failures, format!("failed: {}", e))
                 }
             };
+
+            let metadata: [(
+                OwnershipVoucherStoreMetadataKey,
+                &dyn fdo_store::MetadataValue,
+            ); 3] = [
+                (
+                    OwnershipVoucherStoreMetadataKey::To0AcceptOwnerWaitSeconds,
+                    &time::Duration::new(next_attempt.into(), 0),
+                ),
+                (OwnershipVoucherStoreMetadataKey::To0Failures, &failures),
+                (
+                    OwnershipVoucherStoreMetadataKey::To0LastResult,
+                    &format!(
+                        "{}: {}",
+                        time::OffsetDateTime::now_utc().unix_timestamp(),
+                        last_result
+                    ),
+                ),
+            ];
+            for (key, value) in metadata {
+                if let Err(e) = udt
+                    .ownership_voucher_store
+                    .store_metadata(guid, &fdo_store::MetadataKey::Local(key), value)
+                    .await
+                {
+                    log::error!(
+                        "OV({}): error storing TO0 result: {:?}",
+                        guid.to_string(),
+                        e
+                    );
+                    break;
+                }
+            }
         }
     }
     Ok(())
 }
 
+async fn report_voucher_to_rendezvous(udt: &OwnerServiceUD, ov: &OwnershipVoucher) -> Result<u32> {
+    let wait_seconds = load_u32_metadata(
+        udt,
+        ov.header().guid(),
+        OwnershipVoucherStoreMetadataKey::To0WaitSeconds,
+    )
+    .await
+    .context("Error loading TO0 wait seconds")?
+    .unwrap_or(udt.to0_policy.wait_seconds);
+
+    let owner_key = udt
+        .owner_key_for(ov)
+        .context("Error validating ownership voucher")?
+        .context("Voucher not owned by any of our owner keys")?;
+
+    report_ov_to_rendezvous(
+        ov,
+        &udt.owner_addresses,
+        &owner_key.private_key,
+        wait_seconds,
+    )
+    .await
+}
+
 async fn report_ov_to_rendezvous(
     ov: &OwnershipVoucher,
     owner_addresses: &[TO2AddressEntry],
     owner_key: &PKey<Private>,
+    wait_seconds: u32,
 ) -> Result<u32> {
     let ov_header = ov.header();
-    if ov_header.protocol_version() != ProtocolVersion::Version1_1 {
-        bail!(
-            "Protocol version in OV ({}) not supported ({})",
-            ov_header.protocol_version(),
-            ProtocolVersion::Version1_1
-        );
-    }
+    let protocol_version = ov_header.protocol_version();
     // Determine the RV IP
     let rv_info = ov_header
         .rendezvous_info()
@@ -150,53 +337,137 @@ async fn report_ov_to_rendezvous(
             );
 
             let mut rv_client =
-                fdo_http_wrapper::client::ServiceClient::new(ProtocolVersion::Version1_1, &rv_url);
-
-            // Send: Hello, Receive: HelloAck
-            let hello_ack: RequestResult<messages::v11::to0::HelloAck> = rv_client
-                .send_request(messages::v11::to0::Hello::new(), None)
-                .await;
+                fdo_http_wrapper::client::ServiceClient::new(protocol_version, &rv_url);
 
-            let hello_ack = match hello_ack {
-                Ok(hello_ack) => hello_ack,
-                Err(e) => {
-                    log::info!("Error requesting nonce from rendezvous server: {:?}", e);
-                    continue;
+            let accepted_wait_seconds = match protocol_version {
+                ProtocolVersion::Version1_0 => {
+                    perform_to0_v10(&mut rv_client, ov, owner_addresses, owner_key, wait_seconds)
+                        .await?
                 }
+                ProtocolVersion::Version1_1 => {
+                    perform_to0_v11(&mut rv_client, ov, owner_addresses, owner_key, wait_seconds)
+                        .await?
+                }
+                _ => bail!(
+                    "Protocol version in OV ({}) not supported",
+                    protocol_version
+                ),
+            };
+            let accepted_wait_seconds = match accepted_wait_seconds {
+                Some(accepted_wait_seconds) => accepted_wait_seconds,
+                None => continue,
             };
-
-            // Build to0d and to1d
-            // TODO(runcom): 600 has to come from configuration
-            let to0d = TO0Data::new(ov.clone(), 600, hello_ack.nonce3().clone())
-                .context("Error creating to0d")?;
-            let to0d_vec = to0d.serialize_data().context("Error serializing TO0Data")?;
-            let to0d_hash =
-                Hash::from_data(HashType::Sha384, &to0d_vec).context("Error hashing to0d")?;
-            let to0d = ByteBuf::from(to0d_vec);
-            let to1d_payload = TO1DataPayload::new(Vec::from(owner_addresses), to0d_hash);
-            let to1d =
-                COSESign::new(&to1d_payload, None, owner_key).context("Error signing to1d")?;
-            // Send: OwnerSign, Receive: AcceptOwner
-            let msg = messages::v11::to0::OwnerSign::new(to0d, to1d)
-                .context("Error creating OwnerSign message")?;
-            let accept_owner: RequestResult<messages::v11::to0::AcceptOwner> =
-                rv_client.send_request(msg, None).await;
-            let accept_owner =
-                accept_owner.context("Error registering self to rendezvous server")?;
 
             // Done!
             log::info!(
-                "OV({}): Rendezvous server registered us for {} seconds",
+                "OV({}): Rendezvous server registered us for {} seconds (requested {})",
                 ov_header.guid().to_string(),
-                accept_owner.wait_seconds()
+                accepted_wait_seconds,
+                wait_seconds
             );
 
-            return Ok(accept_owner.wait_seconds());
+            return Ok(accepted_wait_seconds);
         }
     }
     bail!("Report to rendezvous not performed");
 }
 
+/// Builds the to0d and the signed to1d for registering an OV with nonce3
+fn build_to0d_to1d(
+    ov: &OwnershipVoucher,
+    owner_addresses: &[TO2AddressEntry],
+    owner_key: &PKey<Private>,
+    wait_seconds: u32,
+    nonce3: Nonce,
+) -> Result<(TO0Data, COSESign)> {
+    let to0d = TO0Data::new(ov.clone(), wait_seconds, nonce3).context("Error creating to0d")?;
+    let to0d_vec = to0d.serialize_data().context("Error serializing TO0Data")?;
+    let to0d_hash = Hash::from_data(HashType::Sha384, &to0d_vec).context("Error hashing to0d")?;
+    let to1d_payload = TO1DataPayload::new(Vec::from(owner_addresses), to0d_hash);
+    let to1d = COSESign::new(&to1d_payload, None, owner_key).context("Error signing to1d")?;
+
+    Ok((to0d, to1d))
+}
+
+/// Performs TO0 for a protocol 1.1 OV, returning None if no nonce could be requested
+async fn perform_to0_v11(
+    rv_client: &mut fdo_http_wrapper::client::ServiceClient,
+    ov: &OwnershipVoucher,
+    owner_addresses: &[TO2AddressEntry],
+    owner_key: &PKey<Private>,
+    wait_seconds: u32,
+) -> Result<Option<u32>> {
+    // Send: Hello, Receive: HelloAck
+    let hello_ack: RequestResult<messages::v11::to0::HelloAck> = rv_client
+        .send_request(messages::v11::to0::Hello::new(), None)
+        .await;
+
+    let hello_ack = match hello_ack {
+        Ok(hello_ack) => hello_ack,
+        Err(e) => {
+            log::info!("Error requesting nonce from rendezvous server: {:?}", e);
+            return Ok(None);
+        }
+    };
+
+    // Build to0d and to1d
+    let (to0d, to1d) = build_to0d_to1d(
+        ov,
+        owner_addresses,
+        owner_key,
+        wait_seconds,
+        hello_ack.nonce3().clone(),
+    )?;
+    let to0d = ByteBuf::from(to0d.serialize_data().context("Error serializing TO0Data")?);
+    // Send: OwnerSign, Receive: AcceptOwner
+    let msg = messages::v11::to0::OwnerSign::new(to0d, to1d)
+        .context("Error creating OwnerSign message")?;
+    let accept_owner: RequestResult<messages::v11::to0::AcceptOwner> =
+        rv_client.send_request(msg, None).await;
+    let accept_owner = accept_owner.context("Error registering self to rendezvous server")?;
+
+    Ok(Some(accept_owner.wait_seconds()))
+}
+
+/// Performs TO0 for a protocol 1.0 OV, returning None if no nonce could be requested
+async fn perform_to0_v10(
+    rv_client: &mut fdo_http_wrapper::client::ServiceClient,
+    ov: &OwnershipVoucher,
+    owner_addresses: &[TO2AddressEntry],
+    owner_key: &PKey<Private>,
+    wait_seconds: u32,
+) -> Result<Option<u32>> {
+    // Send: Hello, Receive: HelloAck
+    let hello_ack: RequestResult<messages::v10::to0::HelloAck> = rv_client
+        .send_request(messages::v10::to0::Hello::new(), None)
+        .await;
+
+    let hello_ack = match hello_ack {
+        Ok(hello_ack) => hello_ack,
+        Err(e) => {
+            log::info!("Error requesting nonce from rendezvous server: {:?}", e);
+            return Ok(None);
+        }
+    };
+
+    // Build to0d and to1d
+    let (to0d, to1d) = build_to0d_to1d(
+        ov,
+        owner_addresses,
+        owner_key,
+        wait_seconds,
+        hello_ack.nonce3().clone(),
+    )?;
+    // Send: OwnerSign, Receive: AcceptOwner
+    let msg = messages::v10::to0::OwnerSign::new(to0d, to1d)
+        .context("Error creating OwnerSign message")?;
+    let accept_owner: RequestResult<messages::v10::to0::AcceptOwner> =
+        rv_client.send_request(msg, None).await;
+    let accept_owner = accept_owner.context("Error registering self to rendezvous server")?;
+
+    Ok(Some(accept_owner.wait_seconds()))
+}
+
 const MAINTENANCE_INTERVAL: u64 = 60;
 
 async fn perform_maintenance(udt: OwnerServiceUDT) -> std::result::Result<(), &'static str> {
@@ -227,17 +498,51 @@ async fn perform_maintenance(udt: OwnerServiceUDT) -> std::result::Result<(), &'
     }
 }
 
-/// Generate an ephemeral owner2 key: we do not support reuse or resale protocols
-fn generate_owner2_keys() -> Result<(PKey<Private>, PublicKey)> {
+fn load_owner_key(
+    private_key_path: &PrivateKeyLocation,
+    public_key_path: &AbsolutePathBuf,
+) -> Result<OwnerKey> {
+    let private_key = load_private_key(private_key_path)
+        .with_context(|| format!("Error loading owner key from {}", private_key_path))?;
+    let public_key = {
+        let contents = std::fs::read(public_key_path)
+            .with_context(|| format!("Error reading owner public key from {}", public_key_path))?;
+        PublicKey::try_from(X509::from_pem(&contents).context("Error parsing owner public key")?)
+            .context("Error converting owner public key to PK")?
+    };
+    if !private_key.public_eq(public_key.pkey()) {
+        bail!(
+            "Owner key {} does not match the public key in {}",
+            private_key_path,
+            public_key_path
+        );
+    }
+    Ok(OwnerKey {
+        private_key,
+        public_key,
+    })
+}
+
+fn parse_suites<T>(names: &[String]) -> Result<Vec<T>>
+where
+    T: std::str::FromStr<Err = fdo_data_formats::Error>,
+{
+    names
+        .iter()
+        .map(|name| T::from_str(name).map_err(anyhow::Error::from))
+        .collect()
+}
+
+/// Generate a new owner2 key, with a self-signed certificate
+fn generate_owner2_key(common_name: &str) -> Result<(PKey<Private>, X509)> {
     let owner2_key_group =
         EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).context("Error getting nist 256 group")?;
     let owner2_key = EcKey::generate(&owner2_key_group).context("Error generating owned2 key")?;
     let owner2_key =
         PKey::from_ec_key(owner2_key).context("Error converting owner2 key to PKey")?;
 
-    // Create an ephemeral certificate
     let mut subject = X509NameBuilder::new()?;
-    subject.append_entry_by_text("CN", "Ephemeral Owner2 Key")?;
+    subject.append_entry_by_text("CN", common_name)?;
     let subject = subject.build();
 
     let serial = BigNum::from_u32(42)?;
@@ -253,12 +558,52 @@ fn generate_owner2_keys() -> Result<(PKey<Private>, PublicKey)> {
     builder.set_serial_number(&serial)?;
     builder.sign(&owner2_key, MessageDigest::sha384())?;
 
-    let owner2_cert = builder.build();
+    Ok((owner2_key, builder.build()))
+}
+
+/// Load the configured owner2 key, generating it on first start.
+///
+/// Without configuration, an ephemeral key is generated: replacement
+/// credentials and resale vouchers then become unusable after a restart.
+fn load_owner2_key(key_settings: Option<&OwnerKeySettings>) -> Result<OwnerKey> {
+    let key_settings = match key_settings {
+        Some(key_settings) => key_settings,
+        None => {
+            log::warn!("No owner2_key configured, generating an ephemeral Owner2Key");
+            let (private_key, cert) = generate_owner2_key("Ephemeral Owner2 Key")?;
+            let public_key =
+                PublicKey::try_from(cert).context("Error converting ephemeral owner2 key to PK")?;
+            return Ok(OwnerKey {
+                private_key,
+                public_key,
+            });
+        }
+    };
 
-    let pubkey =
-        PublicKey::try_from(owner2_cert).context("Error converting ephemeral owner2 key to PK")?;
+    if let PrivateKeyLocation::File(private_key_path) = &key_settings.private_key_path {
+        let private_key_path: &Path = private_key_path.as_ref();
+        let public_key_path: &Path = key_settings.public_key_path.as_ref();
+        if !private_key_path.exists() && !public_key_path.exists() {
+            log::info!("Generating new Owner2Key at {}", private_key_path.display());
+            let (private_key, cert) = generate_owner2_key("Owner2 Key")?;
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(private_key_path)
+                .with_context(|| format!("Error creating {}", private_key_path.display()))?;
+            file.write_all(&private_key.private_key_to_der()?)
+                .context("Error writing owner2 private key")?;
+            std::fs::write(public_key_path, cert.to_pem()?)
+                .context("Error writing owner2 certificate")?;
+        }
+    }
 
-    Ok((owner2_key, pubkey))
+    load_owner_key(
+        &key_settings.private_key_path,
+        &key_settings.public_key_path,
+    )
+    .context("Error loading owner2 key")
 }
 
 #[tokio::main]
@@ -292,39 +637,54 @@ async fn main() -> Result<()> {
     };
     let trusted_device_keys = X5Bag::with_certs(trusted_device_keys)
         .context("Error building trusted device keys X5Bag")?;
-
-    // Our private key
-    let owner_key = load_private_key(&settings.owner_private_key_path).with_context(|| {
-        format!(
-            "Error loading owner key from {}",
-            &settings.owner_private_key_path
-        )
-    })?;
-    let owner_pubkey = {
-        let contents = std::fs::read(&settings.owner_public_key_path).with_context(|| {
-            format!(
-                "Error reading owner public key from {}",
-                &settings.owner_public_key_path
-            )
-        })?;
-        PublicKey::try_from(X509::from_pem(&contents).context("Error parsing owner public key")?)
-            .context("Error converting owner public key to PK")?
+    let trusted_device_keys = if settings.allow_untrusted_device_certificates {
+        log::warn!("Accepting device certificates without verifying them against trusted CAs");
+        None
+    } else {
+        Some(trusted_device_keys)
     };
 
+    // Our owner keys
+    let mut owner_keys = vec![load_owner_key(
+        &settings.owner_private_key_path,
+        &settings.owner_public_key_path,
+    )?];
+    for key_settings in &settings.additional_owner_keys {
+        owner_keys.push(load_owner_key(
+            &key_settings.private_key_path,
+            &key_settings.public_key_path,
+        )?);
+    }
+
     // Initialize stores
     let ownership_voucher_store = settings
         .ownership_voucher_store_driver
         .initialize()
         .context("Error initializing ownership voucher datastore")?;
+    let resale_ownership_voucher_store = match &settings.resale_ownership_voucher_store_driver {
+        Some(driver) => Some(
+            driver
+                .initialize()
+                .context("Error initializing resale ownership voucher datastore")?,
+        ),
+        None => None,
+    };
     let session_store = settings
         .session_store_driver
         .initialize()
         .context("Error initializing session store")?;
     let session_store = fdo_http_wrapper::server::SessionStore::new(session_store);
 
-    // Generate a new Owner2
-    let (owner2_key, owner2_pub) =
-        generate_owner2_keys().context("Error generating new owner2 keys")?;
+    let owner2_key = load_owner2_key(settings.owner2_key.as_ref())?;
+
+    let allowed_kex_suites = match &settings.allowed_kex_suites {
+        Some(names) => Some(parse_suites(names).context("Invalid allowed_kex_suites")?),
+        None => None,
+    };
+    let allowed_cipher_suites = match &settings.allowed_cipher_suites {
+        Some(names) => Some(parse_suites(names).context("Invalid allowed_cipher_suites")?),
+        None => None,
+    };
 
     let mut owner_addresses: Vec<TO2AddressEntry> = Vec::new();
     for oa in settings.owner_addresses {
@@ -345,24 +705,28 @@ async fn main() -> Result<()> {
     let user_data = Arc::new(OwnerServiceUD {
         // Stores
         ownership_voucher_store,
+        resale_ownership_voucher_store,
         session_store: session_store.clone(),
 
         // Trusted keys
         trusted_device_keys,
 
-        // Private owner key
-        owner_key,
-        owner_pubkey,
+        // Owner keys
+        owner_keys,
 
-        // Ephemeral owner2 key
         owner2_key,
-        owner2_pub,
+        credential_reuse: settings.credential_reuse,
+        allowed_kex_suites,
+        allowed_cipher_suites,
 
         // Service Info
         service_info_api_client,
 
         // Owner addresses
         owner_addresses,
+
+        // Report to rendezvous policy
+        to0_policy: To0Policy::from(&settings.to0),
     });
 
     // Initialize handlers
@@ -376,36 +740,72 @@ async fn main() -> Result<()> {
         session_store.clone(),
         handlers::hello_device,
     );
+    let handler_to2_hello_device_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::hello_device_v10,
+    );
     let handler_to2_get_ov_next_entry = fdo_http_wrapper::server::fdo_request_filter(
         ProtocolVersion::Version1_1,
         user_data.clone(),
         session_store.clone(),
         handlers::get_ov_next_entry,
     );
+    let handler_to2_get_ov_next_entry_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::get_ov_next_entry_v10,
+    );
     let handler_to2_prove_device = fdo_http_wrapper::server::fdo_request_filter(
         ProtocolVersion::Version1_1,
         user_data.clone(),
         session_store.clone(),
         handlers::prove_device,
     );
+    let handler_to2_prove_device_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::prove_device_v10,
+    );
     let handler_to2_device_service_info_ready = fdo_http_wrapper::server::fdo_request_filter(
         ProtocolVersion::Version1_1,
         user_data.clone(),
         session_store.clone(),
         handlers::device_service_info_ready,
     );
+    let handler_to2_device_service_info_ready_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::device_service_info_ready_v10,
+    );
     let handler_to2_device_service_info = fdo_http_wrapper::server::fdo_request_filter(
         ProtocolVersion::Version1_1,
         user_data.clone(),
         session_store.clone(),
         handlers::device_service_info,
     );
+    let handler_to2_device_service_info_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::device_service_info_v10,
+    );
     let handler_to2_done = fdo_http_wrapper::server::fdo_request_filter(
         ProtocolVersion::Version1_1,
         user_data.clone(),
         session_store.clone(),
         handlers::done,
     );
+    let handler_to2_done_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::done_v10,
+    );
 
     let rtr_enabled = settings.report_to_rendezvous_endpoint_enabled;
     let ud = user_data.clone();
@@ -415,44 +815,175 @@ async fn main() -> Result<()> {
         .untuple_one()
         .and_then(handlers::report_to_rendezvous_handler);
 
-    let routes = warp::post()
-        .and(
-            hello
-                .or(handler_ping)
-                .or(handler_report_to_rendezvous)
-                // TO2
-                .or(handler_to2_hello_device)
-                .or(handler_to2_get_ov_next_entry)
-                .or(handler_to2_prove_device)
-                .or(handler_to2_device_service_info_ready)
-                .or(handler_to2_device_service_info)
-                .or(handler_to2_done),
-        )
+    // Ownership Voucher Management API
+    if let Some(ov_management_api) = &settings.ov_management_api {
+        let has_client_ca = settings
+            .tls
+            .as_ref()
+            .and_then(|tls| tls.client_ca_path.as_ref())
+            .is_some();
+        if ov_management_api.client_certificate_authentication && !has_client_ca {
+            bail!("Management API client certificate authentication requires tls.client_ca_path");
+        }
+    }
+    let handler_management =
+        management::routes(user_data.clone(), settings.ov_management_api.as_ref());
+
+    let fdo_routes = warp::post().and(
+        hello
+            .or(handler_ping)
+            .or(handler_report_to_rendezvous)
+            // TO2
+            .or(handler_to2_hello_device)
+            .or(handler_to2_get_ov_next_entry)
+            .or(handler_to2_prove_device)
+            .or(handler_to2_device_service_info_ready)
+            .or(handler_to2_device_service_info)
+            .or(handler_to2_done)
+            .or(handler_to2_hello_device_v10)
+            .or(handler_to2_get_ov_next_entry_v10)
+            .or(handler_to2_prove_device_v10)
+            .or(handler_to2_device_service_info_ready_v10)
+            .or(handler_to2_device_service_info_v10)
+            .or(handler_to2_done_v10),
+    );
+
+    // The management API is only served over HTTP
+    let coap_server = settings
+        .coap_bind
+        .clone()
+        .map(|coap_bind| {
+            bind_coap_server(
+                fdo_routes
+                    .clone()
+                    .recover(fdo_http_wrapper::server::handle_rejection)
+                    .with(warp::log("owner-onboarding-service"))
+                    .boxed(),
+                coap_bind.into(),
+            )
+        })
+        .transpose()?;
+
+    let routes = fdo_routes
+        .or(handler_management)
         .recover(fdo_http_wrapper::server::handle_rejection)
         .with(warp::log("owner-onboarding-service"));
 
-    log::info!("Listening on {}", bind_addr);
-    let server = warp::serve(routes);
+    let server = bind_server(
+        routes.boxed(),
+        bind_addr.into(),
+        settings.tls.as_ref(),
+        async {
+            signal(SignalKind::terminate()).unwrap().recv().await;
+            log::info!("Terminating");
+        },
+    )?;
 
     let maintenance_runner =
         tokio::spawn(async move { perform_maintenance(user_data.clone()).await });
 
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
-            signal(SignalKind::terminate()).unwrap().recv().await;
-            log::info!("Terminating");
-        })
-        .1;
     let server = tokio::spawn(server);
+    // The CoAP server stops along with the HTTP server
+    let coap_server = tokio::spawn(async move {
+        match coap_server {
+            Some(coap_server) => coap_server.await,
+            None => std::future::pending().await,
+        }
+    });
 
     #[allow(clippy::panic)]
     let _ = tokio::select!(
     _ = server => {
         log::info!("Server terminated");
     },
+    _ = coap_server => {
+        log::info!("CoAP server terminated");
+    },
     _ = maintenance_runner => {
         log::info!("Maintenance runner terminated");
     });
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{AbsolutePathBuf, CipherSuite, KexSuite, OwnerKeySettings, To0Policy, To0Settings};
+
+    #[test]
+    fn test_to0_policy() {
+        let policy = To0Policy::from(&To0Settings {
+            wait_seconds: None,
+            renew_before_expiry_seconds: Some(100),
+            retry_initial_seconds: Some(30),
+            retry_max_seconds: Some(200),
+        });
+        assert_eq!(policy.wait_seconds, 600);
+
+        assert_eq!(policy.renew_after(600), 500);
+        assert_eq!(policy.renew_after(120), 60);
+
+        assert_eq!(policy.retry_after(1), 30);
+        assert_eq!(policy.retry_after(2), 60);
+        assert_eq!(policy.retry_after(3), 120);
+        assert_eq!(policy.retry_after(4), 200);
+        assert_eq!(policy.retry_after(40), 200);
+    }
+
+    #[test]
+    fn test_parse_suites() {
+        let kex_suites: Vec<KexSuite> =
+            super::parse_suites(&["ECDH384".to_string(), "ECDH256".to_string()]).unwrap();
+        assert_eq!(kex_suites, vec![KexSuite::Ecdh384, KexSuite::Ecdh256]);
+        let cipher_suites: Vec<CipherSuite> =
+            super::parse_suites(&["A256GCM".to_string()]).unwrap();
+        assert_eq!(cipher_suites, vec![CipherSuite::A256Gcm]);
+
+        assert!(super::parse_suites::<KexSuite>(&["ECDH512".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_load_owner2_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let key_settings = OwnerKeySettings {
+            private_key_path: AbsolutePathBuf::new(dir.join("owner2_key.der"))
+                .unwrap()
+                .into(),
+            public_key_path: AbsolutePathBuf::new(dir.join("owner2_cert.pem")).unwrap(),
+        };
+
+        // Generated on first load, and the same key afterwards
+        let generated = super::load_owner2_key(Some(&key_settings)).unwrap();
+        let loaded = super::load_owner2_key(Some(&key_settings)).unwrap();
+        assert!(generated
+            .public_key
+            .matches_pkey(loaded.private_key.as_ref())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_load_owner_key_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let private_key_path = dir.join("owner_key.der");
+        let public_key_path = dir.join("owner_cert.pem");
+        let (private_key, cert) = super::generate_owner2_key("Owner Key").unwrap();
+        let (other_private_key, _) = super::generate_owner2_key("Other Owner Key").unwrap();
+        std::fs::write(&public_key_path, cert.to_pem().unwrap()).unwrap();
+        let private_key_location = AbsolutePathBuf::new(private_key_path.clone())
+            .unwrap()
+            .into();
+        let public_key_path = AbsolutePathBuf::new(public_key_path).unwrap();
+
+        std::fs::write(&private_key_path, private_key.private_key_to_der().unwrap()).unwrap();
+        assert!(super::load_owner_key(&private_key_location, &public_key_path).is_ok());
+
+        std::fs::write(
+            &private_key_path,
+            other_private_key.private_key_to_der().unwrap(),
+        )
+        .unwrap();
+        assert!(super::load_owner_key(&private_key_location, &public_key_path).is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Serialize;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection, Reply};
+
+use fdo_data_formats::{
+    ownershipvoucher::{OwnershipVoucher, VOUCHER_PEM_TAG},
+    types::Guid,
+    MaybeSerializable, Serializable,
+};
+use fdo_util::servers::{
+    configuration::owner_onboarding_server::OwnershipVoucherManagementApiSettings,
+    OwnershipVoucherStoreMetadataKey, TlsClientCertificate,
+};
+
+use crate::OwnerServiceUDT;
+
+/// Maximum size of an ownership voucher upload request body
+const MAX_UPLOAD_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Error codes as defined in the Ownership Voucher Management API specification
+#[derive(Debug, Serialize)]
+#[serde(
+    tag = "error_code",
+    content = "error_details",
+    rename_all = "snake_case"
+)]
+enum ManagementError {
+    IncompleteVoucher {
+        parsed_correctly: usize,
+    },
+    ParseError {
+        parsed_correctly: usize,
+        description: String,
+    },
+    InvalidNumberOfVouchers {
+        parsed: usize,
+    },
+    UnownedVoucher {
+        unowned: Vec<usize>,
+    },
+    InvalidVoucherSignatures {
+        invalid: Vec<InvalidVoucherSignature>,
+    },
+    UnknownDevice {
+        unknown: Vec<String>,
+    },
+
+    // Errors not specific to any operation
+    Unauthenticated {},
+    NotFound {},
+    InvalidRequest {
+        description: String,
+    },
+    PayloadTooLarge {},
+    InternalServerError {},
+}
+
+impl warp::reject::Reject for ManagementError {}
+
+impl ManagementError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ManagementError::Unauthenticated {} => StatusCode::UNAUTHORIZED,
+            ManagementError::NotFound {} => StatusCode::NOT_FOUND,
+            ManagementError::PayloadTooLarge {} => StatusCode::PAYLOAD_TOO_LARGE,
+            ManagementError::InternalServerError {} => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn invalid_request(description: &str) -> Self {
+        ManagementError::InvalidRequest {
+            description: description.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct InvalidVoucherSignature {
+    index: usize,
+    description: String,
+}
+
+#[derive(Debug, Serialize)]
+struct VoucherInfo {
+    guid: String,
+}
+
+async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Infallible> {
+    let local_err: ManagementError;
+
+    let err = if let Some(err) = err.find::<ManagementError>() {
+        err
+    } else if err.is_not_found() {
+        local_err = ManagementError::NotFound {};
+        &local_err
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        local_err = ManagementError::PayloadTooLarge {};
+        &local_err
+    } else {
+        log::debug!("Invalid management API request: {:?}", err);
+        local_err = ManagementError::invalid_request("Invalid request");
+        &local_err
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(err), err.status_code()).into_response())
+}
+
+// Compares in constant time, so the response time does not reveal how much of a token matched
+fn token_matches(valid: &str, token: &str) -> bool {
+    valid.len() == token.len() && openssl::memcmp::eq(valid.as_bytes(), token.as_bytes())
+}
+
+fn authenticate(
+    settings: &OwnershipVoucherManagementApiSettings,
+    auth_header: Option<String>,
+    client_certificate: Option<TlsClientCertificate>,
+) -> Result<String, Rejection> {
+    if let Some(auth_header) = auth_header {
+        let token = match auth_header.strip_prefix("Bearer ") {
+            Some(token) => token,
+            None => {
+                log::info!("Management API request with unsupported authorization");
+                return Err(warp::reject::custom(ManagementError::Unauthenticated {}));
+            }
+        };
+        return match settings
+            .bearer_tokens
+            .iter()
+            .find(|(_, valid)| token_matches(valid, token))
+        {
+            Some((username, _)) => {
+                log::info!(
+                    "Management API user {} authenticated by bearer token",
+                    username
+                );
+                Ok(username.clone())
+            }
+            None => {
+                log::warn!("Management API request with invalid bearer token");
+                Err(warp::reject::custom(ManagementError::Unauthenticated {}))
+            }
+        };
+    }
+
+    // The TLS layer already verified the certificate against the client CAs
+    match client_certificate {
+        Some(client_certificate) if settings.client_certificate_authentication => {
+            match client_certificate.common_name() {
+                Some(username) => {
+                    log::info!(
+                        "Management API user {} authenticated by client certificate",
+                        username
+                    );
+                    Ok(username)
+                }
+                None => {
+                    log::warn!("Management API request with client certificate without CN");
+                    Err(warp::reject::custom(ManagementError::Unauthenticated {}))
+                }
+            }
+        }
+        _ => {
+            log::info!("Management API request without credentials");
+            Err(warp::reject::custom(ManagementError::Unauthenticated {}))
+        }
+    }
+}
+
+/// Builds the Management API routes, under /management.
+///
+/// If the API is not configured, the returned filter rejects every request as not found.
+pub(crate) fn routes(
+    user_data: OwnerServiceUDT,
+    settings: Option<&OwnershipVoucherManagementApiSettings>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    let settings = match settings {
+        Some(settings) => Arc::new(settings.clone()),
+        None => {
+            return warp::any()
+                .and_then(|| async { Err::<warp::reply::Response, _>(warp::reject::not_found()) })
+                .boxed()
+        }
+    };
+
+    let ud = warp::any().map(move || user_data.clone());
+    let auth = warp::header::optional::<String>("authorization")
+        .and(warp::ext::optional::<TlsClientCertificate>())
+        .and_then(
+            move |auth_header: Option<String>, client_certificate: Option<TlsClientCertificate>| {
+                let settings = settings.clone();
+                async move { authenticate(&settings, auth_header, client_certificate) }
+            },
+        );
+
+    let upload = warp::path!("v1" / "ownership_voucher")
+        .and(warp::post())
+        .and(ud.clone())
+        .and(auth.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<usize>("x-number-of-vouchers"))
+        .and(warp::header::optional::<u32>("x-to0-wait-seconds"))
+        .and(warp::body::content_length_limit(MAX_UPLOAD_SIZE))
+        .and(warp::body::bytes())
+        .and_then(upload_vouchers);
+    let list = warp::path!("v1" / "ownership_voucher")
+        .and(warp::get())
+        .and(ud.clone())
+        .and(auth.clone())
+        .and_then(list_vouchers);
+    let delete = warp::path!("v1" / "ownership_voucher" / "delete")
+        .and(warp::post())
+        .and(ud)
+        .and(auth)
+        .and(warp::body::json())
+        .and_then(delete_vouchers);
+
+    warp::path("management")
+        .and(
+            upload
+                .or(list)
+                .unify()
+                .or(delete)
+                .unify()
+                .recover(handle_rejection)
+                .unify(),
+        )
+        .boxed()
+}
+
+fn parse_failure(parsed_correctly: usize, err: fdo_data_formats::Error) -> ManagementError {
+    match err {
+        fdo_data_formats::Error::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            ManagementError::IncompleteVoucher { parsed_correctly }
+        }
+        e => ManagementError::ParseError {
+            parsed_correctly,
+            description: e.to_string(),
+        },
+    }
+}
+
+fn parse_cbor_vouchers(mut data: &[u8]) -> Result<Vec<OwnershipVoucher>, ManagementError> {
+    let mut vouchers = Vec::new();
+
+    loop {
+        match OwnershipVoucher::maybe_deserialize_from_reader(&mut data) {
+            Ok(Some(ov)) => vouchers.push(ov),
+            Ok(None) => return Ok(vouchers),
+            Err(e) => return Err(parse_failure(vouchers.len(), e)),
+        }
+    }
+}
+
+fn parse_pem_vouchers(data: &[u8]) -> Result<Vec<OwnershipVoucher>, ManagementError> {
+    let blocks = pem::parse_many(data).map_err(|e| ManagementError::ParseError {
+        parsed_correctly: 0,
+        description: e.to_string(),
+    })?;
+
+    let mut vouchers = Vec::new();
+    for block in blocks {
+        if block.tag != VOUCHER_PEM_TAG {
+            return Err(ManagementError::ParseError {
+                parsed_correctly: vouchers.len(),
+                description: format!("Invalid PEM tag: {}", block.tag),
+            });
+        }
+        let ov = OwnershipVoucher::deserialize_data(&block.contents)
+            .map_err(|e| parse_failure(vouchers.len(), e))?;
+        vouchers.push(ov);
+    }
+
+    Ok(vouchers)
+}
+
+async fn upload_vouchers(
+    user_data: OwnerServiceUDT,
+    username: String,
+    content_type: Option<String>,
+    num_vouchers: Option<usize>,
+    to0_wait_seconds: Option<u32>,
+    body: Bytes,
+) -> Result<warp::reply::Response, Rejection> {
+    let vouchers = match content_type
+        .as_deref()
+        .and_then(|ct| ct.split(';').next())
+        .map(str::trim)
+    {
+        Some("application/cbor") => parse_cbor_vouchers(&body),
+        Some("application/x-pem-file") => parse_pem_vouchers(&body),
+        _ => Err(ManagementError::invalid_request("Unsupported Content-Type")),
+    };
+    let vouchers = match vouchers {
+        Ok(v) => v,
+        Err(e) => {
+            log::info!(
+                "User {} uploaded invalid ownership vouchers: {:?}",
+                username,
+                e
+            );
+            return Err(warp::reject::custom(e));
+        }
+    };
+
+    if let Some(num_vouchers) = num_vouchers {
+        if num_vouchers != vouchers.len() {
+            log::info!(
+                "User {} announced {} ownership vouchers, but uploaded {}",
+                username,
+                num_vouchers,
+                vouchers.len()
+            );
+            return Err(warp::reject::custom(
+                ManagementError::InvalidNumberOfVouchers {
+                    parsed: vouchers.len(),
+                },
+            ));
+        }
+    }
+
+    let mut invalid = Vec::new();
+    let mut unowned = Vec::new();
+    for (index, ov) in vouchers.iter().enumerate() {
+        match user_data.owner_key_for(ov) {
+            Ok(Some(_)) => {}
+            Ok(None) => unowned.push(index),
+            Err(e) => invalid.push(InvalidVoucherSignature {
+                index,
+                description: e.to_string(),
+            }),
+        }
+    }
+    if !invalid.is_empty() {
+        log::info!(
+            "User {} uploaded ownership vouchers with invalid signatures: {:?}",
+            username,
+            invalid
+        );
+        return Err(warp::reject::custom(
+            ManagementError::InvalidVoucherSignatures { invalid },
+        ));
+    }
+    if !unowned.is_empty() {
+        log::info!(
+            "User {} uploaded ownership vouchers not owned by us: {:?}",
+            username,
+            unowned
+        );
+        return Err(warp::reject::custom(ManagementError::UnownedVoucher {
+            unowned,
+        }));
+    }
+
+    let mut stored = Vec::new();
+    for ov in vouchers {
+        let guid = ov.header().guid().clone();
+        if let Err(e) = user_data
+            .ownership_voucher_store
+            .store_data(guid.clone(), ov)
+            .await
+        {
+            log::error!(
+                "Error storing ownership voucher for {}: {:?}",
+                guid.to_string(),
+                e
+            );
+            return Err(warp::reject::custom(
+                ManagementError::InternalServerError {},
+            ));
+        }
+        if let Some(to0_wait_seconds) = to0_wait_seconds {
+            if let Err(e) = user_data
+                .ownership_voucher_store
+                .store_metadata(
+                    &guid,
+                    &fdo_store::MetadataKey::Local(
+                        OwnershipVoucherStoreMetadataKey::To0WaitSeconds,
+                    ),
+                    &to0_wait_seconds,
+                )
+                .await
+            {
+                log::error!(
+                    "Error storing TO0 wait seconds for {}: {:?}",
+                    guid.to_string(),
+                    e
+                );
+                return Err(warp::reject::custom(
+                    ManagementError::InternalServerError {},
+                ));
+            }
+        }
+        log::info!(
+            "User {} uploaded ownership voucher for device {}",
+            username,
+            guid.to_string()
+        );
+        stored.push(VoucherInfo {
+            guid: guid.to_string(),
+        });
+    }
+
+    Ok(warp::reply::with_status(warp::reply::json(&stored), StatusCode::CREATED).into_response())
+}
+
+async fn list_vouchers(
+    user_data: OwnerServiceUDT,
+    username: String,
+) -> Result<warp::reply::Response, Rejection> {
+    let vouchers = match user_data.ownership_voucher_store.query_data().await {
+        Ok(ft) => ft.query().await,
+        Err(e) => Err(e),
+    };
+    let vouchers: Vec<VoucherInfo> = match vouchers {
+        Ok(vouchers) => vouchers
+            .into_iter()
+            .flatten()
+            .map(|ov| VoucherInfo {
+                guid: ov.header().guid().to_string(),
+            })
+            .collect(),
+        Err(e) => {
+            log::error!("Error listing ownership vouchers: {:?}", e);
+            return Err(warp::reject::custom(
+                ManagementError::InternalServerError {},
+            ));
+        }
+    };
+
+    log::info!(
+        "User {} listed ownership vouchers ({} found)",
+        username,
+        vouchers.len()
+    );
+
+    Ok(warp::reply::json(&vouchers).into_response())
+}
+
+async fn delete_vouchers(
+    user_data: OwnerServiceUDT,
+    username: String,
+    guids: Vec<String>,
+) -> Result<warp::reply::Response, Rejection> {
+    let mut known = Vec::new();
+    let mut unknown = Vec::new();
+    for guid_str in guids {
+        let guid = match Guid::from_str(&guid_str) {
+            Ok(guid) => guid,
+            Err(_) => {
+                unknown.push(guid_str);
+                continue;
+            }
+        };
+        match user_data.ownership_voucher_store.load_data(&guid).await {
+            Ok(Some(_)) => known.push(guid),
+            Ok(None) => unknown.push(guid_str),
+            Err(e) => {
+                log::error!("Error loading ownership voucher for {}: {:?}", guid_str, e);
+                return Err(warp::reject::custom(
+                    ManagementError::InternalServerError {},
+                ));
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        log::info!(
+            "User {} attempted to delete unknown ownership vouchers: {:?}",
+            username,
+            unknown
+        );
+        return Err(warp::reject::custom(ManagementError::UnknownDevice {
+            unknown,
+        }));
+    }
+
+    for guid in known {
+        if let Err(e) = user_data.ownership_voucher_store.destroy_data(&guid).await {
+            log::error!(
+                "Error deleting ownership voucher for {}: {:?}",
+                guid.to_string(),
+                e
+            );
+            return Err(warp::reject::custom(
+                ManagementError::InternalServerError {},
+            ));
+        }
+        log::info!(
+            "User {} deleted ownership voucher for device {}",
+            username,
+            guid.to_string()
+        );
+    }
+
+    Ok(
+        warp::reply::with_header(warp::reply::reply(), "Content-Type", "application/json")
+            .into_response(),
+    )
+}
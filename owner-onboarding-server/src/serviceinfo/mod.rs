@@ -1,228 +1,794 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
 
-use anyhow::{Context, Error, Result};
-use serde::Deserialize;
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use fdo_data_formats::{
-    constants::HashType,
-    messages,
-    types::{Hash, ServiceInfo},
+    constants::{FedoraIotServiceInfoModule, ServiceInfoModule, StandardServiceInfoModule},
+    messages::v11::to2::OwnerServiceInfo,
+    types::{CborSimpleType, ServiceInfo},
 };
 use fdo_http_wrapper::server::Session;
+use fdo_util::servers::{
+    ServiceInfoApiDeviceResults, ServiceInfoApiReply, ServiceInfoApiReplyConditionalCommands,
+};
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct ServiceInfoSettings {
-    rhsm_organization_id: Option<String>,
-    rhsm_activation_key: Option<String>,
-    rhsm_run_insights: Option<bool>,
-
-    sshkey_user: Option<String>,
-    sshkey_key: Option<String>,
+const STATE_SESSION_KEY: &str = "serviceinfo_state";
+const INSTRUCTIONS_SESSION_KEY: &str = "serviceinfo_instructions";
+const RESPONSES_SESSION_KEY: &str = "serviceinfo_responses";
 
-    files: Option<Vec<ServiceInfoFile>>,
+fn store_value<T: Serialize>(session: &mut Session, key: &str, value: &T) -> Result<()> {
+    let encoded = serde_cbor::to_vec(value).context("Error serializing ServiceInfo state")?;
+    session
+        .insert(key, hex::encode(encoded))
+        .context("Error storing ServiceInfo state")
+}
 
-    commands: Option<Vec<ServiceInfoCommand>>,
+fn load_value<T: DeserializeOwned>(session: &Session, key: &str) -> Result<T> {
+    let encoded: String = session
+        .get(key)
+        .with_context(|| format!("No {} in session", key))?;
+    let encoded = hex::decode(encoded).context("Error decoding ServiceInfo state")?;
+    serde_cbor::from_slice(&encoded).context("Error parsing ServiceInfo state")
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct ServiceInfoFile {
-    path: String,
-    permissions: Option<String>,
-    #[serde(skip)]
-    parsed_permissions: Option<u32>,
-    source_path: String,
+/// The instructions for a single owner module, sent to the device in one round
+#[derive(Debug, Serialize, Deserialize)]
+struct ModuleInstructions {
+    module: ServiceInfoModule,
+    service_info: ServiceInfo,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct ServiceInfoCommand {
-    command: String,
-    args: Vec<String>,
-    #[serde(default)]
-    may_fail: bool,
-    #[serde(default)]
-    return_stdout: bool,
-    #[serde(default)]
-    return_stderr: bool,
+impl ModuleInstructions {
+    fn new(module: ServiceInfoModule) -> Self {
+        ModuleInstructions {
+            module,
+            service_info: ServiceInfo::new(),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct ServiceInfoConfiguration {
-    settings: ServiceInfoSettings,
+/// Per-session state of the TO2 ServiceInfo exchange
+///
+/// Each round, the device gets the instructions of as many modules as fit in
+/// a single message, and the values it returns are collected. Once all
+/// instructions have been sent, any conditional commands matching the
+/// collected values are queued for further rounds, until there is nothing left
+/// to send.
+///
+/// Instructions that do not fit in the maximum message size of the device are
+/// sent over multiple messages, with IsMoreServiceInfo set on all but the last.
+///
+/// The queued instructions and the values returned by the device are each
+/// stored in the session once, so that only the cursor into them changes
+/// every round.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ServiceInfoState {
+    max_message_size: u64,
+    api_queried: bool,
+    device_modules: HashSet<ServiceInfoModule>,
+    /// Acknowledgements, to be sent before any further queued instructions
+    acks: VecDeque<ModuleInstructions>,
+    /// The number of queued instructions, and the index of the next one to send
+    queued: usize,
+    next: usize,
+    outgoing: VecDeque<ServiceInfo>,
+    conditionals: Vec<ServiceInfoApiReplyConditionalCommands>,
+    /// The number of device rounds stored, and the round still being received
+    rounds: usize,
+    incoming: ServiceInfo,
 }
 
-impl ServiceInfoConfiguration {
-    pub(crate) fn from_settings(mut settings: ServiceInfoSettings) -> Result<Self> {
-        // Perform checks on the configuration
+impl ServiceInfoState {
+    /// Creates the state, with the maximum OwnerServiceInfo size the device can receive
+    pub(crate) fn new(max_message_size: u64) -> Self {
+        ServiceInfoState {
+            max_message_size,
+            ..Default::default()
+        }
+    }
 
-        // Check permissions for files are valid
-        settings.files = if let Some(files) = settings.files {
-            let mut new_files = Vec::new();
+    pub(crate) fn from_session(session: &Session) -> Result<Self> {
+        load_value(session, STATE_SESSION_KEY)
+    }
 
-            for mut file in files {
-                let path = &file.path;
+    pub(crate) fn store_in_session(&self, session: &mut Session) -> Result<()> {
+        store_value(session, STATE_SESSION_KEY, self)
+    }
 
-                file.parsed_permissions = if let Some(permissions) = &file.permissions {
-                    Some(u32::from_str_radix(permissions, 8).with_context(|| {
-                        format!(
-                            "Invalid permission string for file {}: {} (invalid octal)",
-                            path, permissions
-                        )
-                    })?)
-                } else {
-                    None
-                };
+    pub(crate) fn device_modules(&self) -> Result<impl Iterator<Item = &ServiceInfoModule>> {
+        if self.device_modules.is_empty() {
+            bail!("No module list found in ServiceInfo");
+        }
+        Ok(self.device_modules.iter())
+    }
+
+    /// Whether the instructions still need to be retrieved from the ServiceInfo API
+    pub(crate) fn needs_api_reply(&self) -> bool {
+        !self.api_queried
+    }
+
+    /// Records a DeviceServiceInfo message, the round is complete once is_more is false
+    pub(crate) fn record_device_service_info(
+        &mut self,
+        session: &mut Session,
+        in_si: &ServiceInfo,
+        is_more: bool,
+    ) -> Result<()> {
+        self.incoming.extend(in_si.clone());
+        if is_more {
+            return Ok(());
+        }
+        let round = std::mem::take(&mut self.incoming)
+            .join_chunks()
+            .context("Error joining ServiceInfo chunks")?;
+
+        for (module, key, value) in round.iter() {
+            if module == StandardServiceInfoModule::DevMod.into() && key == "modules" {
+                let mut rawmodlist: Vec<CborSimpleType> =
+                    serde_cbor::value::from_value(value.clone())?;
+                log::trace!("Received module list: {:?}", rawmodlist);
 
-                new_files.push(file);
+                // Skip the first two items.... They are integers :()
+                for rawmod in rawmodlist.drain(..).skip(2) {
+                    let module: String = serde_cbor::value::from_value(rawmod)?;
+                    self.device_modules.insert(module.parse()?);
+                }
+                log::trace!("Module list: {:?}", self.device_modules);
             }
 
-            Some(new_files)
-        } else {
-            None
-        };
+            if module == StandardServiceInfoModule::FdoSys.into() && key == "status_cb" {
+                let (completed, _, wait_seconds): (bool, i64, u64) =
+                    serde_cbor::value::from_value(value.clone())
+                        .context("Error parsing fdo_sys status_cb")?;
+                if !completed {
+                    // Acknowledge the status, so the device keeps reporting until the command is done
+                    let mut instructions =
+                        ModuleInstructions::new(StandardServiceInfoModule::FdoSys.into());
+                    instructions.service_info.add(
+                        StandardServiceInfoModule::FdoSys,
+                        "active",
+                        &true,
+                    )?;
+                    instructions.service_info.add(
+                        StandardServiceInfoModule::FdoSys,
+                        "status_cb",
+                        &(false, 0, wait_seconds),
+                    )?;
+                    self.acks.push_back(instructions);
+                }
+            }
+        }
 
-        Ok(ServiceInfoConfiguration { settings })
+        if !round.is_empty() {
+            store_value(
+                session,
+                &format!("{}_{}", RESPONSES_SESSION_KEY, self.rounds),
+                &round,
+            )?;
+            self.rounds += 1;
+        }
+        Ok(())
     }
-}
 
-pub(crate) async fn perform_service_info(
-    user_data: super::OwnerServiceUDT,
-    _session: &mut Session,
-    msg: messages::v11::to2::DeviceServiceInfo,
-    loop_num: u32,
-) -> Result<messages::v11::to2::OwnerServiceInfo, Error> {
-    let in_si = msg.service_info();
-    let mut out_si = ServiceInfo::new();
-    let is_done = loop_num != 0;
-
-    log::trace!("Received ServiceInfo loop {}: {:?}", loop_num, in_si);
-
-    for (module, var, value) in in_si.iter() {
-        log::trace!("Received module {}, var {}, value {:?}", module, var, value);
-        if module == "devmod" && var == "modules" {
-            let mut rawmodlist: Vec<serde_cbor::Value> = serde_cbor::value::from_value(value)?;
-            log::trace!("Received module list: {:?}", rawmodlist);
-
-            // Skip the first two items.... They are integers :()
-            let mut modlist: HashSet<String> = HashSet::new();
-            for rawmod in rawmodlist.drain(..).skip(2) {
-                modlist.insert(serde_cbor::value::from_value(rawmod)?);
-            }
-            log::trace!("Module list: {:?}", modlist);
-
-            if modlist.contains("sshkey")
-                && user_data
-                    .service_info_configuration
-                    .settings
-                    .sshkey_user
-                    .is_some()
-            {
-                log::trace!("Found SSH key module, sending SSH key information");
-
-                out_si.add("sshkey", "active", &true)?;
-                out_si.add(
-                    "sshkey",
-                    "username",
-                    &user_data
-                        .service_info_configuration
-                        .settings
-                        .sshkey_user
-                        .as_ref()
-                        .unwrap(),
-                )?;
-                out_si.add(
-                    "sshkey",
-                    "key",
-                    &user_data
-                        .service_info_configuration
-                        .settings
-                        .sshkey_key
-                        .as_ref()
-                        .unwrap(),
-                )?;
+    /// All values returned by the device so far, in the order they were received
+    fn load_responses(&self, session: &Session) -> Result<ServiceInfo> {
+        let mut responses = ServiceInfo::new();
+        for round in 0..self.rounds {
+            let round: ServiceInfo =
+                load_value(session, &format!("{}_{}", RESPONSES_SESSION_KEY, round))?;
+            responses.extend(round);
+        }
+        Ok(responses)
+    }
+
+    fn queue(&mut self, session: &mut Session, instructions: &ModuleInstructions) -> Result<()> {
+        store_value(
+            session,
+            &format!("{}_{}", INSTRUCTIONS_SESSION_KEY, self.queued),
+            instructions,
+        )?;
+        self.queued += 1;
+        Ok(())
+    }
+
+    pub(crate) fn queue_api_reply(
+        &mut self,
+        session: &mut Session,
+        reply: ServiceInfoApiReply,
+    ) -> Result<()> {
+        self.api_queried = true;
+
+        if let Some(initial_user) = reply.initial_user {
+            let mut instructions =
+                ModuleInstructions::new(FedoraIotServiceInfoModule::SSHKey.into());
+            instructions
+                .service_info
+                .add(FedoraIotServiceInfoModule::SSHKey, "active", &true)?;
+            instructions.service_info.add(
+                FedoraIotServiceInfoModule::SSHKey,
+                "username",
+                &initial_user.username,
+            )?;
+            for key in initial_user.ssh_keys.iter() {
+                instructions
+                    .service_info
+                    .add(FedoraIotServiceInfoModule::SSHKey, "key", &key)?;
             }
+            self.queue(session, &instructions)?;
+        }
 
-            if modlist.contains("rhsm")
-                && user_data
-                    .service_info_configuration
-                    .settings
-                    .rhsm_organization_id
-                    .is_some()
-            {
-                log::trace!("Found RHSM module, sending RHSM information");
-
-                out_si.add("rhsm", "active", &true)?;
-                out_si.add(
-                    "rhsm",
-                    "organization_id",
-                    &user_data
-                        .service_info_configuration
-                        .settings
-                        .rhsm_organization_id
-                        .as_ref()
-                        .unwrap(),
-                )?;
-                out_si.add(
-                    "rhsm",
-                    "activation_key",
-                    &user_data
-                        .service_info_configuration
-                        .settings
-                        .rhsm_activation_key
-                        .as_ref()
-                        .unwrap(),
-                )?;
-                out_si.add(
-                    "rhsm",
-                    "perform_insights",
-                    &user_data
-                        .service_info_configuration
-                        .settings
-                        .rhsm_run_insights
-                        .as_ref()
-                        .unwrap(),
+        if let Some(extra_commands) = reply.extra_commands {
+            self.queue_commands(session, extra_commands)?;
+        }
+
+        if let Some(conditionals) = reply.conditional_commands {
+            self.conditionals.extend(conditionals);
+        }
+
+        Ok(())
+    }
+
+    fn queue_commands(
+        &mut self,
+        session: &mut Session,
+        commands: Vec<(ServiceInfoModule, String, serde_json::Value)>,
+    ) -> Result<()> {
+        let mut queued: Vec<ModuleInstructions> = Vec::new();
+        let mut data_chunks = 0;
+
+        for (module, key, value) in commands {
+            let instructions = match queued.iter().position(|i| i.module == module) {
+                Some(pos) => &mut queued[pos],
+                None => {
+                    let mut instructions = ModuleInstructions::new(module.clone());
+                    // The device only accepts instructions for modules activated in the same round
+                    if key != "active" {
+                        instructions
+                            .service_info
+                            .add(module.clone(), "active", &true)?;
+                    }
+                    queued.push(instructions);
+                    queued.last_mut().unwrap()
+                }
+            };
+
+            if key.ends_with("|hex") {
+                let value = hex::decode(
+                    value
+                        .as_str()
+                        .ok_or_else(|| anyhow::anyhow!("Invalid API response: non-hex"))?,
                 )?;
+                let key = key.replace("|hex", "");
+                if module == FedoraIotServiceInfoModule::BinaryFile.into()
+                    && key.starts_with("data")
+                {
+                    // Renumber the file contents into chunks that fit in a single message
+                    let chunk_size = self.data_chunk_size(&module, "data999")?;
+                    for chunk in value.chunks(chunk_size) {
+                        data_chunks += 1;
+                        instructions.service_info.add(
+                            module.clone(),
+                            &format!("data{:03}", data_chunks),
+                            &serde_bytes::Bytes::new(chunk),
+                        )?;
+                    }
+                } else if module == StandardServiceInfoModule::FdoSys.into() && key == "write" {
+                    // Writes append to the file, so they can be split freely
+                    let chunk_size = self.data_chunk_size(&module, "write")?;
+                    for chunk in value.chunks(chunk_size) {
+                        instructions.service_info.add(
+                            module.clone(),
+                            "write",
+                            &serde_bytes::Bytes::new(chunk),
+                        )?;
+                    }
+                } else {
+                    let value = serde_bytes::ByteBuf::from(value);
+                    instructions.service_info.add(module, &key, &value)?;
+                }
+            } else {
+                if module == FedoraIotServiceInfoModule::BinaryFile.into() && key == "name" {
+                    data_chunks = 0;
+                }
+                instructions.service_info.add(module, &key, &value)?;
             }
+        }
 
-            if modlist.contains("binaryfile") {
-                if let Some(files) = &user_data.service_info_configuration.settings.files {
-                    log::trace!("Found binaryfile module, sending files");
-
-                    out_si.add("binaryfile", "active", &true)?;
-                    for file in files {
-                        let contents = std::fs::read(&file.source_path)?;
-                        let hash = Hash::from_data(HashType::Sha384, &contents)?;
-
-                        out_si.add("binaryfile", "name", &file.path)?;
-                        out_si.add("binaryfile", "length", &contents.len())?;
-                        if let Some(parsed_permissions) = file.parsed_permissions {
-                            out_si.add("binaryfile", "mode", &parsed_permissions)?;
-                        }
-                        out_si.add("binaryfile", "data001", &serde_bytes::Bytes::new(&contents))?;
-                        out_si.add("binaryfile", "sha-384", &hash.value_bytes())?;
-                    }
+        for instructions in &queued {
+            self.queue(session, instructions)?;
+        }
+        Ok(())
+    }
+
+    /// Everything the device returned, for reporting back to the ServiceInfo API
+    pub(crate) fn device_results(&self, session: &Session) -> Result<ServiceInfoApiDeviceResults> {
+        let mut results = ServiceInfoApiDeviceResults::default();
+
+        for (module, key, value) in self.load_responses(session)?.iter() {
+            let (key, value) = match value {
+                CborSimpleType::Bytes(bytes) => (
+                    format!("{}|hex", key),
+                    serde_json::Value::String(hex::encode(bytes)),
+                ),
+                value => {
+                    let value = serde_json::to_value(&value)
+                        .with_context(|| format!("Error converting {}:{}", module, key))?;
+                    (key, value)
                 }
+            };
+            results.service_info.push((module, key, value));
+        }
+
+        Ok(results)
+    }
+
+    /// The largest data chunk for a module key that still fits in a single OwnerServiceInfo
+    fn data_chunk_size(&self, module: &ServiceInfoModule, key: &str) -> Result<usize> {
+        let mut empty = ServiceInfo::new();
+        empty.add(module.clone(), key, &serde_bytes::Bytes::new(&[]))?;
+        // The two byte string length headers grow by up to four bytes each with the data
+        let overhead = empty.message_size()? + 8;
+
+        match usize::try_from(self.max_message_size) {
+            Ok(max) if max > overhead => Ok(max - overhead),
+            _ => bail!(
+                "Maximum ServiceInfo size {} is too small",
+                self.max_message_size
+            ),
+        }
+    }
+
+    /// Queues the conditional commands whose condition has been met, returns whether there were any
+    fn queue_matching_conditionals(&mut self, session: &mut Session) -> Result<bool> {
+        if self.conditionals.is_empty() {
+            return Ok(false);
+        }
+        let responses = self.load_responses(session)?;
+        let has_response = |cond: &ServiceInfoApiReplyConditionalCommands| {
+            responses.iter().any(|(module, key, value)| {
+                module == cond.module
+                    && key == cond.key
+                    && serde_json::to_value(&value).ok().as_ref() == Some(&cond.value)
+            })
+        };
+        let (matching, remaining): (Vec<_>, Vec<_>) = std::mem::take(&mut self.conditionals)
+            .into_iter()
+            .partition(has_response);
+        self.conditionals = remaining;
+
+        let any_matched = !matching.is_empty();
+        for cond in matching {
+            log::trace!(
+                "Device returned {}:{} = {}, queueing conditional commands",
+                cond.module,
+                cond.key,
+                cond.value
+            );
+            self.queue_commands(session, cond.commands)?;
+        }
+        Ok(any_matched)
+    }
+
+    /// The next instructions to send, without taking them from the queue
+    fn peek_instructions(&self, session: &Session) -> Result<Option<ModuleInstructions>> {
+        if let Some(ack) = self.acks.front() {
+            return Ok(Some(ModuleInstructions {
+                module: ack.module.clone(),
+                service_info: ack.service_info.clone(),
+            }));
+        }
+        if self.next == self.queued {
+            return Ok(None);
+        }
+        load_value(
+            session,
+            &format!("{}_{}", INSTRUCTIONS_SESSION_KEY, self.next),
+        )
+        .map(Some)
+    }
+
+    fn advance_instructions(&mut self) {
+        if self.acks.pop_front().is_none() {
+            self.next += 1;
+        }
+    }
+
+    /// Determines what to send to the device in the next round
+    pub(crate) fn next_round(&mut self, session: &mut Session) -> Result<OwnerServiceInfo> {
+        if self.outgoing.is_empty() {
+            let mut first = self.peek_instructions(session)?;
+            if first.is_none() && self.queue_matching_conditionals(session)? {
+                first = self.peek_instructions(session)?;
             }
+            let ModuleInstructions {
+                module,
+                mut service_info,
+            } = match first {
+                Some(first) => first,
+                None => {
+                    log::trace!(
+                        "ServiceInfo exchange done, {} conditional commands not matched",
+                        self.conditionals.len(),
+                    );
+                    return Ok(OwnerServiceInfo::new(false, true, ServiceInfo::new()));
+                }
+            };
+            self.advance_instructions();
+            let mut modules = vec![module];
 
-            if modlist.contains("command") {
-                if let Some(commands) = &user_data.service_info_configuration.settings.commands {
-                    log::trace!("Found command module, sending commands");
-
-                    out_si.add("command", "active", &true)?;
-                    for command in commands {
-                        out_si.add("command", "command", &command.command)?;
-                        out_si.add("command", "args", &command.args)?;
-                        out_si.add("command", "may_fail", &command.may_fail)?;
-                        out_si.add("command", "return_stdout", &command.return_stdout)?;
-                        out_si.add("command", "return_stderr", &command.return_stderr)?;
-                        out_si.add("command", "execute", &true)?;
-                    }
+            // Add the instructions of the next modules, as long as everything fits in one message
+            let max_message_size = usize::try_from(self.max_message_size).unwrap_or(usize::MAX);
+            while let Some(next) = self.peek_instructions(session)? {
+                if modules.contains(&next.module) {
+                    break;
                 }
+                let mut packed = service_info.clone();
+                packed.extend(next.service_info);
+                if packed.message_size()? > max_message_size {
+                    break;
+                }
+                service_info = packed;
+                self.advance_instructions();
+                modules.push(next.module);
             }
+
+            log::trace!("Sending instructions for modules {:?}", modules);
+            self.outgoing = service_info
+                .split(self.max_message_size)
+                .with_context(|| format!("Error splitting instructions for {:?}", modules))?
+                .into();
         }
+
+        let service_info = self.outgoing.pop_front().unwrap();
+        Ok(OwnerServiceInfo::new(
+            !self.outgoing.is_empty(),
+            false,
+            service_info,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fdo_data_formats::{
+        constants::{
+            FedoraIotServiceInfoModule, ServiceInfoModule, StandardServiceInfoModule,
+            DEFAULT_MAX_SERVICE_INFO_SIZE,
+        },
+        types::{CborSimpleTypeExt, ServiceInfo},
+    };
+    use fdo_util::servers::{
+        ServiceInfoApiReply, ServiceInfoApiReplyConditionalCommands, ServiceInfoApiReplyInitialUser,
+    };
+
+    use fdo_http_wrapper::server::Session;
+
+    use super::ServiceInfoState;
+
+    fn command(
+        key: &str,
+        value: serde_json::Value,
+    ) -> (ServiceInfoModule, String, serde_json::Value) {
+        (
+            FedoraIotServiceInfoModule::Command.into(),
+            key.to_string(),
+            value,
+        )
     }
 
-    log::trace!("Sending ServiceInfo loop {}: {:?}", loop_num, out_si);
-    Ok(messages::v11::to2::OwnerServiceInfo::new(
-        false, is_done, out_si,
-    ))
+    fn new_state(max_message_size: u64) -> (ServiceInfoState, Session) {
+        let mut devmod = ServiceInfo::new();
+        devmod
+            .add(StandardServiceInfoModule::DevMod, "active", &true)
+            .unwrap();
+        devmod
+            .add_modules(&[
+                StandardServiceInfoModule::DevMod.into(),
+                FedoraIotServiceInfoModule::BinaryFile.into(),
+                FedoraIotServiceInfoModule::Command.into(),
+            ])
+            .unwrap();
+        let mut session = Session::new();
+        let mut state = ServiceInfoState::new(max_message_size);
+        state
+            .record_device_service_info(&mut session, &devmod, false)
+            .unwrap();
+        assert!(state.needs_api_reply());
+        assert_eq!(state.device_modules().unwrap().count(), 3);
+        (state, session)
+    }
+
+    #[test]
+    fn test_conditional_rounds() {
+        let (mut state, mut session) = new_state(DEFAULT_MAX_SERVICE_INFO_SIZE);
+
+        state
+            .queue_api_reply(
+                &mut session,
+                ServiceInfoApiReply {
+                    initial_user: None,
+                    extra_commands: Some(vec![
+                        command("command", serde_json::json!("/usr/bin/true")),
+                        command("execute", serde_json::json!(true)),
+                    ]),
+                    conditional_commands: Some(vec![ServiceInfoApiReplyConditionalCommands {
+                        module: FedoraIotServiceInfoModule::Command.into(),
+                        key: "exit_code".to_string(),
+                        value: serde_json::json!(0),
+                        commands: vec![
+                            command("command", serde_json::json!("/usr/bin/reboot")),
+                            command("execute", serde_json::json!(true)),
+                        ],
+                    }]),
+                },
+            )
+            .unwrap();
+
+        let round = state.next_round(&mut session).unwrap();
+        assert!(!round.is_done());
+        let keys: Vec<String> = round.service_info().iter().map(|(_, k, _)| k).collect();
+        assert_eq!(keys, vec!["active", "command", "execute"]);
+
+        let mut result = ServiceInfo::new();
+        result
+            .add(FedoraIotServiceInfoModule::Command, "exit_code", &Some(0))
+            .unwrap();
+        result
+            .add(
+                FedoraIotServiceInfoModule::Command,
+                "stdout",
+                &serde_bytes::Bytes::new(b"ok"),
+            )
+            .unwrap();
+        state
+            .record_device_service_info(&mut session, &result, false)
+            .unwrap();
+
+        // Results are reported in the order the device returned them
+        let results = state.device_results(&session).unwrap();
+        assert_eq!(
+            results.service_info[results.service_info.len() - 2..],
+            [
+                command("exit_code", serde_json::json!(0)),
+                command("stdout|hex", serde_json::json!("6f6b")),
+            ]
+        );
+
+        state.store_in_session(&mut session).unwrap();
+        let mut state = ServiceInfoState::from_session(&session).unwrap();
+
+        let round = state.next_round(&mut session).unwrap();
+        assert!(!round.is_done());
+        assert!(round.service_info().iter().any(|(_, k, v)| k == "command"
+            && v == serde_cbor::Value::Text("/usr/bin/reboot".to_string())));
+
+        assert!(state.next_round(&mut session).unwrap().is_done());
+    }
+
+    #[test]
+    fn test_packed_modules() {
+        let (mut state, mut session) = new_state(DEFAULT_MAX_SERVICE_INFO_SIZE);
+
+        state
+            .queue_api_reply(
+                &mut session,
+                ServiceInfoApiReply {
+                    initial_user: Some(ServiceInfoApiReplyInitialUser {
+                        username: "admin".to_string(),
+                        ssh_keys: vec!["testkey".to_string()],
+                    }),
+                    extra_commands: Some(vec![
+                        command("command", serde_json::json!("/usr/bin/true")),
+                        command("execute", serde_json::json!(true)),
+                    ]),
+                    conditional_commands: None,
+                },
+            )
+            .unwrap();
+
+        let round = state.next_round(&mut session).unwrap();
+        assert!(!round.is_done());
+        assert!(!round.is_more_service_info());
+        let modules: Vec<ServiceInfoModule> =
+            round.service_info().iter().map(|(m, _, _)| m).collect();
+        assert!(modules.contains(&FedoraIotServiceInfoModule::SSHKey.into()));
+        assert!(modules.contains(&FedoraIotServiceInfoModule::Command.into()));
+
+        assert!(state.next_round(&mut session).unwrap().is_done());
+    }
+
+    #[test]
+    fn test_large_values() {
+        let (mut state, mut session) = new_state(DEFAULT_MAX_SERVICE_INFO_SIZE);
+        let contents: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+
+        // Values without module specific chunking are split and joined again
+        let mut result = ServiceInfo::new();
+        result
+            .add(
+                FedoraIotServiceInfoModule::Command,
+                "stdout",
+                &serde_bytes::Bytes::new(&contents),
+            )
+            .unwrap();
+        let messages = result.split(DEFAULT_MAX_SERVICE_INFO_SIZE).unwrap();
+        assert!(messages.len() > 1);
+        let num_messages = messages.len();
+        for (i, message) in messages.into_iter().enumerate() {
+            state
+                .record_device_service_info(&mut session, &message, i + 1 < num_messages)
+                .unwrap();
+        }
+        let results = state.device_results(&session).unwrap();
+        assert!(results.service_info.contains(&command(
+            "stdout|hex",
+            serde_json::json!(hex::encode(&contents))
+        )));
+
+        state
+            .queue_api_reply(
+                &mut session,
+                ServiceInfoApiReply {
+                    initial_user: None,
+                    extra_commands: Some(vec![command(
+                        "args|hex",
+                        serde_json::json!(hex::encode(&contents)),
+                    )]),
+                    conditional_commands: None,
+                },
+            )
+            .unwrap();
+        let mut received = ServiceInfo::new();
+        loop {
+            let round = state.next_round(&mut session).unwrap();
+            assert!(round.service_info().message_size().unwrap() <= 1300);
+            let is_more = round.is_more_service_info();
+            received.extend(round.into_service_info());
+            if !is_more {
+                break;
+            }
+        }
+        let received = received.join_chunks().unwrap();
+        assert!(received
+            .iter()
+            .any(|(_, k, v)| k == "args" && v.as_bytes() == Some(&contents)));
+    }
+
+    #[test]
+    fn test_binaryfile_fragments() {
+        let (mut state, mut session) = new_state(DEFAULT_MAX_SERVICE_INFO_SIZE);
+        let contents: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+
+        let binaryfile = |key: &str, value: serde_json::Value| {
+            (
+                FedoraIotServiceInfoModule::BinaryFile.into(),
+                key.to_string(),
+                value,
+            )
+        };
+        state
+            .queue_api_reply(
+                &mut session,
+                ServiceInfoApiReply {
+                    initial_user: None,
+                    extra_commands: Some(vec![
+                        binaryfile("name", serde_json::json!("/etc/foo")),
+                        binaryfile("length", serde_json::json!(contents.len())),
+                        binaryfile("data001|hex", serde_json::json!(hex::encode(&contents))),
+                    ]),
+                    conditional_commands: None,
+                },
+            )
+            .unwrap();
+        assert!(!state.needs_api_reply());
+
+        let mut received = ServiceInfo::new();
+        let mut num_messages = 0;
+        loop {
+            let round = state.next_round(&mut session).unwrap();
+            assert!(!round.is_done());
+            assert!(round.service_info().message_size().unwrap() <= 1300);
+            num_messages += 1;
+
+            let is_more = round.is_more_service_info();
+            received.extend(round.into_service_info());
+            if !is_more {
+                break;
+            }
+        }
+        assert!(num_messages > 1);
+
+        let mut data = Vec::new();
+        let mut data_keys = Vec::new();
+        for (_, key, value) in received.iter() {
+            if key.starts_with("data") {
+                data.extend_from_slice(value.as_bytes().unwrap());
+                data_keys.push(key);
+            }
+        }
+        assert_eq!(data, contents);
+        assert_eq!(data_keys[0], "data001");
+        assert_eq!(data_keys[1], "data002");
+
+        assert!(state.next_round(&mut session).unwrap().is_done());
+    }
+
+    #[test]
+    fn test_fdo_sys() {
+        let (mut state, mut session) = new_state(DEFAULT_MAX_SERVICE_INFO_SIZE);
+        let contents: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+
+        let fdo_sys = |key: &str, value: serde_json::Value| {
+            (
+                StandardServiceInfoModule::FdoSys.into(),
+                key.to_string(),
+                value,
+            )
+        };
+        state
+            .queue_api_reply(
+                &mut session,
+                ServiceInfoApiReply {
+                    initial_user: None,
+                    extra_commands: Some(vec![
+                        fdo_sys("filedesc", serde_json::json!("/etc/foo")),
+                        fdo_sys("write|hex", serde_json::json!(hex::encode(&contents))),
+                        fdo_sys("exec_cb", serde_json::json!(["/usr/bin/sleep", "100"])),
+                    ]),
+                    conditional_commands: None,
+                },
+            )
+            .unwrap();
+
+        let mut received = ServiceInfo::new();
+        loop {
+            let round = state.next_round(&mut session).unwrap();
+            assert!(!round.is_done());
+            let is_more = round.is_more_service_info();
+            received.extend(round.into_service_info());
+            if !is_more {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        for (_, key, value) in received.iter() {
+            if key == "write" {
+                data.extend_from_slice(value.as_bytes().unwrap());
+            }
+        }
+        assert_eq!(data, contents);
+        assert!(received.iter().any(|(_, k, _)| k == "exec_cb"));
+
+        // Every incomplete status gets acknowledged
+        let mut status = ServiceInfo::new();
+        status
+            .add(
+                StandardServiceInfoModule::FdoSys,
+                "status_cb",
+                &(false, 0, 5),
+            )
+            .unwrap();
+        state
+            .record_device_service_info(&mut session, &status, false)
+            .unwrap();
+        let round = state.next_round(&mut session).unwrap();
+        assert!(!round.is_done());
+        assert!(round.service_info().iter().any(|(_, k, v)| k == "status_cb"
+            && v == serde_cbor::value::to_value((false, 0, 5)).unwrap()));
+
+        let mut status = ServiceInfo::new();
+        status
+            .add(
+                StandardServiceInfoModule::FdoSys,
+                "status_cb",
+                &(true, 0, 0),
+            )
+            .unwrap();
+        state
+            .record_device_service_info(&mut session, &status, false)
+            .unwrap();
+        assert!(state.next_round(&mut session).unwrap().is_done());
+    }
 }
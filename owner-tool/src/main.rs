@@ -80,6 +80,14 @@ async fn main() -> Result<()> {
                         .takes_value(true)
                         .help("Path to a TOML file containing the rendezvous information")
                         .long("rendezvous-info"),
+                )
+                .arg(
+                    Arg::with_name("protocol-version")
+                        .takes_value(true)
+                        .possible_values(&["100", "101"])
+                        .default_value("101")
+                        .help("FDO protocol version of the device credential and ownership voucher")
+                        .long("protocol-version"),
                 ),
         )
         .subcommand(
@@ -308,6 +316,10 @@ fn initialize_device(matches: &ArgMatches) -> Result<(), Error> {
     let device_cert_ca_private_key_path = matches.value_of("device-cert-ca-private-key").unwrap();
     let device_cert_ca_chain_path = matches.value_of("device-cert-ca-chain").unwrap();
     let rendezvous_info_path = matches.value_of("rendezvous-info").unwrap();
+    let protocol_version = match matches.value_of("protocol-version").unwrap() {
+        "100" => ProtocolVersion::Version1_0,
+        _ => ProtocolVersion::Version1_1,
+    };
 
     let manufacturer_cert = load_x509(manufacturer_cert_path).with_context(|| {
         format!(
@@ -390,7 +402,7 @@ fn initialize_device(matches: &ArgMatches) -> Result<(), Error> {
 
     // Construct Ownership Voucher Header
     let ov_header = OwnershipVoucherHeader::new(
-        ProtocolVersion::Version1_1,
+        protocol_version,
         device_guid.clone(),
         rendezvous_info.clone(),
         device_id.to_string(),
@@ -405,7 +417,7 @@ fn initialize_device(matches: &ArgMatches) -> Result<(), Error> {
     // Build device credential
     let devcred = FileDeviceCredential {
         active: true,
-        protver: ProtocolVersion::Version1_1,
+        protver: protocol_version,
         device_info: device_id.to_string(),
         guid: device_guid.clone(),
         rvinfo: rendezvous_info,
@@ -478,13 +490,6 @@ fn dump_voucher(matches: &ArgMatches) -> Result<(), Error> {
     }
 
     let ov_header = ov.header();
-    if ov_header.protocol_version() != ProtocolVersion::Version1_1 {
-        bail!(
-            "Protocol version in OV ({}) not supported ({})",
-            ov_header.protocol_version(),
-            ProtocolVersion::Version1_1,
-        );
-    }
 
     println!("Header:");
     println!("\tProtocol Version: {}", ov_header.protocol_version());
@@ -542,14 +547,6 @@ fn dump_devcred(matches: &ArgMatches) -> Result<(), Error> {
             .context("Error deserializing device credential")?
     };
 
-    if dc.protver != ProtocolVersion::Version1_1 {
-        bail!(
-            "Protocol version in OV ({}) not supported ({})",
-            dc.protver,
-            ProtocolVersion::Version1_1
-        );
-    }
-
     println!("Active: {}", dc.active);
     println!("Protocol Version: {}", dc.protver);
     println!("Device Info: {}", dc.device_info);
@@ -593,15 +590,6 @@ fn extend_voucher(matches: &ArgMatches) -> Result<(), Error> {
         OwnershipVoucher::from_pem_or_raw(&ov).context("Error deserializing ownership voucher")?
     };
 
-    let ov_header = ov.header();
-    if ov_header.protocol_version() != ProtocolVersion::Version1_1 {
-        bail!(
-            "Protocol version in OV ({}) not supported ({})",
-            ov_header.protocol_version(),
-            ProtocolVersion::Version1_1,
-        );
-    }
-
     let current_owner_private_key =
         load_private_key(current_owner_private_key_path).with_context(|| {
             format!(
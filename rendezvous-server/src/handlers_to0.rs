@@ -2,9 +2,10 @@ use std::convert::TryInto;
 
 use fdo_data_formats::messages;
 use fdo_data_formats::{
-    constants::ErrorCode,
+    constants::{ErrorCode, HashType},
     messages::Message,
-    types::{Nonce, TO1DataPayload},
+    types::{COSESign, Hash, Nonce, TO0Data, TO1DataPayload},
+    ProtocolVersion,
 };
 
 use fdo_http_wrapper::server::Error;
@@ -12,33 +13,98 @@ use fdo_http_wrapper::server::RequestInformation;
 
 use super::StoredItem;
 
+fn new_nonce3<M: Message>(ses_with_store: &mut RequestInformation) -> Result<Nonce, Error> {
+    let nonce3 = Nonce::new().map_err(Error::from_error::<M, _>)?;
+    let nonce3_encoded = nonce3.to_string();
+
+    ses_with_store
+        .session
+        .insert("nonce3", nonce3_encoded)
+        .map_err(Error::from_error::<M, _>)?;
+
+    Ok(nonce3)
+}
+
 pub(super) async fn hello(
     _user_data: super::RendezvousUDT,
     mut ses_with_store: RequestInformation,
     _msg: messages::v11::to0::Hello,
 ) -> Result<(messages::v11::to0::HelloAck, RequestInformation), warp::Rejection> {
-    let mut session = ses_with_store.session;
+    let nonce3 = new_nonce3::<messages::v11::to0::Hello>(&mut ses_with_store)?;
 
-    let nonce3 = Nonce::new().map_err(Error::from_error::<messages::v11::to0::Hello, _>)?;
-    let nonce3_encoded = nonce3.to_string();
-
-    session
-        .insert("nonce3", nonce3_encoded)
-        .map_err(Error::from_error::<messages::v11::to0::Hello, _>)?;
-
-    let res = messages::v11::to0::HelloAck::new(nonce3);
+    Ok((messages::v11::to0::HelloAck::new(nonce3), ses_with_store))
+}
 
-    ses_with_store.session = session;
+pub(super) async fn hello_v10(
+    _user_data: super::RendezvousUDT,
+    mut ses_with_store: RequestInformation,
+    _msg: messages::v10::to0::Hello,
+) -> Result<(messages::v10::to0::HelloAck, RequestInformation), warp::Rejection> {
+    let nonce3 = new_nonce3::<messages::v10::to0::Hello>(&mut ses_with_store)?;
 
-    Ok((res, ses_with_store))
+    Ok((messages::v10::to0::HelloAck::new(nonce3), ses_with_store))
 }
 
 pub(super) async fn ownersign(
     user_data: super::RendezvousUDT,
-    mut ses_with_store: RequestInformation,
+    ses_with_store: RequestInformation,
     msg: messages::v11::to0::OwnerSign,
 ) -> Result<(messages::v11::to0::AcceptOwner, RequestInformation), warp::Rejection> {
-    let session = ses_with_store.session;
+    let to0d = msg
+        .to0d()
+        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+
+    let wait_seconds = store_owner_sign::<messages::v11::to0::OwnerSign, _>(
+        user_data,
+        &ses_with_store,
+        ProtocolVersion::Version1_1,
+        &to0d,
+        msg.to1d(),
+        |hash_type| msg.to0d_hash(hash_type),
+    )
+    .await?;
+
+    Ok((
+        messages::v11::to0::AcceptOwner::new(wait_seconds),
+        ses_with_store,
+    ))
+}
+
+pub(super) async fn ownersign_v10(
+    user_data: super::RendezvousUDT,
+    ses_with_store: RequestInformation,
+    msg: messages::v10::to0::OwnerSign,
+) -> Result<(messages::v10::to0::AcceptOwner, RequestInformation), warp::Rejection> {
+    let wait_seconds = store_owner_sign::<messages::v10::to0::OwnerSign, _>(
+        user_data,
+        &ses_with_store,
+        ProtocolVersion::Version1_0,
+        msg.to0d(),
+        msg.to1d(),
+        |hash_type| msg.to0d_hash(hash_type),
+    )
+    .await?;
+
+    Ok((
+        messages::v10::to0::AcceptOwner::new(wait_seconds),
+        ses_with_store,
+    ))
+}
+
+/// Validates an OwnerSign message and stores its to1d, returning the accepted wait_seconds
+async fn store_owner_sign<M, F>(
+    user_data: super::RendezvousUDT,
+    ses_with_store: &RequestInformation,
+    protocol_version: ProtocolVersion,
+    to0d: &TO0Data,
+    to1d: &COSESign,
+    to0d_hash: F,
+) -> Result<u32, Error>
+where
+    M: Message,
+    F: FnOnce(HashType) -> Result<Hash, fdo_data_formats::Error>,
+{
+    let session = &ses_with_store.session;
 
     // First check the easy things: whether the nonce in to0d is correct
     let correct_nonce: String = match session.get("nonce3") {
@@ -46,15 +112,11 @@ pub(super) async fn ownersign(
         None => {
             return Err(Error::new(
                 ErrorCode::InvalidMessageError,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "Request sequence failure",
-            )
-            .into())
+            ))
         }
     };
-    let to0d = msg
-        .to0d()
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
     let correct_nonce: Nonce = correct_nonce.parse().unwrap();
     log::trace!(
         "Matching correct nonce {:?} to received {:?}",
@@ -64,10 +126,9 @@ pub(super) async fn ownersign(
     if &correct_nonce != to0d.nonce() {
         return Err(Error::new(
             ErrorCode::InvalidMessageError,
-            messages::v11::to0::OwnerSign::message_type(),
+            M::message_type(),
             "Invalid nonce3",
-        )
-        .into());
+        ));
     }
 
     // Now check the OV first public key: is it one we trust?
@@ -84,10 +145,9 @@ pub(super) async fn ownersign(
         if !trusted_manufacturer_keys.contains_publickey(&manufacturer_pubkey) {
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "Ownership voucher manufacturer not trusted",
-            )
-            .into());
+            ));
         }
     }
 
@@ -95,25 +155,23 @@ pub(super) async fn ownersign(
     let ov_iter = to0d
         .ownership_voucher()
         .iter_entries()
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+        .map_err(Error::from_error::<M, _>)?;
     let owner = match ov_iter.last() {
         None => {
             log::error!("No OV entries encountered");
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "Invalid OV",
-            )
-            .into());
+            ));
         }
         Some(Err(e)) => {
             log::error!("Invalid OV entry encountered: {:?}", e);
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "Invalid OV",
-            )
-            .into());
+            ));
         }
         Some(Ok(owner)) => owner,
     };
@@ -123,27 +181,24 @@ pub(super) async fn ownersign(
         "Checking whether to1d payload is signed by owner public key {:?}",
         owner.public_key(),
     );
-    let to1d_payload: TO1DataPayload = match msg.to1d().get_payload(owner.public_key().pkey()) {
+    let to1d_payload: TO1DataPayload = match to1d.get_payload(owner.public_key().pkey()) {
         Err(e) => {
             log::error!("Error verifying to1d: {:?}", e);
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "Invalid TO1D",
-            )
-            .into());
+            ));
         }
         Ok(v) => v,
     };
 
     // Verify the to1d -> to0d hash
     let to1d_to_to0d_hash = to1d_payload.to1d_to_to0d_hash();
-    let to0d_hash = msg
-        .to0d_hash(to1d_to_to0d_hash.get_type())
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+    let to0d_hash = to0d_hash(to1d_to_to0d_hash.get_type()).map_err(Error::from_error::<M, _>)?;
     to1d_to_to0d_hash
         .compare(&to0d_hash)
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+        .map_err(Error::from_error::<M, _>)?;
 
     // Okay, wew! We can now trust the to1d payload, and the other data!
     // First, verify the device certificate chain
@@ -151,28 +206,26 @@ pub(super) async fn ownersign(
         None => {
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "No device certificate",
-            )
-            .into());
+            ));
         }
         Some(v) => v,
     };
-    //let device_pubkey = match device_cert_chain.verify_from_x5bag(&user_data.trusted_device_keys) {
-    let device_pubkey = match device_cert_chain.insecure_verify_without_root_verification() {
+    let device_cert = match &user_data.trusted_device_keys {
+        Some(trusted_device_keys) => device_cert_chain.verify_from_x5bag(trusted_device_keys),
+        None => device_cert_chain.insecure_verify_without_root_verification(),
+    };
+    let device_pubkey = match device_cert {
         Err(cert_chain_err) => {
             log::debug!("Error verifying device certificate: {:?}", cert_chain_err);
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "Device certificate not trusted",
-            )
-            .into());
+            ));
         }
-        Ok(v) => v
-            .clone()
-            .try_into()
-            .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?,
+        Ok(v) => v.clone().try_into().map_err(Error::from_error::<M, _>)?,
     };
 
     // Now compute the new wait_seconds and stuff to store
@@ -196,21 +249,18 @@ pub(super) async fn ownersign(
             device_guid.clone(),
             StoredItem {
                 public_key: device_pubkey,
-                to1d: msg.to1d().clone(),
+                to1d: to1d.clone(),
+                protocol_version,
             },
         )
         .await
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+        .map_err(Error::from_error::<M, _>)?;
 
     user_data
         .store
         .store_metadata(&device_guid, &fdo_store::MetadataKey::Ttl, &ttl)
         .await
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+        .map_err(Error::from_error::<M, _>)?;
 
-    ses_with_store.session = session;
-    Ok((
-        messages::v11::to0::AcceptOwner::new(wait_seconds),
-        ses_with_store,
-    ))
+    Ok(wait_seconds)
 }
@@ -1,7 +1,8 @@
 use fdo_data_formats::{
     constants::{DeviceSigType, ErrorCode},
     messages::{self, Message},
-    types::{Nonce, SigInfo},
+    types::{COSESign, Guid, Nonce, SigInfo},
+    ProtocolVersion,
 };
 
 use fdo_http_wrapper::server::Error;
@@ -12,86 +13,168 @@ pub(super) async fn hello_rv(
     mut ses_with_store: RequestInformation,
     msg: messages::v11::to1::HelloRV,
 ) -> Result<(messages::v11::to1::HelloRVAck, RequestInformation), warp::Rejection> {
-    let mut session = ses_with_store.session;
+    let (nonce4, b_sig_info) = start_hello_rv::<messages::v11::to1::HelloRV>(
+        user_data,
+        &mut ses_with_store,
+        ProtocolVersion::Version1_1,
+        msg.guid(),
+        msg.a_signature_info(),
+    )
+    .await?;
+
+    let res = messages::v11::to1::HelloRVAck::new(nonce4, b_sig_info);
+
+    Ok((res, ses_with_store))
+}
+
+pub(super) async fn hello_rv_v10(
+    user_data: super::RendezvousUDT,
+    mut ses_with_store: RequestInformation,
+    msg: messages::v10::to1::HelloRV,
+) -> Result<(messages::v10::to1::HelloRVAck, RequestInformation), warp::Rejection> {
+    let (nonce4, b_sig_info) = start_hello_rv::<messages::v10::to1::HelloRV>(
+        user_data,
+        &mut ses_with_store,
+        ProtocolVersion::Version1_0,
+        msg.guid(),
+        msg.a_signature_info(),
+    )
+    .await?;
+
+    let res = messages::v10::to1::HelloRVAck::new(nonce4, b_sig_info);
+
+    Ok((res, ses_with_store))
+}
+
+/// Looks up the device and starts a TO1 session, returning nonce4 and the eB signature info
+async fn start_hello_rv<M: Message>(
+    user_data: super::RendezvousUDT,
+    ses_with_store: &mut RequestInformation,
+    protocol_version: ProtocolVersion,
+    guid: &Guid,
+    a_sig_info: &SigInfo,
+) -> Result<(Nonce, SigInfo), Error> {
+    let session = &mut ses_with_store.session;
 
     // Check the signature info
-    let a_sig_info = msg.a_signature_info();
     match a_sig_info.sig_type() {
-        DeviceSigType::StSECP256R1 | DeviceSigType::StSECP384R1 => {}
+        DeviceSigType::StSECP256R1
+        | DeviceSigType::StSECP384R1
+        | DeviceSigType::StRSA2048
+        | DeviceSigType::StRSA3072 => {}
         _ => {
             return Err(Error::new(
                 ErrorCode::InvalidMessageError,
-                messages::v11::to1::HelloRV::message_type(),
+                M::message_type(),
                 "Unsupported signature scheme",
-            )
-            .into())
+            ))
         }
     }
     if !a_sig_info.info().is_empty() {
         return Err(Error::new(
             ErrorCode::InvalidMessageError,
-            messages::v11::to1::HelloRV::message_type(),
+            M::message_type(),
             "Unsupported signature info",
-        )
-        .into());
+        ));
     }
 
     // Look up device
-    log::trace!("Looking up device {:?}", msg.guid());
+    log::trace!("Looking up device {:?}", guid);
     let dev_to1d = user_data
         .store
-        .load_data(msg.guid())
+        .load_data(guid)
         .await
-        .map_err(Error::from_error::<messages::v11::to1::HelloRV, _>)?;
+        .map_err(Error::from_error::<M, _>)?;
     match dev_to1d {
-        Some(_) => {}
+        Some(dev) if dev.protocol_version == protocol_version => {}
+        Some(dev) => {
+            log::debug!(
+                "Device {:?} was registered with protocol version {}, requested {}",
+                guid,
+                dev.protocol_version,
+                protocol_version
+            );
+            return Err(Error::new(
+                ErrorCode::ResourceNotFound,
+                M::message_type(),
+                "Device GUID not found",
+            ));
+        }
         None => {
             return Err(Error::new(
                 ErrorCode::ResourceNotFound,
-                messages::v11::to1::HelloRV::message_type(),
+                M::message_type(),
                 "Device GUID not found",
-            )
-            .into())
+            ))
         }
     }
 
     // Create new nonce
-    let nonce4 = Nonce::new().map_err(Error::from_error::<messages::v11::to1::HelloRV, _>)?;
+    let nonce4 = Nonce::new().map_err(Error::from_error::<M, _>)?;
     let nonce4_encoded = nonce4.to_string();
 
     session
         .insert("nonce4", nonce4_encoded)
-        .map_err(Error::from_error::<messages::v11::to1::HelloRV, _>)?;
+        .map_err(Error::from_error::<M, _>)?;
     session
-        .insert("device_guid", msg.guid().to_string())
-        .map_err(Error::from_error::<messages::v11::to1::HelloRV, _>)?;
+        .insert("device_guid", guid.to_string())
+        .map_err(Error::from_error::<M, _>)?;
 
-    // Build return message
+    // Build return signature info
     let b_sig_info = SigInfo::new(a_sig_info.sig_type(), vec![]);
 
-    let res = messages::v11::to1::HelloRVAck::new(nonce4, b_sig_info);
-
-    // Return message
-    ses_with_store.session = session;
-    Ok((res, ses_with_store))
+    Ok((nonce4, b_sig_info))
 }
 
 pub(super) async fn prove_to_rv(
     user_data: super::RendezvousUDT,
-    mut ses_with_store: RequestInformation,
+    ses_with_store: RequestInformation,
     msg: messages::v11::to1::ProveToRV,
 ) -> Result<(messages::v11::to1::RVRedirect, RequestInformation), warp::Rejection> {
-    let session = ses_with_store.session;
+    let to1d = verify_prove_to_rv::<messages::v11::to1::ProveToRV>(
+        user_data,
+        &ses_with_store,
+        ProtocolVersion::Version1_1,
+        msg.token(),
+    )
+    .await?;
+
+    Ok((messages::v11::to1::RVRedirect::new(to1d), ses_with_store))
+}
+
+pub(super) async fn prove_to_rv_v10(
+    user_data: super::RendezvousUDT,
+    ses_with_store: RequestInformation,
+    msg: messages::v10::to1::ProveToRV,
+) -> Result<(messages::v10::to1::RVRedirect, RequestInformation), warp::Rejection> {
+    let to1d = verify_prove_to_rv::<messages::v10::to1::ProveToRV>(
+        user_data,
+        &ses_with_store,
+        ProtocolVersion::Version1_0,
+        msg.token(),
+    )
+    .await?;
+
+    Ok((messages::v10::to1::RVRedirect::new(to1d), ses_with_store))
+}
+
+/// Verifies the device token, and returns the to1d stored for the device
+async fn verify_prove_to_rv<M: Message>(
+    user_data: super::RendezvousUDT,
+    ses_with_store: &RequestInformation,
+    protocol_version: ProtocolVersion,
+    token: &COSESign,
+) -> Result<COSESign, Error> {
+    let session = &ses_with_store.session;
 
     let nonce4: String = match session.get("nonce4") {
         Some(v) => v,
         None => {
             return Err(Error::new(
                 ErrorCode::InvalidMessageError,
-                messages::v11::to0::OwnerSign::message_type(),
+                M::message_type(),
                 "Request sequence failure",
-            )
-            .into())
+            ))
         }
     };
     let nonce4: Nonce = nonce4.parse().unwrap();
@@ -101,41 +184,38 @@ pub(super) async fn prove_to_rv(
         None => {
             return Err(Error::new(
                 ErrorCode::InvalidMessageError,
-                messages::v11::to1::ProveToRV::message_type(),
+                M::message_type(),
                 "Request sequence failure",
-            )
-            .into())
+            ))
         }
     };
     let device_guid = &device_guid.parse().unwrap();
 
     let (dev_pkey, to1d) = match user_data.store.load_data(device_guid).await {
-        Ok(Some(dev)) => (dev.public_key, dev.to1d),
+        Ok(Some(dev)) if dev.protocol_version == protocol_version => (dev.public_key, dev.to1d),
         Err(e) => {
             log::trace!("Error getting device entry: {:?}", e);
             return Err(Error::new(
                 ErrorCode::InvalidMessageError,
-                messages::v11::to1::ProveToRV::message_type(),
+                M::message_type(),
                 "Request sequence failure",
-            )
-            .into());
+            ));
         }
-        Ok(None) => {
+        Ok(_) => {
             return Err(Error::new(
                 ErrorCode::ResourceNotFound,
-                messages::v11::to1::ProveToRV::message_type(),
+                M::message_type(),
                 "Device not found",
-            )
-            .into());
+            ));
         }
     };
 
     // Check if token is signed
-    let device_eat = msg.token().get_eat(dev_pkey.pkey()).map_err(|e| {
+    let device_eat = token.get_eat(dev_pkey.pkey()).map_err(|e| {
         log::debug!("Error parsing EAToken: {:?}", e);
         Error::new(
             ErrorCode::InvalidMessageError,
-            messages::v11::to1::ProveToRV::message_type(),
+            M::message_type(),
             "Token invaid",
         )
     })?;
@@ -145,15 +225,11 @@ pub(super) async fn prove_to_rv(
     if &nonce4 != signed_nonce {
         return Err(Error::new(
             ErrorCode::InvalidMessageError,
-            messages::v11::to1::ProveToRV::message_type(),
+            M::message_type(),
             "Nonce invaid",
-        )
-        .into());
+        ));
     }
 
     // Okay, device is trusted! Now return their owner information
-    let rv_redirect = messages::v11::to1::RVRedirect::new(to1d);
-
-    ses_with_store.session = session;
-    Ok((rv_redirect, ses_with_store))
+    Ok(to1d)
 }
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use openssl::x509::X509;
 use tokio::signal::unix::{signal, SignalKind};
 use warp::Filter;
@@ -13,7 +13,10 @@ use fdo_data_formats::{
     ProtocolVersion, Serializable,
 };
 use fdo_store::Store;
-use fdo_util::servers::{configuration::rendezvous_server::RendezvousServerSettings, settings_for};
+use fdo_util::servers::{
+    bind_coap_server, bind_server, configuration::rendezvous_server::RendezvousServerSettings,
+    settings_for,
+};
 
 mod handlers_to0;
 mod handlers_to1;
@@ -22,6 +25,7 @@ mod handlers_to1;
 struct StoredItem {
     public_key: PublicKey,
     to1d: COSESign,
+    protocol_version: ProtocolVersion,
 }
 
 impl Serializable for StoredItem {
@@ -29,23 +33,33 @@ impl Serializable for StoredItem {
     where
         R: std::io::Read,
     {
-        let contents: ParsedArray<fdo_data_formats::cborparser::ParsedArraySize2> =
+        let contents: ParsedArray<fdo_data_formats::cborparser::ParsedArraySizeDynamic> =
             ParsedArray::deserialize_from_reader(reader)?;
 
         let public_key = contents.get(0)?;
         let to1d = contents.get(1)?;
-
-        Ok(StoredItem { public_key, to1d })
+        // Items stored before protocol 1.0 support was added are always 1.1
+        let protocol_version = match contents.len() {
+            2 => ProtocolVersion::Version1_1,
+            _ => contents.get(2)?,
+        };
+
+        Ok(StoredItem {
+            public_key,
+            to1d,
+            protocol_version,
+        })
     }
 
     fn serialize_to_writer<W>(&self, writer: W) -> Result<(), fdo_data_formats::Error>
     where
         W: std::io::Write,
     {
-        let mut contents: ParsedArrayBuilder<fdo_data_formats::cborparser::ParsedArraySize2> =
+        let mut contents: ParsedArrayBuilder<fdo_data_formats::cborparser::ParsedArraySize3> =
             ParsedArrayBuilder::new();
         contents.set(0, &self.public_key)?;
         contents.set(1, &self.to1d)?;
+        contents.set(2, &self.protocol_version)?;
         let contents = contents.build();
 
         contents.serialize_to_writer(writer)
@@ -65,6 +79,8 @@ impl fdo_store::MetadataLocalKey for RendezvousStoreMetadataKey {
 struct RendezvousUD {
     max_wait_seconds: u32,
     trusted_manufacturer_keys: Option<X5Bag>,
+    // None if device certificate chains are accepted without root verification
+    trusted_device_keys: Option<X5Bag>,
     store: Box<dyn Store<fdo_store::ReadWriteOpen, Guid, StoredItem, RendezvousStoreMetadataKey>>,
 
     session_store: Arc<fdo_http_wrapper::server::SessionStore>,
@@ -141,12 +157,33 @@ async fn main() -> Result<()> {
         })
         .transpose()
         .context("Error loading trusted manufacturer keys")?;
+    let trusted_device_keys = if settings.allow_untrusted_device_certificates {
+        log::warn!("Accepting device certificates without verifying them against trusted CAs");
+        None
+    } else {
+        let path = match settings.trusted_device_keys_path {
+            Some(path) => path,
+            None => bail!(
+                "trusted_device_keys_path is required unless allow_untrusted_device_certificates is set"
+            ),
+        };
+        let trusted_device_keys = {
+            let contents = std::fs::read(&path)
+                .with_context(|| format!("Error reading trusted device keys at {}", &path))?;
+            X509::stack_from_pem(&contents).context("Error parsing trusted device keys")?
+        };
+        Some(
+            X5Bag::with_certs(trusted_device_keys)
+                .context("Error building trusted device keys X5Bag")?,
+        )
+    };
 
     // Initialize handler stores
     let user_data = Arc::new(RendezvousUD {
         max_wait_seconds,
         store,
         trusted_manufacturer_keys,
+        trusted_device_keys,
 
         session_store: session_store.clone(),
     });
@@ -168,6 +205,18 @@ async fn main() -> Result<()> {
         session_store.clone(),
         handlers_to0::ownersign,
     );
+    let handler_to0_hello_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers_to0::hello_v10,
+    );
+    let handler_to0_ownersign_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers_to0::ownersign_v10,
+    );
 
     // TO1
     let handler_to1_hello_rv = fdo_http_wrapper::server::fdo_request_filter(
@@ -182,6 +231,18 @@ async fn main() -> Result<()> {
         session_store.clone(),
         handlers_to1::prove_to_rv,
     );
+    let handler_to1_hello_rv_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers_to1::hello_rv_v10,
+    );
+    let handler_to1_prove_to_rv_v10 = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_0,
+        user_data.clone(),
+        session_store.clone(),
+        handlers_to1::prove_to_rv_v10,
+    );
 
     let routes = warp::post()
         .and(
@@ -190,32 +251,53 @@ async fn main() -> Result<()> {
                 // TO0
                 .or(handler_to0_hello)
                 .or(handler_to0_ownersign)
+                .or(handler_to0_hello_v10)
+                .or(handler_to0_ownersign_v10)
                 // TO1
                 .or(handler_to1_hello_rv)
-                .or(handler_to1_prove_to_rv),
+                .or(handler_to1_prove_to_rv)
+                .or(handler_to1_hello_rv_v10)
+                .or(handler_to1_prove_to_rv_v10),
         )
         .recover(fdo_http_wrapper::server::handle_rejection)
         .with(warp::log("rendezvous-server"));
 
-    log::info!("Listening on {}", bind_addr);
-    let server = warp::serve(routes);
+    let coap_server = settings
+        .coap_bind
+        .clone()
+        .map(|coap_bind| bind_coap_server(routes.clone().boxed(), coap_bind.into()))
+        .transpose()?;
+
+    let server = bind_server(
+        routes.boxed(),
+        bind_addr.into(),
+        settings.tls.as_ref(),
+        async {
+            signal(SignalKind::terminate()).unwrap().recv().await;
+            log::info!("Terminating");
+        },
+    )?;
 
     let maintenance_runner =
         tokio::spawn(async move { perform_maintenance(user_data.clone()).await });
 
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
-            signal(SignalKind::terminate()).unwrap().recv().await;
-            log::info!("Terminating");
-        })
-        .1;
     let server = tokio::spawn(server);
+    // The CoAP server stops along with the HTTP server
+    let coap_server = tokio::spawn(async move {
+        match coap_server {
+            Some(coap_server) => coap_server.await,
+            None => std::future::pending().await,
+        }
+    });
 
     #[allow(clippy::panic)]
     let _ = tokio::select!(
     _ = server => {
         log::info!("Server terminated");
     },
+    _ = coap_server => {
+        log::info!("CoAP server terminated");
+    },
     _ = maintenance_runner => {
         log::info!("Maintenance runner terminated");
     });
@@ -6,13 +6,18 @@ use tokio::signal::unix::{signal, SignalKind};
 use warp::Filter;
 
 use fdo_data_formats::{
-    constants::{FedoraIotServiceInfoModule, HashType, ServiceInfoModule},
+    constants::{
+        FedoraIotServiceInfoModule, HashType, ServiceInfoModule, StandardServiceInfoModule,
+    },
     types::{Guid, Hash},
 };
 use fdo_store::Store;
 use fdo_util::servers::{
-    configuration::serviceinfo_api_server::{ServiceInfoApiServerSettings, ServiceInfoSettings},
-    settings_for, ServiceInfoApiReply, ServiceInfoApiReplyInitialUser,
+    bind_server,
+    configuration::serviceinfo_api_server::{
+        ServiceInfoApiServerSettings, ServiceInfoFile, ServiceInfoSettings,
+    },
+    settings_for, ServiceInfoApiDeviceResults, ServiceInfoApiReply, ServiceInfoApiReplyInitialUser,
 };
 
 #[derive(Debug)]
@@ -25,43 +30,52 @@ impl ServiceInfoConfiguration {
         // Perform checks on the configuration
 
         // Check permissions for files are valid
-        settings.files = if let Some(files) = settings.files {
-            let mut new_files = Vec::new();
-
-            for mut file in files {
-                let path = &file.path;
-
-                file.parsed_permissions = if let Some(permissions) = &file.permissions {
-                    Some(u32::from_str_radix(permissions, 8).with_context(|| {
-                        format!(
-                            "Invalid permission string for file {}: {} (invalid octal)",
-                            path, permissions
-                        )
-                    })?)
-                } else {
-                    None
-                };
-
-                let contents = std::fs::read(&file.source_path)
-                    .with_context(|| format!("Failed to read file {}", file.source_path))?;
-                file.hash_hex = hex::encode(
-                    &Hash::from_data(HashType::Sha384, &contents)
-                        .with_context(|| format!("Failed to hash file {}", file.source_path))?
-                        .value_bytes(),
-                );
-                file.contents_len = contents.len();
-                file.contents_hex = hex::encode(&contents);
+        settings.files = match settings.files {
+            Some(files) => Some(load_files(files)?),
+            None => None,
+        };
+        if let Some(fdo_sys) = &mut settings.fdo_sys {
+            fdo_sys.files = match fdo_sys.files.take() {
+                Some(files) => Some(load_files(files)?),
+                None => None,
+            };
+        }
 
-                new_files.push(file);
-            }
+        Ok(ServiceInfoConfiguration { settings })
+    }
+}
 
-            Some(new_files)
+fn load_files(files: Vec<ServiceInfoFile>) -> Result<Vec<ServiceInfoFile>> {
+    let mut new_files = Vec::new();
+
+    for mut file in files {
+        let path = &file.path;
+
+        file.parsed_permissions = if let Some(permissions) = &file.permissions {
+            Some(u32::from_str_radix(permissions, 8).with_context(|| {
+                format!(
+                    "Invalid permission string for file {}: {} (invalid octal)",
+                    path, permissions
+                )
+            })?)
         } else {
             None
         };
 
-        Ok(ServiceInfoConfiguration { settings })
+        let contents = std::fs::read(&file.source_path)
+            .with_context(|| format!("Failed to read file {}", file.source_path))?;
+        file.hash_hex = hex::encode(
+            &Hash::from_data(HashType::Sha384, &contents)
+                .with_context(|| format!("Failed to hash file {}", file.source_path))?
+                .value_bytes(),
+        );
+        file.contents_len = contents.len();
+        file.contents_hex = hex::encode(&contents);
+
+        new_files.push(file);
     }
+
+    Ok(new_files)
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -74,7 +88,46 @@ impl fdo_store::MetadataLocalKey for ServiceInfoMetadataKey {
     }
 }
 
-type ServiceInfoStoreData = Vec<(ServiceInfoModule, String, serde_json::Value)>;
+type ServiceInfoStoreLines = Vec<(ServiceInfoModule, String, serde_json::Value)>;
+
+/// The information stored per device
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "ServiceInfoStoreDataVersions")]
+struct ServiceInfoStoreData {
+    /// Additional ServiceInfo to send to the device, submitted via the admin API
+    service_info: ServiceInfoStoreLines,
+    /// The ServiceInfo the device returned during its last onboarding
+    device_results: Option<ServiceInfoApiDeviceResults>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServiceInfoStoreDataVersions {
+    Current {
+        service_info: ServiceInfoStoreLines,
+        device_results: Option<ServiceInfoApiDeviceResults>,
+    },
+    // Entries stored before device results were kept only contain the ServiceInfo
+    ServiceInfoOnly(ServiceInfoStoreLines),
+}
+
+impl From<ServiceInfoStoreDataVersions> for ServiceInfoStoreData {
+    fn from(versions: ServiceInfoStoreDataVersions) -> Self {
+        match versions {
+            ServiceInfoStoreDataVersions::Current {
+                service_info,
+                device_results,
+            } => ServiceInfoStoreData {
+                service_info,
+                device_results,
+            },
+            ServiceInfoStoreDataVersions::ServiceInfoOnly(service_info) => ServiceInfoStoreData {
+                service_info,
+                device_results: None,
+            },
+        }
+    }
+}
 
 struct ServiceInfoApiServerUD {
     // Stores
@@ -165,11 +218,21 @@ async fn admin_v0_handler(
     user_data: ServiceInfoApiServerUDT,
     request_info: AdminV0Request,
 ) -> Result<warp::reply::Json, warp::Rejection> {
-    match user_data
-        .device_specific_store
-        .store_data(request_info.device_guid, request_info.service_info)
-        .await
-    {
+    let result = async {
+        let mut data = user_data
+            .device_specific_store
+            .load_data(&request_info.device_guid)
+            .await?
+            .unwrap_or_default();
+        data.service_info = request_info.service_info;
+        user_data
+            .device_specific_store
+            .store_data(request_info.device_guid, data)
+            .await
+    }
+    .await;
+
+    match result {
         Ok(_) => Ok(warp::reply::json(&AdminV0Reply {
             error: None,
             success: true,
@@ -181,6 +244,52 @@ async fn admin_v0_handler(
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct AdminV0Query {
+    #[serde(deserialize_with = "deserialize_from_str")]
+    device_guid: fdo_data_formats::types::Guid,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminV0GetReply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+
+    success: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_info: Option<Vec<(ServiceInfoModule, String, serde_json::Value)>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_results: Option<ServiceInfoApiDeviceResults>,
+}
+
+async fn admin_v0_get_handler(
+    user_data: ServiceInfoApiServerUDT,
+    query_info: AdminV0Query,
+) -> Result<warp::reply::Json, warp::Rejection> {
+    match user_data
+        .device_specific_store
+        .load_data(&query_info.device_guid)
+        .await
+    {
+        Ok(data) => {
+            let data = data.unwrap_or_default();
+            Ok(warp::reply::json(&AdminV0GetReply {
+                error: None,
+                success: true,
+                service_info: Some(data.service_info),
+                device_results: data.device_results,
+            }))
+        }
+        Err(e) => Ok(warp::reply::json(&AdminV0GetReply {
+            error: Some(e.to_string()),
+            success: false,
+            service_info: None,
+            device_results: None,
+        })),
+    }
+}
+
 async fn serviceinfo_auth_handler(
     user_data: ServiceInfoApiServerUDT,
     auth_header: String,
@@ -290,6 +399,39 @@ async fn serviceinfo_handler(
         }
     }
 
+    if query_info
+        .modules
+        .contains(&StandardServiceInfoModule::FdoSys.into())
+    {
+        if let Some(fdo_sys) = &user_data.service_info_configuration.settings.fdo_sys {
+            for file in fdo_sys.files.iter().flatten() {
+                reply.add_extra(StandardServiceInfoModule::FdoSys, "filedesc", &file.path);
+                reply.add_extra(
+                    StandardServiceInfoModule::FdoSys,
+                    "write|hex",
+                    &file.contents_hex,
+                );
+                if let Some(parsed_permissions) = &file.parsed_permissions {
+                    reply.add_extra(
+                        StandardServiceInfoModule::FdoSys,
+                        "exec",
+                        &[
+                            "chmod".to_string(),
+                            format!("{:o}", parsed_permissions),
+                            file.path.clone(),
+                        ],
+                    );
+                }
+            }
+            for command in fdo_sys.commands.iter().flatten() {
+                let mut args = vec![command.command.clone()];
+                args.extend(command.args.iter().cloned());
+                let key = if command.may_fail { "exec_cb" } else { "exec" };
+                reply.add_extra(StandardServiceInfoModule::FdoSys, key, &args);
+            }
+        }
+    }
+
     if query_info
         .modules
         .contains(&FedoraIotServiceInfoModule::DiskEncryptionClevis.into())
@@ -356,7 +498,7 @@ async fn serviceinfo_handler(
     };
     if let Some(device_specific_info) = device_specific_info {
         log::trace!("Loaded device-specific information");
-        for (module, key, value) in device_specific_info {
+        for (module, key, value) in device_specific_info.service_info {
             reply.add_extra(module, &key, &value);
         }
     }
@@ -364,6 +506,45 @@ async fn serviceinfo_handler(
     Ok(warp::reply::json(&reply.reply))
 }
 
+async fn serviceinfo_results_handler(
+    user_data: ServiceInfoApiServerUDT,
+    query_info: ResultsQueryInfo,
+    results: ServiceInfoApiDeviceResults,
+) -> Result<warp::reply::Json, warp::Rejection> {
+    if query_info.api_version != 2 {
+        log::warn!(
+            "Unsupported API version {} used for device results",
+            query_info.api_version
+        );
+        return Err(warp::reject::reject());
+    }
+    log::info!(
+        "Device results (api version {}) reported for device {:?}",
+        query_info.api_version,
+        query_info.device_guid,
+    );
+
+    let result = async {
+        let mut data = user_data
+            .device_specific_store
+            .load_data(&query_info.device_guid)
+            .await?
+            .unwrap_or_default();
+        data.device_results = Some(results);
+        user_data
+            .device_specific_store
+            .store_data(query_info.device_guid, data)
+            .await
+    }
+    .await;
+    if let Err(e) = result {
+        log::warn!("Error storing device results: {:?}", e);
+        return Err(warp::reject::reject());
+    }
+
+    Ok(warp::reply::json(&serde_json::json!({})))
+}
+
 fn deserialize_from_str<'de, D>(deserializer: D) -> Result<fdo_data_formats::types::Guid, D::Error>
 where
     D: serde::de::Deserializer<'de>,
@@ -394,6 +575,14 @@ struct QueryInfo {
     modules: HashSet<ServiceInfoModule>,
 }
 
+#[derive(Debug, Deserialize)]
+struct ResultsQueryInfo {
+    #[serde(rename = "serviceinfo_api_version")]
+    api_version: u32,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    device_guid: fdo_data_formats::types::Guid,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     fdo_util::add_version!();
@@ -424,7 +613,9 @@ async fn main() -> Result<()> {
         admin_auth_token: settings.admin_auth_token.map(|s| format!("Bearer {}", s)),
     });
     let ud_si = user_data.clone();
+    let ud_si_results = user_data.clone();
     let ud_admin = user_data.clone();
+    let ud_admin_get = user_data.clone();
 
     let serviceinfo = warp::path("device_info")
         .map(move || ud_si.clone())
@@ -433,6 +624,15 @@ async fn main() -> Result<()> {
         .and(warp::query::query::<QueryInfo>())
         .and_then(serviceinfo_handler);
 
+    let serviceinfo_results = warp::post()
+        .and(warp::path("device_info"))
+        .map(move || ud_si_results.clone())
+        .and(warp::header::header("Authorization"))
+        .and_then(serviceinfo_auth_handler)
+        .and(warp::query::query::<ResultsQueryInfo>())
+        .and(warp::body::json())
+        .and_then(serviceinfo_results_handler);
+
     let admin_v0 = warp::post()
         .and(warp::path("admin"))
         .and(warp::path("v0"))
@@ -442,23 +642,68 @@ async fn main() -> Result<()> {
         .and(warp::body::json())
         .and_then(admin_v0_handler);
 
+    let admin_v0_get = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("v0"))
+        .map(move || ud_admin_get.clone())
+        .and(warp::header::header("Authorization"))
+        .and_then(admin_auth_handler)
+        .and(warp::query::query::<AdminV0Query>())
+        .and_then(admin_v0_get_handler);
+
     let handler_ping = fdo_http_wrapper::server::ping_handler();
 
     let routes = warp::get()
         .and(serviceinfo)
+        .or(serviceinfo_results)
         .or(admin_v0)
+        .or(admin_v0_get)
         .or(handler_ping)
         .with(warp::log("serviceinfo-api-server"));
 
-    log::info!("Listening on {}", bind_addr);
-    let server = warp::serve(routes);
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
+    let server = bind_server(
+        routes.boxed(),
+        bind_addr.into(),
+        settings.tls.as_ref(),
+        async {
             signal(SignalKind::terminate()).unwrap().recv().await;
             log::info!("Terminating");
-        })
-        .1;
+        },
+    )?;
     tokio::join!(server);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use fdo_data_formats::{constants::FedoraIotServiceInfoModule, Serializable};
+    use fdo_util::servers::ServiceInfoApiDeviceResults;
+
+    use super::{ServiceInfoStoreData, ServiceInfoStoreLines};
+
+    #[test]
+    fn test_store_data_compatibility() {
+        let service_info: ServiceInfoStoreLines = vec![(
+            FedoraIotServiceInfoModule::Command.into(),
+            "command".to_string(),
+            serde_json::json!("/usr/bin/true"),
+        )];
+
+        // Entries stored by older versions
+        let old = service_info.serialize_data().unwrap();
+        let data = ServiceInfoStoreData::deserialize_data(&old).unwrap();
+        assert_eq!(data.service_info, service_info);
+        assert!(data.device_results.is_none());
+
+        let data = ServiceInfoStoreData {
+            service_info: service_info.clone(),
+            device_results: Some(ServiceInfoApiDeviceResults {
+                service_info: service_info.clone(),
+            }),
+        };
+        let data = ServiceInfoStoreData::deserialize_data(&data.serialize_data().unwrap()).unwrap();
+        assert_eq!(data.service_info, service_info);
+        assert_eq!(data.device_results.unwrap().service_info, service_info);
+    }
+}
@@ -85,6 +85,7 @@ pub struct DirectoryStoreFilterType {
     directory: PathBuf,
     neqs: Vec<(String, Vec<u8>)>,
     lts: Vec<(String, i64)>,
+    error: Option<StoreError>,
 }
 
 fn format_xattr(key: &str) -> String {
@@ -98,13 +99,22 @@ where
     MKT: MetadataLocalKey,
 {
     fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
-        self.neqs
-            .push((key.to_key().to_owned(), expected.to_stored().unwrap()));
+        match expected.to_stored() {
+            Ok(expected) => self.neqs.push((key.to_key().to_owned(), expected)),
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+            }
+        }
     }
     fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
         self.lts.push((key.to_key().to_owned(), max));
     }
     async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
         let dir_entries = match fs::read_dir(&self.directory) {
             Err(e) => {
                 log::trace!(
@@ -138,45 +148,39 @@ where
                 Ok(v) if v.is_file() => {}
                 Ok(_) => continue,
             }
-            let mut neqs: HashSet<PathBuf> = HashSet::new();
-            for neq in &self.neqs {
-                let (key, expected) = neq;
+            let mut matches = true;
+            for (key, expected) in &self.neqs {
                 match xattr::get(path.clone(), format_xattr(key)) {
                     Ok(Some(v)) => {
-                        let matching = expected.iter().zip(&v).filter(|&(a, b)| a == b).count();
-                        if expected.len() != matching {
-                            neqs.insert(path.clone());
+                        if expected == &v {
+                            matches = false;
                         }
                     }
-                    Ok(None) => {
-                        neqs.insert(path.clone());
-                    }
+                    Ok(None) => {}
                     Err(e) => {
-                        log::trace!("Error checking {}: {}", key, e.to_string());
-                        continue;
+                        log::trace!("Error checking {}: {}", key, e);
+                        matches = false;
                     }
                 }
             }
-            for n in neqs {
-                for lt in &self.lts {
-                    let (key, max) = lt;
-                    match xattr::get(n.clone(), format_xattr(key)) {
-                        Ok(Some(v)) => {
-                            let value = i64::from_le_bytes(v.try_into().unwrap());
-                            if value < *max {
-                                results.insert(n.clone());
-                            }
-                        }
-                        Ok(None) => {
-                            results.insert(n.clone());
-                        }
-                        Err(e) => {
-                            log::trace!("Error checking {}: {}", key, e.to_string());
-                            continue;
+            for (key, max) in &self.lts {
+                match xattr::get(path.clone(), format_xattr(key)) {
+                    Ok(Some(v)) => {
+                        let value = i64::from_le_bytes(v.try_into().unwrap());
+                        if value >= *max {
+                            matches = false;
                         }
                     }
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::trace!("Error checking {}: {}", key, e);
+                        matches = false;
+                    }
                 }
             }
+            if matches {
+                results.insert(path);
+            }
         }
         let mut values = Vec::new();
         for r in results {
@@ -248,6 +252,25 @@ where
         })?))
     }
 
+    async fn load_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        let path = self.get_path(key);
+        log::trace!("Attempting to load metadata from {}", path.display());
+
+        match xattr::get(&path, format_xattr(metadata_key.to_key())) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StoreError::Unspecified(format!(
+                "Error reading xattr on {}: {:?}",
+                path.display(),
+                e
+            ))),
+            Ok(v) => Ok(v),
+        }
+    }
+
     async fn store_metadata(
         &self,
         key: &K,
@@ -317,6 +340,7 @@ where
             directory: self.directory.clone(),
             neqs: Vec::new(),
             lts: Vec::new(),
+            error: None,
         }))
     }
 
@@ -413,3 +437,20 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+    use crate::StoreConfig;
+
+    #[tokio::test]
+    async fn test_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StoreConfig::Directory {
+            path: dir.path().to_path_buf(),
+        }
+        .initialize()
+        .unwrap();
+        test_utils::test_filters(store).await;
+    }
+}
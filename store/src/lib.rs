@@ -6,7 +6,7 @@ use thiserror::Error;
 
 use fdo_data_formats::Serializable;
 
-#[derive(Debug, Error)]
+#[derive(Clone, Debug, Error)]
 pub enum StoreError {
     #[error("Unspecified error occured: {0}")]
     Unspecified(String),
@@ -54,6 +54,24 @@ impl MetadataValue for bool {
     }
 }
 
+impl MetadataValue for u32 {
+    fn to_stored(&self) -> Result<Vec<u8>, StoreError> {
+        Ok(self.to_string().as_bytes().to_vec())
+    }
+    fn to_text(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl MetadataValue for String {
+    fn to_stored(&self) -> Result<Vec<u8>, StoreError> {
+        Ok(self.as_bytes().to_vec())
+    }
+    fn to_text(&self) -> String {
+        self.clone()
+    }
+}
+
 impl MetadataValue for time::Duration {
     fn to_stored(&self) -> Result<Vec<u8>, StoreError> {
         let ttl = time::OffsetDateTime::now_utc() + *self;
@@ -76,7 +94,7 @@ pub enum MetadataKey<T: MetadataLocalKey> {
 }
 
 impl<T: MetadataLocalKey> MetadataKey<T> {
-    fn to_key(&self) -> &str {
+    fn to_key(&self) -> &'static str {
         match self {
             MetadataKey::Ttl => "store_ttl",
             MetadataKey::Local(k) => k.to_key(),
@@ -91,7 +109,13 @@ where
     V: Clone,
     MKT: MetadataLocalKey,
 {
+    /// Only match entries where the metadata value differs from expected
+    ///
+    /// Entries without the metadata key set match both neq and lt filters.
+    /// If expected can not be converted to its stored form, query returns
+    /// the conversion error.
     fn neq(&mut self, key: &MetadataKey<MKT>, expected: &dyn MetadataValue);
+    /// Only match entries where the metadata value is lower than max
     fn lt(&mut self, key: &MetadataKey<MKT>, max: i64);
     fn query<'life0, 'async_trait>(
         &'life0 self,
@@ -138,6 +162,7 @@ where
 }
 
 type QueryResult<V, MKT> = Result<Box<dyn FilterType<V, MKT>>, StoreError>;
+type MetadataResult = Result<Option<Vec<u8>>, StoreError>;
 
 pub trait Store<OT: StoreOpenMode, K, V, MKT: MetadataLocalKey>: Send + Sync {
     fn load_data<'life0, 'life1, 'async_trait>(
@@ -150,6 +175,19 @@ pub trait Store<OT: StoreOpenMode, K, V, MKT: MetadataLocalKey>: Send + Sync {
         Self: 'async_trait,
         OT: Readable;
 
+    /// Returns the stored representation of a metadata value, if it is set
+    fn load_metadata<'life0, 'life1, 'life2, 'async_trait>(
+        &'life0 self,
+        key: &'life1 K,
+        metadata_key: &'life2 MetadataKey<MKT>,
+    ) -> Pin<Box<dyn Future<Output = MetadataResult> + 'async_trait + Send>>
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+        OT: Readable;
+
     fn store_metadata<'life0, 'life1, 'life2, 'life3, 'async_trait>(
         &'life0 self,
         key: &'life1 K,
@@ -215,11 +253,27 @@ pub trait Store<OT: StoreOpenMode, K, V, MKT: MetadataLocalKey>: Send + Sync {
 
 #[cfg(feature = "directory")]
 mod directory;
+mod memory;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod sql;
+#[cfg(test)]
+mod test_utils;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum StoreConfig {
     #[cfg(feature = "directory")]
-    Directory { path: std::path::PathBuf },
+    Directory {
+        path: std::path::PathBuf,
+    },
+    InMemory,
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        path: std::path::PathBuf,
+    },
+    #[cfg(feature = "postgres")]
+    Postgres {
+        url: String,
+    },
 }
 
 impl StoreConfig {
@@ -227,13 +281,25 @@ impl StoreConfig {
     where
         OT: StoreOpenMode + 'static,
         // K and V are supersets of the possible requirements for the different implementations
-        K: Eq + std::hash::Hash + Send + Sync + std::string::ToString + std::str::FromStr + 'static,
+        K: Eq
+            + std::hash::Hash
+            + Clone
+            + Send
+            + Sync
+            + std::string::ToString
+            + std::str::FromStr
+            + 'static,
         V: Send + Sync + Clone + Serializable + 'static,
         MKT: crate::MetadataLocalKey + 'static,
     {
         match self {
             #[cfg(feature = "directory")]
             StoreConfig::Directory { path } => directory::initialize(path),
+            StoreConfig::InMemory => memory::initialize(),
+            #[cfg(feature = "sqlite")]
+            StoreConfig::Sqlite { path } => sql::initialize_sqlite(path),
+            #[cfg(feature = "postgres")]
+            StoreConfig::Postgres { url } => sql::initialize_postgres(url),
         }
     }
 }
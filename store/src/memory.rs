@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use crate::{FilterType, MetadataLocalKey, MetadataValue, ValueIter};
+
+use super::Store;
+use super::StoreError;
+
+pub(super) fn initialize<OT, K, V, MKT>() -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    Ok(Box::new(MemoryStore {
+        entries: Arc::new(RwLock::new(HashMap::new())),
+    }))
+}
+
+#[derive(Debug, Clone)]
+struct StoredMetadata {
+    value: Vec<u8>,
+    // The value as an integer, if it is one, for lt filters
+    value_int: Option<i64>,
+}
+
+#[derive(Debug)]
+struct MemoryEntry<V> {
+    value: V,
+    metadata: HashMap<&'static str, StoredMetadata>,
+}
+
+impl<V> MemoryEntry<V> {
+    fn is_expired<MKT: MetadataLocalKey>(&self, now: i64) -> bool {
+        match self.metadata.get(crate::MetadataKey::<MKT>::Ttl.to_key()) {
+            Some(StoredMetadata {
+                value_int: Some(ttl),
+                ..
+            }) => now > *ttl,
+            _ => false,
+        }
+    }
+}
+
+type Entries<K, V> = Arc<RwLock<HashMap<K, MemoryEntry<V>>>>;
+
+#[derive(Debug)]
+struct MemoryStore<K, V> {
+    entries: Entries<K, V>,
+}
+
+pub struct MemoryStoreFilterType<K, V> {
+    entries: Entries<K, V>,
+    neqs: Vec<(&'static str, Vec<u8>)>,
+    lts: Vec<(&'static str, i64)>,
+    error: Option<StoreError>,
+}
+
+#[async_trait]
+impl<K, V, MKT> FilterType<V, MKT> for MemoryStoreFilterType<K, V>
+where
+    K: Send + Sync,
+    V: Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey,
+{
+    fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
+        match expected.to_stored() {
+            Ok(expected) => self.neqs.push((key.to_key(), expected)),
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+    fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
+        self.lts.push((key.to_key(), max));
+    }
+    async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        let entries = self.entries.read().await;
+        let values = entries
+            .values()
+            .filter(|entry| {
+                self.neqs
+                    .iter()
+                    .all(|(key, expected)| match entry.metadata.get(key) {
+                        None => true,
+                        Some(stored) => &stored.value != expected,
+                    })
+            })
+            .filter(|entry| {
+                self.lts
+                    .iter()
+                    .all(|(key, max)| match entry.metadata.get(key) {
+                        None => true,
+                        Some(stored) => matches!(stored.value_int, Some(value) if value < *max),
+                    })
+            })
+            .map(|entry| entry.value.clone())
+            .collect();
+        Ok(Some(ValueIter {
+            index: 0,
+            values,
+            errored: false,
+        }))
+    }
+}
+
+#[async_trait]
+impl<OT, K, V, MKT> Store<OT, K, V, MKT> for MemoryStore<K, V>
+where
+    OT: crate::StoreOpenMode,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError> {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let mut entries = self.entries.write().await;
+
+        match entries.get(key) {
+            None => Ok(None),
+            Some(entry) if entry.is_expired::<MKT>(now) => {
+                log::trace!("Item has expired, removing");
+                entries.remove(key);
+                Ok(None)
+            }
+            Some(entry) => Ok(Some(entry.value.clone())),
+        }
+    }
+
+    async fn load_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.entries.read().await.get(key).and_then(|entry| {
+            entry
+                .metadata
+                .get(metadata_key.to_key())
+                .map(|stored| stored.value.clone())
+        }))
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError> {
+        let stored = StoredMetadata {
+            value: metadata_value.to_stored()?,
+            value_int: metadata_value.to_text().parse().ok(),
+        };
+
+        // Just like with files, metadata for entries that don't exist is dropped
+        if let Some(entry) = self.entries.write().await.get_mut(key) {
+            entry.metadata.insert(metadata_key.to_key(), stored);
+        }
+        Ok(())
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError> {
+        if let Some(entry) = self.entries.write().await.get_mut(key) {
+            entry.metadata.remove(metadata_key.to_key());
+        }
+        Ok(())
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT> {
+        Ok(Box::new(MemoryStoreFilterType {
+            entries: self.entries.clone(),
+            neqs: Vec::new(),
+            lts: Vec::new(),
+            error: None,
+        }))
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError> {
+        // Replacing an entry drops its metadata, matching the directory store
+        self.entries.write().await.insert(
+            key,
+            MemoryEntry {
+                value,
+                metadata: HashMap::new(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError> {
+        self.entries.write().await.remove(key);
+        Ok(())
+    }
+
+    async fn perform_maintenance(&self) -> Result<(), StoreError> {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let mut entries = self.entries.write().await;
+
+        let before = entries.len();
+        entries.retain(|_, entry| !entry.is_expired::<MKT>(now));
+        log::trace!("Removed {} expired entries", before - entries.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{self, TestMetadataKey, TestStore};
+    use crate::{MetadataKey, StoreConfig};
+
+    #[tokio::test]
+    async fn test_filters() {
+        test_utils::test_filters(StoreConfig::InMemory.initialize().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_load_metadata() {
+        let store: TestStore = StoreConfig::InMemory.initialize().unwrap();
+        let key = "entry".to_string();
+
+        store.store_data(key.clone(), key.clone()).await.unwrap();
+        assert_eq!(
+            store
+                .load_metadata(&key, &MetadataKey::Local(TestMetadataKey::Counter))
+                .await
+                .unwrap(),
+            None
+        );
+        store
+            .store_metadata(&key, &MetadataKey::Local(TestMetadataKey::Counter), &42u32)
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .load_metadata(&key, &MetadataKey::Local(TestMetadataKey::Counter))
+                .await
+                .unwrap(),
+            Some(b"42".to_vec())
+        );
+
+        // Replacing the entry drops its metadata
+        store.store_data(key.clone(), key.clone()).await.unwrap();
+        assert_eq!(
+            store
+                .load_metadata(&key, &MetadataKey::Local(TestMetadataKey::Counter))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let store: TestStore = StoreConfig::InMemory.initialize().unwrap();
+
+        for key in ["expired", "valid"] {
+            store
+                .store_data(key.to_string(), key.to_string())
+                .await
+                .unwrap();
+        }
+        store
+            .store_metadata(
+                &"expired".to_string(),
+                &MetadataKey::Ttl,
+                &time::Duration::new(-10, 0),
+            )
+            .await
+            .unwrap();
+        store
+            .store_metadata(
+                &"valid".to_string(),
+                &MetadataKey::Ttl,
+                &time::Duration::new(600, 0),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(store.load_data(&"expired".to_string()).await.unwrap(), None);
+
+        store.perform_maintenance().await.unwrap();
+        let values: Vec<String> = store
+            .query_data()
+            .await
+            .unwrap()
+            .query()
+            .await
+            .unwrap()
+            .unwrap()
+            .collect();
+        assert_eq!(values, vec!["valid".to_string()]);
+    }
+}
@@ -0,0 +1,599 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use sqlx::any::{AnyConnectOptions, AnyPool, AnyPoolOptions};
+use sqlx::Row;
+use tokio::sync::OnceCell;
+
+use fdo_data_formats::Serializable;
+
+use crate::{FilterType, MetadataLocalKey, MetadataValue, ValueIter};
+
+use super::Store;
+use super::StoreError;
+
+const DATA_TABLE: &str = "fdo_store_data";
+const METADATA_TABLE: &str = "fdo_store_metadata";
+
+#[cfg(feature = "sqlite")]
+pub(super) fn initialize_sqlite<OT, K, V, MKT>(
+    path: &std::path::Path,
+) -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    if !path.is_absolute() {
+        return Err(StoreError::Configuration(
+            "Storage database path is not absolute".to_string(),
+        ));
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            StoreError::Configuration(format!(
+                "Storage database directory '{:?}' could not be created: {}",
+                parent, e
+            ))
+        })?;
+    }
+
+    let options = sqlx::sqlite::SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true);
+
+    Ok(initialize(options.into()))
+}
+
+#[cfg(feature = "postgres")]
+pub(super) fn initialize_postgres<OT, K, V, MKT>(
+    url: &str,
+) -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    let options: sqlx::postgres::PgConnectOptions = url.parse().map_err(|e| {
+        StoreError::Configuration(format!("Invalid PostgreSQL connection URL: {}", e))
+    })?;
+
+    Ok(initialize(options.into()))
+}
+
+fn initialize<OT, K, V, MKT>(options: AnyConnectOptions) -> Box<dyn Store<OT, K, V, MKT>>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    // Connections are only established on first use, so that initialize can stay synchronous
+    let pool = AnyPoolOptions::new().connect_lazy_with(options);
+
+    Box::new(SqlStore {
+        phantom_k: PhantomData,
+        phantom_v: PhantomData,
+
+        pool,
+        schema_created: OnceCell::new(),
+    })
+}
+
+fn db_error(context: &str, e: sqlx::Error) -> StoreError {
+    StoreError::Unspecified(format!("Database error {}: {}", context, e))
+}
+
+#[derive(Debug)]
+struct SqlStore<K, V> {
+    phantom_k: PhantomData<K>,
+    phantom_v: PhantomData<V>,
+
+    pool: AnyPool,
+    schema_created: OnceCell<()>,
+}
+
+impl<K, V> SqlStore<K, V> {
+    async fn ensure_schema(&self) -> Result<(), StoreError> {
+        self.schema_created
+            .get_or_try_init(|| async {
+                let blob_type = match self.pool.any_kind() {
+                    #[cfg(feature = "postgres")]
+                    sqlx::any::AnyKind::Postgres => "BYTEA",
+                    #[allow(unreachable_patterns)]
+                    _ => "BLOB",
+                };
+
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                        key TEXT NOT NULL PRIMARY KEY,
+                        value {} NOT NULL
+                    )",
+                    DATA_TABLE, blob_type
+                ))
+                .execute(&self.pool)
+                .await
+                .map_err(|e| db_error("creating data table", e))?;
+                // value_int contains the value as an integer if it is one, for lt filters
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                        key TEXT NOT NULL,
+                        name TEXT NOT NULL,
+                        value {} NOT NULL,
+                        value_int BIGINT,
+                        PRIMARY KEY (key, name)
+                    )",
+                    METADATA_TABLE, blob_type
+                ))
+                .execute(&self.pool)
+                .await
+                .map_err(|e| db_error("creating metadata table", e))?;
+
+                Ok(())
+            })
+            .await
+            .map(|_| ())
+    }
+
+    async fn remove_entry(&self, key: &str) -> Result<(), StoreError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| db_error("starting transaction", e))?;
+        sqlx::query(&format!("DELETE FROM {} WHERE key = $1", METADATA_TABLE))
+            .bind(key)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| db_error("removing metadata", e))?;
+        sqlx::query(&format!("DELETE FROM {} WHERE key = $1", DATA_TABLE))
+            .bind(key)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| db_error("removing data", e))?;
+        tx.commit()
+            .await
+            .map_err(|e| db_error("committing transaction", e))
+    }
+}
+
+pub struct SqlStoreFilterType {
+    pool: AnyPool,
+    neqs: Vec<(String, Vec<u8>)>,
+    lts: Vec<(String, i64)>,
+    error: Option<StoreError>,
+}
+
+#[async_trait]
+impl<V, MKT> FilterType<V, MKT> for SqlStoreFilterType
+where
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey,
+{
+    fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
+        match expected.to_stored() {
+            Ok(expected) => self.neqs.push((key.to_key().to_owned(), expected)),
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+    fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
+        self.lts.push((key.to_key().to_owned(), max));
+    }
+    async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        let mut query = format!("SELECT d.key, d.value FROM {} d WHERE 1 = 1", DATA_TABLE);
+        let mut param = 1;
+        for _ in &self.neqs {
+            query.push_str(&format!(
+                " AND NOT EXISTS (SELECT 1 FROM {} m WHERE m.key = d.key AND m.name = ${} AND m.value = ${})",
+                METADATA_TABLE,
+                param,
+                param + 1
+            ));
+            param += 2;
+        }
+        for _ in &self.lts {
+            query.push_str(&format!(
+                " AND NOT EXISTS (SELECT 1 FROM {} m WHERE m.key = d.key AND m.name = ${} AND (m.value_int IS NULL OR m.value_int >= ${}))",
+                METADATA_TABLE,
+                param,
+                param + 1
+            ));
+            param += 2;
+        }
+
+        let mut query = sqlx::query(&query);
+        for (key, expected) in &self.neqs {
+            query = query.bind(key.as_str()).bind(expected.as_slice());
+        }
+        for (key, max) in &self.lts {
+            query = query.bind(key.as_str()).bind(*max);
+        }
+
+        let rows = query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| db_error("querying data", e))?;
+
+        let mut values = Vec::new();
+        for row in rows {
+            let key: String = row.try_get(0).map_err(|e| db_error("reading key", e))?;
+            let value: Vec<u8> = row.try_get(1).map_err(|e| db_error("reading value", e))?;
+            match V::deserialize_data(&value) {
+                Ok(v) => values.push(v),
+                Err(e) => log::trace!("Error deserializing data {}: {}", key, e),
+            }
+        }
+        Ok(Some(ValueIter {
+            index: 0,
+            values,
+            errored: false,
+        }))
+    }
+}
+
+#[async_trait]
+impl<OT, K, V, MKT> Store<OT, K, V, MKT> for SqlStore<K, V>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError> {
+        self.ensure_schema().await?;
+        let key = key.to_string();
+        log::trace!("Attempting to load data for {}", key);
+
+        let row = sqlx::query(&format!(
+            "SELECT d.value, t.value_int FROM {} d LEFT JOIN {} t ON t.key = d.key AND t.name = $2 WHERE d.key = $1",
+            DATA_TABLE, METADATA_TABLE
+        ))
+        .bind(key.as_str())
+        .bind(crate::MetadataKey::<MKT>::Ttl.to_key())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| db_error("loading data", e))?;
+        let row = match row {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+
+        let ttl: Option<i64> = row.try_get(1).map_err(|e| db_error("reading TTL", e))?;
+        if let Some(ttl) = ttl {
+            if time::OffsetDateTime::now_utc().unix_timestamp() > ttl {
+                log::trace!("Item has expired, attempting removal");
+                if let Err(e) = self.remove_entry(&key).await {
+                    log::info!("Error deleting expired entry {}: {}", key, e);
+                }
+                return Ok(None);
+            }
+        }
+
+        let value: Vec<u8> = row.try_get(0).map_err(|e| db_error("reading value", e))?;
+        Ok(Some(V::deserialize_data(&value).map_err(|e| {
+            StoreError::Unspecified(format!("Error deserializing value: {:?}", e))
+        })?))
+    }
+
+    async fn load_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        self.ensure_schema().await?;
+        let key = key.to_string();
+        log::trace!(
+            "Attempting to load metadata {} for {}",
+            metadata_key.to_key(),
+            key
+        );
+
+        let row = sqlx::query(&format!(
+            "SELECT value FROM {} WHERE key = $1 AND name = $2",
+            METADATA_TABLE
+        ))
+        .bind(key.as_str())
+        .bind(metadata_key.to_key())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| db_error("loading metadata", e))?;
+        match row {
+            None => Ok(None),
+            Some(row) => Ok(Some(
+                row.try_get(0)
+                    .map_err(|e| db_error("reading metadata", e))?,
+            )),
+        }
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError> {
+        self.ensure_schema().await?;
+        let key = key.to_string();
+        log::trace!(
+            "Attempting to store metadata {} for {}",
+            metadata_key.to_key(),
+            key
+        );
+
+        // Just like with files, metadata for entries that don't exist is dropped
+        sqlx::query(&format!(
+            "INSERT INTO {} (key, name, value, value_int)
+                SELECT $1, $2, $3, $4 WHERE EXISTS (SELECT 1 FROM {} WHERE key = $1)
+                ON CONFLICT (key, name) DO UPDATE SET value = excluded.value, value_int = excluded.value_int",
+            METADATA_TABLE, DATA_TABLE
+        ))
+        .bind(key.as_str())
+        .bind(metadata_key.to_key())
+        .bind(metadata_value.to_stored()?)
+        .bind(metadata_value.to_text().parse::<i64>().ok())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| db_error("storing metadata", e))?;
+
+        Ok(())
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError> {
+        self.ensure_schema().await?;
+        let key = key.to_string();
+        log::trace!(
+            "Attempting to remove metadata {} for {}",
+            metadata_key.to_key(),
+            key
+        );
+
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE key = $1 AND name = $2",
+            METADATA_TABLE
+        ))
+        .bind(key.as_str())
+        .bind(metadata_key.to_key())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| db_error("removing metadata", e))?;
+
+        Ok(())
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT> {
+        self.ensure_schema().await?;
+        Ok(Box::new(SqlStoreFilterType {
+            pool: self.pool.clone(),
+            neqs: Vec::new(),
+            lts: Vec::new(),
+            error: None,
+        }))
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError> {
+        self.ensure_schema().await?;
+        let key = key.to_string();
+        log::trace!("Attempting to store data for {}", key);
+
+        let value = value
+            .serialize_data()
+            .map_err(|e| StoreError::Unspecified(format!("Error serializing value: {:?}", e)))?;
+
+        // Replacing an entry drops its metadata, matching the directory store
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| db_error("starting transaction", e))?;
+        sqlx::query(&format!("DELETE FROM {} WHERE key = $1", METADATA_TABLE))
+            .bind(key.as_str())
+            .execute(&mut tx)
+            .await
+            .map_err(|e| db_error("removing metadata", e))?;
+        sqlx::query(&format!(
+            "INSERT INTO {} (key, value) VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            DATA_TABLE
+        ))
+        .bind(key.as_str())
+        .bind(value)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| db_error("storing data", e))?;
+        tx.commit()
+            .await
+            .map_err(|e| db_error("committing transaction", e))
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError> {
+        self.ensure_schema().await?;
+        let key = key.to_string();
+        log::trace!("Attempting to delete data for {}", key);
+
+        self.remove_entry(&key).await
+    }
+
+    async fn perform_maintenance(&self) -> Result<(), StoreError> {
+        self.ensure_schema().await?;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let ttl_key = crate::MetadataKey::<MKT>::Ttl.to_key();
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| db_error("starting transaction", e))?;
+        let removed = sqlx::query(&format!(
+            "DELETE FROM {} WHERE key IN (SELECT key FROM {} WHERE name = $1 AND value_int < $2)",
+            DATA_TABLE, METADATA_TABLE
+        ))
+        .bind(ttl_key)
+        .bind(now)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| db_error("removing expired data", e))?;
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE key NOT IN (SELECT key FROM {})",
+            METADATA_TABLE, DATA_TABLE
+        ))
+        .execute(&mut tx)
+        .await
+        .map_err(|e| db_error("removing orphaned metadata", e))?;
+        tx.commit()
+            .await
+            .map_err(|e| db_error("committing transaction", e))?;
+
+        log::trace!("Removed {} expired entries", removed.rows_affected());
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use crate::test_utils::{self, TestMetadataKey, TestStore};
+    use crate::{MetadataKey, StoreConfig};
+
+    fn sqlite_store(dir: &tempfile::TempDir) -> TestStore {
+        StoreConfig::Sqlite {
+            path: dir.path().join("store.sqlite"),
+        }
+        .initialize()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_store_and_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = sqlite_store(&dir);
+
+        for key in ["one", "two", "three"] {
+            store
+                .store_data(key.to_string(), format!("value-{}", key))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            store.load_data(&"two".to_string()).await.unwrap(),
+            Some("value-two".to_string())
+        );
+        assert_eq!(store.load_data(&"four".to_string()).await.unwrap(), None);
+
+        store
+            .store_metadata(
+                &"one".to_string(),
+                &MetadataKey::Local(TestMetadataKey::Flag),
+                &true,
+            )
+            .await
+            .unwrap();
+        store
+            .store_metadata(
+                &"two".to_string(),
+                &MetadataKey::Local(TestMetadataKey::Counter),
+                &time::Duration::new(3600, 0),
+            )
+            .await
+            .unwrap();
+
+        let mut ft = store.query_data().await.unwrap();
+        ft.neq(&MetadataKey::Local(TestMetadataKey::Flag), &true);
+        ft.lt(
+            &MetadataKey::Local(TestMetadataKey::Counter),
+            time::OffsetDateTime::now_utc().unix_timestamp(),
+        );
+        let values: Vec<String> = ft.query().await.unwrap().unwrap().collect();
+        assert_eq!(values, vec!["value-three".to_string()]);
+
+        assert_eq!(
+            store
+                .load_metadata(
+                    &"one".to_string(),
+                    &MetadataKey::Local(TestMetadataKey::Flag)
+                )
+                .await
+                .unwrap(),
+            Some(b"true".to_vec())
+        );
+        assert_eq!(
+            store
+                .load_metadata(
+                    &"three".to_string(),
+                    &MetadataKey::Local(TestMetadataKey::Flag)
+                )
+                .await
+                .unwrap(),
+            None
+        );
+
+        store.destroy_data(&"three".to_string()).await.unwrap();
+        assert_eq!(store.load_data(&"three".to_string()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        test_utils::test_filters(sqlite_store(&dir)).await;
+    }
+
+    #[tokio::test]
+    async fn test_ttl_maintenance() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = sqlite_store(&dir);
+
+        store
+            .store_data("expired".to_string(), "old".to_string())
+            .await
+            .unwrap();
+        store
+            .store_data("valid".to_string(), "new".to_string())
+            .await
+            .unwrap();
+        store
+            .store_metadata(
+                &"expired".to_string(),
+                &MetadataKey::Ttl,
+                &time::Duration::new(-10, 0),
+            )
+            .await
+            .unwrap();
+        store
+            .store_metadata(
+                &"valid".to_string(),
+                &MetadataKey::Ttl,
+                &time::Duration::new(600, 0),
+            )
+            .await
+            .unwrap();
+
+        store.perform_maintenance().await.unwrap();
+
+        let values: Vec<String> = store
+            .query_data()
+            .await
+            .unwrap()
+            .query()
+            .await
+            .unwrap()
+            .unwrap()
+            .collect();
+        assert_eq!(values, vec!["new".to_string()]);
+        assert_eq!(
+            store.load_data(&"valid".to_string()).await.unwrap(),
+            Some("new".to_string())
+        );
+    }
+}
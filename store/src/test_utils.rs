@@ -0,0 +1,96 @@
+use crate::{MetadataKey, MetadataLocalKey, MetadataValue, ReadWriteOpen, Store, StoreError};
+
+pub(crate) enum TestMetadataKey {
+    Flag,
+    Counter,
+}
+
+impl MetadataLocalKey for TestMetadataKey {
+    fn to_key(&self) -> &'static str {
+        match self {
+            TestMetadataKey::Flag => "test.flag",
+            TestMetadataKey::Counter => "test.counter",
+        }
+    }
+}
+
+pub(crate) type TestStore = Box<dyn Store<ReadWriteOpen, String, String, TestMetadataKey>>;
+
+/// A metadata value that can not be converted to its stored form
+pub(crate) struct UnstorableValue;
+
+impl MetadataValue for UnstorableValue {
+    fn to_stored(&self) -> Result<Vec<u8>, StoreError> {
+        Err(StoreError::Unspecified(
+            "value can not be stored".to_string(),
+        ))
+    }
+    fn to_text(&self) -> String {
+        "unstorable".to_string()
+    }
+}
+
+/// Checks the filter semantics every store backend has to implement
+pub(crate) async fn test_filters(store: TestStore) {
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    for key in ["flagged", "future", "past", "plain"] {
+        store
+            .store_data(key.to_string(), key.to_string())
+            .await
+            .unwrap();
+    }
+    store
+        .store_metadata(
+            &"flagged".to_string(),
+            &MetadataKey::Local(TestMetadataKey::Flag),
+            &true,
+        )
+        .await
+        .unwrap();
+    store
+        .store_metadata(
+            &"future".to_string(),
+            &MetadataKey::Local(TestMetadataKey::Counter),
+            &time::Duration::new(3600, 0),
+        )
+        .await
+        .unwrap();
+    store
+        .store_metadata(
+            &"past".to_string(),
+            &MetadataKey::Local(TestMetadataKey::Counter),
+            &time::Duration::new(-3600, 0),
+        )
+        .await
+        .unwrap();
+
+    let ft = store.query_data().await.unwrap();
+    assert_eq!(ft.query().await.unwrap().unwrap().count(), 4);
+
+    let mut ft = store.query_data().await.unwrap();
+    ft.neq(&MetadataKey::Local(TestMetadataKey::Flag), &true);
+    assert_eq!(ft.query().await.unwrap().unwrap().count(), 3);
+
+    let mut ft = store.query_data().await.unwrap();
+    ft.neq(&MetadataKey::Local(TestMetadataKey::Flag), &true);
+    ft.lt(&MetadataKey::Local(TestMetadataKey::Counter), now);
+    let mut values: Vec<String> = ft.query().await.unwrap().unwrap().collect();
+    values.sort();
+    assert_eq!(values, vec!["past".to_string(), "plain".to_string()]);
+
+    store
+        .destroy_metadata(
+            &"flagged".to_string(),
+            &MetadataKey::Local(TestMetadataKey::Flag),
+        )
+        .await
+        .unwrap();
+    let mut ft = store.query_data().await.unwrap();
+    ft.neq(&MetadataKey::Local(TestMetadataKey::Flag), &true);
+    assert_eq!(ft.query().await.unwrap().unwrap().count(), 4);
+
+    let mut ft = store.query_data().await.unwrap();
+    ft.neq(&MetadataKey::Local(TestMetadataKey::Flag), &UnstorableValue);
+    assert!(ft.query().await.is_err());
+}
@@ -0,0 +1,14 @@
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(ossl300)");
+
+    // The OSSL_STORE_INFO type numbers used for PKCS#11 keys changed in OpenSSL 3.0
+    if let Ok(version) = env::var("DEP_OPENSSL_VERSION_NUMBER") {
+        let version = u64::from_str_radix(&version, 16)
+            .expect("Invalid OpenSSL version number from openssl-sys");
+        if version >= 0x3000_0000 {
+            println!("cargo:rustc-cfg=ossl300");
+        }
+    }
+}
@@ -2,9 +2,13 @@ use std::env;
 use std::fs;
 use std::path::Path;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
-use fdo_data_formats::{devicecredential::FileDeviceCredential, DeviceCredential, Serializable};
+use fdo_data_formats::{
+    devicecredential::FileDeviceCredential,
+    types::{Guid, Hash, RendezvousInfo},
+    DeviceCredential, Serializable,
+};
 
 pub fn find() -> Option<Result<Box<dyn UsableDeviceCredentialLocation>>> {
     let device_credential_locations: &[Box<dyn DeviceCredentialLocation>] = &[
@@ -12,6 +16,7 @@ pub fn find() -> Option<Result<Box<dyn UsableDeviceCredentialLocation>>> {
             path: "/sys/firmware/qemu_fw_cfg/by_name/opt/device_onboarding/devicecredential/raw"
                 .to_string(),
             deactivation_method: DeactivationMethod::None,
+            read_only: true,
         }),
         Box::new(FileSystemPathEnv {
             env_var: "DEVICE_CREDENTIAL".to_string(),
@@ -43,12 +48,19 @@ pub trait DeviceCredentialLocation: std::fmt::Debug {
 pub trait UsableDeviceCredentialLocation: DeviceCredentialLocation {
     fn read(&self) -> Result<Box<dyn DeviceCredential>>;
     fn deactivate(&self) -> Result<()>;
+    /// Whether the credential can be replaced, see `replace`
+    fn is_replaceable(&self) -> bool;
+    /// Replaces the credential with the values the owner sent in TO2.SetupDevice,
+    /// and keeps it active
+    fn replace(&self, guid: &Guid, rvinfo: &RendezvousInfo, pubkey_hash: &Hash) -> Result<()>;
 }
 
 #[derive(Debug, Clone)]
 struct FileSystemPath {
     path: String,
     deactivation_method: DeactivationMethod,
+    // Whether the credential can not be written, like one passed in by the hypervisor
+    read_only: bool,
 }
 
 impl DeviceCredentialLocation for FileSystemPath {
@@ -79,6 +91,33 @@ impl UsableDeviceCredentialLocation for FileSystemPath {
             DeactivationMethod::Deactivate => self.perform_deactivation(),
         }
     }
+
+    fn is_replaceable(&self) -> bool {
+        !self.read_only
+    }
+
+    fn replace(&self, guid: &Guid, rvinfo: &RendezvousInfo, pubkey_hash: &Hash) -> Result<()> {
+        if self.read_only {
+            bail!(
+                "Device credential at {} is read-only and can not be replaced",
+                &self.path
+            );
+        }
+        let contents = fs::read(&self.path)
+            .with_context(|| format!("Error reading (device credential) file at {}", &self.path))?;
+        let mut fdc = FileDeviceCredential::deserialize_data(&contents)
+            .with_context(|| format!("Error parsing device credential from {}", &self.path))?;
+
+        fdc.active = true;
+        fdc.guid = guid.clone();
+        fdc.rvinfo = rvinfo.clone();
+        fdc.pubkey_hash = pubkey_hash.clone();
+        let new_dc_contents = fdc
+            .serialize_data()
+            .context("Error serializing replacement device credential")?;
+        self.write(new_dc_contents)
+            .context("Error writing out replacement device credential")
+    }
 }
 
 impl FileSystemPath {
@@ -133,6 +172,7 @@ impl DeviceCredentialLocation for FileSystemPathEnv {
         FileSystemPath {
             path: env_val,
             deactivation_method,
+            read_only: false,
         }
         .resolve()
     }
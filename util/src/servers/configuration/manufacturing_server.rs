@@ -4,7 +4,7 @@ use fdo_data_formats::constants::{KeyStorageType, MfgStringType, PublicKeyType};
 use fdo_store::StoreConfig;
 use serde::{Deserialize, Serialize};
 
-use super::{AbsolutePathBuf, Bind};
+use super::{AbsolutePathBuf, Bind, PrivateKeyLocation, TlsSettings};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManufacturingServerSettings {
@@ -19,6 +19,9 @@ pub struct ManufacturingServerSettings {
 
     // Bind information
     pub bind: Bind,
+    // TLS termination, plain HTTP if not set
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
 
     pub protocols: ProtocolSetting,
 
@@ -30,11 +33,11 @@ pub struct ManufacturingServerSettings {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManufacturingSettings {
     pub manufacturer_cert_path: AbsolutePathBuf,
-    pub device_cert_ca_private_key: AbsolutePathBuf,
+    pub device_cert_ca_private_key: PrivateKeyLocation,
     pub device_cert_ca_chain: AbsolutePathBuf,
 
     pub owner_cert_path: Option<AbsolutePathBuf>,
-    pub manufacturer_private_key: Option<AbsolutePathBuf>,
+    pub manufacturer_private_key: Option<PrivateKeyLocation>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -71,6 +74,9 @@ impl From<MfgStringTypeString> for MfgStringType {
 pub enum PublicKeyTypeString {
     SECP256R1,
     SECP384R1,
+    RSA2048RESTR,
+    RSAPKCS,
+    RSAPSS,
 }
 
 impl From<PublicKeyTypeString> for PublicKeyType {
@@ -78,6 +84,9 @@ impl From<PublicKeyTypeString> for PublicKeyType {
         match key_type {
             PublicKeyTypeString::SECP256R1 => PublicKeyType::SECP256R1,
             PublicKeyTypeString::SECP384R1 => PublicKeyType::SECP384R1,
+            PublicKeyTypeString::RSA2048RESTR => PublicKeyType::Rsa2048RESTR,
+            PublicKeyTypeString::RSAPKCS => PublicKeyType::RsaPkcs,
+            PublicKeyTypeString::RSAPSS => PublicKeyType::RsaPss,
         }
     }
 }
@@ -117,3 +117,83 @@ impl AsRef<Path> for AbsolutePathBuf {
         &self.0
     }
 }
+
+const PKCS11_URI_PREFIX: &str = "pkcs11:";
+
+/// Where a private key is loaded from: either an absolute path to a DER
+/// file, or a PKCS#11 URI (RFC 7512) for keys that never leave the token.
+pub enum PrivateKeyLocation {
+    File(AbsolutePathBuf),
+    Pkcs11(String),
+}
+
+impl From<AbsolutePathBuf> for PrivateKeyLocation {
+    fn from(path: AbsolutePathBuf) -> Self {
+        PrivateKeyLocation::File(path)
+    }
+}
+
+impl Serialize for PrivateKeyLocation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            PrivateKeyLocation::File(path) => path.serialize(serializer),
+            PrivateKeyLocation::Pkcs11(uri) => serializer.serialize_str(uri),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PrivateKeyLocation {
+    fn deserialize<D>(deserializer: D) -> Result<PrivateKeyLocation, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        if s.starts_with(PKCS11_URI_PREFIX) {
+            return Ok(PrivateKeyLocation::Pkcs11(s));
+        }
+        AbsolutePathBuf::deserialize(serde::de::value::StringDeserializer::new(s))
+            .map(PrivateKeyLocation::File)
+    }
+}
+
+impl std::fmt::Debug for PrivateKeyLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PrivateKeyLocation::File(path) => f.debug_tuple("File").field(path).finish(),
+            PrivateKeyLocation::Pkcs11(_) => {
+                f.debug_tuple("Pkcs11").field(&self.to_string()).finish()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PrivateKeyLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PrivateKeyLocation::File(path) => path.fmt(f),
+            // Do not print any PIN that might be included in the URI
+            PrivateKeyLocation::Pkcs11(uri) => match uri.split_once('?') {
+                Some((path, _)) => f.write_str(path),
+                None => f.write_str(uri),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TlsSettings {
+    // PEM-encoded certificate chain, starting with the server certificate
+    pub cert_path: AbsolutePathBuf,
+    // PEM-encoded PKCS#8 or RSA private key for the server certificate
+    pub key_path: AbsolutePathBuf,
+    // If set, clients need to present a certificate signed by one of these CAs
+    #[serde(default)]
+    pub client_ca_path: Option<AbsolutePathBuf>,
+    // Also accept clients without a certificate. Clients that do present one
+    // still need it to be signed by one of the client CAs.
+    #[serde(default)]
+    pub client_auth_optional: bool,
+}
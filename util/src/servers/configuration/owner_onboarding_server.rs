@@ -1,8 +1,10 @@
+use std::collections::HashMap;
+
 use fdo_data_formats::types::RemoteConnection;
 use fdo_store::StoreConfig;
 use serde::{Deserialize, Serialize};
 
-use super::{AbsolutePathBuf, Bind};
+use super::{AbsolutePathBuf, Bind, PrivateKeyLocation, TlsSettings};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OwnerOnboardingServerSettings {
@@ -12,15 +14,50 @@ pub struct OwnerOnboardingServerSettings {
     // Session store info
     pub session_store_driver: StoreConfig,
 
+    // Ownership vouchers for devices that replaced their credential at the end
+    // of onboarding, so they can be resold. Not stored if not set.
+    #[serde(default)]
+    pub resale_ownership_voucher_store_driver: Option<StoreConfig>,
+    // Let devices keep their credential after onboarding (credential reuse),
+    // instead of replacing it with a new GUID and the Owner2Key
+    #[serde(default)]
+    pub credential_reuse: bool,
+
     // Trusted keys
     pub trusted_device_keys_path: AbsolutePathBuf,
+    // Accept device certificate chains that are not signed by a trusted device CA.
+    // This is insecure, and only meant for lab environments.
+    #[serde(default)]
+    pub allow_untrusted_device_certificates: bool,
 
     // Our private owner key
-    pub owner_private_key_path: AbsolutePathBuf,
+    pub owner_private_key_path: PrivateKeyLocation,
     pub owner_public_key_path: AbsolutePathBuf,
+    // Additional owner keys, for key rotation or serving multiple tenants.
+    // Each voucher is served with the key its last entry was signed over to.
+    #[serde(default)]
+    pub additional_owner_keys: Vec<OwnerKeySettings>,
+    // The Owner2Key that replacement credentials and resale vouchers are
+    // issued to. Generated at these paths on first start if the files do not
+    // exist. If not set, an ephemeral key is generated at every start.
+    #[serde(default)]
+    pub owner2_key: Option<OwnerKeySettings>,
+
+    // Key exchange suites (e.g. ECDH384) and cipher suites (e.g. A256GCM)
+    // devices may use. All supported suites are allowed if not set.
+    #[serde(default)]
+    pub allowed_kex_suites: Option<Vec<String>>,
+    #[serde(default)]
+    pub allowed_cipher_suites: Option<Vec<String>>,
 
     // Bind information
     pub bind: Bind,
+    // TLS termination, plain HTTP if not set
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+    // CoAP (over UDP and TCP) bind information, CoAP disabled if not set
+    #[serde(default)]
+    pub coap_bind: Option<Bind>,
 
     // Service Info API Server
     pub service_info_api_url: String,
@@ -29,4 +66,45 @@ pub struct OwnerOnboardingServerSettings {
     pub owner_addresses: Vec<RemoteConnection>,
 
     pub report_to_rendezvous_endpoint_enabled: bool,
+    // Registration policy for reporting to the rendezvous servers (TO0)
+    #[serde(default)]
+    pub to0: To0Settings,
+
+    // Ownership Voucher Management API, disabled if not set
+    #[serde(default)]
+    pub ov_management_api: Option<OwnershipVoucherManagementApiSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OwnerKeySettings {
+    pub private_key_path: PrivateKeyLocation,
+    pub public_key_path: AbsolutePathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct To0Settings {
+    // Number of seconds the rendezvous servers are asked to keep a registration.
+    // Can be overridden per ownership voucher when it is uploaded.
+    #[serde(default)]
+    pub wait_seconds: Option<u32>,
+    // Number of seconds before a registration expires at which it is renewed
+    #[serde(default)]
+    pub renew_before_expiry_seconds: Option<u32>,
+    // Delay before retrying a failed registration, doubled on each consecutive
+    // failure up to the maximum
+    #[serde(default)]
+    pub retry_initial_seconds: Option<u32>,
+    #[serde(default)]
+    pub retry_max_seconds: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OwnershipVoucherManagementApiSettings {
+    // Usernames mapped to the static shared secret they send as bearer token
+    #[serde(default)]
+    pub bearer_tokens: HashMap<String, String>,
+    // Authenticate users by their TLS client certificate, with the certificate
+    // Common Name as username. Requires tls.client_ca_path to be set.
+    #[serde(default)]
+    pub client_certificate_authentication: bool,
 }
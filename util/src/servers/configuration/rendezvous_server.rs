@@ -1,7 +1,7 @@
 use fdo_store::StoreConfig;
 use serde::{Deserialize, Serialize};
 
-use super::{AbsolutePathBuf, Bind};
+use super::{AbsolutePathBuf, Bind, TlsSettings};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RendezvousServerSettings {
@@ -13,10 +13,21 @@ pub struct RendezvousServerSettings {
 
     // Trusted keys
     pub trusted_manufacturer_keys_path: Option<AbsolutePathBuf>,
+    pub trusted_device_keys_path: Option<AbsolutePathBuf>,
+    // Accept device certificate chains that are not signed by a trusted device CA.
+    // This is insecure, and only meant for lab environments.
+    #[serde(default)]
+    pub allow_untrusted_device_certificates: bool,
 
     // Other info
     pub max_wait_seconds: Option<u32>,
 
     // Bind information
     pub bind: Bind,
+    // TLS termination, plain HTTP if not set
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+    // CoAP (over UDP and TCP) bind information, CoAP disabled if not set
+    #[serde(default)]
+    pub coap_bind: Option<Bind>,
 }
@@ -4,12 +4,15 @@ use fdo_data_formats::constants::ServiceInfoModule;
 use fdo_store::StoreConfig;
 use serde::{Deserialize, Serialize};
 
-use super::Bind;
+use super::{Bind, TlsSettings};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServiceInfoApiServerSettings {
     pub service_info: ServiceInfoSettings,
     pub bind: Bind,
+    // TLS termination, plain HTTP if not set
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
 
     pub service_info_auth_token: String,
     pub admin_auth_token: Option<String>,
@@ -27,6 +30,8 @@ pub struct ServiceInfoSettings {
 
     pub diskencryption_clevis: Option<Vec<ServiceInfoDiskEncryptionClevis>>,
 
+    pub fdo_sys: Option<ServiceInfoFdoSys>,
+
     pub additional_serviceinfo: Option<HashMap<ServiceInfoModule, Vec<(String, String)>>>,
 }
 
@@ -70,6 +75,23 @@ pub struct ServiceInfoCommand {
     pub return_stderr: bool,
 }
 
+/// Instructions for the standard fdo_sys module, sent to every device that supports it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceInfoFdoSys {
+    pub files: Option<Vec<ServiceInfoFile>>,
+    pub commands: Option<Vec<ServiceInfoFdoSysCommand>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceInfoFdoSysCommand {
+    pub command: String,
+    pub args: Vec<String>,
+    /// Run with exec_cb, which reports the exit code to the owner instead of
+    /// failing the onboarding
+    #[serde(default)]
+    pub may_fail: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServiceInfoInitialUser {
     pub username: String,
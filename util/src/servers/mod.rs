@@ -2,21 +2,51 @@ use fdo_data_formats::constants::ServiceInfoModule;
 use glob::glob;
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
 use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
+use hyper::service::Service;
+use openssl::{
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::X509,
+};
+use tokio_rustls::{
+    rustls::{
+        server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+        Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
 
 use serde_cbor::Value as CborValue;
 use serde_yaml::Value;
+use warp::{filters::BoxedFilter, Reply};
 
 pub mod configuration;
+mod pkcs11;
+
+use configuration::{PrivateKeyLocation, TlsSettings};
 
 // TODO(runcom): find a better home for this as it's shared between
 // owner-onboarding-server and manufacturing-server...
 #[non_exhaustive]
 pub enum OwnershipVoucherStoreMetadataKey {
     To2Performed,
+    // When the next registration with the rendezvous server is due
     To0AcceptOwnerWaitSeconds,
+    // Per-voucher override of the requested TO0 wait seconds
+    To0WaitSeconds,
+    // Number of consecutive failed registrations
+    To0Failures,
+    // Outcome of the last registration attempt
+    To0LastResult,
 }
 
 impl fdo_store::MetadataLocalKey for OwnershipVoucherStoreMetadataKey {
@@ -26,6 +56,9 @@ impl fdo_store::MetadataLocalKey for OwnershipVoucherStoreMetadataKey {
             OwnershipVoucherStoreMetadataKey::To0AcceptOwnerWaitSeconds => {
                 "fdo.to0_accept_owner_wait_seconds"
             }
+            OwnershipVoucherStoreMetadataKey::To0WaitSeconds => "fdo.to0_wait_seconds",
+            OwnershipVoucherStoreMetadataKey::To0Failures => "fdo.to0_failures",
+            OwnershipVoucherStoreMetadataKey::To0LastResult => "fdo.to0_last_result",
         }
     }
 }
@@ -57,6 +90,220 @@ pub fn settings_for(component: &str) -> Result<config::Config> {
         .clone())
 }
 
+/// The certificate a client authenticated with during the TLS handshake.
+///
+/// This has been verified against the configured client CAs. When TLS is
+/// terminated by [`bind_server`], it is added to the extensions of every
+/// request on the connection, so handlers can get it with
+/// `warp::ext::optional::<TlsClientCertificate>()`.
+#[derive(Debug, Clone)]
+pub struct TlsClientCertificate(X509);
+
+impl TlsClientCertificate {
+    pub fn certificate(&self) -> &X509 {
+        &self.0
+    }
+
+    /// The Common Name of the certificate subject, if it has one
+    pub fn common_name(&self) -> Option<String> {
+        self.0
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().to_string().ok())
+    }
+}
+
+fn read_pem_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Error opening certificates at {:?}", path))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("Error parsing certificates at {:?}", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found at {:?}", path);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_pem_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Error opening private key at {:?}", path))?,
+    );
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Error parsing private key at {:?}", path))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key)) | Some(rustls_pemfile::Item::RSAKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => bail!("No PKCS#8 or RSA private key found at {:?}", path),
+        }
+    }
+}
+
+fn build_tls_config(tls: &TlsSettings) -> Result<ServerConfig> {
+    let cert_chain = read_pem_certificates(tls.cert_path.as_ref())?;
+    let key = read_pem_private_key(tls.key_path.as_ref())?;
+
+    let config = ServerConfig::builder().with_safe_defaults();
+    let config = match &tls.client_ca_path {
+        None => config.with_no_client_auth(),
+        Some(client_ca_path) => {
+            let mut client_cas = RootCertStore::empty();
+            for cert in read_pem_certificates(client_ca_path.as_ref())? {
+                client_cas
+                    .add(&cert)
+                    .context("Error adding client CA certificate")?;
+            }
+            let verifier = if tls.client_auth_optional {
+                AllowAnyAnonymousOrAuthenticatedClient::new(client_cas).boxed()
+            } else {
+                AllowAnyAuthenticatedClient::new(client_cas).boxed()
+            };
+            config.with_client_cert_verifier(verifier)
+        }
+    };
+    let mut config = config
+        .with_single_cert(cert_chain, key)
+        .context("Error loading TLS certificate and key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+async fn serve_tls_connection<S>(
+    acceptor: TlsAcceptor,
+    stream: tokio::net::TcpStream,
+    mut service: S,
+) -> Result<()>
+where
+    S: Service<
+            hyper::Request<hyper::Body>,
+            Response = hyper::Response<hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let stream = acceptor
+        .accept(stream)
+        .await
+        .context("Error during TLS handshake")?;
+
+    let client_certificate = match stream.get_ref().1.peer_certificates() {
+        Some(certs) if !certs.is_empty() => Some(TlsClientCertificate(
+            X509::from_der(&certs[0].0).context("Error parsing client certificate")?,
+        )),
+        _ => None,
+    };
+
+    let service = hyper::service::service_fn(move |mut req: hyper::Request<hyper::Body>| {
+        if let Some(client_certificate) = &client_certificate {
+            req.extensions_mut().insert(client_certificate.clone());
+        }
+        service.call(req)
+    });
+
+    hyper::server::conn::Http::new()
+        .serve_connection(stream, service)
+        .await
+        .context("Error serving connection")
+}
+
+/// Binds the server for the routes, terminating TLS if it's configured.
+///
+/// The returned future runs the server until `signal` completes.
+pub fn bind_server<T, S>(
+    routes: BoxedFilter<(T,)>,
+    bind_addr: SocketAddr,
+    tls: Option<&TlsSettings>,
+    signal: S,
+) -> Result<Pin<Box<dyn Future<Output = ()> + Send>>>
+where
+    T: Reply + 'static,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let tls = match tls {
+        None => {
+            log::info!("Listening on {}", bind_addr);
+            let (_, server) = warp::serve(routes)
+                .try_bind_with_graceful_shutdown(bind_addr, signal)
+                .context("Error binding server")?;
+            return Ok(Box::pin(server));
+        }
+        Some(tls) => tls,
+    };
+
+    // TLS is terminated here instead of by warp, so that the handlers can see
+    // the client certificate
+    let acceptor = TlsAcceptor::from(Arc::new(
+        build_tls_config(tls).context("Error building TLS configuration")?,
+    ));
+    let listener = std::net::TcpListener::bind(bind_addr).context("Error binding TLS server")?;
+    listener
+        .set_nonblocking(true)
+        .context("Error binding TLS server")?;
+    let service = warp::service(routes);
+    log::info!("Listening on {} (TLS)", bind_addr);
+
+    Ok(Box::pin(async move {
+        let listener = match tokio::net::TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Error setting up TLS listener: {:?}", e);
+                return;
+            }
+        };
+        let mut signal = Box::pin(signal);
+
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                _ = &mut signal => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("Error accepting connection: {:?}", e);
+                        continue;
+                    }
+                },
+            };
+            let acceptor = acceptor.clone();
+            let service = service.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_tls_connection(acceptor, stream, service).await {
+                    log::debug!("Error serving TLS connection from {}: {:?}", remote_addr, e);
+                }
+            });
+        }
+    }))
+}
+
+pub fn bind_coap_server<T>(
+    routes: BoxedFilter<(T,)>,
+    bind_addr: SocketAddr,
+) -> Result<Pin<Box<dyn Future<Output = ()> + Send>>>
+where
+    T: Reply + 'static,
+{
+    let server = fdo_http_wrapper::coap::server::bind(routes, bind_addr)
+        .context("Error binding CoAP server")?;
+    log::info!("Listening on {} (CoAP over UDP and TCP)", bind_addr);
+    Ok(Box::pin(server))
+}
+
+/// Loads a private key, either from a DER file or from a PKCS#11 token
+pub fn load_private_key(location: &PrivateKeyLocation) -> Result<PKey<Private>> {
+    match location {
+        PrivateKeyLocation::File(path) => {
+            let contents = std::fs::read(path).context("Error reading private key")?;
+            PKey::private_key_from_der(&contents).context("Error parsing private key")
+        }
+        PrivateKeyLocation::Pkcs11(uri) => pkcs11::load_private_key(uri),
+    }
+}
+
 pub fn format_conf_env(component: &str) -> String {
     format!("{}_CONF", component_env_prefix(component))
 }
@@ -120,4 +367,24 @@ pub struct ServiceInfoApiReply {
     pub initial_user: Option<ServiceInfoApiReplyInitialUser>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_commands: Option<Vec<(ServiceInfoModule, String, serde_json::Value)>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conditional_commands: Option<Vec<ServiceInfoApiReplyConditionalCommands>>,
+}
+
+/// Commands that are only sent to the device after it returned a specific
+/// ServiceInfo value in an earlier round.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceInfoApiReplyConditionalCommands {
+    pub module: ServiceInfoModule,
+    pub key: String,
+    pub value: serde_json::Value,
+    pub commands: Vec<(ServiceInfoModule, String, serde_json::Value)>,
+}
+
+/// The ServiceInfo a device returned during onboarding, as reported with API version 2.
+///
+/// Binary values are hex-encoded, with `|hex` appended to their key.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ServiceInfoApiDeviceResults {
+    pub service_info: Vec<(ServiceInfoModule, String, serde_json::Value)>,
 }
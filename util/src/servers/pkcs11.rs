@@ -0,0 +1,116 @@
+//! Loads private keys from PKCS#11 tokens through the OpenSSL store API.
+//!
+//! The keys stay on the token: the returned PKey only holds a reference to it, and all
+//! signing operations are performed by the token. This requires an OpenSSL provider that
+//! handles pkcs11: URIs (for example pkcs11-provider) to be enabled in the OpenSSL
+//! configuration. The PIN can be passed in the URI with pin-source or pin-value.
+
+use std::{
+    ffi::CString,
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+};
+
+use anyhow::{bail, Context, Result};
+use foreign_types::ForeignType;
+use openssl::{
+    error::ErrorStack,
+    pkey::{PKey, Private},
+};
+
+#[allow(non_camel_case_types)]
+enum OSSL_STORE_CTX {}
+#[allow(non_camel_case_types)]
+enum OSSL_STORE_INFO {}
+
+// openssl-sys does not bind the store API, the build script checks which numbering the
+// linked OpenSSL uses: 3.0 added OSSL_STORE_INFO_PUBKEY before OSSL_STORE_INFO_PKEY.
+#[cfg(ossl300)]
+const OSSL_STORE_INFO_PKEY: c_int = 4;
+#[cfg(not(ossl300))]
+const OSSL_STORE_INFO_PKEY: c_int = 3;
+
+extern "C" {
+    fn OSSL_STORE_open(
+        uri: *const c_char,
+        ui_method: *const c_void,
+        ui_data: *mut c_void,
+        post_process: *const c_void,
+        post_process_data: *mut c_void,
+    ) -> *mut OSSL_STORE_CTX;
+    fn OSSL_STORE_expect(ctx: *mut OSSL_STORE_CTX, expected_type: c_int) -> c_int;
+    fn OSSL_STORE_load(ctx: *mut OSSL_STORE_CTX) -> *mut OSSL_STORE_INFO;
+    fn OSSL_STORE_eof(ctx: *mut OSSL_STORE_CTX) -> c_int;
+    fn OSSL_STORE_error(ctx: *mut OSSL_STORE_CTX) -> c_int;
+    fn OSSL_STORE_close(ctx: *mut OSSL_STORE_CTX) -> c_int;
+    fn OSSL_STORE_INFO_get_type(info: *const OSSL_STORE_INFO) -> c_int;
+    fn OSSL_STORE_INFO_get1_PKEY(info: *const OSSL_STORE_INFO) -> *mut openssl_sys::EVP_PKEY;
+    fn OSSL_STORE_INFO_free(info: *mut OSSL_STORE_INFO);
+}
+
+struct StoreCtx(*mut OSSL_STORE_CTX);
+
+impl Drop for StoreCtx {
+    fn drop(&mut self) {
+        // SAFETY: the context was returned non-NULL by OSSL_STORE_open, and is only closed here
+        unsafe {
+            OSSL_STORE_close(self.0);
+        }
+    }
+}
+
+pub(super) fn load_private_key(uri: &str) -> Result<PKey<Private>> {
+    openssl_sys::init();
+
+    let c_uri = CString::new(uri).context("Invalid PKCS#11 URI")?;
+    // SAFETY: c_uri is a valid NUL-terminated string that outlives the call, and NULL is
+    // allowed for the UI method and post-processing callbacks and their data
+    let ctx = unsafe {
+        OSSL_STORE_open(
+            c_uri.as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null_mut(),
+        )
+    };
+    if ctx.is_null() {
+        return Err(ErrorStack::get()).context("Error opening PKCS#11 store");
+    }
+    let ctx = StoreCtx(ctx);
+
+    // SAFETY: ctx.0 is a valid store context for the lifetime of ctx
+    if unsafe { OSSL_STORE_expect(ctx.0, OSSL_STORE_INFO_PKEY) } != 1 {
+        return Err(ErrorStack::get()).context("Error limiting PKCS#11 store to keys");
+    }
+
+    // SAFETY: ctx.0 is a valid store context for the lifetime of ctx
+    while unsafe { OSSL_STORE_eof(ctx.0) } == 0 {
+        // SAFETY: ctx.0 is a valid store context, the returned info is owned by us
+        let info = unsafe { OSSL_STORE_load(ctx.0) };
+        if info.is_null() {
+            // SAFETY: ctx.0 is a valid store context for the lifetime of ctx
+            if unsafe { OSSL_STORE_error(ctx.0) } != 0 {
+                return Err(ErrorStack::get()).context("Error loading PKCS#11 object");
+            }
+            continue;
+        }
+        // SAFETY: info is a valid, owned OSSL_STORE_INFO that is freed exactly once here, after
+        // taking a new reference to its key
+        let pkey = unsafe {
+            let pkey = if OSSL_STORE_INFO_get_type(info) == OSSL_STORE_INFO_PKEY {
+                OSSL_STORE_INFO_get1_PKEY(info)
+            } else {
+                ptr::null_mut()
+            };
+            OSSL_STORE_INFO_free(info);
+            pkey
+        };
+        if !pkey.is_null() {
+            // SAFETY: pkey is a valid EVP_PKEY we own a reference to, which PKey takes over
+            return Ok(unsafe { PKey::from_ptr(pkey) });
+        }
+    }
+
+    bail!("No private key found in PKCS#11 store");
+}